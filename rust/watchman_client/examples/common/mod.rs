@@ -0,0 +1,26 @@
+//! Connection setup shared by the examples.
+use watchman_client::prelude::*;
+use watchman_client::test_support::MockServer;
+
+/// The files that the mock server reports
+const MOCK_FILES: &[&str] = &["README.md", "src/lib.rs", "src/main.rs"];
+
+/// Connect to the watchman server.
+/// If `mock` is true, or if the server isn't available, an in-process
+/// mock server is used instead so that the example can still be run.
+/// The mock server is returned so that it stays alive for as long
+/// as the client.
+pub async fn connect(
+    mock: bool,
+) -> Result<(Client, Option<MockServer>), Box<dyn std::error::Error>> {
+    if !mock {
+        match Connector::new().connect().await {
+            Ok(client) => return Ok((client, None)),
+            Err(err) => eprintln!("{}; using the mock server", err),
+        }
+    }
+    let server = MockServer::new();
+    server.serve_files(MOCK_FILES);
+    let client = server.connect();
+    Ok((client, Some(server)))
+}
@@ -3,9 +3,15 @@ use std::path::PathBuf;
 use structopt::StructOpt;
 use watchman_client::prelude::*;
 
+mod common;
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Perform a glob query for a path, using watchman")]
 struct Opt {
+    #[structopt(long)]
+    /// Use an in-process mock server instead of watchman
+    mock: bool,
+
     #[structopt(default_value = ".")]
     path: PathBuf,
 }
@@ -22,7 +28,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
-    let client = Connector::new().connect().await?;
+    let (client, _mock) = common::connect(opt.mock).await?;
     let resolved = client
         .resolve_root(CanonicalPath::canonicalize(opt.path)?)
         .await?;
@@ -5,9 +5,15 @@ use std::path::PathBuf;
 use structopt::StructOpt;
 use watchman_client::prelude::*;
 
+mod common;
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Query files changed since a timestamp")]
 struct Opt {
+    #[structopt(long)]
+    /// Use an in-process mock server instead of watchman
+    mock: bool,
+
     #[structopt()]
     /// Specifies the clock. Use `watchman clock <PATH>` to retrieve the current clock of a watched
     /// directory
@@ -34,7 +40,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
-    let client = Connector::new().connect().await?;
+    let (client, _mock) = common::connect(opt.mock).await?;
     let resolved = client
         .resolve_root(CanonicalPath::canonicalize(opt.path)?)
         .await?;
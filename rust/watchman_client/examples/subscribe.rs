@@ -1,12 +1,21 @@
 //! This example shows how to initiate a subscription and print out
 //! file changes as they are reported
+use serde_bser::value::Value;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use watchman_client::prelude::*;
+use watchman_client::test_support::subscription_pdu;
+use watchman_client::SubscriptionData;
+
+mod common;
 
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Subscribe to watchman and stream file changes for a path")]
 struct Opt {
+    #[structopt(long)]
+    /// Use an in-process mock server instead of watchman
+    mock: bool,
+
     #[structopt(default_value = ".")]
     path: PathBuf,
 }
@@ -23,7 +32,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
-    let client = Connector::new().connect().await?;
+    let (client, mock) = common::connect(opt.mock).await?;
     let resolved = client
         .resolve_root(CanonicalPath::canonicalize(opt.path)?)
         .await?;
@@ -33,8 +42,26 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
 
     println!("{} {:#?}", sub.name(), initial);
+
+    if let Some(server) = mock {
+        // Simulate a change followed by the watch being removed
+        server.push(subscription_pdu(
+            sub.name(),
+            "c:0:1",
+            vec!["src/lib.rs".into()],
+        ));
+        let mut canceled = subscription_pdu(sub.name(), "c:0:2", vec![]);
+        if let Value::Object(pdu) = &mut canceled {
+            pdu.insert("canceled".to_string(), Value::Bool(true));
+        }
+        server.push(canceled);
+    }
+
     loop {
         let item = sub.next().await?;
         println!("{:#?}", item);
+        if let SubscriptionData::Canceled = item {
+            return Ok(());
+        }
     }
 }
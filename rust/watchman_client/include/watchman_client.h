@@ -0,0 +1,67 @@
+/* C interface to the watchman_client crate.
+ *
+ * Build the library with:
+ *   cargo rustc --release --features ffi --crate-type cdylib
+ *
+ * Functions that can fail return NULL and, if `error` is not NULL, store
+ * a message in `*error` that must be released with watchman_string_free.
+ * Queries and results are JSON objects encoded as NUL terminated UTF-8
+ * strings; see https://facebook.github.io/watchman/docs/cmd/query.html
+ */
+#ifndef WATCHMAN_CLIENT_H
+#define WATCHMAN_CLIENT_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct WatchmanClient WatchmanClient;
+typedef struct WatchmanRoot WatchmanRoot;
+typedef struct WatchmanSubscription WatchmanSubscription;
+
+/* Invoked on the client's thread with each subscription notification.
+ * `json` is only valid for the duration of the call. */
+typedef void (*WatchmanSubscriptionCallback)(void *userdata, const char *json);
+
+/* Connect to the server.  If `sock_path` is NULL, the server is located
+ * via WATCHMAN_SOCK or the watchman CLI. */
+WatchmanClient *watchman_connect(const char *sock_path, char **error);
+
+/* Disconnect, after releasing any subscriptions made with the client. */
+void watchman_client_free(WatchmanClient *client);
+
+/* Resolve `path` into a watched root, watching it if necessary. */
+WatchmanRoot *watchman_resolve_root(const WatchmanClient *client,
+                                    const char *path, char **error);
+
+/* Returns the absolute path of the resolved directory, which must be
+ * released with watchman_string_free. */
+char *watchman_root_path(const WatchmanRoot *root);
+
+void watchman_root_free(WatchmanRoot *root);
+
+/* Run a query, returning the response, which must be released with
+ * watchman_string_free. */
+char *watchman_query(const WatchmanClient *client, const WatchmanRoot *root,
+                     const char *query, char **error);
+
+/* Subscribe to changes.  `callback` is invoked with each notification
+ * until the subscription is released; if the connection fails it is
+ * invoked a final time with an object holding an `error` member. */
+WatchmanSubscription *watchman_subscribe(const WatchmanClient *client,
+                                         const WatchmanRoot *root,
+                                         const char *query,
+                                         WatchmanSubscriptionCallback callback,
+                                         void *userdata, char **error);
+
+/* Cancel a subscription.  Once this returns the callback is no longer
+ * invoked.  Must not be called from within the callback. */
+void watchman_subscription_free(WatchmanSubscription *subscription);
+
+void watchman_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
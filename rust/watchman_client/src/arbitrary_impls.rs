@@ -0,0 +1,176 @@
+//! Helpers for the `arbitrary::Arbitrary` implementations of the pdu
+//! types, which are enabled by the `arbitrary` feature.
+//!
+//! Most of the implementations are derived; the functions in this
+//! module generate the handful of fields whose types don't implement
+//! `Arbitrary`, or whose values are constrained by the protocol.
+use arbitrary::{Result, Unstructured};
+use serde_bser::value::Value;
+
+/// How deeply nested a generated `Value` may be
+const MAX_VALUE_DEPTH: usize = 3;
+
+/// The field names that the server knows about
+const FIELD_NAMES: &[&str] = &[
+    "name",
+    "exists",
+    "cclock",
+    "oclock",
+    "ctime",
+    "mtime",
+    "size",
+    "mode",
+    "uid",
+    "gid",
+    "ino",
+    "dev",
+    "nlink",
+    "new",
+    "type",
+    "symlink_target",
+    "content.sha1hex",
+];
+
+/// Generates a list of field names for a query or subscription
+pub(crate) fn field_list(u: &mut Unstructured) -> Result<Vec<&'static str>> {
+    let len = u.arbitrary_len::<u8>()?.min(FIELD_NAMES.len());
+    (0..len).map(|_| u.choose(FIELD_NAMES).copied()).collect()
+}
+
+/// Generates the command of a `StateRequest`
+pub(crate) fn state_command(u: &mut Unstructured) -> Result<&'static str> {
+    u.choose(&["state-enter", "state-leave"]).copied()
+}
+
+/// Generates an optional `Value`.
+/// `Some(Value::Null)` is never produced because it is indistinguishable
+/// from `None` once serialized.
+pub(crate) fn optional_value(u: &mut Unstructured) -> Result<Option<Value>> {
+    if u.arbitrary()? {
+        match value(u, MAX_VALUE_DEPTH)? {
+            Value::Null => Ok(None),
+            value => Ok(Some(value)),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+fn value(u: &mut Unstructured, depth: usize) -> Result<Value> {
+    let max_kind = if depth == 0 { 5 } else { 7 };
+    Ok(match u.int_in_range(0..=max_kind)? {
+        0 => Value::Null,
+        1 => Value::Bool(u.arbitrary()?),
+        2 => Value::Integer(u.arbitrary()?),
+        3 => {
+            // NaN doesn't compare equal to itself, so stick to
+            // finite values
+            let real: f64 = u.arbitrary()?;
+            Value::Real(if real.is_finite() { real } else { 0.0 })
+        }
+        4 => Value::Utf8String(u.arbitrary()?),
+        5 => Value::ByteString(u.arbitrary::<Vec<u8>>()?.into()),
+        6 => Value::Array(
+            (0..u.arbitrary_len::<u8>()?)
+                .map(|_| value(u, depth - 1))
+                .collect::<Result<_>>()?,
+        ),
+        _ => Value::Object(
+            (0..u.arbitrary_len::<u8>()?)
+                .map(|_| Ok((u.arbitrary()?, value(u, depth - 1)?)))
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expr::Expr;
+    use crate::pdu::*;
+    use arbitrary::{Arbitrary, Unstructured};
+    use serde::{de::DeserializeOwned, Serialize};
+    use serde_bser::value::Value;
+
+    const ITERATIONS: usize = 500;
+
+    /// A trivial xorshift generator; the tests need repeatable
+    /// input rather than high quality randomness
+    struct Seed(u64);
+
+    impl Seed {
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    self.0 ^= self.0 << 13;
+                    self.0 ^= self.0 >> 7;
+                    self.0 ^= self.0 << 17;
+                    self.0 as u8
+                })
+                .collect()
+        }
+    }
+
+    /// Calls `check` with `ITERATIONS` arbitrary instances of `T`
+    fn for_each_arbitrary<T, F>(check: F)
+    where
+        T: for<'a> Arbitrary<'a> + std::fmt::Debug,
+        F: Fn(T),
+    {
+        let mut seed = Seed(0x2545_f491_4f6c_dd1d);
+        for iteration in 0..ITERATIONS {
+            let data = seed.bytes(iteration % 256 + 16);
+            if let Ok(value) = T::arbitrary(&mut Unstructured::new(&data)) {
+                check(value);
+            }
+        }
+    }
+
+    fn to_value<T: Serialize>(value: &T) -> Value {
+        let data = serde_bser::ser::serialize(Vec::new(), value).unwrap();
+        serde_bser::from_slice(&data).unwrap()
+    }
+
+    /// Checks that serializing, deserializing and serializing again
+    /// yields the same data
+    fn round_trip<T>()
+    where
+        T: for<'a> Arbitrary<'a> + Serialize + DeserializeOwned + std::fmt::Debug,
+    {
+        for_each_arbitrary(|original: T| {
+            let data = serde_bser::ser::serialize(Vec::new(), &original).unwrap();
+            let decoded: T = serde_bser::from_slice(&data)
+                .unwrap_or_else(|err| panic!("failed to decode {:?}: {}", original, err));
+            assert_eq!(to_value(&original), to_value(&decoded), "{:?}", original);
+        });
+    }
+
+    /// Checks that serializing produces a valid PDU
+    fn serializes<T>()
+    where
+        T: for<'a> Arbitrary<'a> + Serialize + std::fmt::Debug,
+    {
+        for_each_arbitrary(|original: T| {
+            to_value(&original);
+        });
+    }
+
+    #[test]
+    fn clocks_round_trip() {
+        round_trip::<ClockSpec>();
+        round_trip::<Clock>();
+        round_trip::<FatClockData>();
+        round_trip::<ScmAwareClockData>();
+        round_trip::<SavedStateClockData>();
+        round_trip::<FileType>();
+    }
+
+    #[test]
+    fn requests_serialize() {
+        serializes::<ClockRequest>();
+        serializes::<WatchProjectRequest>();
+        serializes::<QueryRequest>();
+        serializes::<SubscribeCommand>();
+        serializes::<Unsubscribe>();
+        serializes::<Expr>();
+    }
+}
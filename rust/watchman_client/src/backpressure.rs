@@ -0,0 +1,424 @@
+//! Bound the results queued for a subscription.
+//!
+//! The results of a subscription are queued until `Subscription::next`
+//! takes them, and by default the queue may grow without limit.  A
+//! consumer that falls behind while a large number of files change,
+//! perhaps during a rebase, can use a lot of memory that way.
+//! `SubscriptionBuilder::bounded` limits the queue, and decides what
+//! happens once it is full with an `OverflowPolicy`:
+//!
+//! ```
+//! use watchman_client::backpressure::OverflowPolicy;
+//! use watchman_client::prelude::*;
+//! use watchman_client::{Error, SubscriptionData};
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let (mut subscription, _) = client
+//!     .subscription_builder(&root)
+//!     .bounded(64, OverflowPolicy::Coalesce)
+//!     .subscribe::<NameOnly>()
+//!     .await?;
+//! # /*
+//! loop {
+//!     match subscription.next().await {
+//!         Ok(SubscriptionData::FilesChanged(result)) => {
+//!             // ... index the changes ...
+//!         }
+//!         Err(Error::SubscriptionLagged { .. }) => {
+//!             // ... query for the changes since subscription.clock() ...
+//!         }
+//!         other => {
+//!             other?;
+//!         }
+//!     }
+//! }
+//! # */
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only the results reporting changed files are ever discarded; state
+//! transitions and cancellation are always delivered, even if that
+//! takes the queue beyond its capacity.
+use crate::ReceivedPdu;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use tokio::sync::Notify;
+
+/// What happens when a result arrives for a subscription whose queue is
+/// full; see `SubscriptionBuilder::bounded`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading from the connection until the subscription takes
+    /// a result.  Nothing is lost, but nothing else is delivered on
+    /// the connection in the meantime either: the consumer of the
+    /// subscription must not wait for a response to a request made
+    /// with the same client before taking its next result.
+    Block,
+    /// Discard the oldest of the queued results.  The changes that it
+    /// reported are never delivered.
+    DropOldest,
+    /// Discard all of the queued results, and have `Subscription::next`
+    /// fail with `Error::SubscriptionLagged` in their place.  The
+    /// consumer can catch up by querying for the changes since
+    /// `Subscription::clock`, and carry on taking results afterwards.
+    Coalesce,
+}
+
+/// The capacity of a subscription's queue and what to do when it fills
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Bound {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+/// An item taken from a subscription's queue
+pub(crate) enum Queued {
+    Pdu(ReceivedPdu),
+    /// Stands in for this many results discarded by
+    /// `OverflowPolicy::Coalesce`
+    Lagged(usize),
+}
+
+struct Entry {
+    item: Queued,
+    /// Whether the policy may discard this entry
+    droppable: bool,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Entry>,
+    waker: Option<Waker>,
+    /// Set when the receiver is closed or dropped
+    closed: bool,
+    sender_dropped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when the receiver takes an item, for `Block`
+    space: Notify,
+    bound: Option<Bound>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Returns the two ends of the queue of PDUs for a subscription,
+/// which is unlimited if `bound` is `None`
+pub(crate) fn channel(bound: Option<Bound>) -> (PduSender, PduReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State::default()),
+        space: Notify::new(),
+        bound,
+    });
+    (
+        PduSender {
+            shared: Arc::clone(&shared),
+        },
+        PduReceiver { shared },
+    )
+}
+
+/// The end of the queue of a subscription that the client task
+/// delivers PDUs to
+pub(crate) struct PduSender {
+    shared: Arc<Shared>,
+}
+
+impl PduSender {
+    /// Returns true if the receiver has been closed or dropped
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+
+    /// Queue `pdu` regardless of the capacity of the queue.
+    /// Fails if the receiver has been closed or dropped.
+    pub fn send(&self, pdu: ReceivedPdu) -> Result<(), ReceivedPdu> {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(pdu);
+        }
+        push(&mut state, Queued::Pdu(pdu), false);
+        Ok(())
+    }
+
+    /// Queue `pdu`, applying the overflow policy if the queue is full.
+    /// `droppable` is true for the PDUs that report changed files.
+    /// Fails if the receiver has been closed or dropped.
+    pub async fn deliver(&self, pdu: ReceivedPdu, droppable: bool) -> Result<(), ReceivedPdu> {
+        let bound = match self.shared.bound {
+            Some(bound) => bound,
+            None => return self.send(pdu),
+        };
+        let mut pdu = pdu;
+        loop {
+            pdu = match self.try_deliver(pdu, droppable, bound)? {
+                None => return Ok(()),
+                Some(pdu) => pdu,
+            };
+            self.shared.space.notified().await;
+        }
+    }
+
+    /// Queue `pdu` as `deliver` does, unless the policy is `Block` and
+    /// the queue is full, in which case `pdu` is handed back
+    fn try_deliver(
+        &self,
+        pdu: ReceivedPdu,
+        droppable: bool,
+        bound: Bound,
+    ) -> Result<Option<ReceivedPdu>, ReceivedPdu> {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(pdu);
+        }
+        if state.queue.len() < bound.capacity {
+            push(&mut state, Queued::Pdu(pdu), droppable);
+            return Ok(None);
+        }
+        match bound.policy {
+            OverflowPolicy::Block => return Ok(Some(pdu)),
+            OverflowPolicy::DropOldest => {
+                if let Some(oldest) = state.queue.iter().position(|e| e.droppable) {
+                    state.queue.remove(oldest);
+                }
+                push(&mut state, Queued::Pdu(pdu), droppable);
+            }
+            OverflowPolicy::Coalesce => {
+                coalesce(&mut state.queue);
+                let start = state.queue.iter().position(|e| e.droppable);
+                match (start, droppable) {
+                    (Some(start), true) => {
+                        if let Some(Entry {
+                            item: Queued::Lagged(count),
+                            ..
+                        }) = state.queue.get_mut(start)
+                        {
+                            *count += 1;
+                        }
+                    }
+                    (None, true) => push(&mut state, Queued::Lagged(1), true),
+                    (_, false) => push(&mut state, Queued::Pdu(pdu), false),
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for PduSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.sender_dropped = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn push(state: &mut State, item: Queued, droppable: bool) {
+    state.queue.push_back(Entry { item, droppable });
+    if let Some(waker) = state.waker.take() {
+        waker.wake();
+    }
+}
+
+/// Replace the droppable entries of `queue` with a single
+/// `Queued::Lagged` entry where the first of them was
+fn coalesce(queue: &mut VecDeque<Entry>) {
+    let start = match queue.iter().position(|e| e.droppable) {
+        Some(start) => start,
+        None => return,
+    };
+    let mut lagged = 0;
+    queue.retain(|entry| {
+        if !entry.droppable {
+            return true;
+        }
+        lagged += match entry.item {
+            Queued::Lagged(count) => count,
+            Queued::Pdu(_) => 1,
+        };
+        false
+    });
+    queue.insert(
+        start,
+        Entry {
+            item: Queued::Lagged(lagged),
+            droppable: true,
+        },
+    );
+}
+
+/// The end of the queue of a subscription that `Subscription` takes
+/// PDUs from
+pub(crate) struct PduReceiver {
+    shared: Arc<Shared>,
+}
+
+impl PduReceiver {
+    /// Take the next item, or `None` once the queue is empty and either
+    /// end has gone away
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Queued>> {
+        let mut state = self.shared.lock();
+        match state.queue.pop_front() {
+            Some(entry) => {
+                drop(state);
+                self.shared.space.notify();
+                Poll::Ready(Some(entry.item))
+            }
+            None if state.closed || state.sender_dropped => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    #[cfg(feature = "ffi")]
+    pub async fn recv(&mut self) -> Option<Queued> {
+        tokio::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Refuse any further PDUs; those already queued can still be taken
+    pub fn close(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.space.notify();
+    }
+}
+
+impl Drop for PduReceiver {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_support::{subscription_pdu, MockServer};
+    use crate::Error;
+    use crate::SubscriptionData;
+    use std::time::Instant;
+
+    fn pdu(tag: &str) -> ReceivedPdu {
+        ReceivedPdu {
+            received: Instant::now(),
+            data: bytes::Bytes::from(tag.to_string()),
+        }
+    }
+
+    async fn deliver(tx: &PduSender, tag: &str, droppable: bool) {
+        assert!(tx.deliver(pdu(tag), droppable).await.is_ok());
+    }
+
+    fn drain(receiver: &mut PduReceiver) -> Vec<String> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut items = vec![];
+        while let Poll::Ready(Some(item)) = receiver.poll_recv(&mut cx) {
+            items.push(match item {
+                Queued::Pdu(pdu) => String::from_utf8(pdu.data.to_vec()).unwrap(),
+                Queued::Lagged(count) => format!("lagged {}", count),
+            });
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let bound = |policy| {
+            Some(Bound {
+                capacity: 2,
+                policy,
+            })
+        };
+
+        let (tx, mut rx) = channel(bound(OverflowPolicy::DropOldest));
+        deliver(&tx, "a", true).await;
+        deliver(&tx, "enter", false).await;
+        deliver(&tx, "b", true).await;
+        deliver(&tx, "c", true).await;
+        assert_eq!(drain(&mut rx), vec!["enter", "c"]);
+
+        let (tx, mut rx) = channel(bound(OverflowPolicy::Coalesce));
+        for tag in &["a", "b", "c"] {
+            deliver(&tx, tag, true).await;
+        }
+        deliver(&tx, "enter", false).await;
+        deliver(&tx, "d", true).await;
+        assert_eq!(drain(&mut rx), vec!["lagged 4", "enter"]);
+        deliver(&tx, "e", true).await;
+        assert_eq!(drain(&mut rx), vec!["e"]);
+
+        let (tx, mut rx) = channel(bound(OverflowPolicy::Block));
+        deliver(&tx, "a", true).await;
+        deliver(&tx, "b", true).await;
+        let blocked = tokio::spawn(async move {
+            deliver(&tx, "c", true).await;
+            tx
+        });
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        assert_eq!(drain(&mut rx), vec!["a", "b"]);
+        let tx = blocked.await.unwrap();
+        assert_eq!(drain(&mut rx), vec!["c"]);
+
+        drop(rx);
+        assert!(tx.deliver(pdu("d"), true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn coalesces_subscription() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let (mut sub, _) = client
+            .subscription_builder(&root)
+            .bounded(1, OverflowPolicy::Coalesce)
+            .subscribe::<NameOnly>()
+            .await
+            .unwrap();
+
+        for (clock, file) in &[("c:0:2", "a.rs"), ("c:0:3", "b.rs"), ("c:0:4", "c.rs")] {
+            server.push(subscription_pdu(sub.name(), clock, vec![(*file).into()]));
+        }
+        // A request issued after the pushes is only answered once they
+        // have all been delivered to the subscription's queue
+        client
+            .clock(&root, SyncTimeout::DisableCookie)
+            .await
+            .unwrap();
+        assert!(matches!(
+            sub.next().await,
+            Err(Error::SubscriptionLagged { dropped: 3 })
+        ));
+
+        server.push(subscription_pdu(sub.name(), "c:0:5", vec!["d.rs".into()]));
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::FilesChanged(_)
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+//! A command line tool built on this crate, useful for debugging and as
+//! an executable reference for its APIs.
+//! Build it with `cargo build --features cli`.
+use serde_bser::de::{Bunser, SliceRead};
+use serde_bser::value::Value;
+use std::io::Read;
+use std::path::PathBuf;
+use structopt::StructOpt;
+use watchman_client::prelude::*;
+use watchman_client::SubscriptionData;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Query and subscribe to the watchman server")]
+struct Opt {
+    #[structopt(long, global = true)]
+    /// The path to the server's unix domain socket.  If not specified,
+    /// `WATCHMAN_SOCK` or the watchman CLI is used to find it.
+    sock: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Print the files matching globs
+    Glob {
+        #[structopt(short, long, default_value = ".")]
+        /// The directory relative to which the globs are matched
+        path: PathBuf,
+
+        #[structopt(required = true)]
+        globs: Vec<String>,
+    },
+
+    /// Print the files that changed since a clock
+    Since {
+        #[structopt(short, long, default_value = ".")]
+        /// The watched directory
+        path: PathBuf,
+
+        /// The clock; use `watchman-rs clock` to retrieve the current clock
+        clock: String,
+    },
+
+    /// Print the current clock
+    Clock {
+        #[structopt(short, long, default_value = ".")]
+        /// The watched directory
+        path: PathBuf,
+    },
+
+    /// Print file changes as they are reported
+    Subscribe {
+        #[structopt(short, long, default_value = ".")]
+        /// The watched directory
+        path: PathBuf,
+
+        #[structopt(long)]
+        /// Print each notification as a line of JSON
+        json: bool,
+    },
+
+    /// Decode BSER PDUs, such as those captured from the server, and
+    /// print them as JSON
+    DumpPdu {
+        /// The file containing the PDUs, or stdin if not specified
+        file: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run(Opt::from_args()).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run(opt: Opt) -> Result<(), Box<dyn std::error::Error>> {
+    if let Command::DumpPdu { file } = &opt.command {
+        return dump_pdus(file.as_ref());
+    }
+
+    let mut connector = Connector::new();
+    if let Some(sock) = &opt.sock {
+        connector = connector.unix_domain_socket(sock);
+    }
+    let client = connector.connect().await?;
+
+    match opt.command {
+        Command::Glob { path, globs } => {
+            let root = client
+                .resolve_root(CanonicalPath::canonicalize(path)?)
+                .await?;
+            let globs: Vec<&str> = globs.iter().map(String::as_str).collect();
+            for file in client.glob(&root, &globs).await? {
+                println!("{}", file.display());
+            }
+        }
+        Command::Since { path, clock } => {
+            let root = client
+                .resolve_root(CanonicalPath::canonicalize(path)?)
+                .await?;
+            let result = client
+                .query::<NameOnly>(
+                    &root,
+                    QueryRequestCommon {
+                        since: Some(Clock::Spec(ClockSpec::parse(&clock)?)),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            if result.is_fresh_instance {
+                eprintln!("fresh instance: listing every file");
+            }
+            for file in result.files.unwrap_or_default() {
+                println!("{}", file.name.display());
+            }
+            eprintln!("clock: {}", serde_json::to_string(&result.clock)?);
+        }
+        Command::Clock { path } => {
+            let root = client
+                .resolve_root(CanonicalPath::canonicalize(path)?)
+                .await?;
+            let clock = client.clock(&root, SyncTimeout::Default).await?;
+            println!("{}", serde_json::to_string(&clock)?);
+        }
+        Command::Subscribe { path, json } => {
+            let root = client
+                .resolve_root(CanonicalPath::canonicalize(path)?)
+                .await?;
+            let (mut subscription, _) = client
+                .subscribe::<NameOnly>(&root, SubscribeRequest::default())
+                .await?;
+            loop {
+                let data = subscription.next().await?;
+                if json {
+                    println!("{}", notification_json(&data)?);
+                } else {
+                    println!("{:#?}", data);
+                }
+                if let SubscriptionData::Canceled = data {
+                    return Ok(());
+                }
+            }
+        }
+        Command::DumpPdu { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+fn notification_json(
+    data: &SubscriptionData<NameOnly>,
+) -> Result<serde_json::Value, serde_json::Error> {
+    Ok(match data {
+        SubscriptionData::Canceled => serde_json::json!({ "canceled": true }),
+        SubscriptionData::FilesChanged(result) => serde_json::json!({
+            "clock": serde_json::to_value(&result.clock)?,
+            "is_fresh_instance": result.is_fresh_instance,
+            "files": result
+                .files
+                .iter()
+                .flatten()
+                .map(|file| file.name.to_string_lossy())
+                .collect::<Vec<_>>(),
+        }),
+        SubscriptionData::StateEnter { state_name, .. } => {
+            serde_json::json!({ "state-enter": state_name })
+        }
+        SubscriptionData::StateLeave { state_name, .. } => {
+            serde_json::json!({ "state-leave": state_name })
+        }
+    })
+}
+
+fn dump_pdus(file: Option<&PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut data = vec![];
+    match file {
+        Some(file) => data = std::fs::read(file)?,
+        None => {
+            std::io::stdin().read_to_end(&mut data)?;
+        }
+    }
+
+    let mut remaining = data.as_slice();
+    while !remaining.is_empty() {
+        let pdu = Bunser::new(SliceRead::new(remaining)).read_pdu()?;
+        let size = (pdu.start + pdu.len) as usize;
+        if size > remaining.len() {
+            return Err(format!(
+                "truncated PDU: expected {} bytes but only {} remain",
+                size,
+                remaining.len()
+            )
+            .into());
+        }
+        let value: Value = serde_bser::from_slice(&remaining[..size])?;
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        remaining = &remaining[size..];
+    }
+    Ok(())
+}
@@ -0,0 +1,391 @@
+//! A client for synchronous code.
+//!
+//! `blocking::Client` owns a tokio runtime, which runs on a background
+//! thread, and blocks the calling thread until each request completes,
+//! so that tools which have no other use for async can talk to watchman
+//! without bringing up a runtime of their own:
+//!
+//! ```no_run
+//! use watchman_client::blocking;
+//! use watchman_client::prelude::{CanonicalPath, Connector, NameOnly, SubscribeRequest};
+//! use watchman_client::SubscriptionData;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = blocking::Client::connect(Connector::new())?;
+//! let root = client.resolve_root(CanonicalPath::canonicalize(".")?)?;
+//! println!("files: {:#?}", client.glob(&root, &["**/*.rs"])?);
+//!
+//! let (sub, _) = client.subscribe::<NameOnly>(&root, SubscribeRequest::default())?;
+//! for data in sub.iter() {
+//!     if let SubscriptionData::FilesChanged(result) = data? {
+//!         println!("{:?}", result.files);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The runtime is shut down once the client, every clone of it and
+//! every subscription made with them has been dropped.
+use crate::blocking_subscription::{ended, forward, ConnectFn, Item};
+use crate::pdu::{
+    ClockSpec, QueryRequestCommon, QueryResult, SubscribeRequest, SubscribeResponse, SyncTimeout,
+};
+use crate::{CanonicalPath, Connector, Error, QueryFieldList, ResolvedRoot, SubscriptionData};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+
+/// The background thread that drives the runtime.
+/// Dropping this shuts the runtime down and waits for the thread to
+/// exit.
+struct RuntimeThread {
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for RuntimeThread {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// A connection to the server whose methods block the calling thread
+/// until the server has responded.
+///
+/// The methods mirror those of the async `Client`, which does the
+/// work on the runtime thread.
+#[derive(Clone)]
+pub struct Client {
+    client: crate::Client,
+    handle: Handle,
+    runtime: Arc<RuntimeThread>,
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Client")
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+impl Client {
+    /// Start the runtime and connect to the server using `connector`,
+    /// blocking until the connection has been established
+    pub fn connect(connector: Connector) -> Result<Self, Error> {
+        Self::spawn(Box::new(move || {
+            Box::pin(async move { connector.connect().await })
+        }))
+    }
+
+    pub(crate) fn spawn(connect: ConnectFn) -> Result<Self, Error> {
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+
+        let thread = std::thread::Builder::new()
+            .name("watchman-client".to_string())
+            .spawn(move || {
+                let mut runtime = match tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        ready_tx.send(Err(Error::from(err))).ok();
+                        return;
+                    }
+                };
+                match runtime.block_on(connect()) {
+                    Ok(client) => {
+                        ready_tx.send(Ok((client, runtime.handle().clone()))).ok();
+                    }
+                    Err(err) => {
+                        ready_tx.send(Err(err)).ok();
+                        return;
+                    }
+                }
+                runtime.block_on(shutdown_rx).ok();
+            })?;
+
+        let runtime = RuntimeThread {
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        };
+        match ready_rx.recv() {
+            Ok(Ok((client, handle))) => Ok(Self {
+                client,
+                handle,
+                runtime: Arc::new(runtime),
+            }),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(Error::generic("the runtime thread panicked")),
+        }
+    }
+
+    /// Run `future` on the runtime thread and block until it completes
+    fn block_on<T, Fut>(&self, future: Fut) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        Fut: Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.handle.spawn(async move {
+            tx.send(future.await).ok();
+        });
+        rx.recv()
+            .map_err(|_| Error::generic("the runtime thread has shut down"))?
+    }
+
+    /// Returns the async client that services this one, for the
+    /// requests that have no blocking equivalent
+    pub fn async_client(&self) -> &crate::Client {
+        &self.client
+    }
+
+    /// Resolve `path` to its root; see `Client::resolve_root`
+    pub fn resolve_root(&self, path: CanonicalPath) -> Result<ResolvedRoot, Error> {
+        let client = self.client.clone();
+        self.block_on(async move { client.resolve_root(path).await })
+    }
+
+    /// Execute a query; see `Client::query`
+    pub fn query<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+    ) -> Result<QueryResult<F>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList + Send + 'static,
+    {
+        let client = self.client.clone();
+        let root = root.clone();
+        self.block_on(async move { client.query(&root, query).await })
+    }
+
+    /// Expand a set of globs; see `Client::glob`
+    pub fn glob(&self, root: &ResolvedRoot, globs: &[&str]) -> Result<Vec<PathBuf>, Error> {
+        let client = self.client.clone();
+        let root = root.clone();
+        let globs: Vec<String> = globs.iter().map(|g| g.to_string()).collect();
+        self.block_on(async move {
+            let globs: Vec<&str> = globs.iter().map(String::as_str).collect();
+            client.glob(&root, &globs).await
+        })
+    }
+
+    /// Returns the current clock of `root`; see `Client::clock`
+    pub fn clock<T: Into<SyncTimeout>>(
+        &self,
+        root: &ResolvedRoot,
+        sync_timeout: T,
+    ) -> Result<ClockSpec, Error> {
+        let client = self.client.clone();
+        let root = root.clone();
+        let sync_timeout = sync_timeout.into();
+        self.block_on(async move { client.clock(&root, sync_timeout).await })
+    }
+
+    /// Subscribe to changes in `root`; see `Client::subscribe`.
+    /// The results are received by blocking on the returned
+    /// `Subscription`.
+    pub fn subscribe<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: SubscribeRequest,
+    ) -> Result<(Subscription<F>, SubscribeResponse), Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList + Send + 'static,
+    {
+        let client = self.client.clone();
+        let root = root.clone();
+        let (subscription, response) =
+            self.block_on(async move { client.subscribe::<F>(&root, query).await })?;
+
+        let name = subscription.name().to_string();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (cancel, canceled) = oneshot::channel();
+        self.handle.spawn(forward(subscription, sender, canceled));
+        Ok((
+            Subscription {
+                name,
+                receiver,
+                cancel: Some(cancel),
+                _runtime: Arc::clone(&self.runtime),
+            },
+            response,
+        ))
+    }
+}
+
+/// A subscription whose results are received by blocking the calling
+/// thread.  Returned by `blocking::Client::subscribe`.
+///
+/// The subscription is canceled when this is dropped.
+/// Once the subscription has been canceled by the server, or the
+/// connection has failed, the final result is delivered and
+/// subsequent receives fail.
+pub struct Subscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    name: String,
+    receiver: Receiver<Item<F>>,
+    cancel: Option<oneshot::Sender<()>>,
+    _runtime: Arc<RuntimeThread>,
+}
+
+impl<F> std::fmt::Debug for Subscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Subscription")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<F> Subscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    /// Returns the name of the subscription
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Block until the next result is available
+    pub fn recv(&self) -> Result<SubscriptionData<F>, Error> {
+        self.receiver.recv().map_err(|_| ended())?
+    }
+
+    /// Block for at most `timeout` for the next result, returning
+    /// `None` if none arrived in that time
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<SubscriptionData<F>>, Error> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(data) => data.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(ended()),
+        }
+    }
+
+    /// Returns the next result if one is available, without blocking
+    pub fn try_recv(&self) -> Result<Option<SubscriptionData<F>>, Error> {
+        match self.receiver.try_recv() {
+            Ok(data) => data.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(ended()),
+        }
+    }
+
+    /// Returns an iterator that blocks waiting for results, and ends
+    /// once the subscription has ended
+    pub fn iter(&self) -> impl Iterator<Item = Result<SubscriptionData<F>, Error>> + '_ {
+        self.receiver.iter()
+    }
+}
+
+impl<F> Drop for Subscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.send(()).ok();
+        }
+        // Wait for the forwarding task to finish unsubscribing, which it
+        // signals by dropping its sender, so that the runtime isn't shut
+        // down underneath it
+        while self.receiver.recv().is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::NameOnly;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer};
+    use serde_bser::value::Value;
+    use std::path::Path;
+
+    fn connect(server: &Arc<MockServer>) -> Client {
+        let mock = Arc::clone(server);
+        Client::spawn(Box::new(move || {
+            Box::pin(async move { Ok(mock.connect()) })
+        }))
+        .unwrap()
+    }
+
+    fn requested(server: &MockServer, command: &str) -> bool {
+        server
+            .requests()
+            .iter()
+            .any(|r| request_arg(r, 0) == Value::from(command))
+    }
+
+    #[test]
+    fn requests() {
+        let server = Arc::new(MockServer::new());
+        server.serve_files(&["a.rs", "b.rs"]);
+        let client = connect(&server);
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .unwrap();
+
+        assert_eq!(
+            client.glob(&root, &["*.rs"]).unwrap(),
+            vec![PathBuf::from("a.rs"), PathBuf::from("b.rs")]
+        );
+        let result: QueryResult<NameOnly> =
+            client.query(&root, QueryRequestCommon::default()).unwrap();
+        assert_eq!(result.files.unwrap().len(), 2);
+        client.clock(&root, SyncTimeout::Default).unwrap();
+        assert!(requested(&server, "clock"));
+
+        let failed = Client::spawn(Box::new(|| {
+            Box::pin(async { Err(Error::generic("no server")) })
+        }));
+        assert!(failed.is_err());
+    }
+
+    #[test]
+    fn subscribes() {
+        let server = Arc::new(MockServer::new());
+        let client = connect(&server);
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .unwrap();
+        let (sub, _) = client
+            .subscribe::<NameOnly>(&root, SubscribeRequest::default())
+            .unwrap();
+        drop(client);
+
+        assert!(sub.try_recv().unwrap().is_none());
+        server.push(subscription_pdu(sub.name(), "c:0:1", vec!["a.rs".into()]));
+        match sub.iter().next().unwrap().unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                let files = result.files.unwrap();
+                assert_eq!(files[0].name.as_path(), Path::new("a.rs"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(sub
+            .recv_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+
+        drop(sub);
+        assert!(requested(&server, "unsubscribe"));
+    }
+}
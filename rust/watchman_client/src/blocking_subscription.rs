@@ -0,0 +1,295 @@
+//! Consume subscriptions from synchronous code.
+//!
+//! `BlockingSubscription` runs a client and a subscription on a
+//! background thread with its own tokio runtime, and delivers the
+//! results through a `std::sync::mpsc` channel, so that GUI
+//! applications and other synchronous code bases can consume watchman
+//! events without adopting async throughout:
+//!
+//! ```no_run
+//! use watchman_client::blocking_subscription::BlockingSubscription;
+//! use watchman_client::prelude::*;
+//! use watchman_client::SubscriptionData;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let sub: BlockingSubscription<NameOnly> =
+//!     BlockingSubscription::new(Connector::new(), ".", SubscribeRequest::default())?;
+//! for data in sub.iter() {
+//!     if let SubscriptionData::FilesChanged(result) = data? {
+//!         println!("{:?}", result.files);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+pub(crate) type ConnectFuture = Pin<Box<dyn Future<Output = Result<Client, Error>>>>;
+pub(crate) type ConnectFn = Box<dyn FnOnce() -> ConnectFuture + Send>;
+pub(crate) type Item<F> = Result<SubscriptionData<F>, Error>;
+
+pub(crate) fn ended() -> Error {
+    Error::generic("the subscription has ended")
+}
+
+/// Send the results of `subscription` to `sender` until the
+/// subscription ends, or cancel it once `canceled` fires
+pub(crate) async fn forward<F>(
+    mut subscription: Subscription<F>,
+    sender: Sender<Item<F>>,
+    mut canceled: oneshot::Receiver<()>,
+) where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    loop {
+        let data = tokio::select! {
+            _ = &mut canceled => {
+                subscription.cancel().await.ok();
+                return;
+            }
+            data = subscription.next() => data,
+        };
+        let last = matches!(data, Ok(SubscriptionData::Canceled) | Err(_));
+        if sender.send(data).is_err() || last {
+            return;
+        }
+    }
+}
+
+/// A subscription whose results are received by blocking the calling
+/// thread.
+///
+/// The subscription is canceled and the background thread shut down
+/// when this is dropped.
+/// Once the subscription has been canceled by the server, or the
+/// connection has failed, the final result is delivered and
+/// subsequent receives fail.
+pub struct BlockingSubscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    name: String,
+    root: ResolvedRoot,
+    response: SubscribeResponse,
+    receiver: Receiver<Item<F>>,
+    cancel: Option<oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl<F> std::fmt::Debug for BlockingSubscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("BlockingSubscription")
+            .field("name", &self.name)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+impl<F> BlockingSubscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList + Send + 'static,
+{
+    /// Connect to the server using `connector`, resolve `path` and
+    /// subscribe to it with `request`, blocking until the subscription
+    /// has been established
+    pub fn new<P: AsRef<Path>>(
+        connector: Connector,
+        path: P,
+        request: SubscribeRequest,
+    ) -> Result<Self, Error> {
+        Self::spawn(
+            Box::new(move || Box::pin(async move { connector.connect().await })),
+            path.as_ref(),
+            request,
+        )
+    }
+
+    pub(crate) fn spawn(
+        connect: ConnectFn,
+        path: &Path,
+        request: SubscribeRequest,
+    ) -> Result<Self, Error> {
+        let path = CanonicalPath::canonicalize(path)?;
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (cancel, canceled) = oneshot::channel();
+
+        let thread = std::thread::Builder::new()
+            .name("watchman-subscription".to_string())
+            .spawn(move || {
+                let mut runtime = match tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        ready_tx.send(Err(Error::from(err))).ok();
+                        return;
+                    }
+                };
+                runtime.block_on(async move {
+                    let subscribe = async {
+                        let client = connect().await?;
+                        let root = client.resolve_root(path).await?;
+                        let (subscription, response) =
+                            client.subscribe::<F>(&root, request).await?;
+                        Ok::<_, Error>((client, root, subscription, response))
+                    };
+                    let (_client, root, subscription, response) = match subscribe.await {
+                        Ok(subscribed) => subscribed,
+                        Err(err) => {
+                            ready_tx.send(Err(err)).ok();
+                            return;
+                        }
+                    };
+                    ready_tx
+                        .send(Ok((subscription.name().to_string(), root, response)))
+                        .ok();
+                    forward(subscription, sender, canceled).await;
+                });
+            })?;
+
+        match ready_rx.recv() {
+            Ok(Ok((name, root, response))) => Ok(Self {
+                name,
+                root,
+                response,
+                receiver,
+                cancel: Some(cancel),
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => {
+                thread.join().ok();
+                Err(err)
+            }
+            Err(_) => Err(Error::generic("the subscription thread panicked")),
+        }
+    }
+
+    /// Returns the name of the subscription
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the resolved root of the subscription
+    pub fn root(&self) -> &ResolvedRoot {
+        &self.root
+    }
+
+    /// Returns the response to the `subscribe` command
+    pub fn response(&self) -> &SubscribeResponse {
+        &self.response
+    }
+
+    /// Block until the next result is available
+    pub fn recv(&self) -> Result<SubscriptionData<F>, Error> {
+        self.receiver.recv().map_err(|_| ended())?
+    }
+
+    /// Block for at most `timeout` for the next result, returning
+    /// `None` if none arrived in that time
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<SubscriptionData<F>>, Error> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(data) => data.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(ended()),
+        }
+    }
+
+    /// Returns the next result if one is available, without blocking
+    pub fn try_recv(&self) -> Result<Option<SubscriptionData<F>>, Error> {
+        match self.receiver.try_recv() {
+            Ok(data) => data.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(ended()),
+        }
+    }
+
+    /// Returns an iterator that blocks waiting for results, and ends
+    /// once the subscription has ended
+    pub fn iter(&self) -> impl Iterator<Item = Result<SubscriptionData<F>, Error>> + '_ {
+        self.receiver.iter()
+    }
+}
+
+impl<F> Drop for BlockingSubscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer};
+    use serde_bser::value::Value;
+    use std::sync::Arc;
+
+    fn spawn(
+        server: &Arc<MockServer>,
+        path: &Path,
+    ) -> Result<BlockingSubscription<NameOnly>, Error> {
+        let mock = Arc::clone(server);
+        BlockingSubscription::spawn(
+            Box::new(move || Box::pin(async move { Ok(mock.connect()) })),
+            path,
+            SubscribeRequest::default(),
+        )
+    }
+
+    #[test]
+    fn receives_and_cancels() {
+        let server = Arc::new(MockServer::new());
+        assert!(spawn(&server, Path::new("/does/not/exist")).is_err());
+
+        let sub = spawn(&server, &std::env::temp_dir()).unwrap();
+        assert!(sub.try_recv().unwrap().is_none());
+        server.push(subscription_pdu(sub.name(), "c:0:1", vec!["a.rs".into()]));
+        match sub.recv().unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                let files = result.files.unwrap();
+                assert_eq!(files[0].name.as_path(), Path::new("a.rs"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(sub
+            .recv_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+
+        drop(sub);
+        assert!(server
+            .requests()
+            .iter()
+            .any(|r| request_arg(r, 0) == Value::from("unsubscribe")));
+    }
+
+    #[test]
+    fn ends_with_connection() {
+        let server = Arc::new(MockServer::new());
+        let sub = spawn(&server, &std::env::temp_dir()).unwrap();
+        server.disconnect_all();
+        assert!(sub.recv().is_err());
+        assert!(sub.recv().is_err());
+        assert_eq!(sub.iter().count(), 0);
+    }
+}
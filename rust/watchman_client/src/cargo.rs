@@ -0,0 +1,150 @@
+//! Watch the files that affect the build of a Cargo project.
+//!
+//! `watch_cargo_project` is a starting point for `cargo watch` style
+//! tools: it resolves the project root and subscribes to the manifests,
+//! lock file, build scripts and sources of the package or workspace,
+//! excluding the `target` directory.
+//! The first result from the subscription lists every matching file;
+//! subsequent results list the files that changed.
+//!
+//! ```
+//! use watchman_client::cargo::watch_cargo_project;
+//! use watchman_client::prelude::*;
+//! use watchman_client::SubscriptionData;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let (root, mut sub) = watch_cargo_project::<NameOnly>(
+//!     &client,
+//!     CanonicalPath::canonicalize(".")?,
+//! )
+//! .await?;
+//! println!("watching {}", root.path().display());
+//! # /*
+//! loop {
+//!     if let SubscriptionData::FilesChanged(result) = sub.next().await? {
+//!         // rebuild
+//!     }
+//! }
+//! # */
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::{Error, Subscription};
+
+/// The files, matched by name anywhere in the tree, that describe how
+/// packages are built
+const BUILD_FILES: &[&str] = &["Cargo.toml", "Cargo.lock", "build.rs"];
+
+/// The directories whose contents are compiled into, or used to test,
+/// the packages
+const SOURCE_DIRS: &[&str] = &["src", "tests", "benches", "examples"];
+
+/// Returns an expression that matches the files that affect the build
+/// of a Cargo package or workspace rooted at the watched directory.
+///
+/// This matches regular files that are either named `Cargo.toml`,
+/// `Cargo.lock` or `build.rs`, or that are beneath a `src`, `tests`,
+/// `benches` or `examples` directory at any depth, except for those
+/// within the `target` and `.cargo` directories at the root.
+pub fn cargo_project_expression() -> Expr {
+    let mut include = vec![Expr::Name(NameTerm {
+        paths: BUILD_FILES.iter().map(Into::into).collect(),
+        wholename: false,
+    })];
+    include.extend(SOURCE_DIRS.iter().map(|dir| {
+        Expr::Match(MatchTerm {
+            // `**/` also matches the root
+            glob: format!("**/{}/**", dir),
+            wholename: true,
+            include_dot_files: true,
+            ..Default::default()
+        })
+    }));
+
+    Expr::All(vec![
+        Expr::FileType(FileType::Regular),
+        Expr::Not(Box::new(Expr::Any(vec![
+            Expr::DirName(DirNameTerm {
+                path: "target".into(),
+                depth: None,
+            }),
+            Expr::DirName(DirNameTerm {
+                path: ".cargo".into(),
+                depth: None,
+            }),
+        ]))),
+        Expr::Any(include),
+    ])
+}
+
+/// Resolve the root for `path` and subscribe to the files that affect
+/// the build of the Cargo project there; see
+/// `cargo_project_expression`.
+///
+/// Returns the resolved root along with the subscription.
+/// The first result of the subscription is a fresh instance listing
+/// every matching file; subsequent results contain the changes.
+/// `F` selects the fields of the results, as for `Client::subscribe`.
+pub async fn watch_cargo_project<F>(
+    client: &Client,
+    path: CanonicalPath,
+) -> Result<(ResolvedRoot, Subscription<F>), Error>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    let root = client.resolve_root(path).await?;
+    let (subscription, _) = client
+        .subscribe(
+            &root,
+            SubscribeRequest {
+                expression: Some(cargo_project_expression()),
+                ..Default::default()
+            },
+        )
+        .await?;
+    Ok((root, subscription))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer};
+    use serde_bser::value::Value;
+
+    #[tokio::test]
+    async fn subscribes_with_expression() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let (root, sub) = watch_cargo_project::<NameOnly>(
+            &client,
+            CanonicalPath::with_canonicalized_path("/repo/crate".into()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(root.path(), std::path::Path::new("/repo/crate"));
+
+        let subscribe = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .unwrap();
+        let request = match request_arg(&subscribe, 3) {
+            Value::Object(request) => request,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(!request.contains_key("empty_on_fresh_instance"));
+        let expression = format!("{:?}", request["expression"]);
+        for needle in &["Cargo.lock", "build.rs", "**/src/**", "target"] {
+            assert!(expression.contains(needle), "{} in {}", needle, expression);
+        }
+        sub.cancel().await.unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+//! Match paths without regard to case.
+//!
+//! On case insensitive filesystems, such as the defaults on Windows
+//! and macOS, `README.md` and `readme.md` name the same file, but the
+//! server reports the name with the case that it was created with.
+//! A program that compares the paths that a user typed against query
+//! results needs to fold the case of both sides, or it misses matches
+//! whenever the case drifts:
+//!
+//! ```
+//! use watchman_client::case_fold::CaseFoldedNames;
+//! use std::path::Path;
+//!
+//! let names: CaseFoldedNames = vec!["src/Main.rs", "README.md"].into_iter().collect();
+//! assert_eq!(names.get("readme.MD"), Some(Path::new("README.md")));
+//! assert_eq!(names.get("src/main.rs"), Some(Path::new("src/Main.rs")));
+//! ```
+//!
+//! `Client::preserved_case_names` asks the server for the names of the
+//! files matching a set of paths ignoring case.
+//!
+//! Case is folded by lowercasing, which covers the case insensitivity
+//! of the common filesystems but not every locale specific rule.
+//! Paths that are not valid UTF-8 are compared exactly.
+use crate::expr::NameTerm;
+use crate::prelude::*;
+use crate::Error;
+use std::collections::HashMap;
+use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+
+/// Returns `path` with its case folded, so that two paths that differ
+/// only in case fold to the same path
+pub fn fold_path(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(text) => text.to_lowercase().into(),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Returns true if `a` and `b` are the same path, ignoring case
+pub fn eq_ignore_case<A: AsRef<Path>, B: AsRef<Path>>(a: A, b: B) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    a == b || fold_path(a) == fold_path(b)
+}
+
+/// A set of names that can be looked up ignoring case, yielding the
+/// names with their case preserved
+#[derive(Debug, Clone, Default)]
+pub struct CaseFoldedNames {
+    names: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl CaseFoldedNames {
+    /// Create an empty set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `name` to the set
+    pub fn insert<P: Into<PathBuf>>(&mut self, name: P) {
+        let name = name.into();
+        let names = self.names.entry(fold_path(&name)).or_default();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    /// Returns the name that matches `path` ignoring case.
+    /// If several names match, which can happen when the names come
+    /// from a case sensitive filesystem, the one that matches exactly
+    /// is preferred, and otherwise the first one that was inserted.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&Path> {
+        let path = path.as_ref();
+        let names = self.get_all(path);
+        names
+            .iter()
+            .find(|name| *name == path)
+            .or_else(|| names.first())
+            .map(PathBuf::as_path)
+    }
+
+    /// Returns all of the names that match `path` ignoring case
+    pub fn get_all<P: AsRef<Path>>(&self, path: P) -> &[PathBuf] {
+        self.names
+            .get(&fold_path(path.as_ref()))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns true if a name matches `path` ignoring case
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        !self.get_all(path).is_empty()
+    }
+
+    /// Returns the number of names in the set
+    pub fn len(&self) -> usize {
+        self.names.values().map(Vec::len).sum()
+    }
+
+    /// Returns true if the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl<P: Into<PathBuf>> FromIterator<P> for CaseFoldedNames {
+    fn from_iter<I: IntoIterator<Item = P>>(iter: I) -> Self {
+        let mut names = Self::new();
+        for name in iter {
+            names.insert(name);
+        }
+        names
+    }
+}
+
+impl Client {
+    /// Returns the names, as stored by the server, of the files that
+    /// match `paths` ignoring case.  The paths are relative to `root`.
+    /// The result has an entry for each of `paths`, in the same order,
+    /// which is `None` if there is no such file.
+    ///
+    /// This uses the `iname` expression term, which matches ignoring
+    /// case even when the server treats the root as case sensitive.
+    pub async fn preserved_case_names<P: AsRef<Path>>(
+        &self,
+        root: &ResolvedRoot,
+        paths: &[P],
+    ) -> Result<Vec<Option<PathBuf>>, Error> {
+        if paths.is_empty() {
+            return Ok(vec![]);
+        }
+        let result: QueryResult<NameOnly> = self
+            .query(
+                root,
+                QueryRequestCommon {
+                    expression: Some(Expr::All(vec![
+                        Expr::Exists,
+                        Expr::IName(NameTerm {
+                            paths: paths.iter().map(|p| p.as_ref().to_path_buf()).collect(),
+                            wholename: true,
+                        }),
+                    ])),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let names: CaseFoldedNames = result
+            .files
+            .unwrap_or_default()
+            .into_iter()
+            .map(|file| file.name.into_inner())
+            .collect();
+        Ok(paths
+            .iter()
+            .map(|path| names.get(path).map(Path::to_path_buf))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockServer;
+
+    #[test]
+    fn folded_lookup() {
+        assert!(eq_ignore_case("Src/Ärger.RS", "src/ärger.rs"));
+        assert!(!eq_ignore_case("src/a.rs", "src/b.rs"));
+
+        let names: CaseFoldedNames = vec!["Makefile", "makefile", "src/Lib.rs"]
+            .into_iter()
+            .collect();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names.get("makefile"), Some(Path::new("makefile")));
+        assert_eq!(names.get("MAKEFILE"), Some(Path::new("Makefile")));
+        assert_eq!(names.get_all("MakeFile").len(), 2);
+        assert!(names.contains("SRC/lib.rs"));
+        assert_eq!(names.get("src/lib.rs/x"), None);
+    }
+
+    #[tokio::test]
+    async fn preserved_case_names() {
+        let server = MockServer::new();
+        server.serve_files(&["README.md", "src/Main.rs"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let names = client
+            .preserved_case_names(&root, &["readme.md", "SRC/main.rs", "missing"])
+            .await
+            .unwrap();
+        assert_eq!(
+            names,
+            vec![
+                Some(PathBuf::from("README.md")),
+                Some(PathBuf::from("src/Main.rs")),
+                None
+            ]
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! Share one subscription between several consumers.
+//!
+//! Tools such as IDEs have many components that each want to hear
+//! about changes to the same tree, at their own pace: an indexer that
+//! works through changes in the background, a file tree that refreshes
+//! immediately, a linter that only looks when the user pauses.
+//! A `ChangeJournal` records the changes reported by a single
+//! subscription in an append-only, sequence-numbered log, and each
+//! component reads from it through its own `JournalReader`:
+//!
+//! ```
+//! use watchman_client::change_journal::ChangeJournal;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::{subscription_pdu, MockServer};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let journal = ChangeJournal::new(&client, &root, None, None).await?;
+//! let mut indexer = journal.reader();
+//! let mut file_tree = journal.reader();
+//! # server.push(subscription_pdu(journal.name(), "c:0:1", vec!["main.rs".into()]));
+//! let entry = indexer.next().await?;
+//! for path in &entry.paths {
+//!     println!("reindex {}", path.display());
+//! }
+//! // `file_tree` has yet to see the entry
+//! assert_eq!(file_tree.position(), entry.seq);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The journal retains a bounded number of entries; see
+//! `ChangeJournal::set_capacity` for what happens to readers that fall
+//! further behind than that.
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+/// The number of entries retained by a journal unless changed with
+/// `ChangeJournal::set_capacity`
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// A batch of changes recorded by a `ChangeJournal`
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    /// The position of the entry in the journal.  Entries are numbered
+    /// consecutively from 0.
+    pub seq: u64,
+    /// The paths that changed, relative to the root.
+    /// This includes paths that were deleted.
+    pub paths: Vec<PathBuf>,
+    /// If true, the journal can't describe what changed, either
+    /// because the server recrawled the tree or because the reader fell
+    /// too far behind; everything should be treated as changed, and
+    /// `paths` is empty
+    pub is_fresh_instance: bool,
+    /// The clock up to which changes have been recorded
+    pub clock: Option<Clock>,
+}
+
+struct State {
+    entries: VecDeque<Arc<JournalEntry>>,
+    /// The sequence number that the next entry will be given
+    next_seq: u64,
+    capacity: usize,
+    /// Set if the subscription ended, after which nothing is recorded
+    error: Option<String>,
+}
+
+impl State {
+    fn first_seq(&self) -> u64 {
+        self.next_seq - self.entries.len() as u64
+    }
+
+    fn append(&mut self, paths: Vec<PathBuf>, is_fresh_instance: bool, clock: Clock) {
+        self.entries.push_back(Arc::new(JournalEntry {
+            seq: self.next_seq,
+            paths,
+            is_fresh_instance,
+            clock: Some(clock),
+        }));
+        self.next_seq += 1;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Records the changes beneath a root for consumption by any number of
+/// `JournalReader`s.
+///
+/// Changes are collected by a task running on the tokio runtime from
+/// which the journal was created.  Dropping the journal, or calling
+/// `stop`, cancels the subscription; readers can still read the
+/// entries that were recorded.
+pub struct ChangeJournal {
+    name: String,
+    shared: Arc<Shared>,
+    appended: watch::Receiver<u64>,
+    cancel: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for ChangeJournal {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let state = self.shared.state();
+        fmt.debug_struct("ChangeJournal")
+            .field("name", &self.name)
+            .field("first_seq", &state.first_seq())
+            .field("next_seq", &state.next_seq)
+            .field("capacity", &state.capacity)
+            .finish()
+    }
+}
+
+impl ChangeJournal {
+    /// Start recording the changes to the files beneath `root` that
+    /// match `expression`, or to all files if `expression` is `None`.
+    ///
+    /// If `since` is provided, the changes made since that clock are
+    /// recorded as the first entry; otherwise the first entry is a
+    /// fresh instance.
+    pub async fn new(
+        client: &Client,
+        root: &ResolvedRoot,
+        expression: Option<Expr>,
+        since: Option<Clock>,
+    ) -> Result<Self, Error> {
+        let (subscription, _) = client
+            .subscribe::<NameOnly>(
+                root,
+                SubscribeRequest {
+                    since,
+                    expression,
+                    // A fresh instance means that everything changed,
+                    // so listing the files would be redundant
+                    empty_on_fresh_instance: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                entries: VecDeque::new(),
+                next_seq: 0,
+                capacity: DEFAULT_CAPACITY,
+                error: None,
+            }),
+        });
+        let (appended_tx, appended) = watch::channel(0);
+        let (cancel, canceled) = oneshot::channel();
+        let name = subscription.name().to_string();
+        let task = tokio::spawn(record(
+            subscription,
+            Arc::clone(&shared),
+            appended_tx,
+            canceled,
+        ));
+        Ok(Self {
+            name,
+            shared,
+            appended,
+            cancel: Some(cancel),
+            task: Some(task),
+        })
+    }
+
+    /// Returns the name of the underlying subscription
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns a reader positioned after the most recent entry, which
+    /// will read the entries recorded from now on
+    pub fn reader(&self) -> JournalReader {
+        let position = self.shared.state().next_seq;
+        self.reader_at(position)
+    }
+
+    /// Returns a reader positioned at the oldest retained entry
+    pub fn reader_from_start(&self) -> JournalReader {
+        let position = self.shared.state().first_seq();
+        self.reader_at(position)
+    }
+
+    /// Returns a reader positioned at the entry numbered `seq`, which
+    /// may not have been recorded yet.  This lets a component that was
+    /// restarted resume from the position of its previous reader.
+    pub fn reader_at(&self, seq: u64) -> JournalReader {
+        JournalReader {
+            shared: Arc::clone(&self.shared),
+            appended: self.appended.clone(),
+            position: seq,
+        }
+    }
+
+    /// Returns the sequence number that the next entry will be given
+    pub fn next_seq(&self) -> u64 {
+        self.shared.state().next_seq
+    }
+
+    /// Set the number of entries retained by the journal, discarding
+    /// the oldest entries if there are more than `capacity`.
+    ///
+    /// A reader positioned before the oldest retained entry reads an
+    /// entry with `is_fresh_instance` set, numbered just before the
+    /// oldest retained entry, in place of the entries that it missed.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut state = self.shared.state();
+        state.capacity = capacity.max(1);
+        state.trim();
+    }
+
+    /// Stop recording and cancel the subscription
+    pub async fn stop(mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.send(()).ok();
+        }
+        if let Some(task) = self.task.take() {
+            task.await.ok();
+        }
+    }
+}
+
+/// Reads the entries of a `ChangeJournal` in order, independently of
+/// any other readers.  Cloning a reader produces another reader at the
+/// same position.
+#[derive(Clone)]
+pub struct JournalReader {
+    shared: Arc<Shared>,
+    appended: watch::Receiver<u64>,
+    position: u64,
+}
+
+impl std::fmt::Debug for JournalReader {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("JournalReader")
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl JournalReader {
+    /// Returns the sequence number of the next entry to be read
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the next entry if it has been recorded, or `None` if it
+    /// has not.
+    /// Fails once every recorded entry has been read if the
+    /// subscription has ended, for example because the connection to
+    /// the server was lost.
+    pub fn try_next(&mut self) -> Result<Option<Arc<JournalEntry>>, Error> {
+        let state = self.shared.state();
+        let first_seq = state.first_seq();
+        if self.position < first_seq {
+            // The entries that this reader missed have been discarded
+            self.position = first_seq;
+            return Ok(Some(Arc::new(JournalEntry {
+                seq: first_seq - 1,
+                paths: vec![],
+                is_fresh_instance: true,
+                clock: None,
+            })));
+        }
+        match state.entries.get((self.position - first_seq) as usize) {
+            Some(entry) => {
+                self.position += 1;
+                Ok(Some(Arc::clone(entry)))
+            }
+            None => match &state.error {
+                Some(error) => Err(Error::generic(error)),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Returns all of the entries that have been recorded and not yet
+    /// read by this reader
+    pub fn read_available(&mut self) -> Result<Vec<Arc<JournalEntry>>, Error> {
+        let mut entries = vec![];
+        loop {
+            match self.try_next() {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => return Ok(entries),
+                Err(err) if entries.is_empty() => return Err(err),
+                Err(_) => return Ok(entries),
+            }
+        }
+    }
+
+    /// Wait for the next entry to be recorded and return it.
+    /// Fails under the same conditions as `try_next`.
+    pub async fn next(&mut self) -> Result<Arc<JournalEntry>, Error> {
+        loop {
+            if let Some(entry) = self.try_next()? {
+                return Ok(entry);
+            }
+            if self.appended.recv().await.is_none() {
+                // Recording has stopped; there may be a final entry or
+                // error to report
+                return self
+                    .try_next()?
+                    .ok_or_else(|| Error::generic("the change journal was stopped"));
+            }
+        }
+    }
+}
+
+/// Records the changes reported by the subscription until it is
+/// canceled or fails
+async fn record(
+    mut subscription: Subscription<NameOnly>,
+    shared: Arc<Shared>,
+    appended: watch::Sender<u64>,
+    mut canceled: oneshot::Receiver<()>,
+) {
+    loop {
+        let data = tokio::select! {
+            _ = &mut canceled => {
+                shared.state().error = Some("the change journal was stopped".to_string());
+                subscription.cancel().await.ok();
+                return;
+            }
+            data = subscription.next() => data,
+        };
+        let mut state = shared.state();
+        match data {
+            Ok(SubscriptionData::FilesChanged(result)) => {
+                let paths: Vec<PathBuf> = if result.is_fresh_instance {
+                    vec![]
+                } else {
+                    result
+                        .files
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|file| file.name.into_inner())
+                        .collect()
+                };
+                if paths.is_empty() && !result.is_fresh_instance {
+                    continue;
+                }
+                state.append(paths, result.is_fresh_instance, result.clock);
+            }
+            Ok(SubscriptionData::StateEnter { .. }) | Ok(SubscriptionData::StateLeave { .. }) => {
+                continue;
+            }
+            Ok(SubscriptionData::Canceled) => {
+                state.error = Some("the subscription was canceled by the server".to_string());
+            }
+            Err(err) => state.error = Some(err.to_string()),
+        }
+        let ended = state.error.is_some();
+        let next_seq = state.next_seq;
+        drop(state);
+        if ended {
+            return;
+        }
+        appended.broadcast(next_seq).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{subscription_pdu, MockServer};
+
+    fn paths(entry: &JournalEntry) -> Vec<&str> {
+        entry
+            .paths
+            .iter()
+            .map(|path| path.to_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn independent_readers() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let journal = ChangeJournal::new(&client, &root, None, None)
+            .await
+            .unwrap();
+        let mut fast = journal.reader();
+        let mut slow = journal.reader();
+        assert!(fast.try_next().unwrap().is_none());
+
+        for (clock, file) in &[("c:0:1", "a.rs"), ("c:0:2", "b.rs"), ("c:0:3", "c.rs")] {
+            server.push(subscription_pdu(
+                journal.name(),
+                clock,
+                vec![(*file).into()],
+            ));
+            let entry = fast.next().await.unwrap();
+            assert_eq!(paths(&entry), vec![*file]);
+        }
+        assert_eq!(fast.position(), 3);
+
+        let entries = slow.read_available().unwrap();
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), [0, 1, 2]);
+        let mut late = journal.reader_from_start();
+        assert_eq!(late.next().await.unwrap().seq, 0);
+
+        // Readers that fall behind the retained entries are told that
+        // they missed something
+        journal.set_capacity(1);
+        let entry = late.next().await.unwrap();
+        assert!(entry.is_fresh_instance);
+        assert_eq!(entry.seq, 1);
+        assert_eq!(paths(&late.next().await.unwrap()), vec!["c.rs"]);
+
+        journal.stop().await;
+        assert!(fast.next().await.is_err());
+        assert!(slow.try_next().is_err());
+    }
+}
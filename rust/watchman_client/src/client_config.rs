@@ -0,0 +1,225 @@
+//! Configure connections with a TOML file.
+//!
+//! Deployments can tune how tools connect to the server without
+//! recompiling them by describing the connection in a TOML file and
+//! having the tools use `Connector::from_env_config`, which reads the
+//! file named by the `WATCHMAN_CLIENT_CONFIG` environment variable:
+//!
+//! ```toml
+//! # The server's socket; discovered using the watchman CLI if not set
+//! unix_domain_socket = "/run/watchman/state/sock"
+//! # The watchman CLI used for discovery
+//! watchman_cli_path = "/opt/watchman/bin/watchman"
+//! # How long to wait for the server to respond to a request
+//! request_timeout_ms = 30000
+//! # The sync cookie timeout used by default; 0 disables sync cookies
+//! sync_timeout_ms = 10000
+//! # How long to wait for the connection to be established
+//! connect_timeout_ms = 5000
+//! # How many times to retry connecting, and how long to wait in between
+//! connect_retries = 3
+//! connect_retry_delay_ms = 200
+//! # How many requests may be queued for sending to the server
+//! request_queue_size = 256
+//! # See `Connector::max_outstanding_requests`
+//! max_outstanding_requests = 8
+//! # See `Connector::cache_resolved_roots`
+//! cache_resolved_roots = true
+//! # See `Connector::identify`
+//! identify = true
+//! ```
+//!
+//! Every setting is optional; unknown settings are rejected so that
+//! typos don't go unnoticed.
+//! This module is available when the `toml` feature is enabled.
+use crate::{Connector, Error};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The environment variable naming the config file used by
+/// `Connector::from_env_config`
+pub const CONFIG_ENV_VAR: &str = "WATCHMAN_CLIENT_CONFIG";
+
+/// The settings held by a client config file.
+/// Settings that are `None` leave the corresponding `Connector`
+/// setting unchanged.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// See `Connector::unix_domain_socket`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unix_domain_socket: Option<PathBuf>,
+
+    /// See `Connector::watchman_cli_path`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watchman_cli_path: Option<PathBuf>,
+
+    /// See `Connector::request_timeout`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_timeout_ms: Option<u64>,
+
+    /// See `Connector::sync_timeout`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync_timeout_ms: Option<u64>,
+
+    /// See `Connector::connect_timeout`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_ms: Option<u64>,
+
+    /// See `Connector::connect_retries`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_retries: Option<usize>,
+
+    /// See `Connector::connect_retries`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_retry_delay_ms: Option<u64>,
+
+    /// See `Connector::request_queue_size`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_queue_size: Option<usize>,
+
+    /// See `Connector::max_outstanding_requests`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_outstanding_requests: Option<usize>,
+
+    /// See `Connector::cache_resolved_roots`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_resolved_roots: Option<bool>,
+
+    /// See `Connector::identify`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identify: Option<bool>,
+}
+
+impl ClientConfig {
+    /// Parse the contents of a config file
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        toml::from_str(contents).map_err(|source| Error::Deserialize {
+            source: Box::new(source),
+            data: contents.as_bytes().to_vec(),
+        })
+    }
+
+    /// Read and parse the file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    /// Returns `connector` with the settings of this config applied
+    pub fn apply(&self, mut connector: Connector) -> Connector {
+        let millis = Duration::from_millis;
+        if let Some(path) = &self.unix_domain_socket {
+            connector = connector.unix_domain_socket(path);
+        }
+        if let Some(path) = &self.watchman_cli_path {
+            connector = connector.watchman_cli_path(path);
+        }
+        if let Some(timeout) = self.request_timeout_ms {
+            connector = connector.request_timeout(millis(timeout));
+        }
+        if let Some(timeout) = self.sync_timeout_ms {
+            connector = connector.sync_timeout(millis(timeout));
+        }
+        if let Some(timeout) = self.connect_timeout_ms {
+            connector = connector.connect_timeout(millis(timeout));
+        }
+        if self.connect_retries.is_some() || self.connect_retry_delay_ms.is_some() {
+            let retries = self.connect_retries.unwrap_or(connector.connect_retries);
+            let delay = self
+                .connect_retry_delay_ms
+                .map_or(connector.connect_retry_delay, millis);
+            connector = connector.connect_retries(retries, delay);
+        }
+        if let Some(size) = self.request_queue_size {
+            connector = connector.request_queue_size(size);
+        }
+        if let Some(limit) = self.max_outstanding_requests {
+            connector = connector.max_outstanding_requests(limit);
+        }
+        if let Some(enable) = self.cache_resolved_roots {
+            connector = connector.cache_resolved_roots(enable);
+        }
+        if let Some(enable) = self.identify {
+            connector = connector.identify(enable);
+        }
+        connector
+    }
+}
+
+impl Connector {
+    /// Set up the connector with the system defaults, as
+    /// `Connector::new` does, and then apply the settings in the
+    /// config file at `path`.
+    /// See the [client_config](client_config/index.html) module for
+    /// the format of the file.
+    pub fn from_config<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(ClientConfig::load(path)?.apply(Self::new()))
+    }
+
+    /// Like `from_config`, using the file named by the
+    /// `WATCHMAN_CLIENT_CONFIG` environment variable, or the same as
+    /// `Connector::new` if it isn't set.
+    pub fn from_env_config() -> Result<Self, Error> {
+        match std::env::var_os(CONFIG_ENV_VAR) {
+            Some(path) => Self::from_config(path),
+            None => Ok(Self::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_apply() {
+        let config = ClientConfig::parse(
+            r#"
+            unix_domain_socket = "/tmp/sock"
+            request_timeout_ms = 1500
+            sync_timeout_ms = 0
+            connect_timeout_ms = 250
+            connect_retry_delay_ms = 20
+            request_queue_size = 4
+            max_outstanding_requests = 3
+            identify = true
+            "#,
+        )
+        .unwrap();
+        let connector =
+            config.apply(Connector::default().connect_retries(2, Duration::from_secs(1)));
+        assert_eq!(connector.unix_domain, Some(PathBuf::from("/tmp/sock")));
+        assert_eq!(connector.request_timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(
+            connector.sync_timeout,
+            crate::pdu::SyncTimeout::DisableCookie
+        );
+        assert_eq!(connector.connect_timeout, Some(Duration::from_millis(250)));
+        assert_eq!(connector.connect_retries, 2);
+        assert_eq!(connector.connect_retry_delay, Duration::from_millis(20));
+        assert_eq!(connector.request_queue_size, Some(4));
+        assert_eq!(connector.max_outstanding_requests, Some(3));
+        assert!(connector.identify);
+        assert!(!connector.cache_roots);
+
+        assert!(ClientConfig::parse("request_timeout = 5").is_err());
+        assert!(ClientConfig::parse("identify = 1").is_err());
+        assert_eq!(ClientConfig::parse("").unwrap(), ClientConfig::default());
+    }
+
+    #[test]
+    fn load() {
+        let path =
+            std::env::temp_dir().join(format!("watchman-client-{}.toml", std::process::id()));
+        std::fs::write(&path, "watchman_cli_path = \"/opt/watchman\"\n").unwrap();
+        let connector = Connector::from_config(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            connector.watchman_cli_path,
+            Some(PathBuf::from("/opt/watchman"))
+        );
+        assert!(Connector::from_config(&path).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//! Ask what changed since the last run.
+//!
+//! A tool that runs repeatedly, such as a linter invoked from a
+//! pre-commit hook, only needs to look at the files that changed since
+//! it last ran.  `Client::query_since` returns the files that changed
+//! since a clock along with the clock to pass next time, and a
+//! `ClockStore` keeps that clock between invocations:
+//!
+//! ```
+//! use watchman_client::clock_store::{ClockStore, FileClockStore};
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["main.rs"]);
+//! # let client = server.connect();
+//! # let state_dir = std::env::temp_dir().join(format!("watchman-doc-clock-{}", std::process::id()));
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let store = FileClockStore::new(state_dir.join("lint.clock"));
+//! let changes = client
+//!     .query_since::<NameOnly>(&root, store.load()?, None)
+//!     .await?;
+//! if changes.is_fresh_instance {
+//!     // ... lint everything ...
+//! } else {
+//!     for file in &changes.files {
+//!         println!("lint {}", file.name.display());
+//!     }
+//! }
+//! // Only once the changes have been dealt with
+//! store.save(&changes.clock)?;
+//! # std::fs::remove_dir_all(&state_dir)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Save the new clock only after the changes have been processed, so
+//! that a run that fails part way through is repeated in full next
+//! time.
+//! A clock is only meaningful for the root that it was obtained from,
+//! so use a separate store for each root.
+use crate::pdu::{Clock, QueryRequestCommon, QueryResult};
+use crate::prelude::*;
+use crate::Error;
+use std::path::{Path, PathBuf};
+
+/// The result of `Client::query_since`
+#[derive(Debug, Clone)]
+pub struct SinceResult<F> {
+    /// The files that changed since the clock, including those that
+    /// were deleted.  If `is_fresh_instance` is true, this is instead
+    /// every file that exists.
+    pub files: Vec<F>,
+    /// The clock to pass to the next `query_since`
+    pub clock: Clock,
+    /// True if the changes since the clock are unknown, because no
+    /// clock was given or because the server has restarted or
+    /// recrawled since it was obtained.  In that case anything may
+    /// have changed, and any state derived from previous runs should
+    /// be discarded.
+    pub is_fresh_instance: bool,
+}
+
+impl Client {
+    /// Returns the files beneath `root` that match `expression`, or all
+    /// files if it is `None`, that changed since `clock`, along with the
+    /// clock to pass next time.
+    /// If `clock` is `None`, every matching file is returned as a fresh
+    /// instance.
+    /// The `F` type is a struct defined by the
+    /// [query_result_type!](../macro.query_result_type.html) macro or
+    /// [NameOnly](../struct.NameOnly.html), as for `query`.
+    pub async fn query_since<F>(
+        &self,
+        root: &ResolvedRoot,
+        clock: Option<Clock>,
+        expression: Option<Expr>,
+    ) -> Result<SinceResult<F>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let is_first = clock.is_none();
+        let result: QueryResult<F> = self
+            .query(
+                root,
+                QueryRequestCommon {
+                    since: clock,
+                    expression,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(SinceResult {
+            files: result.files.unwrap_or_default(),
+            clock: result.clock,
+            is_fresh_instance: is_first || result.is_fresh_instance,
+        })
+    }
+}
+
+/// Somewhere to keep a clock between runs of a program
+pub trait ClockStore {
+    /// Returns the saved clock, or `None` if no clock has been saved
+    fn load(&self) -> Result<Option<Clock>, Error>;
+
+    /// Save `clock`, replacing any previously saved clock
+    fn save(&self, clock: &Clock) -> Result<(), Error>;
+}
+
+/// A `ClockStore` that keeps the clock in a file, as JSON.
+/// The file and its parent directories are created when the clock is
+/// first saved.
+#[derive(Debug, Clone)]
+pub struct FileClockStore {
+    path: PathBuf,
+}
+
+impl FileClockStore {
+    /// Keep the clock in the file at `path`
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Returns the path to the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove the saved clock, so that the next run starts afresh
+    pub fn clear(&self) -> Result<(), Error> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ClockStore for FileClockStore {
+    fn load(&self) -> Result<Option<Clock>, Error> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|source| Error::Deserialize {
+                source: Box::new(source),
+                data,
+            })
+    }
+
+    fn save(&self, clock: &Clock) -> Result<(), Error> {
+        let data = serde_json::to_vec(clock).map_err(|source| Error::Serialize {
+            source: Box::new(source),
+        })?;
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Written alongside and renamed into place so that a run that
+        // is interrupted doesn't leave a truncated clock behind
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        std::fs::write(&temp, data)?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer};
+    use serde_bser::value::Value;
+
+    fn since(server: &MockServer) -> Vec<Value> {
+        server
+            .requests()
+            .iter()
+            .filter(|r| request_arg(r, 0) == Value::from("query"))
+            .map(|r| match request_arg(r, 2) {
+                Value::Object(mut query) => query.remove("since").unwrap_or(Value::Null),
+                _ => Value::Null,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn query_since() {
+        let server = MockServer::new();
+        server.serve_files(&["a.rs"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let first = client
+            .query_since::<NameOnly>(&root, None, None)
+            .await
+            .unwrap();
+        assert!(first.is_fresh_instance);
+        assert_eq!(first.files.len(), 1);
+
+        let second = client
+            .query_since::<NameOnly>(&root, Some(first.clock.clone()), Some(Expr::Exists))
+            .await
+            .unwrap();
+        assert_eq!(second.files.len(), 1);
+        let first_clock = match first.clock {
+            Clock::Spec(spec) => Value::from(spec),
+            other => panic!("unexpected clock {:?}", other),
+        };
+        assert_eq!(since(&server), vec![Value::Null, first_clock]);
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = std::env::temp_dir().join(format!("watchman-clock-store-{}", std::process::id()));
+        let store = FileClockStore::new(dir.join("nested/clock"));
+        assert!(store.load().unwrap().is_none());
+        store.clear().unwrap();
+
+        store
+            .save(&Clock::Spec(ClockSpec::StringClock("c:1:2".into())))
+            .unwrap();
+        match store.load().unwrap() {
+            Some(Clock::Spec(ClockSpec::StringClock(clock))) => assert_eq!(clock, "c:1:2"),
+            other => panic!("unexpected clock {:?}", other),
+        }
+        store
+            .save(&Clock::Spec(ClockSpec::UnixTimestamp(5)))
+            .unwrap();
+        assert!(matches!(
+            store.load().unwrap(),
+            Some(Clock::Spec(ClockSpec::UnixTimestamp(5)))
+        ));
+
+        std::fs::write(store.path(), "garbage").unwrap();
+        assert!(store.load().is_err());
+        store.clear().unwrap();
+        assert!(store.load().unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
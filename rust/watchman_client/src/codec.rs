@@ -0,0 +1,222 @@
+//! Choose the encoding that is spoken to the server.
+//!
+//! The client speaks BSER v2 by default, but the server also accepts
+//! line-delimited JSON.  That is useful when the connection passes
+//! through a proxy that only relays text, or when debugging with a
+//! server that has been built without BSER support:
+//!
+//! ```
+//! use watchman_client::codec::Codec;
+//! use watchman_client::prelude::*;
+//!
+//! # async fn connect() -> Result<Client, watchman_client::Error> {
+//! let client = Connector::new().codec(Codec::Json).connect().await?;
+//! # Ok(client)
+//! # }
+//! ```
+//!
+//! JSON can only carry UTF-8 text, so a request that contains anything
+//! that isn't valid UTF-8, such as an unusual path, fails with
+//! `Error::Serialize` rather than being sent.
+//! Responses are translated back into BSER as they are read, so the
+//! choice of codec makes no difference to the rest of the client.
+use crate::Error;
+use bytes::Bytes;
+use serde_bser::value::Value;
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+/// The encoding used for the PDUs exchanged with the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// Version 2 of the binary BSER encoding
+    #[default]
+    BserV2,
+    /// JSON, with a newline terminating each PDU
+    Json,
+}
+
+impl Codec {
+    /// Encode the BSER request `pdu` for sending to the server
+    pub(crate) fn encode(self, pdu: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+        match self {
+            Self::BserV2 => Ok(Cow::Borrowed(pdu)),
+            Self::Json => {
+                let value: Value =
+                    serde_bser::from_slice(pdu).map_err(|source| Error::Serialize {
+                        source: Box::new(source),
+                    })?;
+                let mut line =
+                    serde_json::to_vec(&to_json(value)?).map_err(|source| Error::Serialize {
+                        source: Box::new(source),
+                    })?;
+                line.push(b'\n');
+                Ok(Cow::Owned(line))
+            }
+        }
+    }
+}
+
+/// Translate a line of JSON received from the server into the BSER
+/// PDU that the rest of the client expects
+pub(crate) fn json_to_bser(line: &[u8]) -> Result<Bytes, Error> {
+    let deserialize_error = |source: Box<dyn std::error::Error + Send>| Error::Deserialize {
+        source,
+        data: line.to_vec(),
+    };
+    let value: serde_json::Value =
+        serde_json::from_slice(line).map_err(|source| deserialize_error(Box::new(source)))?;
+    let pdu = serde_bser::ser::serialize(Vec::new(), from_json(value))
+        .map_err(|source| deserialize_error(Box::new(source)))?;
+    Ok(pdu.into())
+}
+
+/// Translate `value` into JSON, failing if it contains a byte string
+/// that isn't valid UTF-8
+pub(crate) fn to_json(value: Value) -> Result<serde_json::Value, Error> {
+    Ok(match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Bool(b) => b.into(),
+        Value::Integer(i) => i.into(),
+        Value::Real(f) => f.into(),
+        Value::Utf8String(s) => s.into(),
+        Value::ByteString(s) => TryInto::<String>::try_into(s)
+            .map_err(|source| Error::Serialize {
+                source: Box::new(source),
+            })?
+            .into(),
+        Value::Array(values) => values.into_iter().map(to_json).collect::<Result<_, _>>()?,
+        Value::Object(map) => serde_json::Value::Object(
+            map.into_iter()
+                .map(|(k, v)| Ok((k, to_json(v)?)))
+                .collect::<Result<_, Error>>()?,
+        ),
+    })
+}
+
+fn from_json(value: serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Bool(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            // BSER integers are signed, so larger values become reals
+            None => Value::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(s) => Value::Utf8String(s),
+        serde_json::Value::Array(values) => {
+            Value::Array(values.into_iter().map(from_json).collect())
+        }
+        serde_json::Value::Object(map) => {
+            Value::Object(map.into_iter().map(|(k, v)| (k, from_json(v))).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_support::subscription_pdu;
+    use crate::SubscriptionData;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    #[test]
+    fn round_trips() {
+        let request = serde_bser::ser::serialize(
+            Vec::new(),
+            Value::Array(vec![
+                "query".into(),
+                Value::ByteString(b"/repo/x".to_vec().into()),
+                Value::Integer(-3),
+            ]),
+        )
+        .unwrap();
+        let line = Codec::Json.encode(&request).unwrap();
+        assert_eq!(&line[..], b"[\"query\",\"/repo/x\",-3]\n");
+        assert_eq!(&Codec::BserV2.encode(&request).unwrap()[..], &request[..]);
+
+        // Paths that aren't UTF-8 can't be represented in JSON
+        let request = serde_bser::ser::serialize(
+            Vec::new(),
+            Value::Array(vec![
+                "query".into(),
+                Value::ByteString(b"/repo/\xffx".to_vec().into()),
+            ]),
+        )
+        .unwrap();
+        assert!(matches!(
+            Codec::Json.encode(&request),
+            Err(Error::Serialize { .. })
+        ));
+        assert!(Codec::BserV2.encode(&request).is_ok());
+
+        let pdu = json_to_bser(br#"{"clock":"c:1","big":18446744073709551615}"#).unwrap();
+        let value: Value = serde_bser::from_slice(&pdu).unwrap();
+        match value {
+            Value::Object(map) => {
+                assert_eq!(map["clock"], Value::from("c:1"));
+                assert_eq!(map["big"], Value::Real(18446744073709551615.0));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            json_to_bser(b"{\"clock\""),
+            Err(Error::Deserialize { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn speaks_json() {
+        let (stream, server) = tokio::net::UnixStream::pair().unwrap();
+        let client = Connector::new()
+            .codec(Codec::Json)
+            .spawn_client(Box::new(stream), None);
+        let (reader, mut writer) = tokio::io::split(server);
+        let mut lines = BufReader::new(reader).lines();
+
+        let subscribe = tokio::spawn(async move {
+            let root = crate::ResolvedRoot {
+                root: "/a".into(),
+                relative: None,
+                watcher: "fake".into(),
+            };
+            let (mut sub, _) = client
+                .subscribe::<NameOnly>(&root, Default::default())
+                .await
+                .unwrap();
+            sub.next().await.unwrap()
+        });
+
+        let line = lines.next_line().await.unwrap().unwrap();
+        let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(request[0], "subscribe");
+        assert_eq!(request[1], "/a");
+        let name = request[2].as_str().unwrap().to_string();
+        let response = serde_json::json!({
+            "version": "fake",
+            "subscribe": name,
+            "clock": "c:0:1",
+        });
+        writer
+            .write_all(format!("{}\n", response).as_bytes())
+            .await
+            .unwrap();
+
+        // Unilateral PDUs are translated too
+        let update = to_json(subscription_pdu(&name, "c:0:2", vec!["a.rs".into()])).unwrap();
+        writer
+            .write_all(format!("{}\n", update).as_bytes())
+            .await
+            .unwrap();
+
+        match subscribe.await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                let files = result.files.unwrap();
+                assert_eq!(*files[0].name, std::path::PathBuf::from("a.rs"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
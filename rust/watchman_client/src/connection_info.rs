@@ -0,0 +1,115 @@
+//! Describe the connection that a client is using.
+//!
+//! Diagnostics and bug reports usually want to say exactly which
+//! socket or named pipe a client connected to and which version of the
+//! server is on the other end.  `Client::connection_info` reports both:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let info = client.connection_info().await?;
+//! if let Some(endpoint) = &info.endpoint {
+//!     println!("connected to {}", endpoint.display());
+//! }
+//! println!("over {:?} to watchman {}", info.transport, info.server_version);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The version is fetched from the server the first time that it is
+//! asked for and remembered by the client and its clones from then on.
+use crate::{Client, Error};
+use std::path::PathBuf;
+
+/// How a client is connected to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TransportKind {
+    /// A unix domain socket
+    UnixSocket,
+    /// A Windows named pipe
+    NamedPipe,
+    /// A stream passed to `Connector::with_stream`, or opened by a
+    /// `Transport`
+    Custom,
+}
+
+/// Describes the connection that a client is using; returned by
+/// `Client::connection_info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The socket or named pipe that the client connected to.
+    /// This is `None` for a `TransportKind::Custom` connection.
+    pub endpoint: Option<PathBuf>,
+    /// How the client is connected to the server
+    pub transport: TransportKind,
+    /// The version reported by the server
+    pub server_version: String,
+}
+
+impl Client {
+    /// Returns the endpoint that this client connected to, how it is
+    /// connected, and the version of the server.
+    /// Only the first call for a client, or any of its clones, sends a
+    /// request to the server.
+    pub async fn connection_info(&self) -> Result<ConnectionInfo, Error> {
+        let cached = self.server_version.lock().unwrap().clone();
+        let server_version = match cached {
+            Some(version) => version,
+            None => {
+                let version = self.version(&[], &[]).await?.version;
+                *self.server_version.lock().unwrap() = Some(version.clone());
+                version
+            }
+        };
+        let transport = match &self.endpoint {
+            None => TransportKind::Custom,
+            Some(_) if cfg!(windows) => TransportKind::NamedPipe,
+            Some(_) => TransportKind::UnixSocket,
+        };
+        Ok(ConnectionInfo {
+            endpoint: self.endpoint.as_deref().map(PathBuf::from),
+            transport,
+            server_version,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer, MOCK_VERSION};
+    use serde_bser::value::Value;
+
+    #[tokio::test]
+    async fn connection_info() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let info = client.connection_info().await.unwrap();
+        assert_eq!(
+            info,
+            ConnectionInfo {
+                endpoint: None,
+                transport: TransportKind::Custom,
+                server_version: MOCK_VERSION.to_string(),
+            }
+        );
+
+        // The version is remembered by the clones of the client
+        client.clone().connection_info().await.unwrap();
+        let versions = server
+            .requests()
+            .into_iter()
+            .filter(|r| request_arg(r, 0) == Value::from("version"))
+            .count();
+        assert_eq!(versions, 1);
+    }
+}
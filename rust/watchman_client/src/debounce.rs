@@ -0,0 +1,163 @@
+//! Buffers the subscription PDUs that arrive in quick succession, and
+//! merges them into a single PDU once the subscription has been quiet
+//! for a while; see `Subscription::debounced`.
+use crate::state_consolidation::merge;
+use crate::ReceivedPdu;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Delay, Instant};
+
+pub(crate) struct Debounce {
+    window: Duration,
+    buffered: Vec<ReceivedPdu>,
+    /// Fires once `window` has passed since the last PDU was buffered
+    quiet: Option<Delay>,
+    ready: VecDeque<ReceivedPdu>,
+}
+
+impl Debounce {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            buffered: vec![],
+            quiet: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Returns true if any PDUs are buffered
+    pub fn is_buffering(&self) -> bool {
+        !self.buffered.is_empty()
+    }
+
+    /// Buffer `pdu`, restarting the quiet window
+    pub fn buffer(&mut self, pdu: ReceivedPdu) {
+        self.buffered.push(pdu);
+        let deadline = Instant::now() + self.window;
+        match &mut self.quiet {
+            Some(quiet) => quiet.reset(deadline),
+            None => self.quiet = Some(tokio::time::delay_until(deadline)),
+        }
+    }
+
+    /// Returns the next PDU to be decoded ahead of all others
+    pub fn take_ready(&mut self) -> Option<ReceivedPdu> {
+        self.ready.pop_front()
+    }
+
+    /// Queue `pdu` to be decoded after those already made ready
+    pub fn push_ready(&mut self, pdu: ReceivedPdu) {
+        self.ready.push_back(pdu);
+    }
+
+    /// Merge the buffered PDUs into one and make it ready.  Should the
+    /// PDUs not be mergeable, they are made ready as they are.
+    pub fn flush(&mut self) {
+        self.quiet = None;
+        if self.buffered.is_empty() {
+            return;
+        }
+        let buffered = std::mem::take(&mut self.buffered);
+        match merge(&buffered) {
+            Ok(data) => self.ready.push_back(ReceivedPdu {
+                received: buffered.last().unwrap().received,
+                data: data.into(),
+            }),
+            Err(_) => self.ready.extend(buffered),
+        }
+    }
+
+    /// Forget the buffered PDUs, which are superseded by a
+    /// `Error::SubscriptionLagged`
+    pub fn discard(&mut self) {
+        self.quiet = None;
+        self.buffered.clear();
+    }
+
+    /// Flush the buffered PDUs once the quiet window has passed
+    pub fn poll_quiet(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let quiet = match &mut self.quiet {
+            Some(quiet) => quiet,
+            None => return Poll::Pending,
+        };
+        match Pin::new(quiet).poll(cx) {
+            Poll::Ready(()) => {
+                self.flush();
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_support::fake_subscription;
+    use crate::SubscriptionData;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn clock_of(result: &QueryResult<NameOnly>) -> &str {
+        match &result.clock {
+            Clock::Spec(ClockSpec::StringClock(clock)) => clock,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn merges_bursts() {
+        let (sub, feed) = fake_subscription::<NameOnly>("/repo");
+        let mut sub = sub.debounced(Duration::from_millis(20));
+
+        feed.files_changed(vec!["a.rs".into(), "b.rs".into()]);
+        feed.files_changed(vec!["b.rs".into(), "c.rs".into()]);
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                let names: Vec<PathBuf> = result
+                    .files
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .map(|f| f.name.to_path_buf())
+                    .collect();
+                assert_eq!(
+                    names,
+                    vec![PathBuf::from("a.rs"), "b.rs".into(), "c.rs".into()]
+                );
+                assert_eq!(clock_of(&result), "c:0:2");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // A state transition ends the burst before it
+        feed.files_changed(vec!["d.rs".into()]);
+        feed.state_enter("hg.update", None);
+        feed.files_changed(vec!["e.rs".into()]);
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => assert_eq!(clock_of(&result), "c:0:3"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::StateEnter { .. }
+        ));
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => assert_eq!(clock_of(&result), "c:0:5"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // What was buffered is delivered before the end of the
+        // subscription is reported
+        feed.files_changed(vec!["f.rs".into()]);
+        drop(feed);
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::FilesChanged(_)
+        ));
+        assert!(sub.next().await.is_err());
+    }
+}
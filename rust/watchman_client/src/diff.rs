@@ -0,0 +1,308 @@
+//! Describe what changed between two clocks.
+//!
+//! The server can only answer "what changed since this clock", relative
+//! to its current view of the tree.  `Client::diff` builds on that using
+//! the created and observed clocks of each file to report the changes
+//! between two earlier points in time, without the caller having to
+//! store a listing of the tree at each of them.
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["src/lib.rs"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let before = client.clock(&root, SyncTimeout::Default).await?;
+//! // ... time passes ...
+//! let diff = client.diff(&root, before, None).await?;
+//! println!("added: {:?}", diff.added);
+//! println!("changed: {:?}", diff.changed);
+//! println!("removed: {:?}", diff.removed);
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::Error;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::path::PathBuf;
+
+query_result_type! {
+    struct DiffFile {
+        name: NameField,
+        exists: ExistsField,
+        cclock: CreatedClockField,
+        oclock: ObservedClockField,
+    }
+}
+
+/// The changes between two clocks, as reported by `Client::diff`.
+/// The paths are relative to the root, and sorted.
+#[derive(Debug, Clone)]
+pub struct SnapshotDiff {
+    /// The files that exist at the later clock but did not exist at
+    /// the earlier clock
+    pub added: Vec<PathBuf>,
+    /// The files that existed at both clocks and were modified in
+    /// between
+    pub changed: Vec<PathBuf>,
+    /// The files that existed at the earlier clock but not at the
+    /// later clock
+    pub removed: Vec<PathBuf>,
+    /// The files that changed after the later clock, which hides
+    /// whether they also changed between the two clocks.
+    /// This is always empty when diffing up to the current time.
+    pub indeterminate: Vec<PathBuf>,
+    /// True if the server couldn't describe the changes since the
+    /// earlier clock, for example because it was restarted or the
+    /// clock is the null clock, in which case every file is listed in
+    /// `added`
+    pub is_fresh_instance: bool,
+    /// The later clock
+    pub clock: ClockSpec,
+}
+
+/// Splits a clock of the form `c:<instance>:<ticks>` into the part
+/// that identifies the server instance and the ticks
+fn parse_clock(clock: &ClockSpec) -> Option<(&str, u64)> {
+    match clock {
+        ClockSpec::StringClock(clock) if clock.starts_with("c:") => {
+            let (instance, ticks) = clock.rsplit_once(':')?;
+            Some((instance, ticks.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// Compares two clocks that were produced by the same server instance
+fn compare_clocks(a: &ClockSpec, b: &ClockSpec) -> Option<Ordering> {
+    let (a_instance, a_ticks) = parse_clock(a)?;
+    let (b_instance, b_ticks) = parse_clock(b)?;
+    if a_instance == b_instance {
+        Some(a_ticks.cmp(&b_ticks))
+    } else {
+        None
+    }
+}
+
+impl Client {
+    /// Returns the changes made beneath `root` between the clocks
+    /// `from` and `to`, or between `from` and now if `to` is `None`.
+    ///
+    /// `to` must be a clock previously returned by the same server
+    /// instance, such as from `Client::clock`.
+    /// Files that were both created and deleted between the two clocks
+    /// are not reported.
+    /// If `from` is not a clock string, such as a named cursor, files
+    /// that were created since `from` can't be told apart from those
+    /// that were modified and are all reported as `changed`.
+    pub async fn diff(
+        &self,
+        root: &ResolvedRoot,
+        from: ClockSpec,
+        to: Option<ClockSpec>,
+    ) -> Result<SnapshotDiff, Error> {
+        if let Some(to) = &to {
+            if parse_clock(to).is_none() {
+                return Err(Error::generic(format!(
+                    "can't diff up to {:?}; it is not a clock string",
+                    to
+                )));
+            }
+        }
+
+        let result: QueryResult<DiffFile> = self
+            .query(
+                root,
+                QueryRequestCommon {
+                    since: Some(Clock::Spec(from.clone())),
+                    relative_root: root.relative.clone(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let now = match result.clock {
+            Clock::Spec(clock) | Clock::ScmAware(FatClockData { clock, .. }) => clock,
+        };
+        if let Some(to) = &to {
+            if compare_clocks(to, &now).is_none() {
+                return Err(Error::generic(format!(
+                    "can't diff up to {:?}; it is from a different server instance than {:?}",
+                    to, now
+                )));
+            }
+        }
+
+        let mut diff = SnapshotDiff {
+            added: vec![],
+            changed: vec![],
+            removed: vec![],
+            indeterminate: vec![],
+            is_fresh_instance: result.is_fresh_instance,
+            clock: to.clone().unwrap_or(now),
+        };
+        for file in result.files.unwrap_or_default() {
+            let name = file.name.into_inner();
+            let after = |clock: &ClockSpec, bound: &ClockSpec| {
+                compare_clocks(clock, bound) == Some(Ordering::Greater)
+            };
+            let created = diff.is_fresh_instance || after(&file.cclock, &from);
+            if let Some(to) = &to {
+                if after(&file.oclock, to) {
+                    // The file changed after `to`, which hides any
+                    // changes made before it, unless it has existed
+                    // continuously since before `to`
+                    let existed_at_to = *file.exists && !after(&file.cclock, to);
+                    if existed_at_to && created {
+                        diff.added.push(name);
+                    } else if diff.is_fresh_instance && !existed_at_to {
+                        // A fresh instance only lists the files that
+                        // exist now, so this one didn't exist at `to`
+                    } else {
+                        diff.indeterminate.push(name);
+                    }
+                    continue;
+                }
+            }
+            match (*file.exists, created) {
+                (true, true) => diff.added.push(name),
+                (true, false) => diff.changed.push(name),
+                (false, false) => diff.removed.push(name),
+                (false, true) => {}
+            }
+        }
+        diff.added.sort();
+        diff.changed.sort();
+        diff.removed.sort();
+        diff.indeterminate.sort();
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    fn clock(ticks: u64) -> ClockSpec {
+        ClockSpec::StringClock(format!("c:123:1:{}", ticks))
+    }
+
+    fn serve(
+        server: &MockServer,
+        is_fresh_instance: bool,
+        files: Vec<(&'static str, bool, u64, u64)>,
+    ) {
+        server.respond("query", move |_| {
+            let files = files
+                .iter()
+                .map(|&(name, exists, cclock, oclock)| {
+                    Value::from(hashmap! {
+                        "name".to_string() => Value::from(name),
+                        "exists".to_string() => Value::Bool(exists),
+                        "cclock".to_string() => Value::from(format!("c:123:1:{}", cclock)),
+                        "oclock".to_string() => Value::from(format!("c:123:1:{}", oclock)),
+                    })
+                })
+                .collect();
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "clock".to_string() => Value::from("c:123:1:30"),
+                "is_fresh_instance".to_string() => Value::Bool(is_fresh_instance),
+                "files".to_string() => Value::Array(files),
+            }
+            .into()
+        });
+    }
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[tokio::test]
+    async fn classifies_changes() {
+        let server = MockServer::new();
+        serve(
+            &server,
+            false,
+            vec![
+                ("changed", true, 5, 15),
+                ("added", true, 12, 12),
+                ("removed", false, 3, 18),
+                ("transient", false, 13, 14),
+                ("later", true, 2, 25),
+                ("added_later", true, 12, 25),
+            ],
+        );
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let diff = client
+            .diff(&root, clock(10), Some(clock(20)))
+            .await
+            .unwrap();
+        assert_eq!(diff.added, paths(&["added", "added_later"]));
+        assert_eq!(diff.changed, paths(&["changed"]));
+        assert_eq!(diff.removed, paths(&["removed"]));
+        assert_eq!(diff.indeterminate, paths(&["later"]));
+        assert!(!diff.is_fresh_instance);
+        assert!(matches!(diff.clock, ClockSpec::StringClock(c) if c == "c:123:1:20"));
+
+        let diff = client.diff(&root, clock(10), None).await.unwrap();
+        assert_eq!(diff.added, paths(&["added", "added_later"]));
+        assert_eq!(diff.changed, paths(&["changed", "later"]));
+        assert!(diff.indeterminate.is_empty());
+        assert!(matches!(diff.clock, ClockSpec::StringClock(c) if c == "c:123:1:30"));
+
+        assert!(client
+            .diff(
+                &root,
+                clock(10),
+                Some(ClockSpec::StringClock("c:456:1:20".into()))
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn fresh_instance() {
+        let server = MockServer::new();
+        serve(
+            &server,
+            true,
+            vec![
+                ("old", true, 2, 5),
+                ("touched", true, 2, 25),
+                ("new", true, 25, 25),
+            ],
+        );
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let diff = client
+            .diff(&root, ClockSpec::null(), Some(clock(20)))
+            .await
+            .unwrap();
+        assert!(diff.is_fresh_instance);
+        // `new` didn't exist yet at the later clock
+        assert_eq!(diff.added, paths(&["old", "touched"]));
+        assert!(diff.indeterminate.is_empty());
+    }
+}
@@ -0,0 +1,344 @@
+//! Accumulate changed paths between builds.
+//!
+//! Build systems commonly collect the paths that were invalidated while
+//! a build was running, or while they were idle, and then consume them
+//! all at once at the start of the next build.
+//! `DirtyTracker` implements that pattern on top of a subscription:
+//!
+//! ```
+//! use watchman_client::dirty_tracker::DirtyTracker;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let tracker = DirtyTracker::new(&client, &root, None, None).await?;
+//! # /*
+//! loop {
+//!     tracker.wait().await;
+//! # */
+//!     let dirty = tracker.take()?;
+//!     if dirty.is_fresh_instance {
+//!         // Everything is potentially dirty: do a full build
+//!     } else {
+//!         // Rebuild whatever depends on dirty.paths
+//!     }
+//!     // Persist dirty.clock, and pass it as `since` when next
+//!     // creating a tracker to pick up where this one left off
+//! # /*
+//! }
+//! # */
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+
+/// The paths that were changed between two calls to
+/// `DirtyTracker::take`
+#[derive(Debug, Clone)]
+pub struct DirtySet {
+    /// The paths that changed, relative to the root, in sorted order.
+    /// This includes paths that were deleted.
+    pub paths: Vec<PathBuf>,
+    /// If true, the tracker can't describe what changed, either
+    /// because it was started without a `since` clock or because the
+    /// server recrawled the tree; everything should be treated as
+    /// dirty, and `paths` is empty
+    pub is_fresh_instance: bool,
+    /// The clock up to which changes have been observed, or `None`
+    /// if the first notification has not yet been received
+    pub clock: Option<Clock>,
+}
+
+impl DirtySet {
+    /// Returns true if nothing changed
+    pub fn is_clean(&self) -> bool {
+        !self.is_fresh_instance && self.paths.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    paths: BTreeSet<PathBuf>,
+    is_fresh_instance: bool,
+    clock: Option<Clock>,
+    /// Set if the subscription failed, after which nothing is tracked
+    error: Option<String>,
+}
+
+/// Tracks the paths that change beneath a root until they are
+/// consumed by `take`.
+///
+/// The tracker can be shared between threads; changes are collected
+/// by a task running on the tokio runtime from which it was created.
+pub struct DirtyTracker {
+    state: Arc<Mutex<State>>,
+    notify: Arc<Notify>,
+    cancel: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for DirtyTracker {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let state = self.state();
+        fmt.debug_struct("DirtyTracker")
+            .field("dirty", &state.paths.len())
+            .field("is_fresh_instance", &state.is_fresh_instance)
+            .field("clock", &state.clock)
+            .finish()
+    }
+}
+
+impl DirtyTracker {
+    /// Start tracking the files beneath `root` that match `expression`,
+    /// or all files if `expression` is `None`.
+    ///
+    /// If `since` is provided, typically the `clock` of the last
+    /// `DirtySet` produced by an earlier tracker, the changes made
+    /// since that clock are reported as dirty; otherwise the first
+    /// `DirtySet` is a fresh instance.
+    pub async fn new(
+        client: &Client,
+        root: &ResolvedRoot,
+        expression: Option<Expr>,
+        since: Option<Clock>,
+    ) -> Result<Self, Error> {
+        let (subscription, _) = client
+            .subscribe::<NameOnly>(
+                root,
+                SubscribeRequest {
+                    since,
+                    expression,
+                    // A fresh instance means that everything is dirty,
+                    // so listing the files would be redundant
+                    empty_on_fresh_instance: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let notify = Arc::new(Notify::new());
+        let (cancel, canceled) = oneshot::channel();
+        let task = tokio::spawn(collect(
+            subscription,
+            Arc::clone(&state),
+            Arc::clone(&notify),
+            canceled,
+        ));
+        Ok(Self {
+            state,
+            notify,
+            cancel: Some(cancel),
+            task: Some(task),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Returns true if there are changes that have not been taken
+    pub fn is_dirty(&self) -> bool {
+        let state = self.state();
+        state.is_fresh_instance || !state.paths.is_empty()
+    }
+
+    /// Returns the clock up to which changes have been observed
+    pub fn clock(&self) -> Option<Clock> {
+        self.state().clock.clone()
+    }
+
+    /// Take the changes accumulated since the previous call, leaving
+    /// the tracker clean.
+    /// Fails if the subscription has ended, for example because the
+    /// connection to the server was lost, after which changes are no
+    /// longer tracked and everything should be treated as dirty.
+    pub fn take(&self) -> Result<DirtySet, Error> {
+        let mut state = self.state();
+        if let Some(error) = &state.error {
+            return Err(Error::generic(error));
+        }
+        Ok(DirtySet {
+            paths: std::mem::take(&mut state.paths).into_iter().collect(),
+            is_fresh_instance: std::mem::replace(&mut state.is_fresh_instance, false),
+            clock: state.clock.clone(),
+        })
+    }
+
+    /// Wait until there are changes to take, or the subscription has
+    /// ended
+    pub async fn wait(&self) {
+        loop {
+            {
+                let state = self.state();
+                if state.is_fresh_instance || !state.paths.is_empty() || state.error.is_some() {
+                    return;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Stop tracking and cancel the subscription
+    pub async fn stop(mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.send(()).ok();
+        }
+        if let Some(task) = self.task.take() {
+            task.await.ok();
+        }
+    }
+}
+
+/// Records the changes reported by the subscription until it is
+/// canceled or fails
+async fn collect(
+    mut subscription: Subscription<NameOnly>,
+    state: Arc<Mutex<State>>,
+    notify: Arc<Notify>,
+    mut canceled: oneshot::Receiver<()>,
+) {
+    loop {
+        let data = tokio::select! {
+            _ = &mut canceled => {
+                subscription.cancel().await.ok();
+                return;
+            }
+            data = subscription.next() => data,
+        };
+        let mut state = match state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match data {
+            Ok(SubscriptionData::FilesChanged(result)) => {
+                if result.is_fresh_instance {
+                    state.is_fresh_instance = true;
+                    state.paths.clear();
+                } else if !state.is_fresh_instance {
+                    state.paths.extend(
+                        result
+                            .files
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|file| file.name.into_inner()),
+                    );
+                }
+                state.clock = Some(result.clock);
+            }
+            Ok(SubscriptionData::StateEnter { .. }) | Ok(SubscriptionData::StateLeave { .. }) => {
+                continue;
+            }
+            Ok(SubscriptionData::Canceled) => {
+                state.error = Some("the subscription was canceled by the server".to_string());
+            }
+            Err(err) => state.error = Some(err.to_string()),
+        }
+        let ended = state.error.is_some();
+        drop(state);
+        notify.notify();
+        if ended {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer};
+    use serde_bser::value::Value;
+
+    #[tokio::test]
+    async fn accumulates_and_takes() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let since = Clock::Spec(ClockSpec::StringClock("c:0:1".to_string()));
+        let tracker = DirtyTracker::new(&client, &root, None, Some(since))
+            .await
+            .unwrap();
+        assert!(!tracker.is_dirty());
+        assert!(tracker.take().unwrap().is_clean());
+
+        let subscribe = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .unwrap();
+        let name = match request_arg(&subscribe, 2) {
+            Value::Utf8String(name) => name,
+            other => panic!("unexpected {:?}", other),
+        };
+        server.push(subscription_pdu(&name, "c:0:2", vec!["b.rs".into()]));
+        server.push(subscription_pdu(
+            &name,
+            "c:0:3",
+            vec!["a.rs".into(), "b.rs".into()],
+        ));
+        while !matches!(tracker.clock(), Some(Clock::Spec(ClockSpec::StringClock(c))) if c == "c:0:3")
+        {
+            tracker.wait().await;
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+
+        let dirty = tracker.take().unwrap();
+        assert_eq!(
+            dirty.paths,
+            vec![PathBuf::from("a.rs"), PathBuf::from("b.rs")]
+        );
+        assert!(!dirty.is_fresh_instance);
+        assert!(!tracker.is_dirty());
+
+        let mut pdu = subscription_pdu(&name, "c:0:4", vec![]);
+        if let Value::Object(pdu) = &mut pdu {
+            pdu.insert("is_fresh_instance".to_string(), Value::Bool(true));
+        }
+        server.push(pdu);
+        tracker.wait().await;
+        let dirty = tracker.take().unwrap();
+        assert!(dirty.is_fresh_instance);
+        assert!(dirty.paths.is_empty());
+
+        tracker.stop().await;
+        assert!(server
+            .requests()
+            .iter()
+            .any(|r| request_arg(r, 0) == Value::from("unsubscribe")));
+    }
+
+    #[tokio::test]
+    async fn reports_lost_subscription() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let tracker = DirtyTracker::new(&client, &root, None, None).await.unwrap();
+        server.disconnect_all();
+        tracker.wait().await;
+        assert!(tracker.take().is_err());
+    }
+}
@@ -0,0 +1,489 @@
+//! Choose the fields of query results at runtime.
+//!
+//! The `query_result_type!` macro fixes the fields of a result type at
+//! compile time, which doesn't suit a service whose users pick the
+//! fields that they want, such as a GraphQL layer.
+//! `Client::query_dynamic` requests a list of `FieldName`s chosen at
+//! runtime instead, and decodes each file into `DynamicFields`, a map
+//! from the name of each field to its typed value:
+//!
+//! ```
+//! use watchman_client::dynamic_fields::{FieldName, FieldValue};
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["src/lib.rs"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let fields: Vec<FieldName> = vec!["name".parse()?, "size".parse()?];
+//! let result = client
+//!     .query_dynamic(&root, QueryRequestCommon::default(), &fields)
+//!     .await?;
+//! for file in result.files.unwrap_or_default() {
+//!     if let Some(FieldValue::Unsigned(size)) = file.get(FieldName::Size) {
+//!         println!("{:?} is {} bytes", file.name(), size);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A field that the server leaves out for a file, as it does for most
+//! of them once the file has been deleted, is absent from its map.
+// NewField is deprecated, but it is still a field that may be requested
+#![allow(deprecated)]
+
+use crate::fields::*;
+use crate::pdu::{ClockSpec, ContentSha1Hex, FileType, QueryRequestCommon, QueryResult};
+use crate::{Client, Error, ResolvedRoot};
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Names a field that the server can report for a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum FieldName {
+    /// `name`; see `NameField`
+    Name,
+    /// `exists`; see `ExistsField`
+    Exists,
+    /// `cclock`; see `CreatedClockField`
+    CreatedClock,
+    /// `oclock`; see `ObservedClockField`
+    ObservedClock,
+    /// `content.sha1hex`; see `ContentSha1HexField`
+    ContentSha1Hex,
+    /// `ctime`; see `CTimeField`
+    CTime,
+    /// `ctime_f`; see `CTimeAsFloatField`
+    CTimeAsFloat,
+    /// `ctime_ms`; see `CTimeAsMillisField`
+    CTimeAsMillis,
+    /// `ctime_us`; see `CTimeAsMicrosField`
+    CTimeAsMicros,
+    /// `ctime_ns`; see `CTimeAsNanosField`
+    CTimeAsNanos,
+    /// `mtime`; see `MTimeField`
+    MTime,
+    /// `mtime_f`; see `MTimeAsFloatField`
+    MTimeAsFloat,
+    /// `mtime_ms`; see `MTimeAsMillisField`
+    MTimeAsMillis,
+    /// `mtime_us`; see `MTimeAsMicrosField`
+    MTimeAsMicros,
+    /// `mtime_ns`; see `MTimeAsNanosField`
+    MTimeAsNanos,
+    /// `size`; see `SizeField`
+    Size,
+    /// `mode`; see `ModeAndPermissionsField`
+    ModeAndPermissions,
+    /// `uid`; see `OwnerUidField`
+    OwnerUid,
+    /// `gid`; see `OwnerGidField`
+    OwnerGid,
+    /// `ino`; see `InodeNumberField`
+    InodeNumber,
+    /// `dev`; see `DeviceNumberField`
+    DeviceNumber,
+    /// `nlink`; see `NumberOfLinksField`
+    NumberOfLinks,
+    /// `type`; see `FileTypeField`
+    FileType,
+    /// `symlink_target`; see `SymlinkTargetField`
+    SymlinkTarget,
+    /// `new`; see `NewField`
+    New,
+}
+
+impl FieldName {
+    /// Every field that the server can report
+    pub const ALL: &'static [FieldName] = &[
+        Self::Name,
+        Self::Exists,
+        Self::CreatedClock,
+        Self::ObservedClock,
+        Self::ContentSha1Hex,
+        Self::CTime,
+        Self::CTimeAsFloat,
+        Self::CTimeAsMillis,
+        Self::CTimeAsMicros,
+        Self::CTimeAsNanos,
+        Self::MTime,
+        Self::MTimeAsFloat,
+        Self::MTimeAsMillis,
+        Self::MTimeAsMicros,
+        Self::MTimeAsNanos,
+        Self::Size,
+        Self::ModeAndPermissions,
+        Self::OwnerUid,
+        Self::OwnerGid,
+        Self::InodeNumber,
+        Self::DeviceNumber,
+        Self::NumberOfLinks,
+        Self::FileType,
+        Self::SymlinkTarget,
+        Self::New,
+    ];
+
+    /// Returns the name of the field as the server knows it
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Name => NameField::field_name(),
+            Self::Exists => ExistsField::field_name(),
+            Self::CreatedClock => CreatedClockField::field_name(),
+            Self::ObservedClock => ObservedClockField::field_name(),
+            Self::ContentSha1Hex => ContentSha1HexField::field_name(),
+            Self::CTime => CTimeField::field_name(),
+            Self::CTimeAsFloat => CTimeAsFloatField::field_name(),
+            Self::CTimeAsMillis => CTimeAsMillisField::field_name(),
+            Self::CTimeAsMicros => CTimeAsMicrosField::field_name(),
+            Self::CTimeAsNanos => CTimeAsNanosField::field_name(),
+            Self::MTime => MTimeField::field_name(),
+            Self::MTimeAsFloat => MTimeAsFloatField::field_name(),
+            Self::MTimeAsMillis => MTimeAsMillisField::field_name(),
+            Self::MTimeAsMicros => MTimeAsMicrosField::field_name(),
+            Self::MTimeAsNanos => MTimeAsNanosField::field_name(),
+            Self::Size => SizeField::field_name(),
+            Self::ModeAndPermissions => ModeAndPermissionsField::field_name(),
+            Self::OwnerUid => OwnerUidField::field_name(),
+            Self::OwnerGid => OwnerGidField::field_name(),
+            Self::InodeNumber => InodeNumberField::field_name(),
+            Self::DeviceNumber => DeviceNumberField::field_name(),
+            Self::NumberOfLinks => NumberOfLinksField::field_name(),
+            Self::FileType => FileTypeField::field_name(),
+            Self::SymlinkTarget => SymlinkTargetField::field_name(),
+            Self::New => NewField::field_name(),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldName {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+/// Parses the name of the field as the server knows it
+impl std::str::FromStr for FieldName {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|field| field.as_str() == name)
+            .ok_or_else(|| Error::generic(format!("unknown field {:?}", name)))
+    }
+}
+
+/// The value of a field of a file
+#[derive(Debug, Clone)]
+pub enum FieldValue {
+    /// The value of `name`
+    Path(PathBuf),
+    /// The value of `exists` or `new`
+    Bool(bool),
+    /// The value of `cclock` or `oclock`
+    Clock(ClockSpec),
+    /// The value of `content.sha1hex`
+    ContentSha1Hex(ContentSha1Hex),
+    /// The value of `ctime`, `mtime`, or one of their integer variants
+    Integer(i64),
+    /// The value of `size`, `mode`, `uid`, `gid`, `ino`, `dev` or
+    /// `nlink`
+    Unsigned(u64),
+    /// The value of `ctime_f` or `mtime_f`
+    Float(f64),
+    /// The value of `type`
+    FileType(FileType),
+    /// The value of `symlink_target`, which is `None` unless the file
+    /// is a symlink
+    SymlinkTarget(Option<String>),
+}
+
+/// The fields of a file from a query whose fields were chosen at
+/// runtime; see `Client::query_dynamic`
+#[derive(Debug, Clone, Default)]
+pub struct DynamicFields {
+    fields: BTreeMap<FieldName, FieldValue>,
+}
+
+impl DynamicFields {
+    /// Returns the value of `field`, if the server reported it
+    pub fn get(&self, field: FieldName) -> Option<&FieldValue> {
+        self.fields.get(&field)
+    }
+
+    /// Returns the name of the file, if it was requested
+    pub fn name(&self) -> Option<&Path> {
+        match self.get(FieldName::Name) {
+            Some(FieldValue::Path(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the fields that the server reported, in the order that
+    /// they are listed in `FieldName::ALL`
+    pub fn iter(&self) -> impl Iterator<Item = (FieldName, &FieldValue)> {
+        self.fields.iter().map(|(field, value)| (*field, value))
+    }
+
+    /// Returns the number of fields that the server reported
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Returns true if the server reported none of the fields
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Consumes the fields and returns them as a map
+    pub fn into_map(self) -> BTreeMap<FieldName, FieldValue> {
+        self.fields
+    }
+}
+
+/// The server's default fields are requested when `DynamicFields` is
+/// used with `Client::query` or `Client::subscribe`
+impl QueryFieldList for DynamicFields {
+    fn field_list() -> Vec<&'static str> {
+        DEFAULT_FIELDS.to_vec()
+    }
+}
+
+/// The server reports each file as an object keyed by field name, or
+/// as a bare name when that is the only field requested.
+/// Fields that this crate doesn't know about are ignored.
+impl<'de> Deserialize<'de> for DynamicFields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldsVisitor;
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = DynamicFields;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an object of file fields, or a file name")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                self.visit_path(PathBuf::from(name))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, name: &[u8]) -> Result<Self::Value, E> {
+                let name = PathBuf::deserialize(serde::de::value::BytesDeserializer::new(name))?;
+                self.visit_path(name)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut fields = BTreeMap::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    let field = match key.parse::<FieldName>() {
+                        Ok(field) => field,
+                        Err(_) => {
+                            map.next_value::<IgnoredAny>()?;
+                            continue;
+                        }
+                    };
+                    if let Some(value) = next_value(&mut map, field)? {
+                        fields.insert(field, value);
+                    }
+                }
+                Ok(DynamicFields { fields })
+            }
+        }
+
+        impl FieldsVisitor {
+            fn visit_path<E>(self, name: PathBuf) -> Result<DynamicFields, E> {
+                let mut fields = BTreeMap::new();
+                fields.insert(FieldName::Name, FieldValue::Path(name));
+                Ok(DynamicFields { fields })
+            }
+        }
+
+        deserializer.deserialize_any(FieldsVisitor)
+    }
+}
+
+/// Decode the value of `field`, returning `None` if the server
+/// reported it as null
+fn next_value<'de, A: MapAccess<'de>>(
+    map: &mut A,
+    field: FieldName,
+) -> Result<Option<FieldValue>, A::Error> {
+    use FieldName::*;
+    Ok(match field {
+        Name => map.next_value::<Option<PathBuf>>()?.map(FieldValue::Path),
+        Exists | New => map.next_value::<Option<bool>>()?.map(FieldValue::Bool),
+        CreatedClock | ObservedClock => map
+            .next_value::<Option<ClockSpec>>()?
+            .map(FieldValue::Clock),
+        FieldName::ContentSha1Hex => Some(FieldValue::ContentSha1Hex(map.next_value()?)),
+        CTime | CTimeAsMillis | CTimeAsMicros | CTimeAsNanos | MTime | MTimeAsMillis
+        | MTimeAsMicros | MTimeAsNanos => map.next_value::<Option<i64>>()?.map(FieldValue::Integer),
+        CTimeAsFloat | MTimeAsFloat => map.next_value::<Option<f64>>()?.map(FieldValue::Float),
+        Size | ModeAndPermissions | OwnerUid | OwnerGid | InodeNumber | DeviceNumber
+        | NumberOfLinks => map.next_value::<Option<u64>>()?.map(FieldValue::Unsigned),
+        FieldName::FileType => map
+            .next_value::<Option<crate::pdu::FileType>>()?
+            .map(FieldValue::FileType),
+        SymlinkTarget => Some(FieldValue::SymlinkTarget(map.next_value()?)),
+    })
+}
+
+impl Client {
+    /// Like `query`, but requests `fields`, which may be chosen at
+    /// runtime, and decodes each file into `DynamicFields`.
+    /// The server's default fields are requested if `fields` is empty.
+    pub async fn query_dynamic(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+        fields: &[FieldName],
+    ) -> Result<QueryResult<DynamicFields>, Error> {
+        let mut query = self.query_request::<DynamicFields>(root, query);
+        if !fields.is_empty() {
+            query.2.fields = fields.iter().map(|field| field.as_str()).collect();
+        }
+        self.generic_request_with_timeout(query, self.request_timeout)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_support::{request_arg, MockServer};
+    use serde_bser::value::Value;
+    use serde_json::json;
+
+    #[test]
+    fn field_names() {
+        for field in FieldName::ALL {
+            assert_eq!(field.as_str().parse::<FieldName>().unwrap(), *field);
+        }
+        assert_eq!(FieldName::ContentSha1Hex.to_string(), "content.sha1hex");
+        assert!("sha1".parse::<FieldName>().is_err());
+    }
+
+    #[test]
+    fn decodes_typed_values() {
+        let file: DynamicFields = serde_json::from_value(json!({
+            "name": "src/lib.rs",
+            "exists": true,
+            "oclock": "c:0:3",
+            "content.sha1hex": {"error": "file deleted while hashing"},
+            "mtime_ns": 1_600_000_000_123_456_789i64,
+            "ctime_f": 1.5,
+            "ino": 18_446_744_073_709_551_615u64,
+            "type": "l",
+            "symlink_target": "../lib.rs",
+            "size": null,
+            "from_the_future": [1, 2],
+        }))
+        .unwrap();
+        assert_eq!(file.name(), Some(Path::new("src/lib.rs")));
+        assert!(matches!(
+            file.get(FieldName::Exists),
+            Some(FieldValue::Bool(true))
+        ));
+        assert!(matches!(
+            file.get(FieldName::ObservedClock),
+            Some(FieldValue::Clock(ClockSpec::StringClock(clock))) if clock == "c:0:3"
+        ));
+        assert!(matches!(
+            file.get(FieldName::ContentSha1Hex),
+            Some(FieldValue::ContentSha1Hex(ContentSha1Hex::Error { .. }))
+        ));
+        assert!(matches!(
+            file.get(FieldName::MTimeAsNanos),
+            Some(FieldValue::Integer(1_600_000_000_123_456_789))
+        ));
+        assert!(matches!(
+            file.get(FieldName::CTimeAsFloat),
+            Some(FieldValue::Float(1.5))
+        ));
+        assert!(matches!(
+            file.get(FieldName::InodeNumber),
+            Some(FieldValue::Unsigned(u64::MAX))
+        ));
+        assert!(matches!(
+            file.get(FieldName::FileType),
+            Some(FieldValue::FileType(FileType::Symlink))
+        ));
+        assert!(matches!(
+            file.get(FieldName::SymlinkTarget),
+            Some(FieldValue::SymlinkTarget(Some(target))) if target == "../lib.rs"
+        ));
+        // Null and unknown fields are left out
+        assert!(file.get(FieldName::Size).is_none());
+        assert_eq!(file.len(), 9);
+
+        let bare: DynamicFields = serde_json::from_value(json!("a.rs")).unwrap();
+        assert_eq!(bare.name(), Some(Path::new("a.rs")));
+        assert_eq!(bare.len(), 1);
+
+        assert!(serde_json::from_value::<DynamicFields>(json!({"size": "big"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn query_dynamic() {
+        let server = MockServer::new();
+        server.serve_files(&["a.rs"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let fields = [FieldName::Name, FieldName::Size, FieldName::FileType];
+        let result = client
+            .query_dynamic(&root, QueryRequestCommon::default(), &fields)
+            .await
+            .unwrap();
+        let file = &result.files.unwrap()[0];
+        assert_eq!(file.name(), Some(Path::new("a.rs")));
+        assert!(matches!(
+            file.get(FieldName::Size),
+            Some(FieldValue::Unsigned(0))
+        ));
+        assert!(matches!(
+            file.get(FieldName::FileType),
+            Some(FieldValue::FileType(FileType::Regular))
+        ));
+
+        // Asking for the name alone gets bare names
+        let result = client
+            .query_dynamic(&root, QueryRequestCommon::default(), &[FieldName::Name])
+            .await
+            .unwrap();
+        assert_eq!(result.files.unwrap()[0].name(), Some(Path::new("a.rs")));
+
+        let requested: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .filter(|r| request_arg(r, 0) == Value::from("query"))
+            .map(|r| match request_arg(&r, 2) {
+                Value::Object(mut query) => query.remove("fields").unwrap(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            requested,
+            vec![
+                Value::Array(vec!["name".into(), "size".into(), "type".into()]),
+                Value::Array(vec!["name".into()]),
+            ]
+        );
+    }
+}
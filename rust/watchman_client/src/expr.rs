@@ -1,13 +1,30 @@
 //! Working with the watchman expression term syntax
+//!
+//! Expressions can be built from the `Expr` variants directly, or with
+//! the constructors and combinators on `Expr`, which read closer to the
+//! intent and flatten nested `allof` and `anyof` terms:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//!
+//! let sources = Expr::suffix("rs")
+//!     .and(Expr::type_file())
+//!     .and_not(Expr::dirname("target"));
+//! // The same, with operators
+//! let sources = Expr::suffix("rs") & Expr::type_file() & !Expr::dirname("target");
+//! let manifests = Expr::name("Cargo.toml") | Expr::name("Cargo.lock");
+//! # let _ = (sources, manifests);
+//! ```
 use crate::pdu::*;
 use maplit::hashmap;
 use serde::Serialize;
 use serde_bser::value::Value;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::path::PathBuf;
 
 /// An expression term used to filter candidate files from query results.
 #[derive(Serialize, Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(into = "Value")]
 pub enum Expr {
     /// Always evaluates to true
@@ -45,10 +62,18 @@ pub enum Expr {
     /// <https://facebook.github.io/watchman/docs/expr/match.html>
     Match(MatchTerm),
 
+    /// Performs a case insensitive glob-style match against the file name
+    /// <https://facebook.github.io/watchman/docs/expr/match.html>
+    IMatch(MatchTerm),
+
     /// Performs an exact match against the file name.
     /// <https://facebook.github.io/watchman/docs/expr/name.html>
     Name(NameTerm),
 
+    /// Performs a case insensitive exact match against the file name.
+    /// <https://facebook.github.io/watchman/docs/expr/name.html>
+    IName(NameTerm),
+
     /// Use PCRE to match the filename.
     /// Note that this is an optional server feature and using this term
     /// on a server that doesn't support this feature will generate an
@@ -77,63 +102,187 @@ pub enum Expr {
     FileType(FileType),
 }
 
-impl Into<Value> for Expr {
-    fn into(self) -> Value {
+impl Expr {
+    /// Matches files whose name ends in `.suffix`, case insensitively
+    pub fn suffix<P: Into<PathBuf>>(suffix: P) -> Self {
+        Self::Suffix(vec![suffix.into()])
+    }
+
+    /// Matches files whose name ends in any of `suffixes`
+    pub fn suffixes<I, P>(suffixes: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        Self::Suffix(suffixes.into_iter().map(Into::into).collect())
+    }
+
+    /// Matches files whose basename is exactly `name`
+    pub fn name<P: Into<PathBuf>>(name: P) -> Self {
+        Self::Name(NameTerm {
+            paths: vec![name.into()],
+            wholename: false,
+        })
+    }
+
+    /// Matches the file whose path relative to the root is exactly
+    /// `path`
+    pub fn wholename<P: Into<PathBuf>>(path: P) -> Self {
+        Self::Name(NameTerm {
+            paths: vec![path.into()],
+            wholename: true,
+        })
+    }
+
+    /// Matches files whose basename matches `glob`
+    pub fn glob<S: Into<String>>(glob: S) -> Self {
+        Self::Match(MatchTerm {
+            glob: glob.into(),
+            ..Default::default()
+        })
+    }
+
+    /// Matches files whose path relative to the root matches `glob`,
+    /// which may use `**` to match any number of directories
+    pub fn wholename_glob<S: Into<String>>(glob: S) -> Self {
+        Self::Match(MatchTerm {
+            glob: glob.into(),
+            wholename: true,
+            ..Default::default()
+        })
+    }
+
+    /// Matches files at any depth beneath the directory `path`
+    pub fn dirname<P: Into<PathBuf>>(path: P) -> Self {
+        Self::DirName(DirNameTerm {
+            path: path.into(),
+            depth: None,
+        })
+    }
+
+    /// Matches files of type `file_type`
+    pub fn file_type(file_type: FileType) -> Self {
+        Self::FileType(file_type)
+    }
+
+    /// Matches regular files
+    pub fn type_file() -> Self {
+        Self::FileType(FileType::Regular)
+    }
+
+    /// Matches directories
+    pub fn type_dir() -> Self {
+        Self::FileType(FileType::Directory)
+    }
+
+    /// Matches symbolic links
+    pub fn type_symlink() -> Self {
+        Self::FileType(FileType::Symlink)
+    }
+
+    /// Matches files that match all of `terms`
+    pub fn all<I: IntoIterator<Item = Expr>>(terms: I) -> Self {
+        terms.into_iter().fold(Self::All(vec![]), Self::and)
+    }
+
+    /// Matches files that match any of `terms`
+    pub fn any<I: IntoIterator<Item = Expr>>(terms: I) -> Self {
+        terms.into_iter().fold(Self::Any(vec![]), Self::or)
+    }
+
+    /// Matches files that match both this and `other`.
+    /// The same as `self & other`.
+    pub fn and(self, other: Expr) -> Self {
+        let mut terms = match self {
+            Self::All(terms) => terms,
+            term => vec![term],
+        };
+        match other {
+            Self::All(more) => terms.extend(more),
+            term => terms.push(term),
+        }
+        Self::All(terms)
+    }
+
+    /// Matches files that match either this or `other`.
+    /// The same as `self | other`.
+    pub fn or(self, other: Expr) -> Self {
+        let mut terms = match self {
+            Self::Any(terms) => terms,
+            term => vec![term],
+        };
+        match other {
+            Self::Any(more) => terms.extend(more),
+            term => terms.push(term),
+        }
+        Self::Any(terms)
+    }
+
+    /// Matches files that match this but not `other`.
+    /// The same as `self & !other`.
+    pub fn and_not(self, other: Expr) -> Self {
+        self.and(!other)
+    }
+}
+
+impl std::ops::BitAnd for Expr {
+    type Output = Expr;
+
+    fn bitand(self, other: Expr) -> Expr {
+        self.and(other)
+    }
+}
+
+impl std::ops::BitOr for Expr {
+    type Output = Expr;
+
+    fn bitor(self, other: Expr) -> Expr {
+        self.or(other)
+    }
+}
+
+/// A double negation cancels out
+impl std::ops::Not for Expr {
+    type Output = Expr;
+
+    fn not(self) -> Expr {
         match self {
-            Self::True => "true".into(),
-            Self::False => "false".into(),
-            Self::Not(expr) => Value::Array(vec!["not".into(), (*expr).into()]),
-            Self::All(expr) => {
+            Self::Not(expr) => *expr,
+            expr => Self::Not(Box::new(expr)),
+        }
+    }
+}
+
+impl From<Expr> for Value {
+    fn from(expr: Expr) -> Value {
+        match expr {
+            Expr::True => "true".into(),
+            Expr::False => "false".into(),
+            Expr::Not(expr) => Value::Array(vec!["not".into(), (*expr).into()]),
+            Expr::All(expr) => {
                 let mut expr: Vec<Value> = expr.into_iter().map(Into::into).collect();
                 expr.insert(0, "allof".into());
                 Value::Array(expr)
             }
-            Self::Any(expr) => {
+            Expr::Any(expr) => {
                 let mut expr: Vec<Value> = expr.into_iter().map(Into::into).collect();
                 expr.insert(0, "anyof".into());
                 Value::Array(expr)
             }
-            Self::DirName(term) => {
+            Expr::DirName(term) => {
                 let mut expr: Vec<Value> = vec!["dirname".into(), term.path.try_into().unwrap()];
                 if let Some(depth) = term.depth {
                     expr.push(depth.into_term("depth"));
                 }
                 expr.into()
             }
-            Self::Empty => "empty".into(),
-            Self::Exists => "exists".into(),
-            Self::Match(term) => vec![
-                "match".into(),
-                term.glob.into(),
-                if term.wholename {
-                    "wholename"
-                } else {
-                    "basename"
-                }
-                .into(),
-                Value::Object(hashmap! {
-                    "includedotfiles".to_string() => term.include_dot_files.into(),
-                    "noescape".to_string() => term.no_escape.into()
-                }),
-            ]
-            .into(),
-            Self::Name(term) => vec![
-                "name".into(),
-                Value::Array(
-                    term.paths
-                        .into_iter()
-                        .map(|p| p.try_into().unwrap())
-                        .collect(),
-                ),
-                if term.wholename {
-                    "wholename"
-                } else {
-                    "basename"
-                }
-                .into(),
-            ]
-            .into(),
-            Self::Pcre(term) => vec![
+            Expr::Empty => "empty".into(),
+            Expr::Exists => "exists".into(),
+            Expr::Match(term) => term.into_term("match"),
+            Expr::IMatch(term) => term.into_term("imatch"),
+            Expr::Name(term) => term.into_term("name"),
+            Expr::IName(term) => term.into_term("iname"),
+            Expr::Pcre(term) => vec![
                 "pcre".into(),
                 term.pattern.into(),
                 if term.wholename {
@@ -144,7 +293,7 @@ impl Into<Value> for Expr {
                 .into(),
             ]
             .into(),
-            Self::Since(term) => match term {
+            Expr::Since(term) => match term {
                 SinceTerm::ObservedClock(c) => {
                     vec!["since".into(), c.into(), "oclock".into()].into()
                 }
@@ -158,13 +307,13 @@ impl Into<Value> for Expr {
                     vec!["since".into(), c.to_string().into(), "ctime".into()].into()
                 }
             },
-            Self::Size(term) => term.into_term("size"),
-            Self::Suffix(term) => vec![
+            Expr::Size(term) => term.into_term("size"),
+            Expr::Suffix(term) => vec![
                 "suffix".into(),
                 Value::Array(term.into_iter().map(|p| p.try_into().unwrap()).collect()),
             ]
             .into(),
-            Self::FileType(term) => vec!["type".into(), term.to_string().into()].into(),
+            Expr::FileType(term) => vec!["type".into(), term.to_string().into()].into(),
         }
     }
 }
@@ -172,6 +321,7 @@ impl Into<Value> for Expr {
 /// Performs an exact match against the file name.
 /// <https://facebook.github.io/watchman/docs/expr/name.html>
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct NameTerm {
     pub paths: Vec<PathBuf>,
     /// By default, the name is evaluated against the basename portion
@@ -180,9 +330,31 @@ pub struct NameTerm {
     pub wholename: bool,
 }
 
+impl NameTerm {
+    fn into_term(self, name: &str) -> Value {
+        vec![
+            name.into(),
+            Value::Array(
+                self.paths
+                    .into_iter()
+                    .map(|p| p.try_into().unwrap())
+                    .collect(),
+            ),
+            if self.wholename {
+                "wholename"
+            } else {
+                "basename"
+            }
+            .into(),
+        ]
+        .into()
+    }
+}
+
 /// Match on the parent directory structure
 /// <https://facebook.github.io/watchman/docs/expr/dirname.html>
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct DirNameTerm {
     /// The path to a directory
     pub path: PathBuf,
@@ -200,6 +372,7 @@ pub struct DirNameTerm {
 /// error in response to the query.
 /// <https://facebook.github.io/watchman/docs/expr/pcre.html>
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct PcreTerm {
     /// The perl compatible regular expression
     pub pattern: String,
@@ -213,6 +386,7 @@ pub struct PcreTerm {
 /// Encodes the match expression term
 /// <https://facebook.github.io/watchman/docs/expr/match.html>
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct MatchTerm {
     /// The glob expression to evaluate
     pub glob: String,
@@ -228,8 +402,29 @@ pub struct MatchTerm {
     pub no_escape: bool,
 }
 
+impl MatchTerm {
+    fn into_term(self, name: &str) -> Value {
+        vec![
+            name.into(),
+            self.glob.into(),
+            if self.wholename {
+                "wholename"
+            } else {
+                "basename"
+            }
+            .into(),
+            Value::Object(hashmap! {
+                "includedotfiles".to_string() => self.include_dot_files.into(),
+                "noescape".to_string() => self.no_escape.into()
+            }),
+        ]
+        .into()
+    }
+}
+
 /// Specifies a relational comparison with an integer value
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum RelOp {
     Equal(usize),
     NotEqual(usize),
@@ -249,7 +444,10 @@ impl RelOp {
             Self::Less(value) => ("lt", value),
             Self::LessOrEqual(value) => ("le", value),
         };
-        Value::Array(vec![field.into(), op.into(), value.try_into().unwrap()])
+        // Values beyond i64::MAX can't be represented in BSER and
+        // can't match anything anyway, so saturate rather than panic
+        let value = i64::try_from(value).unwrap_or(i64::MAX);
+        Value::Array(vec![field.into(), op.into(), value.into()])
     }
 }
 
@@ -257,6 +455,7 @@ impl RelOp {
 /// than the since value.
 /// <https://facebook.github.io/watchman/docs/expr/since.html>
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum SinceTerm {
     /// Yield true if the file was observed to be modified more recently than
     /// the specified clockspec
@@ -275,6 +474,73 @@ pub enum SinceTerm {
     CTime(i64),
 }
 
+/// Expand the `{a,b}` alternatives in a glob into separate globs, as a
+/// shell would: `**/*.{rs,toml}` expands to `**/*.rs` and `**/*.toml`.
+/// The server doesn't support alternatives in globs, so this is applied
+/// to the globs of `QueryRequestCommon::glob` as a query is sent, which
+/// includes those passed to `Client::glob`, and to those passed to
+/// `QueryPlanner::glob`, the [file_watcher](../file_watcher/index.html)
+/// builder and the [lsp](../lsp/index.html) module.
+///
+/// Alternatives may be nested, and a brace that is escaped with `\` or
+/// that is unbalanced is left as it is.  Duplicate expansions are only
+/// returned once, and empty expansions, which match nothing, aren't
+/// returned at all.
+pub fn expand_braces(glob: &str) -> Vec<String> {
+    let mut expanded = vec![];
+    expand_braces_into(glob, &mut expanded);
+    expanded
+}
+
+fn expand_braces_into(glob: &str, expanded: &mut Vec<String>) {
+    // Find the first top level group and its alternatives
+    let mut depth = 0;
+    let mut open = 0;
+    let mut start = 0;
+    let mut alternatives = vec![];
+    let mut chars = glob.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                // The escaped character is never special
+                chars.next();
+            }
+            '{' => {
+                if depth == 0 {
+                    open = index;
+                    start = index + 1;
+                }
+                depth += 1;
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    alternatives.push(&glob[start..index]);
+                    let prefix = &glob[..open];
+                    let suffix = &glob[index + 1..];
+                    for alternative in alternatives {
+                        expand_braces_into(
+                            &format!("{}{}{}", prefix, alternative, suffix),
+                            expanded,
+                        );
+                    }
+                    return;
+                }
+            }
+            ',' if depth == 1 => {
+                alternatives.push(&glob[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    // There are no groups, or the first is unbalanced and is treated
+    // literally
+    if !glob.is_empty() && !expanded.iter().any(|existing| existing == glob) {
+        expanded.push(glob.to_string());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -283,6 +549,24 @@ mod tests {
         expr.into()
     }
 
+    #[test]
+    fn braces() {
+        assert_eq!(expand_braces("*.rs"), vec!["*.rs"]);
+        assert_eq!(
+            expand_braces("**/*.{rs,toml}"),
+            vec!["**/*.rs", "**/*.toml"]
+        );
+        assert_eq!(
+            expand_braces("{a,b{c,d}}/x{1,2}"),
+            vec!["a/x1", "a/x2", "bc/x1", "bc/x2", "bd/x1", "bd/x2"]
+        );
+        assert_eq!(expand_braces("{a,b"), vec!["{a,b"]);
+        assert_eq!(expand_braces("}{a,b}"), vec!["}a", "}b"]);
+        assert_eq!(expand_braces(r"\{a,b}{c,d}"), vec![r"\{a,b}c", r"\{a,b}d"]);
+        assert_eq!(expand_braces("{a,a,}"), vec!["a"]);
+        assert!(expand_braces("{,}").is_empty());
+    }
+
     #[test]
     fn exprs() {
         assert_eq!(val(Expr::True), "true".into());
@@ -373,6 +657,37 @@ mod tests {
             .into()
         );
 
+        assert_eq!(
+            val(Expr::IName(NameTerm {
+                paths: vec!["Foo".into()],
+                wholename: false,
+            })),
+            vec![
+                "iname".into(),
+                vec![Value::ByteString("Foo".into())].into(),
+                "basename".into()
+            ]
+            .into()
+        );
+
+        assert_eq!(
+            val(Expr::IMatch(MatchTerm {
+                glob: "*.TXT".into(),
+                ..Default::default()
+            })),
+            vec![
+                "imatch".into(),
+                "*.TXT".into(),
+                "basename".into(),
+                hashmap! {
+                    "includedotfiles".to_string() => Value::Bool(false),
+                    "noescape".to_string() => Value::Bool(false),
+                }
+                .into()
+            ]
+            .into()
+        );
+
         assert_eq!(
             val(Expr::Pcre(PcreTerm {
                 pattern: "foo$".into(),
@@ -404,4 +719,92 @@ mod tests {
             vec!["since".into(), "c:0:0".into(), "oclock".into()].into()
         );
     }
+
+    /// Serialize `expr` as it is sent to the server
+    fn bser(expr: Expr) -> Vec<u8> {
+        serde_bser::ser::serialize(Vec::new(), expr).unwrap()
+    }
+
+    #[test]
+    fn combinators() {
+        let expr = Expr::suffix("rs")
+            .and(Expr::type_file())
+            .and_not(Expr::dirname("target"));
+        let expected: Value = vec![
+            "allof".into(),
+            vec!["suffix".into(), vec![Value::ByteString("rs".into())].into()].into(),
+            vec!["type".into(), "f".into()].into(),
+            vec![
+                "not".into(),
+                vec!["dirname".into(), Value::ByteString("target".into())].into(),
+            ]
+            .into(),
+        ]
+        .into();
+        assert_eq!(val(expr.clone()), expected);
+        assert_eq!(
+            bser(expr),
+            serde_bser::ser::serialize(Vec::new(), &expected).unwrap()
+        );
+
+        // The operators build the same terms, flattening as they go
+        let ops = Expr::suffix("rs") & Expr::type_file() & !Expr::dirname("target");
+        assert_eq!(val(ops), expected);
+        assert_eq!(
+            val(Expr::name("a") | Expr::wholename("b/c") | Expr::glob("*.md")),
+            vec![
+                "anyof".into(),
+                vec![
+                    "name".into(),
+                    vec![Value::ByteString("a".into())].into(),
+                    "basename".into()
+                ]
+                .into(),
+                vec![
+                    "name".into(),
+                    vec![Value::ByteString("b/c".into())].into(),
+                    "wholename".into()
+                ]
+                .into(),
+                val(Expr::Match(MatchTerm {
+                    glob: "*.md".into(),
+                    ..Default::default()
+                })),
+            ]
+            .into()
+        );
+        assert_eq!(
+            val((Expr::type_dir() | Expr::type_symlink()) & (Expr::Exists & Expr::Empty)),
+            vec![
+                "allof".into(),
+                vec![
+                    "anyof".into(),
+                    vec!["type".into(), "d".into()].into(),
+                    vec!["type".into(), "l".into()].into(),
+                ]
+                .into(),
+                "exists".into(),
+                "empty".into(),
+            ]
+            .into()
+        );
+        assert_eq!(val(!!Expr::Exists), "exists".into());
+        assert_eq!(
+            val(Expr::wholename_glob("src/**/*.rs")),
+            val(Expr::Match(MatchTerm {
+                glob: "src/**/*.rs".into(),
+                wholename: true,
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            val(Expr::all(vec![Expr::True, Expr::all(vec![Expr::False])])),
+            vec!["allof".into(), "true".into(), "false".into()].into()
+        );
+        assert_eq!(val(Expr::any(vec![])), vec!["anyof".into()].into());
+        assert_eq!(
+            val(Expr::suffixes(vec!["c", "h"])),
+            val(Expr::Suffix(vec!["c".into(), "h".into()]))
+        );
+    }
 }
@@ -0,0 +1,579 @@
+//! A C ABI for applications that are not written in Rust.
+//!
+//! This module is available when the `ffi` feature is enabled.
+//! It exposes a small, stable set of functions, declared in
+//! `include/watchman_client.h`, that cover connecting, resolving
+//! roots, issuing queries and receiving subscription notifications.
+//! Queries and results are exchanged as JSON, using the same structure
+//! as the JSON protocol of the server, so the C interface doesn't need
+//! to change as the server gains new query features.
+//!
+//! To build a shared library, run:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! Each client owns a background thread that runs the connection.
+//! Functions that can fail return `NULL` and, if `error` is not `NULL`,
+//! store a message in `*error` that must be released with
+//! `watchman_string_free`.
+use crate::backpressure::Queued;
+use crate::prelude::*;
+use crate::{Error, Subscription, TaskItem};
+use serde_bser::value::Value;
+use std::ffi::{CStr, CString};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::os::raw::{c_char, c_void};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+type ConnectFuture = Pin<Box<dyn Future<Output = Result<Client, Error>>>>;
+type ConnectFn = Box<dyn FnOnce() -> ConnectFuture + Send>;
+
+/// The callback invoked with each subscription notification, encoded
+/// as a JSON object.  The string is only valid for the duration of the
+/// call.
+pub type WatchmanSubscriptionCallback = extern "C" fn(userdata: *mut c_void, json: *const c_char);
+
+/// A connection to the server, along with the thread that runs it
+pub struct WatchmanClient {
+    client: Client,
+    handle: tokio::runtime::Handle,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+/// A resolved root
+pub struct WatchmanRoot(ResolvedRoot);
+
+/// An active subscription
+pub struct WatchmanSubscription {
+    cancel: Option<oneshot::Sender<()>>,
+    done: std::sync::mpsc::Receiver<()>,
+}
+
+/// The callback and its userdata, which the caller is responsible for
+/// making safe to use from the client's thread
+struct Callback {
+    callback: WatchmanSubscriptionCallback,
+    userdata: *mut c_void,
+}
+
+unsafe impl Send for Callback {}
+
+impl Callback {
+    fn call(&self, json: &str) {
+        // JSON output never contains NUL bytes
+        if let Ok(json) = CString::new(json) {
+            (self.callback)(self.userdata, json.as_ptr());
+        }
+    }
+}
+
+impl WatchmanClient {
+    fn spawn(connect: ConnectFn) -> Result<Self, Error> {
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("watchman-ffi".to_string())
+            .spawn(move || {
+                let mut runtime = match tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        ready_tx.send(Err(Error::from(err))).ok();
+                        return;
+                    }
+                };
+                let handle = runtime.handle().clone();
+                runtime.block_on(async move {
+                    match connect().await {
+                        Ok(client) => {
+                            ready_tx.send(Ok((client, handle))).ok();
+                        }
+                        Err(err) => {
+                            ready_tx.send(Err(err)).ok();
+                            return;
+                        }
+                    }
+                    // Run the tasks spawned via the handle until shut down
+                    shutdown_rx.await.ok();
+                });
+            })?;
+
+        match ready_rx.recv() {
+            Ok(Ok((client, handle))) => Ok(Self {
+                client,
+                handle,
+                shutdown: Some(shutdown),
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => {
+                thread.join().ok();
+                Err(err)
+            }
+            Err(_) => Err(Error::generic("the client thread panicked")),
+        }
+    }
+
+    /// Run `future` on the client's thread and wait for its result
+    fn run<T, Fut>(&self, make_future: impl FnOnce(Client) -> Fut) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        Fut: Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let future = make_future(self.client.clone());
+        self.handle.spawn(async move {
+            tx.send(future.await).ok();
+        });
+        rx.recv()
+            .map_err(|_| Error::generic("the client thread has exited"))?
+    }
+}
+
+impl Drop for WatchmanClient {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+unsafe fn set_error<E: std::fmt::Display>(error: *mut *mut c_char, err: E) {
+    if !error.is_null() {
+        let message = err.to_string().replace('\0', "\\0");
+        *error = CString::new(message).map_or(ptr::null_mut(), CString::into_raw);
+    }
+}
+
+/// Stores the error of `result`, if any, in `error` and returns the
+/// successful value as a pointer
+unsafe fn into_ptr<T>(result: Result<T, Error>, error: *mut *mut c_char) -> *mut T {
+    match result {
+        Ok(value) => Box::into_raw(Box::new(value)),
+        Err(err) => {
+            set_error(error, err);
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe fn into_string(result: Result<String, Error>, error: *mut *mut c_char) -> *mut c_char {
+    match result.and_then(|s| CString::new(s).map_err(Error::generic)) {
+        Ok(s) => s.into_raw(),
+        Err(err) => {
+            set_error(error, err);
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(arg: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if arg.is_null() {
+        return Err(Error::generic(format!("`{}` must not be NULL", name)));
+    }
+    CStr::from_ptr(arg)
+        .to_str()
+        .map_err(|err| Error::generic(format!("`{}` is not valid UTF-8: {}", name, err)))
+}
+
+/// Parses a JSON query object, defaulting its `relative_root` to that
+/// of the resolved root
+unsafe fn query_arg(query: *const c_char, root: &ResolvedRoot) -> Result<serde_json::Value, Error> {
+    let mut query: serde_json::Value = serde_json::from_str(str_arg(query, "query")?)
+        .map_err(|err| Error::generic(format!("`query` is not valid JSON: {}", err)))?;
+    match &mut query {
+        serde_json::Value::Object(object) => {
+            if let Some(relative) = &root.relative {
+                object
+                    .entry("relative_root")
+                    .or_insert_with(|| relative.to_string_lossy().into_owned().into());
+            }
+        }
+        _ => return Err(Error::generic("`query` must be a JSON object")),
+    }
+    Ok(query)
+}
+
+/// Render `value` as JSON text.
+/// The server sends file names as byte strings, which become JSON
+/// strings rather than arrays of bytes.
+fn to_json(value: Value) -> Result<String, Error> {
+    serde_json::to_string(&crate::codec::to_json(value)?).map_err(Error::generic)
+}
+
+/// Connect to the server.
+/// If `sock_path` is `NULL`, the server is located via `WATCHMAN_SOCK`
+/// or the watchman CLI.
+/// The client must be released with `watchman_client_free`.
+///
+/// # Safety
+/// `sock_path` must be `NULL` or a NUL terminated string, and `error`
+/// must be `NULL` or point to writable storage for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_connect(
+    sock_path: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut WatchmanClient {
+    let connector = if sock_path.is_null() {
+        Ok(Connector::new())
+    } else {
+        str_arg(sock_path, "sock_path").map(|path| Connector::new().unix_domain_socket(path))
+    };
+    let result = connector.and_then(|connector| {
+        WatchmanClient::spawn(Box::new(move || {
+            Box::pin(async move { connector.connect().await })
+        }))
+    });
+    into_ptr(result, error)
+}
+
+/// Disconnect from the server, releasing the client.
+///
+/// # Safety
+/// `client` must be `NULL` or a client returned by `watchman_connect`
+/// that has not yet been released, and any subscriptions made with it
+/// must have been released first.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_client_free(client: *mut WatchmanClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Resolve `path` into a watched root, watching it if necessary.
+/// The root must be released with `watchman_root_free`.
+///
+/// # Safety
+/// `client` must be a live client, `path` must be a NUL terminated
+/// string, and `error` must be `NULL` or point to writable storage for
+/// a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_resolve_root(
+    client: *const WatchmanClient,
+    path: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut WatchmanRoot {
+    let client = &*client;
+    let result = str_arg(path, "path")
+        .and_then(|path| Ok(CanonicalPath::canonicalize(path)?))
+        .and_then(|path| client.run(move |client| async move { client.resolve_root(path).await }));
+    into_ptr(result.map(WatchmanRoot), error)
+}
+
+/// Returns the absolute path of the directory that was resolved.
+/// The string must be released with `watchman_string_free`.
+///
+/// # Safety
+/// `root` must be a live root returned by `watchman_resolve_root`.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_root_path(root: *const WatchmanRoot) -> *mut c_char {
+    let path = (*root).0.path().to_string_lossy().into_owned();
+    into_string(Ok(path), ptr::null_mut())
+}
+
+/// Release a root.
+///
+/// # Safety
+/// `root` must be `NULL` or a root returned by `watchman_resolve_root`
+/// that has not yet been released.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_root_free(root: *mut WatchmanRoot) {
+    if !root.is_null() {
+        drop(Box::from_raw(root));
+    }
+}
+
+/// Run a query, returning the response as a JSON object.
+/// `query` is a JSON object holding the query parameters, such as
+/// `{"expression": ["suffix", "c"], "fields": ["name"]}`; see
+/// <https://facebook.github.io/watchman/docs/cmd/query.html>.
+/// The result must be released with `watchman_string_free`.
+///
+/// # Safety
+/// `client` and `root` must be live, `query` must be a NUL terminated
+/// string, and `error` must be `NULL` or point to writable storage for
+/// a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_query(
+    client: *const WatchmanClient,
+    root: *const WatchmanRoot,
+    query: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    let client = &*client;
+    let root = (*root).0.clone();
+    let result = query_arg(query, &root).and_then(|query| {
+        client.run(move |client| async move {
+            let response: Value = client
+                .generic_request(("query", root.root.clone(), query))
+                .await?;
+            to_json(response)
+        })
+    });
+    into_string(result, error)
+}
+
+/// Subscribe to changes, invoking `callback` on the client's thread
+/// with each notification from the server encoded as a JSON object,
+/// including the notifications of assertion and subscription
+/// cancellation.  If the connection fails, `callback` is invoked a
+/// final time with an object holding an `error` member.
+/// `query` is as for `watchman_query`; see
+/// <https://facebook.github.io/watchman/docs/cmd/subscribe.html>.
+/// The subscription must be released with `watchman_subscription_free`.
+///
+/// # Safety
+/// `client` and `root` must be live, `query` must be a NUL terminated
+/// string, `callback` must be safe to invoke with `userdata` from
+/// another thread until the subscription is released, and `error` must
+/// be `NULL` or point to writable storage for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_subscribe(
+    client: *const WatchmanClient,
+    root: *const WatchmanRoot,
+    query: *const c_char,
+    callback: WatchmanSubscriptionCallback,
+    userdata: *mut c_void,
+    error: *mut *mut c_char,
+) -> *mut WatchmanSubscription {
+    let client = &*client;
+    let root = (*root).0.clone();
+    let callback = Callback { callback, userdata };
+    let result = query_arg(query, &root).and_then(|query| {
+        client.run(move |client| async move {
+            let subscription = subscribe_raw(&client, root, query).await?;
+            let (cancel, canceled) = oneshot::channel();
+            let (done_tx, done) = std::sync::mpsc::channel();
+            tokio::spawn(async move {
+                deliver(subscription, callback, canceled).await;
+                done_tx.send(()).ok();
+            });
+            Ok(WatchmanSubscription {
+                cancel: Some(cancel),
+                done,
+            })
+        })
+    });
+    into_ptr(result, error)
+}
+
+/// Subscribe with a query that is passed through to the server as is
+async fn subscribe_raw(
+    client: &Client,
+    root: ResolvedRoot,
+    query: serde_json::Value,
+) -> Result<Subscription<NameOnly>, Error> {
+    let name = crate::identity::ClientIdentity::current()
+        .subscription_name("ffi", crate::SUB_ID.fetch_add(1, Ordering::Relaxed));
+    let (tx, responses) = crate::backpressure::channel(None);
+    let command = ("subscribe", root.root.clone(), name.clone(), query);
+    client
+        .inner
+        .lock()
+        .await
+        .request_tx
+        .send(TaskItem::RegisterSubscription(
+            name.clone(),
+            tx,
+            crate::serialize_request(&command)?,
+        ))
+        .await
+        .map_err(Error::generic)?;
+    let _: Value = client.generic_request(command).await?;
+    Ok(Subscription {
+        name,
+        inner: Arc::clone(&client.inner),
+        root,
+        responses,
+        decode_errors: Default::default(),
+        stats: Default::default(),
+        asserted_states: Default::default(),
+        consolidation: None,
+        debounce: None,
+        metrics: client.metrics.clone(),
+        clock: None,
+        _phantom: PhantomData,
+    })
+}
+
+/// Passes the raw notifications of `subscription` to `callback` until
+/// it is canceled or ends
+async fn deliver(
+    mut subscription: Subscription<NameOnly>,
+    callback: Callback,
+    mut canceled: oneshot::Receiver<()>,
+) {
+    loop {
+        let pdu = tokio::select! {
+            _ = &mut canceled => {
+                subscription.cancel().await.ok();
+                return;
+            }
+            pdu = subscription.responses.recv() => pdu,
+        };
+        let json = match pdu {
+            Some(Queued::Pdu(pdu)) => serde_bser::from_slice::<Value>(&pdu.data)
+                .map_err(Error::generic)
+                .and_then(to_json),
+            Some(Queued::Lagged(dropped)) => Err(Error::SubscriptionLagged { dropped }),
+            None => Err(Error::generic("the connection to the server was lost")),
+        };
+        match json {
+            Ok(json) => callback.call(&json),
+            Err(err) => {
+                callback.call(&serde_json::json!({ "error": err.to_string() }).to_string());
+                return;
+            }
+        }
+    }
+}
+
+/// Cancel a subscription and release it.
+/// Once this returns, the callback of the subscription is no longer
+/// invoked.
+///
+/// # Safety
+/// `subscription` must be `NULL` or a subscription returned by
+/// `watchman_subscribe` that has not yet been released, and this must
+/// not be called from within its callback.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_subscription_free(subscription: *mut WatchmanSubscription) {
+    if !subscription.is_null() {
+        let mut subscription = Box::from_raw(subscription);
+        if let Some(cancel) = subscription.cancel.take() {
+            cancel.send(()).ok();
+        }
+        subscription.done.recv().ok();
+    }
+}
+
+/// Release a string returned by one of these functions.
+///
+/// # Safety
+/// `s` must be `NULL` or a string returned by one of these functions
+/// that has not yet been released.
+#[no_mangle]
+pub unsafe extern "C" fn watchman_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use std::sync::Mutex;
+
+    extern "C" fn record(userdata: *mut c_void, json: *const c_char) {
+        let events = unsafe { &*(userdata as *const Mutex<Vec<serde_json::Value>>) };
+        let json = unsafe { CStr::from_ptr(json) }.to_str().unwrap();
+        events
+            .lock()
+            .unwrap()
+            .push(serde_json::from_str(json).unwrap());
+    }
+
+    unsafe fn take_string(s: *mut c_char) -> String {
+        assert!(!s.is_null());
+        let result = CStr::from_ptr(s).to_str().unwrap().to_string();
+        watchman_string_free(s);
+        result
+    }
+
+    #[test]
+    fn query_and_subscribe() {
+        let server = Arc::new(MockServer::new());
+        server.serve_files(&["a.c"]);
+        let mock = Arc::clone(&server);
+        let client = Box::into_raw(Box::new(
+            WatchmanClient::spawn(Box::new(move || {
+                Box::pin(async move { Ok(mock.connect()) })
+            }))
+            .unwrap(),
+        ));
+        let dir = CString::new(std::env::temp_dir().to_string_lossy().into_owned()).unwrap();
+        let mut error = ptr::null_mut();
+
+        unsafe {
+            let root = watchman_resolve_root(client, dir.as_ptr(), &mut error);
+            assert!(!root.is_null());
+            assert!(!take_string(watchman_root_path(root)).is_empty());
+
+            // The server sends file names as byte strings
+            server.respond_once(
+                "query",
+                hashmap! {
+                    "version".to_string() => Value::from(MOCK_VERSION),
+                    "clock".to_string() => Value::from("c:0:1"),
+                    "files".to_string() => Value::Array(vec![
+                        Value::ByteString(b"a.c".to_vec().into()),
+                    ]),
+                }
+                .into(),
+            );
+            let query = CString::new(r#"{"fields": ["name"]}"#).unwrap();
+            let response = watchman_query(client, root, query.as_ptr(), &mut error);
+            let response: serde_json::Value = serde_json::from_str(&take_string(response)).unwrap();
+            assert_eq!(response["files"], serde_json::json!(["a.c"]));
+
+            let invalid = CString::new("[").unwrap();
+            assert!(watchman_query(client, root, invalid.as_ptr(), &mut error).is_null());
+            assert!(take_string(error).contains("not valid JSON"));
+
+            let events: Mutex<Vec<serde_json::Value>> = Mutex::new(vec![]);
+            let subscription = watchman_subscribe(
+                client,
+                root,
+                query.as_ptr(),
+                record,
+                &events as *const _ as *mut c_void,
+                &mut error,
+            );
+            assert!(!subscription.is_null());
+            let name = server
+                .requests()
+                .into_iter()
+                .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+                .map(|r| match request_arg(&r, 2) {
+                    Value::Utf8String(name) => name,
+                    other => panic!("unexpected {:?}", other),
+                })
+                .unwrap();
+            server.push(subscription_pdu(
+                &name,
+                "c:0:1",
+                vec![Value::ByteString(b"b.c".to_vec().into())],
+            ));
+            while events.lock().unwrap().is_empty() {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            assert_eq!(
+                events.lock().unwrap()[0]["files"],
+                serde_json::json!(["b.c"])
+            );
+
+            watchman_subscription_free(subscription);
+            assert!(server
+                .requests()
+                .iter()
+                .any(|r| request_arg(r, 0) == Value::from("unsubscribe")));
+            watchman_root_free(root);
+            watchman_client_free(client);
+        }
+    }
+}
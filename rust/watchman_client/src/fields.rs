@@ -92,13 +92,14 @@ define_field!(
     /// change in this file or its metadata.
     ObservedClockField,
     ClockSpec,
-    "cclock"
+    "oclock"
 );
 
 define_field!(
     /// The field corresponding to the `content.sha1hex` field.
     /// For regular files this evaluates to the sha1 hash of the
-    /// file contents.
+    /// file contents, or to the reason that the server was unable to
+    /// compute it; see `ContentSha1Hex::into_result`.
     ContentSha1HexField,
     ContentSha1Hex,
     "content.sha1hex"
@@ -122,6 +123,33 @@ define_field!(
     "ctime_f"
 );
 
+define_field!(
+    /// The field corresponding to the `ctime_ms` field.
+    /// ctime is the last inode change time measured in integer milliseconds
+    /// since the unix epoch.
+    CTimeAsMillisField,
+    i64,
+    "ctime_ms"
+);
+
+define_field!(
+    /// The field corresponding to the `ctime_us` field.
+    /// ctime is the last inode change time measured in integer microseconds
+    /// since the unix epoch.
+    CTimeAsMicrosField,
+    i64,
+    "ctime_us"
+);
+
+define_field!(
+    /// The field corresponding to the `ctime_ns` field.
+    /// ctime is the last inode change time measured in integer nanoseconds
+    /// since the unix epoch.
+    CTimeAsNanosField,
+    i64,
+    "ctime_ns"
+);
+
 define_field!(
     /// The field corresponding to the `mtime` field.
     /// mtime is the last modified time measured in integer seconds
@@ -140,6 +168,33 @@ define_field!(
     "mtime_f"
 );
 
+define_field!(
+    /// The field corresponding to the `mtime_ms` field.
+    /// mtime is the last modified time measured in integer milliseconds
+    /// since the unix epoch.
+    MTimeAsMillisField,
+    i64,
+    "mtime_ms"
+);
+
+define_field!(
+    /// The field corresponding to the `mtime_us` field.
+    /// mtime is the last modified time measured in integer microseconds
+    /// since the unix epoch.
+    MTimeAsMicrosField,
+    i64,
+    "mtime_us"
+);
+
+define_field!(
+    /// The field corresponding to the `mtime_ns` field.
+    /// mtime is the last modified time measured in integer nanoseconds
+    /// since the unix epoch.
+    MTimeAsNanosField,
+    i64,
+    "mtime_ns"
+);
+
 define_field!(
     /// The field corresponding to the `size` field.
     /// This represents the size of the file in bytes.
@@ -277,6 +332,41 @@ define_field!(
 /// the automatic field naming and correct deserialization regardless
 /// of the field name in the struct.  As such, you should consider
 /// the set of fields to be limited to those provided by this crate.
+///
+/// `#[derive(QueryFields)]` does the same job for a struct that is
+/// written out by hand, which leaves room for doc comments, other
+/// attributes and generic parameters, and allows a single field:
+///
+/// ```
+/// use watchman_client::prelude::*;
+///
+/// /// The files that have been modified
+/// #[derive(QueryFields, Debug, Clone)]
+/// pub struct Modified<T> {
+///     /// Relative to the root
+///     pub name: NameField,
+///     pub mtime: MTimeField,
+///     pub extra: T,
+/// }
+///
+/// assert_eq!(
+///     Modified::<SizeField>::field_list(),
+///     vec!["name", "mtime", "size"]
+/// );
+/// # let file: Modified<SizeField> = serde_json::from_value(
+/// #     serde_json::json!({"name": "a.rs", "mtime": 1, "size": 12})
+/// # )?;
+/// # assert_eq!(*file.extra, 12);
+/// # assert!(serde_json::from_value::<Modified<SizeField>>(
+/// #     serde_json::json!({"name": "a.rs"})
+/// # ).is_err());
+/// # Ok::<(), serde_json::Error>(())
+/// ```
+///
+/// The derive implements `Deserialize` itself, so the struct must not
+/// also derive it.  A struct whose only field is a `NameField` should
+/// use `NameOnly` instead, as the server reports such results as bare
+/// names.
 #[macro_export]
 macro_rules! query_result_type {(
     $struct_vis:vis struct $tyname:ident {
@@ -327,3 +417,257 @@ impl From<PathBuf> for NameOnly {
         }
     }
 }
+
+/// The fields that the server reports when a query doesn't specify any
+pub(crate) const DEFAULT_FIELDS: &[&str] = &["name", "exists", "new", "size", "mode"];
+
+/// A `Value` receives the fields of a file without imposing a schema on
+/// them; the server's default fields are requested, unless the query,
+/// such as one made with `Client::query_raw`, specifies others.
+impl QueryFieldList for serde_bser::value::Value {
+    fn field_list() -> Vec<&'static str> {
+        DEFAULT_FIELDS.to_vec()
+    }
+}
+
+/// Use the `AllFields` struct when you want every field that the server
+/// can report for a file, without defining a result type of your own.
+///
+/// The members are optional because the server leaves out a field that
+/// it can't determine for a file, such as most of them once the file
+/// has been deleted.
+/// This is the most expensive field list to request: the server reads
+/// the content of every regular file to produce its hash, and the link
+/// of every symlink.
+///
+/// ```
+/// use watchman_client::prelude::*;
+/// # use watchman_client::test_support::MockServer;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let server = MockServer::new();
+/// # server.serve_files(&["src/lib.rs"]);
+/// # let client = server.connect();
+/// # /*
+/// let client = Connector::new().connect().await?;
+/// # */
+/// let root = client
+///     .resolve_root(CanonicalPath::canonicalize(".")?)
+///     .await?;
+/// let result: QueryResult<AllFields> = client
+///     .query(&root, QueryRequestCommon::default())
+///     .await?;
+/// for file in result.files.unwrap_or_default() {
+///     println!("{} {:?} {:?}", file.name.display(), file.size, file.mtime);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct AllFields {
+    /// The path to the file, relative to the root
+    pub name: PathBuf,
+    /// Whether the file exists
+    pub exists: Option<bool>,
+    /// The clock when the file was first observed
+    pub cclock: Option<ClockSpec>,
+    /// The clock when the file was last observed to change
+    pub oclock: Option<ClockSpec>,
+    /// The hash of the content of a regular file
+    #[serde(rename = "content.sha1hex")]
+    pub content_sha1hex: Option<ContentSha1Hex>,
+    /// The last inode change time in seconds since the unix epoch
+    pub ctime: Option<i64>,
+    /// The last inode change time in fractional seconds since the
+    /// unix epoch
+    pub ctime_f: Option<f32>,
+    /// The last inode change time in milliseconds since the unix epoch
+    pub ctime_ms: Option<i64>,
+    /// The last inode change time in microseconds since the unix epoch
+    pub ctime_us: Option<i64>,
+    /// The last inode change time in nanoseconds since the unix epoch
+    pub ctime_ns: Option<i64>,
+    /// The last modified time in seconds since the unix epoch
+    pub mtime: Option<i64>,
+    /// The last modified time in fractional seconds since the unix
+    /// epoch
+    pub mtime_f: Option<f32>,
+    /// The last modified time in milliseconds since the unix epoch
+    pub mtime_ms: Option<i64>,
+    /// The last modified time in microseconds since the unix epoch
+    pub mtime_us: Option<i64>,
+    /// The last modified time in nanoseconds since the unix epoch
+    pub mtime_ns: Option<i64>,
+    /// The size of the file in bytes
+    pub size: Option<usize>,
+    /// The file type and permission bits
+    pub mode: Option<u64>,
+    /// The owning uid
+    pub uid: Option<u32>,
+    /// The owning gid
+    pub gid: Option<u32>,
+    /// The inode number
+    pub ino: Option<u64>,
+    /// The device number
+    pub dev: Option<u64>,
+    /// The number of hard links to the file
+    pub nlink: Option<u64>,
+    /// The type of the file
+    #[serde(rename = "type")]
+    pub file_type: Option<FileType>,
+    /// The target of a symlink
+    pub symlink_target: Option<String>,
+}
+
+impl QueryFieldList for AllFields {
+    fn field_list() -> Vec<&'static str> {
+        vec![
+            NameField::field_name(),
+            ExistsField::field_name(),
+            CreatedClockField::field_name(),
+            ObservedClockField::field_name(),
+            ContentSha1HexField::field_name(),
+            CTimeField::field_name(),
+            CTimeAsFloatField::field_name(),
+            CTimeAsMillisField::field_name(),
+            CTimeAsMicrosField::field_name(),
+            CTimeAsNanosField::field_name(),
+            MTimeField::field_name(),
+            MTimeAsFloatField::field_name(),
+            MTimeAsMillisField::field_name(),
+            MTimeAsMicrosField::field_name(),
+            MTimeAsNanosField::field_name(),
+            SizeField::field_name(),
+            ModeAndPermissionsField::field_name(),
+            OwnerUidField::field_name(),
+            OwnerGidField::field_name(),
+            InodeNumberField::field_name(),
+            DeviceNumberField::field_name(),
+            NumberOfLinksField::field_name(),
+            FileTypeField::field_name(),
+            SymlinkTargetField::field_name(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer};
+    use serde_bser::value::Value;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn all_fields() {
+        let server = MockServer::new();
+        server.serve_files(&["a.rs"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let result: QueryResult<AllFields> = client
+            .query(&root, QueryRequestCommon::default())
+            .await
+            .unwrap();
+        let file = &result.files.unwrap()[0];
+        assert_eq!(file.name, PathBuf::from("a.rs"));
+        assert_eq!(file.file_type, Some(FileType::Regular));
+        assert_eq!(file.nlink, Some(1));
+        assert_eq!(file.symlink_target, None);
+
+        let request = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("query"))
+            .unwrap();
+        match request_arg(&request, 2) {
+            Value::Object(query) => assert_eq!(
+                query["fields"],
+                Value::Array(
+                    AllFields::field_list()
+                        .into_iter()
+                        .map(Value::from)
+                        .collect()
+                )
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // The server leaves out the fields of a deleted file
+        let deleted: AllFields =
+            serde_json::from_value(json!({"name": "gone.rs", "exists": false})).unwrap();
+        assert_eq!(deleted.exists, Some(false));
+        assert_eq!(deleted.size, None);
+    }
+
+    #[test]
+    fn time_fields() {
+        query_result_type! {
+            struct Times {
+                ctime_ms: CTimeAsMillisField,
+                ctime_us: CTimeAsMicrosField,
+                ctime_ns: CTimeAsNanosField,
+                mtime_ms: MTimeAsMillisField,
+                mtime_us: MTimeAsMicrosField,
+                mtime_ns: MTimeAsNanosField,
+            }
+        }
+
+        assert_eq!(
+            Times::field_list(),
+            vec!["ctime_ms", "ctime_us", "ctime_ns", "mtime_ms", "mtime_us", "mtime_ns"]
+        );
+        let times: Times = serde_json::from_value(json!({
+            "ctime_ms": 1_600_000_000_123i64,
+            "ctime_us": 1_600_000_000_123_456i64,
+            "ctime_ns": 1_600_000_000_123_456_789i64,
+            "mtime_ms": -1,
+            "mtime_us": 0,
+            "mtime_ns": 1_600_000_000_000_000_001i64,
+        }))
+        .unwrap();
+        assert_eq!(*times.ctime_ms, 1_600_000_000_123);
+        assert_eq!(*times.ctime_us, 1_600_000_000_123_456);
+        assert_eq!(*times.ctime_ns, 1_600_000_000_123_456_789);
+        assert_eq!(*times.mtime_ms, -1);
+        assert_eq!(*times.mtime_us, 0);
+        assert_eq!(times.mtime_ns.into_inner(), 1_600_000_000_000_000_001);
+    }
+
+    #[test]
+    fn identity_fields() {
+        query_result_type! {
+            struct Identity {
+                ino: InodeNumberField,
+                dev: DeviceNumberField,
+                file_type: FileTypeField,
+                symlink_target: SymlinkTargetField,
+            }
+        }
+
+        let link: Identity = serde_json::from_value(json!({
+            "ino": 18_446_744_073_709_551_615u64,
+            "dev": 2049,
+            "type": "l",
+            "symlink_target": "../lib.rs",
+        }))
+        .unwrap();
+        assert_eq!(*link.ino, u64::MAX);
+        assert_eq!(*link.dev, 2049);
+        assert_eq!(*link.file_type, FileType::Symlink);
+        assert_eq!(link.symlink_target.as_deref(), Some("../lib.rs"));
+
+        // Only symlinks have a target
+        let file: Identity = serde_json::from_value(json!({
+            "ino": 0,
+            "dev": 0,
+            "type": "f",
+            "symlink_target": null,
+        }))
+        .unwrap();
+        assert_eq!(*file.file_type, FileType::Regular);
+        assert_eq!(*file.symlink_target, None);
+    }
+}
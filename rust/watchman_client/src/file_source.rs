@@ -0,0 +1,415 @@
+//! A source of file listings and changes that degrades gracefully when
+//! watchman is not available.
+//!
+//! Tools that are shipped to users who may not have installed watchman
+//! can use `FileSource` to prefer watchman when it is running, and to
+//! otherwise fall back to crawling the filesystem and comparing
+//! modification times:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use watchman_client::file_source::FileSource;
+//! use watchman_client::prelude::*;
+//!
+//! # async fn example() -> Result<(), watchman_client::Error> {
+//! let mut source = FileSource::new(Connector::new(), CanonicalPath::canonicalize(".")?).await?;
+//! if !source.is_watchman() {
+//!     eprintln!("watchman is unavailable; falling back to polling");
+//! }
+//! loop {
+//!     let changes = source.wait_for_changes(Duration::from_secs(1)).await?;
+//!     println!("changed: {:?}, deleted: {:?}", changes.changed, changes.deleted);
+//! }
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::Error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Directories that the crawler doesn't descend into, matching the
+/// default behavior of the server with respect to version control
+/// metadata
+const IGNORED_DIRS: &[&str] = &[".git", ".hg", ".svn"];
+
+query_result_type! {
+    struct SourceFile {
+        name: NameField,
+        exists: ExistsField,
+    }
+}
+
+/// The changes reported by `FileSource::changes`.
+/// Paths are relative to the root of the source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// When true, `changed` holds every file rather than a delta,
+    /// either because this is the first result or because the backend
+    /// lost track of the state of the tree
+    pub is_fresh_instance: bool,
+    /// Files that were created or modified
+    pub changed: Vec<PathBuf>,
+    /// Files that were deleted
+    pub deleted: Vec<PathBuf>,
+}
+
+impl Changes {
+    /// Returns true if there are no changes to report
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// Lists the files beneath a root and reports changes to them, using
+/// watchman when it is available and a filesystem crawler otherwise.
+///
+/// Only files are reported; directories are not.
+pub struct FileSource {
+    backend: Backend,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Backend {
+    /// Changes are obtained by querying the watchman server
+    Watchman {
+        client: Client,
+        root: ResolvedRoot,
+        clock: Option<Clock>,
+    },
+    /// Changes are obtained by crawling the filesystem
+    Crawler(Crawler),
+}
+
+impl std::fmt::Debug for FileSource {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("FileSource")
+            .field("watchman", &self.is_watchman())
+            .field("root", &self.root())
+            .finish()
+    }
+}
+
+impl FileSource {
+    /// Create a source for `path`, using watchman if it can be reached
+    /// via `connector` and resolves `path`, or crawling otherwise.
+    /// Fails only if `path` can not be crawled either.
+    pub async fn new(connector: Connector, path: CanonicalPath) -> Result<Self, Error> {
+        let root = path.0.clone();
+        if let Ok(client) = connector.connect().await {
+            if let Ok(resolved) = client.resolve_root(path).await {
+                return Ok(Self::watchman(client, resolved));
+            }
+        }
+        if !root.is_dir() {
+            return Err(Error::generic(format!(
+                "{} is not a directory that can be crawled",
+                root.display()
+            )));
+        }
+        Ok(Self::crawler(root))
+    }
+
+    /// Create a source that queries `root` via `client`
+    pub fn watchman(client: Client, root: ResolvedRoot) -> Self {
+        Self {
+            backend: Backend::Watchman {
+                client,
+                root,
+                clock: None,
+            },
+        }
+    }
+
+    /// Create a source that crawls `root`
+    pub fn crawler<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            backend: Backend::Crawler(Crawler::new(root.into())),
+        }
+    }
+
+    /// Returns true if the source is backed by watchman
+    pub fn is_watchman(&self) -> bool {
+        matches!(self.backend, Backend::Watchman { .. })
+    }
+
+    /// Returns the absolute path to the root of the source
+    pub fn root(&self) -> PathBuf {
+        match &self.backend {
+            Backend::Watchman { root, .. } => root.path(),
+            Backend::Crawler(crawler) => crawler.root.clone(),
+        }
+    }
+
+    /// Returns the changes since the previous call.
+    /// The first call returns every file as a fresh instance.
+    pub async fn changes(&mut self) -> Result<Changes, Error> {
+        match &mut self.backend {
+            Backend::Watchman {
+                client,
+                root,
+                clock,
+            } => {
+                // The clock is only advanced once the query succeeds, so
+                // that a failed query doesn't lose track of the changes
+                let result: QueryResult<SourceFile> = client
+                    .query(
+                        root,
+                        QueryRequestCommon {
+                            since: clock.clone(),
+                            expression: Some(Expr::Not(Box::new(Expr::FileType(
+                                FileType::Directory,
+                            )))),
+                            ..Default::default()
+                        },
+                    )
+                    .await?;
+                *clock = Some(result.clock);
+
+                let mut changes = Changes {
+                    is_fresh_instance: result.is_fresh_instance,
+                    ..Default::default()
+                };
+                for file in result.files.unwrap_or_default() {
+                    let name = file.name.into_inner();
+                    if *file.exists {
+                        changes.changed.push(name);
+                    } else if !changes.is_fresh_instance {
+                        changes.deleted.push(name);
+                    }
+                }
+                Ok(changes)
+            }
+            Backend::Crawler(crawler) => crawler.changes().await,
+        }
+    }
+
+    /// Wait until there are changes to report, checking for them every
+    /// `interval`
+    pub async fn wait_for_changes(&mut self, interval: Duration) -> Result<Changes, Error> {
+        loop {
+            let changes = self.changes().await?;
+            if changes.is_fresh_instance || !changes.is_empty() {
+                return Ok(changes);
+            }
+            tokio::time::delay_for(interval).await;
+        }
+    }
+}
+
+/// Identifies a version of a file for the purposes of change detection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+/// Reports changes by crawling a directory tree and comparing the
+/// modification time and size of each file with those seen by the
+/// previous crawl.
+///
+/// Changes that preserve both the modification time and the size of a
+/// file can not be detected.
+#[derive(Debug)]
+struct Crawler {
+    root: PathBuf,
+    stamps: Option<HashMap<PathBuf, Stamp>>,
+}
+
+impl Crawler {
+    fn new(root: PathBuf) -> Self {
+        Self { root, stamps: None }
+    }
+
+    async fn changes(&mut self) -> Result<Changes, Error> {
+        // Crawling a large tree takes a while, so is done on the
+        // blocking pool rather than holding up the runtime
+        let root = self.root.clone();
+        let stamps = tokio::task::spawn_blocking(move || crawl(&root))
+            .await
+            .map_err(Error::generic)??;
+        let changes = match &self.stamps {
+            None => Changes {
+                is_fresh_instance: true,
+                changed: stamps.keys().cloned().collect(),
+                deleted: vec![],
+            },
+            Some(previous) => Changes {
+                is_fresh_instance: false,
+                changed: stamps
+                    .iter()
+                    .filter(|(name, stamp)| previous.get(*name) != Some(stamp))
+                    .map(|(name, _)| name.clone())
+                    .collect(),
+                deleted: previous
+                    .keys()
+                    .filter(|name| !stamps.contains_key(*name))
+                    .cloned()
+                    .collect(),
+            },
+        };
+        self.stamps = Some(stamps);
+        Ok(changes)
+    }
+}
+
+/// Returns the stamp of each file under `root`
+fn crawl(root: &Path) -> Result<HashMap<PathBuf, Stamp>, Error> {
+    let mut stamps = HashMap::new();
+    let walker = walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| {
+            !(entry.file_type().is_dir()
+                && entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| IGNORED_DIRS.contains(&name)))
+        });
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // The tree may be changing as we crawl it; treat
+            // anything that vanished as deleted
+            Err(err)
+                if err.io_error().map(std::io::Error::kind)
+                    == Some(std::io::ErrorKind::NotFound) =>
+            {
+                continue
+            }
+            Err(err) if err.depth() == 0 => {
+                return Err(Error::generic(format!(
+                    "crawling {}: {}",
+                    root.display(),
+                    err
+                )))
+            }
+            Err(_) => continue,
+        };
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let name = relative_name(root, entry.path());
+        stamps.insert(
+            name,
+            Stamp {
+                modified: metadata.modified().ok(),
+                len: metadata.len(),
+            },
+        );
+    }
+    Ok(stamps)
+}
+
+fn relative_name(root: &Path, path: &Path) -> PathBuf {
+    path.strip_prefix(root).unwrap_or(path).to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{error_response, request_arg, MockServer};
+    use serde_bser::value::Value;
+
+    /// Creates an empty directory for a test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "watchman-file-source-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sorted(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        paths.sort();
+        paths
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_crawler() {
+        let dir = temp_dir("fallback");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+        std::fs::write(dir.join("README.md"), "").unwrap();
+        std::fs::write(dir.join(".git/HEAD"), "").unwrap();
+
+        let connector = Connector::new().unix_domain_socket(dir.join("no-such-sock"));
+        let mut source = FileSource::new(connector, CanonicalPath::canonicalize(&dir).unwrap())
+            .await
+            .unwrap();
+        assert!(!source.is_watchman());
+
+        let changes = source.changes().await.unwrap();
+        assert!(changes.is_fresh_instance);
+        assert_eq!(
+            sorted(changes.changed),
+            vec![PathBuf::from("README.md"), PathBuf::from("src/lib.rs")]
+        );
+        assert!(source.changes().await.unwrap().is_empty());
+
+        std::fs::write(dir.join("src/lib.rs"), "fn main() {}").unwrap();
+        std::fs::write(dir.join("new.txt"), "").unwrap();
+        std::fs::remove_file(dir.join("README.md")).unwrap();
+        let changes = source
+            .wait_for_changes(Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert!(!changes.is_fresh_instance);
+        assert_eq!(
+            sorted(changes.changed),
+            vec![PathBuf::from("new.txt"), PathBuf::from("src/lib.rs")]
+        );
+        assert_eq!(changes.deleted, vec![PathBuf::from("README.md")]);
+
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(FileSource::new(
+            Connector::new().unix_domain_socket("/no-such-sock"),
+            CanonicalPath::with_canonicalized_path(dir)
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn prefers_watchman() {
+        let server = MockServer::new();
+        server.serve_files(&["a.txt", "b/c.txt"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let mut source = FileSource::watchman(client, root);
+        assert!(source.is_watchman());
+        assert_eq!(source.root(), Path::new("/repo"));
+
+        let changes = source.changes().await.unwrap();
+        assert!(changes.is_fresh_instance);
+        assert_eq!(changes.changed.len(), 2);
+
+        // The clock from the first result is passed to the next
+        // query, and is kept when that query fails
+        server.respond_once("query", error_response("query failed"));
+        assert!(source.changes().await.is_err());
+        source.changes().await.unwrap();
+        let queries: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .filter(|r| request_arg(r, 0) == Value::from("query"))
+            .collect();
+        let since = |query: &Value| match request_arg(query, 2) {
+            Value::Object(query) => query.get("since").cloned(),
+            query => panic!("unexpected {:?}", query),
+        };
+        assert!(since(&queries[1]).is_some());
+        assert_eq!(since(&queries[2]), since(&queries[1]));
+    }
+}
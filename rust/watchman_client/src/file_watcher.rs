@@ -0,0 +1,355 @@
+//! Receive file change events with minimal ceremony.
+//!
+//! `FileWatcher` wraps up the steps that most consumers of
+//! subscriptions repeat: connecting to the server, resolving the root,
+//! subscribing to the files matching some globs, skipping the initial
+//! listing of the tree and batching changes that arrive in quick
+//! succession.
+//!
+//! ```
+//! use std::time::Duration;
+//! use watchman_client::file_watcher::FileWatcher;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! let builder = FileWatcher::builder(".")
+//!     .globs(["**/*.rs", "Cargo.toml"])
+//!     .debounce(Duration::from_millis(100));
+//! # let mut watcher = builder.build_with_client(server.connect()).await?;
+//! # /*
+//! let mut watcher = builder.build().await?;
+//! loop {
+//!     let events = watcher.next().await?;
+//!     if events.is_fresh_instance {
+//!         println!("anything may have changed");
+//!     }
+//!     for path in &events.changed {
+//!         println!("changed: {}", path.display());
+//!     }
+//!     for path in &events.removed {
+//!         println!("removed: {}", path.display());
+//!     }
+//! }
+//! # */
+//! # watcher.cancel().await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::expr::expand_braces;
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The debounce window used unless overridden via
+/// `FileWatcherBuilder::debounce`
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+query_result_type! {
+    struct WatchedFile {
+        name: NameField,
+        exists: ExistsField,
+    }
+}
+
+/// A batch of changes reported by `FileWatcher::next`.
+/// Paths are absolute, and each appears at most once.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileEvents {
+    /// The files that were created or modified
+    pub changed: Vec<PathBuf>,
+    /// The files that were deleted
+    pub removed: Vec<PathBuf>,
+    /// True if the server recrawled the tree and may have missed
+    /// changes; `changed` and `removed` are empty in that case, and
+    /// anything may have changed
+    pub is_fresh_instance: bool,
+}
+
+/// Configures a `FileWatcher`; see `FileWatcher::builder`
+pub struct FileWatcherBuilder {
+    path: PathBuf,
+    globs: Vec<String>,
+    debounce: Duration,
+    connector: Option<Connector>,
+}
+
+impl std::fmt::Debug for FileWatcherBuilder {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("FileWatcherBuilder")
+            .field("path", &self.path)
+            .field("globs", &self.globs)
+            .field("debounce", &self.debounce)
+            .finish()
+    }
+}
+
+impl FileWatcherBuilder {
+    /// Only report the files matching any of `globs`, which are
+    /// matched against paths relative to the watched directory and
+    /// may use `**` to match any number of directories and `{a,b}`
+    /// alternatives.
+    /// All files are reported if no globs are specified.
+    pub fn globs<I, S>(mut self, globs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.globs.extend(
+            globs
+                .into_iter()
+                .flat_map(|glob| expand_braces(glob.as_ref())),
+        );
+        self
+    }
+
+    /// Set how long to wait for further changes before reporting a
+    /// batch.  Changes that arrive within the window are reported
+    /// together.
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Use `connector` to connect to the server, rather than
+    /// `Connector::new`
+    pub fn connector(mut self, connector: Connector) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Connect to the server and start watching
+    pub async fn build(mut self) -> Result<FileWatcher, Error> {
+        // `Connector::new`, unlike `Connector::default`, honors
+        // `WATCHMAN_SOCK`
+        let connector = match self.connector.take() {
+            Some(connector) => connector,
+            None => Connector::new(),
+        };
+        let client = connector.connect().await?;
+        self.build_with_client(client).await
+    }
+
+    /// Start watching using an existing connection to the server
+    pub async fn build_with_client(self, client: Client) -> Result<FileWatcher, Error> {
+        let root = client
+            .resolve_root(CanonicalPath::canonicalize(&self.path)?)
+            .await?;
+        let expression = if self.globs.is_empty() {
+            None
+        } else {
+            Some(Expr::Any(
+                self.globs
+                    .into_iter()
+                    .map(|glob| {
+                        Expr::Match(MatchTerm {
+                            glob,
+                            wholename: true,
+                            include_dot_files: true,
+                            ..Default::default()
+                        })
+                    })
+                    .collect(),
+            ))
+        };
+        let (subscription, _) = client
+            .subscribe(
+                &root,
+                SubscribeRequest {
+                    expression,
+                    // The initial listing of the tree is not reported
+                    empty_on_fresh_instance: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(FileWatcher {
+            root: root.path(),
+            debounce: self.debounce,
+            _client: client,
+            subscription,
+            initial: true,
+            pending: BTreeMap::new(),
+            is_fresh_instance: false,
+        })
+    }
+}
+
+/// Reports changes to the files beneath a directory.
+pub struct FileWatcher {
+    root: PathBuf,
+    debounce: Duration,
+    /// Keeps the connection alive for the subscription
+    _client: Client,
+    subscription: Subscription<WatchedFile>,
+    initial: bool,
+    /// Whether each path that changed in the current batch exists
+    pending: BTreeMap<PathBuf, bool>,
+    is_fresh_instance: bool,
+}
+
+impl std::fmt::Debug for FileWatcher {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("FileWatcher")
+            .field("root", &self.root)
+            .field("debounce", &self.debounce)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl FileWatcher {
+    /// Returns a builder that watches `path`, which is canonicalized
+    /// when the watcher is built
+    pub fn builder<P: Into<PathBuf>>(path: P) -> FileWatcherBuilder {
+        FileWatcherBuilder {
+            path: path.into(),
+            globs: vec![],
+            debounce: DEFAULT_DEBOUNCE,
+            connector: None,
+        }
+    }
+
+    /// Returns the absolute path of the watched directory
+    pub fn root(&self) -> &std::path::Path {
+        &self.root
+    }
+
+    /// Wait for the next batch of changes.
+    /// This is cancel safe: changes received before the returned
+    /// future is dropped are reported by the next call.
+    pub async fn next(&mut self) -> Result<FileEvents, Error> {
+        loop {
+            let pending = self.is_fresh_instance || !self.pending.is_empty();
+            let data = if pending {
+                match tokio::time::timeout(self.debounce, self.subscription.next()).await {
+                    Ok(data) => data?,
+                    Err(_) => return Ok(self.take()),
+                }
+            } else {
+                self.subscription.next().await?
+            };
+            match data {
+                SubscriptionData::FilesChanged(result) => {
+                    if std::mem::replace(&mut self.initial, false) && result.is_fresh_instance {
+                        continue;
+                    }
+                    if result.is_fresh_instance {
+                        self.is_fresh_instance = true;
+                        self.pending.clear();
+                    } else if !self.is_fresh_instance {
+                        for file in result.files.unwrap_or_default() {
+                            self.pending.insert(file.name.into_inner(), *file.exists);
+                        }
+                    }
+                }
+                SubscriptionData::StateEnter { .. } | SubscriptionData::StateLeave { .. } => {}
+                SubscriptionData::Canceled => {
+                    return Err(Error::generic(
+                        "the subscription was canceled by the server",
+                    ))
+                }
+            }
+        }
+    }
+
+    fn take(&mut self) -> FileEvents {
+        let mut events = FileEvents {
+            is_fresh_instance: std::mem::replace(&mut self.is_fresh_instance, false),
+            ..Default::default()
+        };
+        for (path, exists) in std::mem::take(&mut self.pending) {
+            let path = self.root.join(path);
+            if exists {
+                events.changed.push(path);
+            } else {
+                events.removed.push(path);
+            }
+        }
+        events
+    }
+
+    /// Stop watching and cancel the subscription
+    pub async fn cancel(self) -> Result<(), Error> {
+        self.subscription.cancel().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    fn pdu(name: &str, files: &[(&str, bool)], is_fresh_instance: bool) -> Value {
+        hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "unilateral".to_string() => Value::Bool(true),
+            "subscription".to_string() => Value::from(name),
+            "clock".to_string() => Value::from("c:0:1"),
+            "is_fresh_instance".to_string() => Value::Bool(is_fresh_instance),
+            "files".to_string() => Value::Array(
+                files
+                    .iter()
+                    .map(|&(name, exists)| {
+                        Value::from(hashmap! {
+                            "name".to_string() => Value::from(name),
+                            "exists".to_string() => Value::Bool(exists),
+                        })
+                    })
+                    .collect(),
+            ),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn debounces_changes() {
+        let server = MockServer::new();
+        let dir = std::env::temp_dir();
+        let mut watcher = FileWatcher::builder(&dir)
+            .globs(["**/*.rs"])
+            .debounce(Duration::from_millis(20))
+            .build_with_client(server.connect())
+            .await
+            .unwrap();
+        let root = watcher.root().to_path_buf();
+
+        let subscribe = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .unwrap();
+        let name = match request_arg(&subscribe, 2) {
+            Value::Utf8String(name) => name,
+            other => panic!("unexpected {:?}", other),
+        };
+        match request_arg(&subscribe, 3) {
+            Value::Object(request) => {
+                assert!(format!("{:?}", request["expression"]).contains("**/*.rs"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        server.push(pdu(&name, &[], true));
+        server.push(pdu(&name, &[("a.rs", true), ("b.rs", true)], false));
+        server.push(pdu(&name, &[("b.rs", false)], false));
+        assert_eq!(
+            watcher.next().await.unwrap(),
+            FileEvents {
+                changed: vec![root.join("a.rs")],
+                removed: vec![root.join("b.rs")],
+                is_fresh_instance: false,
+            }
+        );
+
+        server.push(pdu(&name, &[], true));
+        assert!(watcher.next().await.unwrap().is_fresh_instance);
+        watcher.cancel().await.unwrap();
+    }
+}
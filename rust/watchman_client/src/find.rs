@@ -0,0 +1,136 @@
+//! List the files that match a set of patterns.
+//!
+//! `Client::find` uses the server's `find` command, which is cheaper
+//! than a query when all that is wanted is a one-off listing, as it
+//! takes no expression to evaluate and needs no field list:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["src/lib.rs", "src/find.rs"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! for file in client.find(&root, &["*.rs"]).await? {
+//!     println!("{} is {} bytes", file.name.display(), *file.size);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each pattern is matched against the file's name, or against its
+//! path relative to the root when preceded by `-p`; see the
+//! [find](https://facebook.github.io/watchman/docs/cmd/find.html)
+//! documentation for the rest of the pattern syntax.  With no patterns,
+//! every file is listed.
+use crate::pdu::{FindRequest, FindResponse};
+use crate::prelude::*;
+use crate::Error;
+use serde::Deserialize;
+
+/// A file listed by `Client::find`.
+/// The server always reports these fields for `find`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct FoundFile {
+    /// The path to the file, relative to the root
+    #[serde(flatten)]
+    pub name: NameField,
+    /// Whether the file exists
+    #[serde(flatten)]
+    pub exists: ExistsField,
+    /// The size of the file in bytes
+    #[serde(flatten)]
+    pub size: SizeField,
+    /// The file type and permission bits
+    #[serde(flatten)]
+    pub mode: ModeAndPermissionsField,
+    /// The owning uid
+    #[serde(flatten)]
+    pub uid: OwnerUidField,
+    /// The owning gid
+    #[serde(flatten)]
+    pub gid: OwnerGidField,
+    /// The last modified time in seconds since the unix epoch
+    #[serde(flatten)]
+    pub mtime: MTimeField,
+    /// The last inode change time in seconds since the unix epoch
+    #[serde(flatten)]
+    pub ctime: CTimeField,
+    /// The inode number
+    #[serde(flatten)]
+    pub ino: InodeNumberField,
+    /// The device number
+    #[serde(flatten)]
+    pub dev: DeviceNumberField,
+    /// The number of hard links to the file
+    #[serde(flatten)]
+    pub nlink: NumberOfLinksField,
+    /// The clock when the file was first observed
+    #[serde(flatten)]
+    pub cclock: CreatedClockField,
+    /// The clock when the file was last observed to change
+    #[serde(flatten)]
+    pub oclock: ObservedClockField,
+}
+
+impl Client {
+    /// Returns the files beneath `root` that match any of `patterns`,
+    /// or every file if `patterns` is empty.
+    /// The names of the files are relative to the root of the watch,
+    /// rather than to `root.relative`.
+    pub async fn find(
+        &self,
+        root: &ResolvedRoot,
+        patterns: &[&str],
+    ) -> Result<Vec<FoundFile>, Error> {
+        let response: FindResponse<FoundFile> = self
+            .generic_request(FindRequest(
+                "find",
+                root.root.clone(),
+                patterns.iter().map(|p| p.to_string()).collect(),
+            ))
+            .await?;
+        Ok(response.files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer};
+    use serde_bser::value::Value;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn find() {
+        let server = MockServer::new();
+        server.serve_files(&["a.rs", "b/c.rs"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let files = client.find(&root, &["-p", "*.rs"]).await.unwrap();
+        let names: Vec<_> = files.iter().map(|f| f.name.to_path_buf()).collect();
+        assert_eq!(names, vec![PathBuf::from("a.rs"), PathBuf::from("b/c.rs")]);
+        assert!(*files[0].exists);
+        assert_eq!(*files[0].nlink, 1);
+
+        let request = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("find"))
+            .unwrap();
+        assert_eq!(request_arg(&request, 2), Value::from("-p"));
+        assert_eq!(request_arg(&request, 3), Value::from("*.rs"));
+    }
+}
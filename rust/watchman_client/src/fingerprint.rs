@@ -0,0 +1,322 @@
+//! Compute cache keys from the changes since a clock.
+//!
+//! Build caching systems often need a cheap answer to "has anything
+//! relevant changed since this clock, and if so, is it the same change
+//! as last time".  `fingerprint_since` queries the files that changed
+//! since a clock and digests their names, and optionally their content
+//! hashes, into a `Fingerprint` that is stable across processes, hosts
+//! and versions of this crate:
+//!
+//! ```
+//! use watchman_client::fingerprint::fingerprint_since;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["src/lib.rs"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let since = client.clock(&root, SyncTimeout::Default).await?;
+//! // ... later ...
+//! let result = fingerprint_since(
+//!     &client,
+//!     &root,
+//!     since,
+//!     Some(Expr::Suffix(vec!["rs".into()])),
+//!     true,
+//! )
+//! .await?;
+//! println!("cache key: {}", result.fingerprint);
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::Error;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+query_result_type! {
+    struct ChangedFile {
+        name: NameField,
+        exists: ExistsField,
+    }
+}
+
+query_result_type! {
+    struct HashedFile {
+        name: NameField,
+        exists: ExistsField,
+        hash: ContentSha1HexField,
+    }
+}
+
+const FNV_OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013B;
+
+/// A 128 bit digest of a set of changes.
+/// The digest is computed with FNV-1a, which is stable but not
+/// cryptographically secure: it is suitable for cache keys, but not
+/// for guarding against deliberately crafted collisions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Fingerprint(u128);
+
+impl Fingerprint {
+    /// Returns the digest as an integer
+    pub fn as_u128(self) -> u128 {
+        self.0
+    }
+}
+
+/// Formats the fingerprint as 32 hex digits
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{:032x}", self.0)
+    }
+}
+
+struct Hasher(u128);
+
+impl Hasher {
+    fn new() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u128::from(byte);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    /// Writes a length prefixed value, so that the boundaries between
+    /// values contribute to the digest
+    fn write_value(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+
+    fn write_path(&mut self, path: &Path) {
+        // Use `/` as the separator so that the digest is the same on
+        // every platform
+        let path = path.to_string_lossy().replace('\\', "/");
+        self.write_value(path.as_bytes());
+    }
+
+    fn finish(self) -> Fingerprint {
+        Fingerprint(self.0)
+    }
+}
+
+/// The result of `fingerprint_since`
+#[derive(Debug, Clone)]
+pub struct InvalidationFingerprint {
+    /// The digest of the changes.
+    /// Equal fingerprints mean that the same files changed, and, if
+    /// content hashes were requested, that they have the same content.
+    pub fingerprint: Fingerprint,
+    /// The number of files that contributed to the fingerprint
+    pub files: usize,
+    /// True if the server couldn't describe the changes since the
+    /// clock, in which case the fingerprint covers every matching file
+    /// rather than just those that changed
+    pub is_fresh_instance: bool,
+    /// The clock as of which the fingerprint was computed
+    pub clock: ClockSpec,
+}
+
+impl InvalidationFingerprint {
+    /// Returns true if no matching files changed since the clock
+    pub fn is_unchanged(&self) -> bool {
+        !self.is_fresh_instance && self.files == 0
+    }
+}
+
+/// Compute a fingerprint of the files beneath `root` that match
+/// `expression`, or all files if it is `None`, that changed since
+/// `since`.
+///
+/// The fingerprint covers the names of the files and whether they
+/// still exist.  If `content_hashes` is true it also covers the SHA1
+/// hashes of their contents, which the server computes and caches, so
+/// that different edits to the same set of files produce different
+/// fingerprints.
+/// Fails if the server couldn't hash one of the files.
+pub async fn fingerprint_since(
+    client: &Client,
+    root: &ResolvedRoot,
+    since: ClockSpec,
+    expression: Option<Expr>,
+    content_hashes: bool,
+) -> Result<InvalidationFingerprint, Error> {
+    let query = QueryRequestCommon {
+        since: Some(Clock::Spec(since)),
+        expression,
+        relative_root: root.relative.clone(),
+        ..Default::default()
+    };
+
+    let mut hasher = Hasher::new();
+    let (mut files, clock, is_fresh_instance) = if content_hashes {
+        let result: QueryResult<HashedFile> = client.query(root, query).await?;
+        let mut files = vec![];
+        for file in result.files.unwrap_or_default() {
+            let name = file.name.into_inner();
+            let hash = match file.hash.into_inner() {
+                ContentSha1Hex::Hash(hash) => Some(hash),
+                ContentSha1Hex::None => None,
+                ContentSha1Hex::Error { error } => {
+                    return Err(Error::generic(format!(
+                        "while hashing {}: {}",
+                        name.display(),
+                        error
+                    )))
+                }
+            };
+            files.push((name, *file.exists, hash));
+        }
+        (files, result.clock, result.is_fresh_instance)
+    } else {
+        let result: QueryResult<ChangedFile> = client.query(root, query).await?;
+        let files = result
+            .files
+            .unwrap_or_default()
+            .into_iter()
+            .map(|file| (file.name.into_inner(), *file.exists, None))
+            .collect();
+        (files, result.clock, result.is_fresh_instance)
+    };
+
+    // The server doesn't return the files in any particular order
+    files.sort();
+    hasher.write(&[is_fresh_instance as u8, content_hashes as u8]);
+    for (name, exists, hash) in &files {
+        hasher.write_path(name);
+        hasher.write(&[*exists as u8]);
+        if let Some(hash) = hash {
+            hasher.write_value(hash.as_bytes());
+        }
+    }
+
+    Ok(InvalidationFingerprint {
+        fingerprint: hasher.finish(),
+        files: files.len(),
+        is_fresh_instance,
+        clock: match clock {
+            Clock::Spec(clock) | Clock::ScmAware(FatClockData { clock, .. }) => clock,
+        },
+    })
+}
+
+/// Returns the fingerprint of `paths`, as `fingerprint_since` would
+/// compute it for changes to those existing files without content
+/// hashes.  This is useful for comparing against fingerprints computed
+/// from a file list obtained some other way.
+pub fn fingerprint_paths<P: AsRef<Path>>(paths: &[P]) -> Fingerprint {
+    let mut paths: Vec<PathBuf> = paths.iter().map(|p| p.as_ref().to_path_buf()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut hasher = Hasher::new();
+    hasher.write(&[0, 0]);
+    for path in &paths {
+        hasher.write_path(path);
+        hasher.write(&[1]);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use std::sync::{Arc, Mutex};
+
+    fn serve(server: &MockServer, files: Arc<Mutex<Vec<(&'static str, &'static str)>>>) {
+        server.respond("query", move |_| {
+            let files = files
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|&(name, hash)| {
+                    Value::from(hashmap! {
+                        "name".to_string() => Value::from(name),
+                        "exists".to_string() => Value::Bool(true),
+                        "content.sha1hex".to_string() => Value::from(hash),
+                    })
+                })
+                .collect();
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "clock".to_string() => Value::from("c:0:2"),
+                "files".to_string() => Value::Array(files),
+            }
+            .into()
+        });
+    }
+
+    #[test]
+    fn stable() {
+        // Changing the digest would invalidate everyone's caches
+        assert_eq!(
+            fingerprint_paths(&["b.rs", "a.rs"]).to_string(),
+            fingerprint_paths(&["a.rs", "b.rs", "a.rs"]).to_string()
+        );
+        assert_eq!(
+            fingerprint_paths::<&str>(&[]).to_string(),
+            "0880945b4fab1be95aa0733055273845"
+        );
+        assert_ne!(
+            fingerprint_paths(&["ab", "c"]),
+            fingerprint_paths(&["a", "bc"])
+        );
+    }
+
+    #[tokio::test]
+    async fn fingerprints_changes() {
+        let server = MockServer::new();
+        let files = Arc::new(Mutex::new(vec![("b.rs", "1111"), ("a.rs", "2222")]));
+        serve(&server, Arc::clone(&files));
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let since = ClockSpec::StringClock("c:0:1".into());
+
+        let names = fingerprint_since(&client, &root, since.clone(), None, false)
+            .await
+            .unwrap();
+        assert_eq!(names.files, 2);
+        assert!(!names.is_unchanged());
+        assert_eq!(names.fingerprint, fingerprint_paths(&["a.rs", "b.rs"]));
+
+        let contents = fingerprint_since(&client, &root, since.clone(), None, true)
+            .await
+            .unwrap();
+        assert_ne!(contents.fingerprint, names.fingerprint);
+
+        files.lock().unwrap()[0].1 = "3333";
+        let changed = fingerprint_since(&client, &root, since.clone(), None, true)
+            .await
+            .unwrap();
+        assert_ne!(changed.fingerprint, contents.fingerprint);
+        let unchanged = fingerprint_since(&client, &root, since.clone(), None, false)
+            .await
+            .unwrap();
+        assert_eq!(unchanged.fingerprint, names.fingerprint);
+
+        files.lock().unwrap().clear();
+        let empty = fingerprint_since(&client, &root, since, None, false)
+            .await
+            .unwrap();
+        assert!(empty.is_unchanged());
+    }
+}
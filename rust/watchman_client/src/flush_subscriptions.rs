@@ -0,0 +1,134 @@
+//! Deliver the pending changes of subscriptions on demand.
+//!
+//! A subscription that defers or drops its notifications while a root
+//! is in a named state, or that has simply yet to be notified of the
+//! most recent changes, can be brought up to date with
+//! `Client::flush_subscriptions`:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use std::time::Duration;
+//! # use watchman_client::test_support::MockServer;
+//! # use maplit::hashmap;
+//! # use serde_bser::value::Value;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.respond("flush-subscriptions", |_| {
+//! #     hashmap! {
+//! #         "version".to_string() => Value::from("mock"),
+//! #         "synced".to_string() => Value::Array(vec!["mysub".into()]),
+//! #     }
+//! #     .into()
+//! # });
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let flushed = client
+//!     .flush_subscriptions(&root, Duration::from_secs(5), None)
+//!     .await?;
+//! for name in &flushed.synced {
+//!     println!("{} has changes to process", name);
+//! }
+//! # assert_eq!(flushed.synced, vec!["mysub".to_string()]);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The server sends the changes for each of the `synced` subscriptions
+//! before its response, so by the time that `flush_subscriptions`
+//! returns they are waiting to be read from the `Subscription`.
+use crate::pdu::{FlushSubscriptionsParams, FlushSubscriptionsRequest, FlushSubscriptionsResponse};
+use crate::prelude::*;
+use crate::Error;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+impl Client {
+    /// Deliver the pending changes of the subscriptions named `names`
+    /// on `root`, or of all of this client's subscriptions on `root` if
+    /// `names` is `None`, without waiting for any state that they defer
+    /// their notifications for to be left.
+    /// The server waits for up to `timeout` for its view of the
+    /// filesystem to catch up before flushing the subscriptions.
+    /// Naming a subscription that this client does not have, or one on
+    /// a different root, is an error.
+    pub async fn flush_subscriptions(
+        &self,
+        root: &ResolvedRoot,
+        timeout: Duration,
+        names: Option<&[&str]>,
+    ) -> Result<FlushSubscriptionsResponse, Error> {
+        let request = FlushSubscriptionsRequest(
+            "flush-subscriptions",
+            root.root.clone(),
+            FlushSubscriptionsParams {
+                sync_timeout: i64::try_from(timeout.as_millis()).unwrap_or(i64::MAX),
+                subscriptions: names.map(|names| names.iter().map(|n| n.to_string()).collect()),
+            },
+        );
+        self.generic_request(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    #[tokio::test]
+    async fn flushes() {
+        let server = MockServer::new();
+        server.respond("flush-subscriptions", |_| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "synced".to_string() => Value::Array(vec!["a".into()]),
+                "dropped".to_string() => Value::Array(vec!["b".into()]),
+            }
+            .into()
+        });
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let flushed = client
+            .flush_subscriptions(&root, Duration::from_millis(250), Some(&["a", "b"]))
+            .await
+            .unwrap();
+        assert_eq!(flushed.synced, vec!["a".to_string()]);
+        assert!(flushed.no_sync_needed.is_empty());
+        assert_eq!(flushed.dropped, vec!["b".to_string()]);
+
+        client
+            .flush_subscriptions(&root, Duration::from_secs(1), None)
+            .await
+            .unwrap();
+        let params: Vec<Value> = server
+            .requests()
+            .iter()
+            .filter(|r| request_arg(r, 0) == Value::from("flush-subscriptions"))
+            .map(|r| request_arg(r, 2))
+            .collect();
+        assert_eq!(
+            params,
+            vec![
+                Value::from(hashmap! {
+                    "sync_timeout".to_string() => Value::Integer(250),
+                    "subscriptions".to_string() => Value::Array(vec!["a".into(), "b".into()]),
+                }),
+                Value::from(hashmap! {
+                    "sync_timeout".to_string() => Value::Integer(1000),
+                }),
+            ]
+        );
+    }
+}
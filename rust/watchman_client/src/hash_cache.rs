@@ -0,0 +1,346 @@
+//! Cache content hashes, invalidated by a subscription.
+//!
+//! The server can compute the SHA1 hash of a file's contents via the
+//! `content.sha1hex` field, and caches it internally, but each lookup
+//! is still a round trip.  `ContentHashCache` remembers the hashes it
+//! has looked up and forgets them when the subscription reports that a
+//! file changed, so repeated lookups of unchanged files are answered
+//! from memory:
+//!
+//! ```
+//! use std::path::Path;
+//! use watchman_client::hash_cache::ContentHashCache;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["Cargo.toml"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let cache = ContentHashCache::new(&client, &root).await?;
+//! if let ContentSha1Hex::Hash(hash) = cache.hash(&client, Path::new("Cargo.toml")).await? {
+//!     println!("{}", hash);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::oneshot;
+
+query_result_type! {
+    struct HashedFile {
+        name: NameField,
+        hash: ContentSha1HexField,
+    }
+}
+
+#[derive(Default)]
+struct State {
+    hashes: HashMap<PathBuf, ContentSha1Hex>,
+    /// Counts the invalidations of each path, so that a lookup that
+    /// raced with a change doesn't cache a stale result
+    generations: HashMap<PathBuf, u64>,
+    /// Counts the invalidations of everything, due to fresh instances
+    epoch: u64,
+}
+
+impl State {
+    fn generation(&self, path: &Path) -> (u64, u64) {
+        (
+            self.epoch,
+            self.generations.get(path).copied().unwrap_or_default(),
+        )
+    }
+
+    fn invalidate(&mut self, path: PathBuf) {
+        self.hashes.remove(&path);
+        *self.generations.entry(path).or_default() += 1;
+    }
+}
+
+/// Answers "what is the content hash of this file" lookups from a
+/// cache that is kept current by a subscription.
+///
+/// Hashes are cached only if the server computed them successfully;
+/// errors are retried on the next lookup.
+pub struct ContentHashCache {
+    root: ResolvedRoot,
+    state: Arc<Mutex<State>>,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+impl std::fmt::Debug for ContentHashCache {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("ContentHashCache")
+            .field("root", &self.root)
+            .field("cached", &self.state().hashes.len())
+            .finish()
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    match state.lock() {
+        Ok(state) => state,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl ContentHashCache {
+    /// Create an empty cache for the files beneath `root`
+    pub async fn new(client: &Client, root: &ResolvedRoot) -> Result<Self, Error> {
+        let (subscription, _) = client
+            .subscribe::<NameOnly>(
+                root,
+                SubscribeRequest {
+                    empty_on_fresh_instance: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let (cancel, canceled) = oneshot::channel();
+        tokio::spawn(invalidate(subscription, Arc::clone(&state), canceled));
+        Ok(Self {
+            root: root.clone(),
+            state,
+            cancel: Some(cancel),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    /// Returns the hash of `path`, which is relative to the root.
+    /// `client` must be connected to the same server as the client
+    /// used to create the cache.
+    /// Returns `ContentSha1Hex::None` if the file doesn't exist.
+    pub async fn hash(&self, client: &Client, path: &Path) -> Result<ContentSha1Hex, Error> {
+        let mut hashes = self.hashes(client, &[path.to_path_buf()]).await?;
+        Ok(hashes.remove(path).unwrap_or(ContentSha1Hex::None))
+    }
+
+    /// Returns the hashes of `paths`, which are relative to the root,
+    /// looking up all of those that are not cached in a single query.
+    /// Paths that don't exist are omitted from the result.
+    pub async fn hashes(
+        &self,
+        client: &Client,
+        paths: &[PathBuf],
+    ) -> Result<HashMap<PathBuf, ContentSha1Hex>, Error> {
+        let mut result = HashMap::new();
+        let mut misses = vec![];
+        {
+            let state = self.state();
+            for path in paths {
+                match state.hashes.get(path) {
+                    Some(hash) => {
+                        result.insert(path.clone(), hash.clone());
+                    }
+                    None => misses.push((path.clone(), state.generation(path))),
+                }
+            }
+        }
+        if misses.is_empty() {
+            return Ok(result);
+        }
+
+        let response: QueryResult<HashedFile> = client
+            .query(
+                &self.root,
+                QueryRequestCommon {
+                    path: Some(
+                        misses
+                            .iter()
+                            .map(|(path, _)| PathGeneratorElement::ConstrainedDepth {
+                                path: path.clone(),
+                                depth: 0,
+                            })
+                            .collect(),
+                    ),
+                    expression: Some(Expr::Exists),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let generations: HashMap<PathBuf, (u64, u64)> = misses.into_iter().collect();
+        let mut state = self.state();
+        for file in response.files.unwrap_or_default() {
+            let name = file.name.into_inner();
+            let hash = file.hash.into_inner();
+            // Only remember the hash if the file hasn't changed since
+            // we started looking it up
+            if let ContentSha1Hex::Hash(_) = &hash {
+                if generations.get(&name) == Some(&state.generation(&name)) {
+                    state.hashes.insert(name.clone(), hash.clone());
+                }
+            }
+            if generations.contains_key(&name) {
+                result.insert(name, hash);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns the number of cached hashes
+    pub fn len(&self) -> usize {
+        self.state().hashes.len()
+    }
+
+    /// Returns true if no hashes are cached
+    pub fn is_empty(&self) -> bool {
+        self.state().hashes.is_empty()
+    }
+}
+
+impl Drop for ContentHashCache {
+    fn drop(&mut self) {
+        // Ends the task that maintains the cache
+        if let Some(cancel) = self.cancel.take() {
+            cancel.send(()).ok();
+        }
+    }
+}
+
+/// Forgets the hashes of the files that change, until the cache is
+/// dropped or the subscription ends
+async fn invalidate(
+    mut subscription: Subscription<NameOnly>,
+    state: Arc<Mutex<State>>,
+    mut canceled: oneshot::Receiver<()>,
+) {
+    loop {
+        let data = tokio::select! {
+            _ = &mut canceled => {
+                subscription.cancel().await.ok();
+                return;
+            }
+            data = subscription.next() => data,
+        };
+        match data {
+            Ok(SubscriptionData::FilesChanged(result)) => {
+                let mut state = lock(&state);
+                if result.is_fresh_instance {
+                    state.hashes.clear();
+                    state.epoch += 1;
+                }
+                for file in result.files.unwrap_or_default() {
+                    state.invalidate(file.name.into_inner());
+                }
+            }
+            Ok(SubscriptionData::StateEnter { .. }) | Ok(SubscriptionData::StateLeave { .. }) => {}
+            Ok(SubscriptionData::Canceled) | Err(_) => {
+                // Without invalidations the cache can't be trusted,
+                // so stop caching altogether
+                let mut state = lock(&state);
+                state.hashes.clear();
+                state.epoch = u64::MAX;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn caches_until_changed() {
+        let server = MockServer::new();
+        let queries = Arc::new(AtomicUsize::new(0));
+        {
+            let queries = Arc::clone(&queries);
+            // Respond with a hash derived from the number of queries
+            // made so far, for each requested path other than
+            // `missing`
+            server.respond("query", move |request| {
+                let count = queries.fetch_add(1, Ordering::SeqCst);
+                let paths = match request_arg(request, 2) {
+                    Value::Object(mut query) => match query.remove("path") {
+                        Some(Value::Array(paths)) => paths,
+                        _ => vec![],
+                    },
+                    _ => vec![],
+                };
+                let files = paths
+                    .into_iter()
+                    .filter_map(|element| match element {
+                        Value::Object(mut element) => element.remove("path"),
+                        _ => None,
+                    })
+                    .filter(|path| *path != Value::from("missing"))
+                    .map(|path| {
+                        Value::from(hashmap! {
+                            "name".to_string() => path,
+                            "content.sha1hex".to_string() => Value::from(format!("{:040}", count)),
+                        })
+                    })
+                    .collect();
+                hashmap! {
+                    "version".to_string() => Value::from("mock"),
+                    "clock".to_string() => Value::from("c:0:1"),
+                    "files".to_string() => Value::Array(files),
+                }
+                .into()
+            });
+        }
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let cache = ContentHashCache::new(&client, &root).await.unwrap();
+
+        let a = Path::new("a.txt");
+        let first = cache.hash(&client, a).await.unwrap();
+        assert!(matches!(first, ContentSha1Hex::Hash(_)));
+        assert_eq!(cache.hash(&client, a).await.unwrap(), first);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cache.hash(&client, Path::new("missing")).await.unwrap(),
+            ContentSha1Hex::None
+        );
+        assert_eq!(cache.len(), 1);
+
+        let hashes = cache
+            .hashes(&client, &[a.to_path_buf(), "b.txt".into()])
+            .await
+            .unwrap();
+        assert_eq!(hashes[a], first);
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+
+        let subscribe = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .unwrap();
+        let name = match request_arg(&subscribe, 2) {
+            Value::Utf8String(name) => name,
+            other => panic!("unexpected {:?}", other),
+        };
+        server.push(subscription_pdu(&name, "c:0:2", vec!["a.txt".into()]));
+        while cache.len() == 2 {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        assert_ne!(cache.hash(&client, a).await.unwrap(), first);
+    }
+}
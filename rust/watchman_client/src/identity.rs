@@ -0,0 +1,69 @@
+//! Identify the process that owns a connection to the server.
+//!
+//! The server's logs and its `debug-get-subscriptions` output only show
+//! the names that clients chose for their subscriptions, which makes it
+//! hard to tell which process a misbehaving subscription belongs to.
+//! This crate names its subscriptions after the `ClientIdentity` of the
+//! process, and `Connector::identify` additionally writes it to the
+//! server's log on connecting, so that the log lines that follow can be
+//! tied to the owning process.
+use std::fmt;
+
+/// Describes the process using this crate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// The name that the process was invoked with
+    pub argv0: String,
+    /// The id of the process
+    pub pid: u32,
+    /// The version of this crate
+    pub crate_version: &'static str,
+}
+
+impl ClientIdentity {
+    /// Returns the identity of the current process
+    pub fn current() -> Self {
+        Self {
+            argv0: std::env::args()
+                .next()
+                .unwrap_or_else(|| "<no-argv-0>".to_string()),
+            pid: std::process::id(),
+            crate_version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+
+    /// Returns the name to use for the subscription with the given
+    /// number
+    pub(crate) fn subscription_name(&self, prefix: &str, id: usize) -> String {
+        format!("{}-[{}:{}]-{}", prefix, self.argv0, self.pid, id)
+    }
+}
+
+/// Formats the identity as it is written to the server's log
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} (pid {}, watchman_client {})",
+            self.argv0, self.pid, self.crate_version
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current() {
+        let identity = ClientIdentity::current();
+        assert_eq!(identity.pid, std::process::id());
+        assert_eq!(
+            identity.subscription_name("sub", 3),
+            format!("sub-[{}:{}]-3", identity.argv0, identity.pid)
+        );
+        assert!(identity
+            .to_string()
+            .ends_with(&format!("watchman_client {})", env!("CARGO_PKG_VERSION"))));
+    }
+}
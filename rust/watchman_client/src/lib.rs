@@ -11,11 +11,17 @@
 //! This example shows how to connect and expand a glob from the
 //! current working directory:
 //!
-//! ```norun
+//! ```
 //! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
 //! #[tokio::main]
 //! async fn main() -> Result<(), Box<dyn std::error::Error>> {
-//!   let mut client = Connector::new().connect().await?;
+//! #   let server = MockServer::new();
+//! #   server.serve_files(&["src/lib.rs"]);
+//! #   /*
+//!   let client = Connector::new().connect().await?;
+//! #   */
+//! #   let client = server.connect();
 //!   let resolved = client
 //!      .resolve_root(CanonicalPath::canonicalize(".")?)
 //!      .await?;
@@ -26,24 +32,150 @@
 //!   Ok(())
 //! }
 //! ```
+//!
+//! The examples in this documentation are run against the in-process
+//! [MockServer](test_support/struct.MockServer.html), which is available
+//! when the `test-support` feature is enabled, so that they can be
+//! verified without a real watchman server.
+//!
+//! Enabling the `arbitrary` feature provides implementations of
+//! `arbitrary::Arbitrary` for the types in the `pdu` and `expr`
+//! modules, which is useful for fuzzing and property testing.
+//!
+//! Enabling the `blocking` feature provides the
+//! [blocking](blocking/index.html) module, whose `Client` runs its own
+//! runtime and blocks the calling thread, for use from synchronous code.
+//!
+//! Enabling the `toml` feature provides the
+//! [client_config](client_config/index.html) module, which configures
+//! connections using a TOML file.
+//!
+//! Enabling the `tracing` feature instruments connecting, the tasks
+//! that service the connection, requests and `Subscription::next` with
+//! [tracing](https://docs.rs/tracing) spans and events, which record
+//! command names, PDU sizes and the depth of the request queue.
+//!
+//! Enabling the `unicode-normalization` feature provides the
+//! [unicode_paths](unicode_paths/index.html) module, which normalizes
+//! the paths in queries and results so that they compare reliably on
+//! macOS.
+//!
+//! Enabling the `cli` feature builds the `watchman-rs` command line
+//! tool, whose `glob`, `since`, `clock`, `subscribe` and `dump-pdu`
+//! subcommands are handy for debugging and double as examples of
+//! using this crate.
+#[cfg(feature = "arbitrary")]
+mod arbitrary_impls;
+pub mod backpressure;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod blocking_subscription;
+pub mod cargo;
+pub mod case_fold;
+pub mod change_journal;
+#[cfg(feature = "toml")]
+pub mod client_config;
+pub mod clock_store;
+pub mod codec;
+pub mod connection_info;
+mod debounce;
+pub mod diff;
+pub mod dirty_tracker;
+pub mod dynamic_fields;
 pub mod expr;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod fields;
+pub mod file_source;
+pub mod file_watcher;
+pub mod find;
+pub mod fingerprint;
+pub mod flush_subscriptions;
+pub mod hash_cache;
+pub mod identity;
+pub mod lifecycle;
+pub mod log_stream;
+#[cfg(feature = "lsp-types")]
+pub mod lsp;
+pub mod metrics;
+pub mod name_subscription;
 mod named_pipe;
+#[cfg(feature = "notify")]
+pub mod notify_compat;
 pub mod pdu;
-use serde_bser::de::{Bunser, PduInfo, SliceRead};
+pub mod presets;
+pub mod query_planner;
+pub mod query_stream;
+pub mod reconnect;
+pub mod resume;
+mod root_cache;
+pub mod scm_status;
+pub mod server_info;
+pub mod shutdown;
+pub mod snapshot_verify;
+mod state_consolidation;
+pub mod state_guard;
+pub mod state_wait;
+pub mod subscription_builder;
+pub mod subscription_group;
+pub mod subscription_stats;
+pub mod symlink_escape;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod traffic_log;
+pub mod transport;
+pub mod tree_mirror;
+pub mod triggers;
+#[cfg(feature = "unicode-normalization")]
+pub mod unicode_paths;
+pub mod watchman_config;
+pub mod workspace;
+use backpressure::{Bound, PduReceiver, PduSender, Queued};
+use bytes::{Bytes, BytesMut};
+use codec::Codec;
+use debounce::Debounce;
+use identity::ClientIdentity;
+use lifecycle::{ConnectionEvent, EventSink};
+use metrics::{ClientMetrics, SharedMetrics};
+use query_stream::{RowSink, SharedStreams};
+use reconnect::{Dialer, ReconnectPolicy, Reconnector};
+use root_cache::RootCache;
+use serde_bser::de::{Bunser, SliceRead};
 use serde_bser::value::Value;
-use std::collections::{HashMap, VecDeque};
+use state_consolidation::StateConsolidation;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::future::Future;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use subscription_stats::SubscriptionStats;
 use thiserror::Error;
 #[cfg(unix)]
 use tokio::net::UnixStream;
 use tokio::prelude::*;
 use tokio::process::Command;
-use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedSender};
 use tokio::sync::Mutex;
+use traffic_log::{Direction, TrafficLogger};
+use transport::Transport;
+
+/// Used by the code generated by `#[derive(QueryFields)]`
+#[doc(hidden)]
+pub mod __private {
+    pub use serde;
+}
+
+/// Emits a `tracing` event when the `tracing` feature is enabled, and
+/// otherwise expands to nothing, so that its arguments cost nothing
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)*);
+    }};
+}
 
 /// The next id number to use when generating a subscription name
 static SUB_ID: AtomicUsize = AtomicUsize::new(1);
@@ -56,6 +188,7 @@ pub mod prelude {
     pub use crate::pdu::*;
     pub use crate::query_result_type;
     pub use crate::{CanonicalPath, Client, Connector, ResolvedRoot};
+    pub use watchman_client_derive::QueryFields;
 }
 
 use prelude::*;
@@ -71,7 +204,12 @@ pub enum Error {
         stderr: String,
     },
     #[error("The watchman server reported an error: \"{}\", while executing command: {}", .message, .command)]
-    WatchmanServerError { message: String, command: String },
+    WatchmanServerError {
+        message: String,
+        command: String,
+        /// What went wrong, as far as can be told from `message`
+        kind: ServerErrorKind,
+    },
     #[error("The watchman server reported an error: \"{}\"", .message)]
     WatchmanResponseError { message: String },
     #[error("The watchman server didn't return a value for field `{}` in response to a `{}` command. {:?}", .fieldname, .command, .response)]
@@ -83,6 +221,18 @@ pub enum Error {
     #[error("Unexpected EOF from server")]
     Eof,
 
+    #[error("The watchman server (version {version}) doesn't support the required capabilities: {}", .missing.join(", "))]
+    MissingCapability {
+        version: String,
+        missing: Vec<String>,
+    },
+
+    #[error("The subscription fell behind, and {dropped} of its results were discarded")]
+    SubscriptionLagged { dropped: usize },
+
+    #[error("Timed out waiting for the watchman server to respond to: {command}")]
+    Timeout { command: String },
+
     #[error("{source} (data: {data:x?})")]
     Deserialize {
         source: Box<dyn std::error::Error + Send>,
@@ -108,6 +258,71 @@ impl Error {
     fn generic<T: std::fmt::Display>(error: T) -> Self {
         Self::Generic(format!("{}", error))
     }
+
+    /// Returns the kind of error reported by the server, if this is
+    /// `Error::WatchmanServerError`
+    pub fn server_error_kind(&self) -> Option<ServerErrorKind> {
+        match self {
+            Self::WatchmanServerError { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+}
+
+/// The categories of error that the server reports, so that callers
+/// can tell them apart without matching on the message themselves.
+/// The server reports errors only as messages, so these are recognized
+/// by the wording that it uses for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerErrorKind {
+    /// The root isn't watched, and the command doesn't watch it
+    RootNotWatched,
+    /// The path couldn't be resolved to a root for another reason, such
+    /// as not existing or being excluded by the server's configuration
+    RootResolveError,
+    /// The server didn't observe its sync cookie within the
+    /// `SyncTimeout`, which usually means that it is busy crawling
+    SyncTimeout,
+    /// The server, or this client, lacks permission to do what was
+    /// asked, such as watching a new root as a user other than the
+    /// server's owner
+    PermissionDenied,
+    /// The query or expression couldn't be parsed
+    QueryParseError,
+    /// The command is unknown, or its arguments are invalid
+    InvalidCommand,
+    /// Any other error
+    Unknown,
+}
+
+impl ServerErrorKind {
+    /// Classify the error `message` sent by the server
+    pub fn from_message(message: &str) -> Self {
+        // The more specific causes are checked first, as they may be
+        // wrapped in the more general ones
+        if message.contains("timed out waiting for cookie") {
+            Self::SyncTimeout
+        } else if message.contains("is not watched") {
+            Self::RootNotWatched
+        } else if message.contains("ermission denied")
+            || message.contains("not the process owner")
+            || message.contains("to prevent watching")
+        {
+            Self::PermissionDenied
+        } else if message.contains("RootResolveError") || message.contains("unable to resolve root")
+        {
+            Self::RootResolveError
+        } else if message.starts_with("failed to parse query") {
+            Self::QueryParseError
+        } else if message.starts_with("failed to validate command")
+            || message.contains("unknown command")
+        {
+            Self::InvalidCommand
+        } else {
+            Self::Unknown
+        }
+    }
 }
 
 /// The Connector defines how to connect to the watchman server.
@@ -120,6 +335,52 @@ impl Error {
 pub struct Connector {
     watchman_cli_path: Option<PathBuf>,
     unix_domain: Option<PathBuf>,
+    traffic_logger: Option<TrafficLogger>,
+    events: EventSink,
+    cache_roots: bool,
+    sync_timeout: SyncTimeout,
+    identify: bool,
+    request_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    connect_retries: usize,
+    connect_retry_delay: Duration,
+    request_queue_size: Option<usize>,
+    max_outstanding_requests: Option<usize>,
+    reconnect: Option<ReconnectPolicy>,
+    /// Opens further connections to the endpoint, for `reconnect`
+    dialer: Option<Dialer>,
+    transport: Option<Arc<dyn Transport>>,
+    codec: Codec,
+    metrics: SharedMetrics,
+}
+
+/// The number of requests that may be queued for the client task
+/// before callers wait for space, unless overridden with
+/// `Connector::request_queue_size`
+const DEFAULT_REQUEST_QUEUE_SIZE: usize = 128;
+
+impl std::fmt::Debug for Connector {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Connector")
+            .field("watchman_cli_path", &self.watchman_cli_path)
+            .field("unix_domain", &self.unix_domain)
+            .field("traffic_logger", &self.traffic_logger)
+            .field("lifecycle_events", &self.events.has_callback())
+            .field("cache_roots", &self.cache_roots)
+            .field("sync_timeout", &self.sync_timeout)
+            .field("identify", &self.identify)
+            .field("request_timeout", &self.request_timeout)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("connect_retries", &self.connect_retries)
+            .field("connect_retry_delay", &self.connect_retry_delay)
+            .field("request_queue_size", &self.request_queue_size)
+            .field("max_outstanding_requests", &self.max_outstanding_requests)
+            .field("reconnect", &self.reconnect)
+            .field("transport", &self.transport.is_some())
+            .field("codec", &self.codec)
+            .field("metrics", &self.metrics.is_some())
+            .finish()
+    }
 }
 
 impl Connector {
@@ -152,99 +413,490 @@ impl Connector {
         self
     }
 
+    /// Connect to the server using streams opened by `transport`, such
+    /// as a tunnel to a remote machine, rather than the local socket.
+    /// No discovery is performed and the endpoint settings are ignored,
+    /// but `connect_timeout`, `connect_retries` and `reconnect` apply as
+    /// they do to the local socket.
+    /// See the [transport](transport/index.html) module.
+    pub fn with_transport(mut self, transport: impl Transport) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Encode the PDUs exchanged with the server using `codec`.
+    /// The default is `Codec::BserV2`; `Codec::Json` is slower, but
+    /// can pass through proxies that only relay text.
+    /// See the [codec](codec/index.html) module.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Report measurements of the requests, traffic and subscriptions
+    /// of the client to `metrics`.
+    /// See the [metrics](metrics/index.html) module.
+    pub fn with_metrics(mut self, metrics: impl ClientMetrics) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Record every PDU exchanged with the server using the supplied
+    /// logger.  This is intended to help with debugging and is not
+    /// recommended for general use.
+    /// See the [traffic_log](traffic_log/index.html) module for more details.
+    pub fn traffic_logger(mut self, logger: TrafficLogger) -> Self {
+        self.traffic_logger = Some(logger);
+        self
+    }
+
+    /// Register a callback that will be invoked as the connection
+    /// progresses through its lifecycle.
+    /// See the [lifecycle](lifecycle/index.html) module for more details.
+    pub fn lifecycle_events<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&ConnectionEvent) + Send + Sync + 'static,
+    {
+        self.events = EventSink::new(callback);
+        self
+    }
+
+    /// Have the client remember the roots returned by
+    /// `Client::resolve_root`, so that resolving the same path again,
+    /// whether via the same client or a clone of it, doesn't make a
+    /// request to the server.
+    /// A path is forgotten when the server reports that it stopped
+    /// watching the root, which it does by canceling the subscriptions
+    /// on that root; without a subscription on the root, the client
+    /// can't tell that the watch was removed, for example by
+    /// `watchman watch-del`, unless it was removed via
+    /// `Client::watch_del` or `Client::watch_del_all`.  Use
+    /// `Client::forget_resolved_roots` if that is a concern.
+    /// The default is not to remember the roots.
+    pub fn cache_resolved_roots(mut self, enable: bool) -> Self {
+        self.cache_roots = enable;
+        self
+    }
+
+    /// Set the sync cookie timeout that the client uses in place of
+    /// `SyncTimeout::Default` for queries and `clock` calls, so that it
+    /// doesn't need to be specified for each of them.
+    /// Accepts a `SyncTimeout` or a `Duration`; a zero duration disables
+    /// the use of sync cookies.
+    /// The default is the server's default timeout.
+    pub fn sync_timeout<T: Into<SyncTimeout>>(mut self, timeout: T) -> Self {
+        self.sync_timeout = timeout.into();
+        self
+    }
+
+    /// Write the `ClientIdentity` of this process to the server's log
+    /// at the `debug` level when connecting, so that the server's logs
+    /// can be tied back to the process.
+    /// It is written in the background, and failing to write it doesn't
+    /// prevent the connection from being used.
+    /// See the [identity](identity/index.html) module for more details.
+    /// The default is not to write it.
+    pub fn identify(mut self, enable: bool) -> Self {
+        self.identify = enable;
+        self
+    }
+
+    /// Set the default timeout for requests made via the client; see
+    /// `Client::request_timeout`
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail `connect` with `Error::Timeout` if the server can't be
+    /// reached within `timeout`, which covers discovering the server
+    /// via the watchman CLI as well as any `connect_retries`.
+    /// This also limits each attempt to `reconnect`.
+    /// Requests made once connected are instead subject to
+    /// `request_timeout`.
+    /// The default is to wait indefinitely.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// If connecting to the server fails, for example because it is
+    /// restarting, retry up to `retries` times, waiting `delay` before
+    /// each retry.
+    /// The default is not to retry.
+    pub fn connect_retries(mut self, retries: usize, delay: Duration) -> Self {
+        self.connect_retries = retries;
+        self.connect_retry_delay = delay;
+        self
+    }
+
+    /// If the connection to the server is lost, for example because it
+    /// was restarted, connect again as described by `policy` and
+    /// re-establish the subscriptions rather than failing them.
+    /// See the [reconnect](reconnect/index.html) module for more details.
+    /// The default is not to reconnect.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Set the number of requests that may be queued for sending to
+    /// the server, across all of the clones of the client, before
+    /// making further requests waits for space.  The default is 128.
+    pub fn request_queue_size(mut self, size: usize) -> Self {
+        self.request_queue_size = Some(size.max(1));
+        self
+    }
+
+    /// Set the number of requests that may have been sent to the server
+    /// without having been responded to yet.  The server responds to
+    /// the requests on a connection in the order that it receives them,
+    /// so sending the requests that are queued without waiting for the
+    /// responses to those before them saves a round trip for each.
+    /// The default is 1, which waits for each response before sending
+    /// the next request.
+    pub fn max_outstanding_requests(mut self, limit: usize) -> Self {
+        self.max_outstanding_requests = Some(limit.max(1));
+        self
+    }
+
     /// Resolve the unix domain socket path, taking either the override
     /// or performing discovery.
     async fn resolve_unix_domain_path(&self) -> Result<PathBuf, Error> {
-        if let Some(path) = self.unix_domain.as_ref() {
-            Ok(path.clone())
+        let start = Instant::now();
+        let (endpoint, discovered) = if let Some(path) = self.unix_domain.as_ref() {
+            (path.clone(), false)
         } else {
-            let watchman_path = self
-                .watchman_cli_path
-                .as_ref()
-                .map(|p| p.as_ref())
-                .unwrap_or_else(|| Path::new("watchman"));
-
-            let output = Command::new(watchman_path)
-                .args(&["--output-encoding", "bser-v2", "get-sockname"])
-                .output()
-                .await
-                .map_err(|source| Error::ConnectionDiscovery {
+            match self.discover_unix_domain_path().await {
+                Ok(path) => (path, true),
+                Err(err) => {
+                    self.events.emit(ConnectionEvent::DiscoveryFailed {
+                        reason: err.to_string(),
+                    });
+                    return Err(err);
+                }
+            }
+        };
+        self.events.emit(ConnectionEvent::EndpointChosen {
+            endpoint: endpoint.clone(),
+            discovered,
+            elapsed: start.elapsed(),
+        });
+        Ok(endpoint)
+    }
+
+    /// Invoke the watchman CLI to discover the unix domain socket path
+    async fn discover_unix_domain_path(&self) -> Result<PathBuf, Error> {
+        let watchman_path = self
+            .watchman_cli_path
+            .as_ref()
+            .map(|p| p.as_ref())
+            .unwrap_or_else(|| Path::new("watchman"));
+
+        self.events.emit(ConnectionEvent::DiscoveryStarted {
+            watchman_path: watchman_path.to_path_buf(),
+        });
+
+        let output = Command::new(watchman_path)
+            .args(["--output-encoding", "bser-v2", "get-sockname"])
+            // Don't leave the CLI running if `connect_timeout` expires
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|source| Error::ConnectionDiscovery {
+                watchman_path: watchman_path.to_path_buf(),
+                reason: source.to_string(),
+                stderr: "".to_string(),
+            })?;
+
+        let info: GetSockNameResponse =
+            serde_bser::from_slice(&output.stdout).map_err(|source| {
+                Error::ConnectionDiscovery {
                     watchman_path: watchman_path.to_path_buf(),
                     reason: source.to_string(),
-                    stderr: "".to_string(),
-                })?;
-
-            let info: GetSockNameResponse =
-                serde_bser::from_slice(&output.stdout).map_err(|source| {
-                    Error::ConnectionDiscovery {
-                        watchman_path: watchman_path.to_path_buf(),
-                        reason: source.to_string(),
-                        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
-                    }
-                })?;
-
-            let debug = format!("{:#?}", info);
+                    stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                }
+            })?;
 
-            if let Some(message) = info.error {
-                return Err(Error::WatchmanServerError {
-                    message,
-                    command: "get-sockname".into(),
-                });
-            }
+        let debug = format!("{:#?}", info);
+        self.events.server_version(&info.version);
 
-            info.sockname.ok_or_else(|| Error::MissingField {
-                fieldname: "sockname",
+        if let Some(message) = info.error {
+            return Err(Error::WatchmanServerError {
+                kind: ServerErrorKind::from_message(&message),
+                message,
                 command: "get-sockname".into(),
-                response: debug,
-            })
+            });
         }
+
+        info.sockname.ok_or_else(|| Error::MissingField {
+            fieldname: "sockname",
+            command: "get-sockname".into(),
+            response: debug,
+        })
     }
 
     /// Establish a connection to the watchman server.
     /// If the connector was configured to perform discovery (which is
     /// the default configuration), then this will attempt to start
     /// the watchman server.
-    pub async fn connect(self) -> Result<Client, Error> {
-        let sock_path = self.resolve_unix_domain_path().await?;
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub async fn connect(mut self) -> Result<Client, Error> {
+        let transport = self.transport.take();
+        let (sock_path, dialer, stream) = with_connect_timeout(self.connect_timeout, async {
+            let (sock_path, dialer): (Option<PathBuf>, Dialer) = match transport {
+                Some(transport) => {
+                    trace_event!(debug, "connecting using a transport");
+                    let dialer = Arc::new(move || {
+                        let transport = Arc::clone(&transport);
+                        Box::pin(async move { transport.connect().await }) as _
+                    });
+                    (None, dialer)
+                }
+                None => {
+                    let sock_path = self.resolve_unix_domain_path().await?;
+                    trace_event!(debug, endpoint = %sock_path.display(), "connecting");
+                    let endpoint = sock_path.clone();
+                    let events = self.events.clone();
+                    let dialer = Arc::new(move || {
+                        Box::pin(connect_endpoint(endpoint.clone(), events.clone())) as _
+                    });
+                    (Some(sock_path), dialer)
+                }
+            };
+
+            let mut retries = self.connect_retries;
+            loop {
+                match dialer().await {
+                    Ok(stream) => break Ok((sock_path, dialer, stream)),
+                    Err(err) => {
+                        if retries == 0 {
+                            return Err(err);
+                        }
+                        trace_event!(debug, error = %err, retries, "connect failed, retrying");
+                        retries -= 1;
+                        tokio::time::delay_for(self.connect_retry_delay).await;
+                    }
+                }
+            }
+        })
+        .await?;
+
+        if self.reconnect.is_some() {
+            let timeout = self.connect_timeout;
+            self.dialer = Some(Arc::new(move || {
+                Box::pin(with_connect_timeout(timeout, dialer()))
+            }));
+        }
 
-        #[cfg(unix)]
-        let stream: Box<dyn ReadWriteStream> = Box::new(UnixStream::connect(sock_path).await?);
+        let identify = self.identify;
+        let client = self.spawn_client(stream, sock_path);
+        if identify {
+            spawn_identify(&client);
+        }
+        Ok(client)
+    }
 
-        #[cfg(windows)]
-        let stream: Box<dyn ReadWriteStream> =
-            Box::new(named_pipe::NamedPipe::connect(sock_path).await?);
+    /// Returns a Client that talks to the server over `stream`, which
+    /// must already be connected to it, or to something that speaks the
+    /// protocol in its place, such as
+    /// [MockServer](test_support/struct.MockServer.html).
+    /// No discovery is performed, and the endpoint and connect retry
+    /// settings are ignored.
+    /// `reconnect` has no effect, as there is no way to establish a new
+    /// stream once this one is closed.
+    /// As the tasks that service the connection are spawned, this must
+    /// be called from within a tokio runtime.
+    ///
+    /// ```
+    /// use watchman_client::prelude::*;
+    /// use watchman_client::test_support::MockServer;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let server = MockServer::new();
+    /// let client = Connector::new().with_stream(server.stream());
+    /// let root = client
+    ///     .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_stream<S>(self, stream: S) -> Client
+    where
+        S: AsyncRead + AsyncWrite + std::marker::Unpin + Send + 'static,
+    {
+        let identify = self.identify;
+        let client = self.spawn_client(Box::new(stream), None);
+        if identify {
+            spawn_identify(&client);
+        }
+        client
+    }
 
+    /// Spawn the tasks that service a connection over `stream`
+    /// and return the associated Client.
+    /// `endpoint` is the path that `stream` is connected to, if any.
+    fn spawn_client(self, stream: Box<dyn ReadWriteStream>, endpoint: Option<PathBuf>) -> Client {
         let (reader, writer) = tokio::io::split(stream);
 
-        let (request_tx, request_rx) = tokio::sync::mpsc::channel(128);
+        let (request_tx, request_rx) = tokio::sync::mpsc::channel(
+            self.request_queue_size
+                .unwrap_or(DEFAULT_REQUEST_QUEUE_SIZE),
+        );
+        // The reader task has a channel of its own, so that the client
+        // task ends once every handle to the client has been dropped
+        let (reader_tx, reader_rx) = tokio::sync::mpsc::channel(DEFAULT_REQUEST_QUEUE_SIZE);
+        let (reader_shutdown, shutdown) = tokio::sync::oneshot::channel();
+        let streams = SharedStreams::default();
+
+        let events = &self.events;
+        let traffic_logger = self.traffic_logger.map(|logger| {
+            Arc::new(TrafficLogState {
+                logger: std::sync::Mutex::new(Some(logger)),
+                events: events.clone(),
+            })
+        });
+        let root_cache = if self.cache_roots {
+            Some(RootCache::default())
+        } else {
+            None
+        };
 
         let mut reader_task = ReaderTask {
             reader,
-            request_tx: request_tx.clone(),
+            buf: PduBuffer::with_codec(self.codec),
+            request_tx: reader_tx.clone(),
+            shutdown,
+            streams: Arc::clone(&streams),
+            traffic_logger: traffic_logger.clone(),
+            metrics: self.metrics.clone(),
         };
+        let events = self.events.clone();
         tokio::spawn(async move {
             if let Err(err) = reader_task.run().await {
                 eprintln!("watchman reader task failed: {}", err);
+                events.disconnected(err.to_string());
             }
         });
 
+        let inner = Arc::new(Mutex::new(ClientInner {
+            request_tx,
+            events: self.events.clone(),
+        }));
+
+        let reconnect = match (self.reconnect, self.dialer) {
+            (Some(policy), Some(dialer)) => Some(Reconnector::new(
+                policy,
+                dialer,
+                self.events.clone(),
+                &inner,
+            )),
+            _ => None,
+        };
         let mut task = ClientTask {
             writer,
             request_rx,
+            reader_tx,
+            reader_rx,
+            reader_shutdown,
+            streams,
             request_queue: VecDeque::new(),
-            waiting_response: false,
+            in_flight: VecDeque::new(),
+            max_in_flight: self.max_outstanding_requests.unwrap_or(1),
+            broken: false,
             subscriptions: HashMap::new(),
+            log_streams: vec![],
+            traffic_logger,
+            root_cache: root_cache.clone(),
+            reconnect,
+            codec: self.codec,
+            metrics: self.metrics.clone(),
         };
+        let events = self.events.clone();
         tokio::spawn(async move {
-            if let Err(err) = task.run().await {
-                eprintln!("watchman client task failed: {}", err);
+            match task.run().await {
+                Err(err) => {
+                    eprintln!("watchman client task failed: {}", err);
+                    events.disconnected(err.to_string());
+                }
+                Ok(()) => events.disconnected("the client was dropped".to_string()),
             }
         });
 
-        let inner = Arc::new(Mutex::new(ClientInner { request_tx }));
+        Client {
+            inner,
+            endpoint: endpoint.map(Arc::from),
+            root_cache,
+            sync_timeout: self.sync_timeout,
+            request_timeout: self.request_timeout,
+            server_version: Arc::default(),
+            metrics: self.metrics,
+        }
+    }
+}
+
+/// Await `connect`, failing with `Error::Timeout` if it takes longer
+/// than `timeout`
+async fn with_connect_timeout<T>(
+    timeout: Option<Duration>,
+    connect: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match timeout {
+        None => connect.await,
+        Some(timeout) => {
+            tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| Error::Timeout {
+                    command: "connect".to_string(),
+                })?
+        }
+    }
+}
+
+/// Write the `ClientIdentity` of this process to the server's log in
+/// the background, so that a server that doesn't respond can't hold up
+/// connecting; see `Connector::identify`
+fn spawn_identify(client: &Client) {
+    let client = client.clone();
+    tokio::spawn(async move {
+        let request = LogRequest(
+            "log",
+            "debug".to_string(),
+            format!(
+                "watchman_client: connected by {}",
+                ClientIdentity::current()
+            ),
+        );
+        let _: Result<LogResponse, _> = client.generic_request(request).await;
+    });
+}
+
+/// Make a single attempt to connect to `endpoint`, reporting the
+/// outcome to `events`
+async fn connect_endpoint(
+    endpoint: PathBuf,
+    events: EventSink,
+) -> Result<Box<dyn ReadWriteStream>, Error> {
+    #[cfg(unix)]
+    let stream = UnixStream::connect(&endpoint)
+        .await
+        .map(|stream| Box::new(stream) as Box<dyn ReadWriteStream>)
+        .map_err(Error::from);
+
+    #[cfg(windows)]
+    let stream = named_pipe::NamedPipe::connect(endpoint.clone())
+        .await
+        .map(|stream| Box::new(stream) as Box<dyn ReadWriteStream>);
 
-        Ok(Client { inner })
+    match &stream {
+        Ok(_) => events.emit(ConnectionEvent::Connected { endpoint }),
+        Err(err) => events.emit(ConnectionEvent::ConnectFailed {
+            endpoint,
+            reason: err.to_string(),
+        }),
     }
+    stream
 }
 
 /// Represents a canonical path in the filesystem.
@@ -264,7 +916,7 @@ impl CanonicalPath {
 
     /// Construct from an already canonicalized path.
     /// This function will panic if the supplied path is not an absolute
-    /// path!
+    /// path!  Use `try_with_canonicalized_path` to handle that case.
     pub fn with_canonicalized_path(path: PathBuf) -> Self {
         assert!(
             path.is_absolute(),
@@ -275,6 +927,45 @@ impl CanonicalPath {
         Self(Self::strip_unc_escape(path))
     }
 
+    /// Construct from an already canonicalized path, failing with
+    /// `ErrorKind::InvalidInput` rather than panicking if the supplied
+    /// path is not an absolute path.
+    pub fn try_with_canonicalized_path(path: PathBuf) -> Result<Self, std::io::Error> {
+        if !path.is_absolute() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not an absolute path", path.display()),
+            ));
+        }
+        Ok(Self(Self::strip_unc_escape(path)))
+    }
+
+    /// Construct the canonical version of the supplied path, except
+    /// that if its final component is a symlink, the symlink is
+    /// preserved rather than resolved: only the parent directory is
+    /// canonicalized.
+    /// This is useful for watching a checkout through a symlink, so
+    /// that the paths reported by watchman are relative to the symlink
+    /// rather than to wherever it currently points.
+    /// The final component must exist, but need not be a symlink.
+    pub fn canonicalize_preserving_symlink<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<Self, std::io::Error> {
+        let path = path.as_ref();
+        let name = match path.components().next_back() {
+            Some(std::path::Component::Normal(name)) => name,
+            // `..`, `.` and the root have no parent to canonicalize
+            _ => return Self::canonicalize(path),
+        };
+        // Fail if the path doesn't exist, as `canonicalize` does
+        std::fs::symlink_metadata(path)?;
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => std::fs::canonicalize(parent)?,
+            _ => std::env::current_dir()?,
+        };
+        Ok(Self(Self::strip_unc_escape(parent.join(name))))
+    }
+
     /// Watchman doesn't like the UNC prefix being present for incoming paths
     /// in its current implementation: we should fix that, but in the meantime
     /// we want clients to be able to connect to existing versions, so let's
@@ -293,6 +984,51 @@ impl CanonicalPath {
     fn strip_unc_escape(path: PathBuf) -> PathBuf {
         path
     }
+
+    /// Returns the canonical path
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Consumes the CanonicalPath, returning the canonical path
+    pub fn into_path_buf(self) -> PathBuf {
+        self.0
+    }
+}
+
+impl AsRef<Path> for CanonicalPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for CanonicalPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for CanonicalPath {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.display().fmt(fmt)
+    }
+}
+
+/// Equivalent to `CanonicalPath::try_with_canonicalized_path`
+impl std::convert::TryFrom<PathBuf> for CanonicalPath {
+    type Error = std::io::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        Self::try_with_canonicalized_path(path)
+    }
+}
+
+impl From<CanonicalPath> for PathBuf {
+    fn from(path: CanonicalPath) -> Self {
+        path.0
+    }
 }
 
 /// Data that describes a watched filesystem location.
@@ -340,101 +1076,287 @@ impl ResolvedRoot {
     }
 }
 
-trait ReadWriteStream: AsyncRead + AsyncWrite + std::marker::Unpin + Send {}
+/// A connection to the server, or to something that relays its
+/// protocol; see `Transport`.
+/// This is implemented for every suitable stream.
+pub trait ReadWriteStream: AsyncRead + AsyncWrite + std::marker::Unpin + Send {}
 
-#[cfg(unix)]
-impl ReadWriteStream for UnixStream {}
+impl<T: AsyncRead + AsyncWrite + std::marker::Unpin + Send> ReadWriteStream for T {}
 
 struct SendRequest {
     /// The serialized request to send to the server
-    buf: Vec<u8>,
+    buf: Bytes,
     /// to pass the response back to the requstor
-    tx: tokio::sync::oneshot::Sender<Result<Vec<u8>, String>>,
+    tx: tokio::sync::oneshot::Sender<Result<Bytes, String>>,
+    /// When the request was written to the server
+    sent: Option<Instant>,
+    /// Where the files in the response are sent by the reader task,
+    /// for the requests made by `Client::query_stream`
+    stream: Option<RowSink>,
 }
 
 impl SendRequest {
-    fn respond(self, result: Result<Vec<u8>, String>) -> Result<(), Error> {
+    /// Returns true if the requestor is no longer waiting for the
+    /// response, which happens if the request future was dropped.
+    fn is_abandoned(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    fn respond(self, result: Result<Bytes, String>) -> Result<(), Error> {
         self.tx
             .send(result)
             .map_err(|_| Error::generic("requestor has dropped its receiver"))
     }
 }
 
+/// A PDU destined for a `Subscription`, along with when it was read
+/// from the connection
+pub(crate) struct ReceivedPdu {
+    received: Instant,
+    data: Bytes,
+}
+
 enum TaskItem {
     QueueRequest(SendRequest),
-    ProcessReceivedPdu(Vec<u8>),
-    RegisterSubscription(String, UnboundedSender<Vec<u8>>),
+    ProcessReceivedPdu(Bytes),
+    /// Deliver the PDUs for the named subscription to the sender.
+    /// The serialized `subscribe` command is used to re-establish the
+    /// subscription after reconnecting.
+    RegisterSubscription(String, PduSender, Vec<u8>),
+    /// Stop delivering PDUs for, and re-establishing, the named
+    /// subscription
+    UnregisterSubscription(String),
+    /// Deliver the unilateral `log` PDUs to the sender
+    RegisterLogStream(UnboundedSender<ReceivedPdu>),
+    /// The ReaderTask encountered an error and the connection
+    /// is no longer usable
+    ConnectionLost(Error),
+    /// The requestor of a request dropped its `PendingResponse`
+    RequestAbandoned,
 }
 
 /// A live connection to a watchman server.
 /// Use [Connector](struct.Connector.html) to establish a connection.
+///
+/// The futures returned by the request methods are safe to drop
+/// (for example, when used with `tokio::select!` or a timeout):
+/// a request that has not yet been sent to the server is discarded
+/// and the response to a request that has already been sent is
+/// ignored when it arrives, so subsequent requests continue to
+/// receive the correct responses.
+///
+/// Cloning a client is cheap and produces another handle to the same
+/// connection, which can be moved into another task in order to share
+/// the connection.  Requests made via any of the handles are sent to
+/// the server in the order that they were made, and the server responds
+/// to them in the same order, so a slow query delays the requests queued
+/// behind it; use a separate connection for work that must not be delayed.
+/// By default, each request is sent once the previous one has been
+/// responded to; see `Connector::max_outstanding_requests`.
+/// Each handle has its own `request_timeout`, copied from the handle
+/// that it was cloned from.
+/// The connection is closed once every handle, and every subscription
+/// made with them, has been dropped.
+#[derive(Clone)]
 pub struct Client {
     inner: Arc<Mutex<ClientInner>>,
+    endpoint: Option<Arc<Path>>,
+    root_cache: Option<RootCache>,
+    sync_timeout: SyncTimeout,
+    request_timeout: Option<Duration>,
+    /// Remembers the version reported by `connection_info`
+    server_version: Arc<std::sync::Mutex<Option<String>>>,
+    metrics: SharedMetrics,
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Client")
+            .field("endpoint", &self.endpoint)
+            .field("request_timeout", &self.request_timeout)
+            .field("cache_roots", &self.root_cache.is_some())
+            .field("sync_timeout", &self.sync_timeout)
+            .field("identity", &format_args!("{}", ClientIdentity::current()))
+            // Counts this and the other clones of the client, as well
+            // as the subscriptions that keep the connection open
+            .field("handles", &Arc::strong_count(&self.inner))
+            .finish()
+    }
 }
 
 /// The reader task lives to read a PDU and send it to the ClientTask
 struct ReaderTask {
     reader: tokio::io::ReadHalf<Box<dyn ReadWriteStream>>,
+    buf: PduBuffer,
     request_tx: Sender<TaskItem>,
+    /// Resolves once the client task has ended, or has replaced this
+    /// reader after reconnecting
+    shutdown: tokio::sync::oneshot::Receiver<()>,
+    /// The requests whose files are streamed as they are read
+    streams: SharedStreams,
+    traffic_logger: Option<SharedTrafficLogger>,
+    metrics: SharedMetrics,
 }
 
 impl ReaderTask {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "reader_task", level = "debug", skip_all)
+    )]
     async fn run(&mut self) -> Result<(), Error> {
         loop {
-            let pdu = self.read_pdu_vec().await?;
+            let read = tokio::select! {
+                read = query_stream::read_pdu(&self.streams, &mut self.buf, &mut self.reader) => read,
+                _ = &mut self.shutdown => return Ok(()),
+            };
+            let (pdu, len) = match read {
+                Ok(read) => read,
+                Err(err) => {
+                    trace_event!(debug, error = %err, "connection lost");
+                    // Let the client task know, so that it can fail any
+                    // outstanding requests and subscriptions
+                    return self
+                        .request_tx
+                        .send(TaskItem::ConnectionLost(err))
+                        .await
+                        .map_err(Error::generic);
+                }
+            };
+            log_traffic(&self.traffic_logger, Direction::Receive, &pdu);
+            if let Some(metrics) = &self.metrics {
+                metrics.bytes_read(len);
+            }
+            trace_event!(trace, len, "received pdu");
             self.request_tx
                 .send(TaskItem::ProcessReceivedPdu(pdu))
                 .await
                 .map_err(Error::generic)?;
         }
     }
+}
 
-    /// Sniffs out the BSER PDU header to determine the length of data that
-    /// needs to be read in order to decode the full PDU
-    async fn read_bser_pdu_length(&mut self) -> Result<PduHeader, Error> {
-        // We know that the smallest full PDU returned by the server
-        // won't ever be smaller than this size
-        const BUF_SIZE: usize = 16;
-        let mut buf = [0u8; BUF_SIZE];
+/// The smallest full PDU returned by the server won't ever be smaller
+/// than this size, so reading this much never reads past the end of a
+/// PDU
+const MIN_PDU_SIZE: usize = 16;
 
-        let pos = self.reader.read(&mut buf).await?;
-        if pos == 0 {
-            return Err(Error::Eof);
-        }
+/// The capacity of a `PduBuffer` when it is created
+const PDU_BUFFER_SIZE: usize = 8 * 1024;
 
-        let buf = &buf[..pos];
+/// The buffer that PDUs are read into.
+/// Each PDU is split off as `Bytes`, which can be passed on to its
+/// requestor or subscription without being copied.  The storage is
+/// reused for later PDUs once every `Bytes` split from it has been
+/// dropped, so a steady stream of PDUs doesn't allocate for each one.
+struct PduBuffer {
+    buf: BytesMut,
+    codec: Codec,
+}
+
+impl PduBuffer {
+    fn new() -> Self {
+        Self::with_codec(Codec::BserV2)
+    }
+
+    fn with_codec(codec: Codec) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(PDU_BUFFER_SIZE),
+            codec,
+        }
+    }
 
-        let mut bunser = Bunser::new(SliceRead::new(buf));
-        let pdu = bunser.read_pdu().map_err(|source| Error::Deserialize {
-            source: Box::new(source),
-            data: buf.to_vec(),
-        })?;
-        let buf = buf.to_vec();
-        Ok(PduHeader { buf, pdu })
+    /// Read the next PDU, returning it BSER encoded whatever the codec
+    async fn read_pdu<R>(&mut self, reader: &mut R) -> Result<Bytes, Error>
+    where
+        R: AsyncRead + std::marker::Unpin,
+    {
+        match self.codec {
+            Codec::BserV2 => self.read_bser_pdu(reader).await,
+            Codec::Json => self.read_json_pdu(reader).await,
+        }
     }
 
-    /// Read the bytes that comprise a BSER encoded PDU
-    async fn read_pdu_vec(&mut self) -> Result<Vec<u8>, Error> {
-        let header = self.read_bser_pdu_length().await?;
-        let total_size = (header.pdu.start + header.pdu.len) as usize;
-        let mut buf = header.buf;
+    /// Read a newline terminated JSON PDU and translate it to BSER.
+    /// Unlike `read_bser_pdu` this may read beyond the end of the PDU,
+    /// leaving the start of the next one in the buffer.
+    async fn read_json_pdu<R>(&mut self, reader: &mut R) -> Result<Bytes, Error>
+    where
+        R: AsyncRead + std::marker::Unpin,
+    {
+        let mut searched = 0;
+        loop {
+            if let Some(pos) = self.buf[searched..].iter().position(|&b| b == b'\n') {
+                let line = self.buf.split_to(searched + pos + 1);
+                return codec::json_to_bser(&line[..line.len() - 1]);
+            }
+            searched = self.buf.len();
+            self.read_up_to(reader, searched + PDU_BUFFER_SIZE).await?;
+        }
+    }
 
-        let mut end = buf.len();
+    /// Read the bytes that comprise a BSER encoded PDU.
+    /// Only the bytes of that PDU are read from `reader`, so it may be
+    /// handed on to another reader afterwards.
+    async fn read_bser_pdu<R>(&mut self, reader: &mut R) -> Result<Bytes, Error>
+    where
+        R: AsyncRead + std::marker::Unpin,
+    {
+        let (_, total_size) = self.read_bser_header(reader).await?;
+        while self.buf.len() < total_size {
+            self.read_up_to(reader, total_size).await?;
+        }
+        Ok(self.buf.split().freeze())
+    }
 
-        buf.resize(total_size, 0);
+    /// Read at least the header of a BSER encoded PDU, returning the
+    /// size of the header and of the whole PDU.
+    /// The header is left at the start of the buffer.
+    async fn read_bser_header<R>(&mut self, reader: &mut R) -> Result<(usize, usize), Error>
+    where
+        R: AsyncRead + std::marker::Unpin,
+    {
+        // The header is at most the 2 byte magic, the 4 byte
+        // capabilities and a 9 byte integer length
+        const MAX_HEADER_SIZE: usize = 15;
+        const MAGIC: &[u8] = b"\x00\x02";
 
-        while end != total_size {
-            let n = self
-                .reader
-                .read(&mut buf.as_mut_slice()[end..total_size])
-                .await?;
-            if n == 0 {
-                return Err(Error::Eof);
+        loop {
+            if !self.buf.is_empty() {
+                let mut bunser = Bunser::new(SliceRead::new(&self.buf));
+                match bunser.read_pdu() {
+                    Ok(pdu) => return Ok((pdu.start as usize, (pdu.start + pdu.len) as usize)),
+                    // A short read may have split the header; keep
+                    // reading unless the data can't possibly be the
+                    // start of a PDU
+                    Err(_)
+                        if self.buf.len() < MAX_HEADER_SIZE
+                            && self.buf.iter().zip(MAGIC).all(|(a, b)| a == b) => {}
+                    Err(source) => {
+                        return Err(Error::Deserialize {
+                            source: Box::new(source),
+                            data: self.buf.split().to_vec(),
+                        })
+                    }
+                }
             }
-            end += n;
+            self.read_up_to(reader, MIN_PDU_SIZE).await?;
         }
+    }
 
-        Ok(buf)
+    /// Read from `reader` into the buffer, without reading beyond
+    /// `size` bytes in total
+    async fn read_up_to<R>(&mut self, reader: &mut R, size: usize) -> Result<(), Error>
+    where
+        R: AsyncRead + std::marker::Unpin,
+    {
+        let end = self.buf.len();
+        self.buf.resize(size, 0);
+        let result = reader.read(&mut self.buf[end..]).await;
+        self.buf.truncate(end + *result.as_ref().unwrap_or(&0));
+        match result? {
+            0 => Err(Error::Eof),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -442,10 +1364,36 @@ impl ReaderTask {
 /// unilateral results
 struct ClientTask {
     writer: tokio::io::WriteHalf<Box<dyn ReadWriteStream>>,
+    /// Items from the handles to the client, which close the channel
+    /// once they have all been dropped
     request_rx: Receiver<TaskItem>,
+    /// Passed to the reader task of each connection.
+    /// Kept here so that `reader_rx` stays open between connections.
+    reader_tx: Sender<TaskItem>,
+    /// Items from the reader task
+    reader_rx: Receiver<TaskItem>,
+    /// Dropped to stop the reader task of the current connection
+    reader_shutdown: tokio::sync::oneshot::Sender<()>,
+    /// Shared with the reader task, which streams the files of the
+    /// responses to `Client::query_stream`
+    streams: SharedStreams,
+    /// The requests that have yet to be sent
     request_queue: VecDeque<SendRequest>,
-    waiting_response: bool,
-    subscriptions: HashMap<String, UnboundedSender<Vec<u8>>>,
+    /// The requests that have been sent, in the order that the server
+    /// will respond to them
+    in_flight: VecDeque<SendRequest>,
+    max_in_flight: usize,
+    /// Set when a write fails while reconnection is enabled: requests
+    /// are held back until the connection has been re-established
+    broken: bool,
+    subscriptions: HashMap<String, PduSender>,
+    /// Where the unilateral `log` PDUs are delivered
+    log_streams: Vec<UnboundedSender<ReceivedPdu>>,
+    traffic_logger: Option<SharedTrafficLogger>,
+    root_cache: Option<RootCache>,
+    reconnect: Option<Reconnector>,
+    codec: Codec,
+    metrics: SharedMetrics,
 }
 
 impl Drop for ClientTask {
@@ -455,6 +1403,10 @@ impl Drop for ClientTask {
 }
 
 impl ClientTask {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "client_task", level = "debug", skip_all)
+    )]
     async fn run(&mut self) -> Result<(), Error> {
         // process things, and if we encounter an error, ensure that
         // we fail all outstanding requests
@@ -469,85 +1421,181 @@ impl ClientTask {
 
     async fn run_loop(&mut self) -> Result<(), Error> {
         loop {
-            match self.request_rx.recv().await {
+            // The requests that have already been made are taken first,
+            // so that they see the connection fail rather than finding
+            // the client task gone
+            let item = match self.request_rx.try_recv() {
+                Ok(item) => Some(item),
+                Err(TryRecvError::Closed) => None,
+                Err(TryRecvError::Empty) => tokio::select! {
+                    item = self.request_rx.recv() => item,
+                    item = self.reader_rx.recv() => item,
+                },
+            };
+            match item {
                 Some(TaskItem::QueueRequest(request)) => self.queue_request(request).await?,
                 Some(TaskItem::ProcessReceivedPdu(pdu)) => self.process_pdu(pdu).await?,
-                Some(TaskItem::RegisterSubscription(name, tx)) => {
-                    self.register_subscription(name, tx)
+                Some(TaskItem::RegisterSubscription(name, tx, command)) => {
+                    self.register_subscription(name, tx, &command)
+                }
+                Some(TaskItem::UnregisterSubscription(name)) => self.unregister_subscription(&name),
+                Some(TaskItem::RegisterLogStream(tx)) => self.log_streams.push(tx),
+                Some(TaskItem::RequestAbandoned) => self.discard_abandoned(),
+                Some(TaskItem::ConnectionLost(err)) => {
+                    trace_event!(
+                        debug,
+                        error = %err,
+                        in_flight = self.in_flight.len(),
+                        queued = self.request_queue.len(),
+                        "connection lost"
+                    );
+                    if self.reconnect.is_none() {
+                        return Err(err);
+                    }
+                    if !self.reconnect(err).await? {
+                        break;
+                    }
                 }
+                // Every handle to the client has been dropped
                 None => break,
             };
         }
         Ok(())
     }
 
-    fn register_subscription(&mut self, name: String, tx: UnboundedSender<Vec<u8>>) {
+    fn register_subscription(&mut self, name: String, tx: PduSender, command: &[u8]) {
+        if let Some(reconnect) = &mut self.reconnect {
+            reconnect.register(&name, command);
+        }
+        self.streams.subscribed();
         self.subscriptions.insert(name, tx);
     }
 
+    fn unregister_subscription(&mut self, name: &str) {
+        if let Some(reconnect) = &mut self.reconnect {
+            reconnect.forget(name);
+        }
+        self.subscriptions.remove(name);
+    }
+
     /// Generate an error for each queued request.
     /// This is called in situations where the state of the connection
     /// to the serve is non-recoverable.
     fn fail_all(&mut self, err: &Error) {
-        while let Some(request) = self.request_queue.pop_front() {
+        self.streams.clear();
+        for request in self.in_flight.drain(..).chain(self.request_queue.drain(..)) {
             request.respond(Err(err.to_string())).ok();
         }
     }
 
-    /// If we're not waiting for the response to a request,
-    /// then send the next one!
+    /// Send queued requests until `max_in_flight` of them are waiting
+    /// for their responses.
+    /// Requests that were abandoned by their requestor before we got
+    /// around to sending them are discarded without being sent.
     async fn send_next_request(&mut self) -> Result<(), Error> {
-        if !self.waiting_response && !self.request_queue.is_empty() {
-            match self
-                .writer
-                .write_all(&self.request_queue.front().expect("not empty").buf)
-                .await
-            {
+        while self.in_flight.len() < self.max_in_flight && !self.broken {
+            let mut request = match self.request_queue.pop_front() {
+                Some(request) if request.is_abandoned() => continue,
+                Some(request) => request,
+                None => break,
+            };
+            log_traffic(&self.traffic_logger, Direction::Send, &request.buf);
+            trace_event!(
+                debug,
+                command = command_name(&request.buf).as_deref().unwrap_or("?"),
+                len = request.buf.len(),
+                in_flight = self.in_flight.len(),
+                queued = self.request_queue.len(),
+                "sending request"
+            );
+            let encoded = match self.codec.encode(&request.buf) {
+                Ok(encoded) => encoded,
+                Err(err) => {
+                    request.respond(Err(err.to_string())).ok();
+                    continue;
+                }
+            };
+            // The reader task is told about the request before the
+            // server can possibly respond to it
+            self.streams.sent(request.stream.clone());
+            match self.writer.write_all(&encoded).await {
+                Err(_) if self.reconnect.is_some() => {
+                    self.streams.unsent();
+                    // The reader will find that the connection was
+                    // lost, and the request is sent again once it has
+                    // been re-established
+                    self.request_queue.push_front(request);
+                    self.broken = true;
+                }
                 Err(err) => {
                     // A failed write breaks our world; we don't want to
                     // try to continue
+                    self.streams.unsent();
+                    self.request_queue.push_front(request);
                     return Err(err.into());
                 }
-                Ok(_) => self.waiting_response = true,
+                Ok(_) => {
+                    if let Some(metrics) = &self.metrics {
+                        metrics.bytes_written(request.buf.len());
+                    }
+                    request.sent = Some(Instant::now());
+                    self.in_flight.push_back(request);
+                }
             }
         }
+        self.report_queue_length();
         Ok(())
     }
 
+    fn report_queue_length(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.queue_length(self.request_queue.len(), self.in_flight.len());
+        }
+    }
+
+    /// Remove the requests that were abandoned before being sent from
+    /// the queue.
+    /// The abandoned requests that have already been sent stay in
+    /// `in_flight`, so that their responses are discarded rather than
+    /// being mistaken for those to the requests sent after them.
+    fn discard_abandoned(&mut self) {
+        self.request_queue.retain(|request| !request.is_abandoned());
+        self.report_queue_length();
+    }
+
     /// Queue up a new request from the client code, and then
     /// check to see if we can send a queued request to the server.
     async fn queue_request(&mut self, request: SendRequest) -> Result<(), Error> {
         self.request_queue.push_back(request);
+        trace_event!(
+            trace,
+            in_flight = self.in_flight.len(),
+            queued = self.request_queue.len(),
+            "queued request"
+        );
         self.send_next_request().await?;
         Ok(())
     }
 
     /// Dispatch a PDU that we just read to the appropriate client code.
-    async fn process_pdu(&mut self, pdu: Vec<u8>) -> Result<(), Error> {
-        use serde::Deserialize;
-        #[derive(Deserialize, Debug)]
-        pub struct Unilateral {
-            pub unilateral: bool,
-            pub subscription: String,
-        }
-
-        if let Ok(unilateral) = bunser::<Unilateral>(&pdu) {
-            if let Some(subscription) = self.subscriptions.get_mut(&unilateral.subscription) {
-                if subscription.send(pdu).is_err() {
-                    // The `Subscription` was dropped; we don't need to
-                    // treat this as terminal for this client session,
-                    // so just de-register the handler
-                    self.subscriptions.remove(&unilateral.subscription);
-                }
+    async fn process_pdu(&mut self, pdu: Bytes) -> Result<(), Error> {
+        if self.dispatch_unilateral(&pdu).await {
+            // Delivered to its subscription
+        } else if let Some(request) = self.in_flight.pop_front() {
+            trace_event!(
+                trace,
+                len = pdu.len(),
+                in_flight = self.in_flight.len(),
+                "received response"
+            );
+            if let (Some(metrics), Some(sent)) = (&self.metrics, request.sent) {
+                let command = command_name(&request.buf);
+                metrics.request_completed(command.as_deref().unwrap_or("?"), sent.elapsed());
             }
-        } else if self.waiting_response {
-            let request = self
-                .request_queue
-                .pop_front()
-                .expect("waiting_response is only true when request_queue is not empty");
-            self.waiting_response = false;
-
-            request.respond(Ok(pdu))?;
+            // If the requestor has gone away (for example, because it
+            // timed out) then there is no one to deliver this to,
+            // but that isn't fatal to the connection.
+            request.respond(Ok(pdu)).ok();
         } else {
             // This should never happen as we're not doing any subscription stuff
             return Err(Error::generic("received a unilateral PDU from the server"));
@@ -556,26 +1604,213 @@ impl ClientTask {
         self.send_next_request().await?;
         Ok(())
     }
-}
-
-struct PduHeader {
-    buf: Vec<u8>,
-    pdu: PduInfo,
-}
 
-fn bunser<T>(buf: &[u8]) -> Result<T, Error>
-where
-    T: serde::de::DeserializeOwned,
-{
-    let response: T = serde_bser::from_slice(&buf).map_err(|source| Error::Deserialize {
-        source: Box::new(source),
-        data: buf.to_vec(),
-    })?;
+    /// If `pdu` is a unilateral PDU, deliver it to its subscription and
+    /// return true
+    async fn dispatch_unilateral(&mut self, pdu: &Bytes) -> bool {
+        let unilateral = match bunser::<UnilateralPdu>(pdu) {
+            Ok(unilateral) => unilateral,
+            Err(_) => return false,
+        };
+        if unilateral.log.is_some() {
+            // Streams whose `LogStream` was dropped are forgotten
+            self.log_streams.retain(|stream| {
+                stream
+                    .send(ReceivedPdu {
+                        received: Instant::now(),
+                        data: pdu.clone(),
+                    })
+                    .is_ok()
+            });
+            return true;
+        }
+        if let (true, Some(root), Some(cache)) =
+            (unilateral.canceled, &unilateral.root, &self.root_cache)
+        {
+            cache.invalidate(root);
+        }
+        if let Some(reconnect) = &mut self.reconnect {
+            reconnect.observe(&unilateral);
+        }
+        trace_event!(
+            trace,
+            subscription = %unilateral.subscription,
+            len = pdu.len(),
+            "received unilateral pdu"
+        );
+        if let Some(subscription) = self.subscriptions.get(&unilateral.subscription) {
+            let pdu = ReceivedPdu {
+                received: Instant::now(),
+                data: pdu.clone(),
+            };
+            // Only the results listing files may be discarded when
+            // the subscription's queue is full
+            let droppable = unilateral.files.is_some()
+                && !unilateral.canceled
+                && unilateral.state_enter.is_none()
+                && unilateral.state_leave.is_none();
+            if subscription.deliver(pdu, droppable).await.is_err() {
+                // The `Subscription` was dropped; we don't need to
+                // treat this as terminal for this client session,
+                // so just de-register the handler
+                self.subscriptions.remove(&unilateral.subscription);
+            }
+        }
+        true
+    }
+}
+
+/// The fields of a unilateral PDU that the client task looks at
+#[derive(serde::Deserialize, Debug)]
+struct UnilateralPdu {
+    #[allow(dead_code)]
+    unilateral: bool,
+    /// Empty for the `log` PDUs, which aren't associated with a
+    /// subscription
+    #[serde(default)]
+    subscription: String,
+    #[serde(default)]
+    log: Option<serde::de::IgnoredAny>,
+    #[serde(default)]
+    canceled: bool,
+    #[serde(default)]
+    root: Option<PathBuf>,
+    #[serde(default)]
+    clock: Option<Value>,
+    #[serde(default)]
+    files: Option<serde::de::IgnoredAny>,
+    #[serde(default, rename = "state-enter")]
+    state_enter: Option<String>,
+    #[serde(default, rename = "state-leave")]
+    state_leave: Option<String>,
+}
+
+/// The traffic logger of a connection, shared by its tasks
+struct TrafficLogState {
+    /// Discarded once it fails to write
+    logger: std::sync::Mutex<Option<TrafficLogger>>,
+    events: EventSink,
+}
+
+type SharedTrafficLogger = Arc<TrafficLogState>;
+
+/// Record a PDU in the traffic log, if one was configured.
+/// Failure to log isn't fatal to the connection: the logger is
+/// discarded and the failure is reported via
+/// `ConnectionEvent::TrafficLogFailed`.
+fn log_traffic(logger: &Option<SharedTrafficLogger>, direction: Direction, pdu: &[u8]) {
+    if let Some(state) = logger {
+        let mut logger = match state.logger.lock() {
+            Ok(logger) => logger,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let err = match logger.as_mut().map(|logger| logger.record(direction, pdu)) {
+            Some(Err(err)) => err,
+            _ => return,
+        };
+        if let Some(logger) = logger.take() {
+            state.events.emit(ConnectionEvent::TrafficLogFailed {
+                path: logger.path().to_path_buf(),
+                reason: err.to_string(),
+            });
+        }
+    }
+}
+
+/// Returns the name of the command in the serialized request `pdu`,
+/// for reporting in `tracing` events and metrics
+fn command_name(pdu: &[u8]) -> Option<String> {
+    struct CommandName(String);
+
+    impl<'de> serde::Deserialize<'de> for CommandName {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct Visitor;
+            impl<'de> serde::de::Visitor<'de> for Visitor {
+                type Value = CommandName;
+
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    f.write_str("a watchman command")
+                }
+
+                fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                    self,
+                    mut seq: A,
+                ) -> Result<CommandName, A::Error> {
+                    let name = seq
+                        .next_element()?
+                        .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                    while seq.next_element::<serde::de::IgnoredAny>()?.is_some() {}
+                    Ok(CommandName(name))
+                }
+            }
+            deserializer.deserialize_seq(Visitor)
+        }
+    }
+
+    bunser::<CommandName>(pdu).ok().map(|name| name.0)
+}
+
+/// Serialize `request` as a BSER PDU
+fn serialize_request<T: serde::Serialize>(request: &T) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    serde_bser::ser::serialize(&mut data, request).map_err(|source| Error::Serialize {
+        source: Box::new(source),
+    })?;
+    Ok(data)
+}
+
+/// Returns a new name for a subscription made by this process
+fn subscription_name() -> String {
+    ClientIdentity::current().subscription_name("sub", SUB_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Returns the fields to request for a raw query or subscription
+/// given those that the caller asked for
+fn raw_fields(fields: &[&'static str]) -> Vec<&'static str> {
+    if fields.is_empty() {
+        Value::field_list()
+    } else {
+        fields.to_vec()
+    }
+}
+
+fn bunser<T>(buf: &[u8]) -> Result<T, Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let response: T = serde_bser::from_slice(buf).map_err(|source| Error::Deserialize {
+        source: Box::new(source),
+        data: buf.to_vec(),
+    })?;
     Ok(response)
 }
 
 struct ClientInner {
     request_tx: Sender<TaskItem>,
+    events: EventSink,
+}
+
+/// Receives the response to a request from the client task.
+/// Dropping it before the response has arrived abandons the request,
+/// and lets the client task know so that it can discard the request
+/// without waiting to reach it in its queue.
+pub(crate) struct PendingResponse {
+    rx: tokio::sync::oneshot::Receiver<Result<Bytes, String>>,
+    request_tx: Sender<TaskItem>,
+    received: bool,
+}
+
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        if !self.received {
+            // Closing first ensures that the client task sees the
+            // request as abandoned when it is told about it.
+            // If the client task's queue is full, the request is
+            // discarded once it is reached instead.
+            self.rx.close();
+            self.request_tx.try_send(TaskItem::RequestAbandoned).ok();
+        }
+    }
 }
 
 impl ClientInner {
@@ -584,6 +1819,7 @@ impl ClientInner {
     /// This is really an internal method, but it is made public in case a
     /// consumer of this crate needs to issue a command for which we haven't
     /// yet made an ergonomic wrapper.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
     pub(crate) async fn generic_request<Request, Response>(
         &mut self,
         request: Request,
@@ -591,53 +1827,168 @@ impl ClientInner {
     where
         Request: serde::Serialize + std::fmt::Debug,
         Response: serde::de::DeserializeOwned,
+    {
+        let response = self.queue_request(&request).await?;
+        self.await_response(response, &request).await
+    }
+
+    /// Ask the client task to send `request`, returning the receiver
+    /// for its response.  Requests are sent in the order that they are
+    /// queued, so queueing several requests before waiting for their
+    /// responses lets the client task send each as soon as the server
+    /// responds to the previous one.
+    pub(crate) async fn queue_request<Request>(
+        &mut self,
+        request: &Request,
+    ) -> Result<PendingResponse, Error>
+    where
+        Request: serde::Serialize + std::fmt::Debug,
+    {
+        self.queue_request_with_stream(request, None).await
+    }
+
+    /// As `queue_request`, with the files of the response being sent
+    /// to `stream` as they are read, rather than in the response
+    pub(crate) async fn queue_request_with_stream<Request>(
+        &mut self,
+        request: &Request,
+        stream: Option<RowSink>,
+    ) -> Result<PendingResponse, Error>
+    where
+        Request: serde::Serialize + std::fmt::Debug,
     {
         // Step 1: serialize into a bser byte buffer
-        let mut request_data = vec![];
-        serde_bser::ser::serialize(&mut request_data, &request).map_err(|source| {
-            Error::Serialize {
-                source: Box::new(source),
-            }
-        })?;
+        let request_data = serialize_request(request)?;
+        trace_event!(
+            debug,
+            command = command_name(&request_data).as_deref().unwrap_or("?"),
+            len = request_data.len(),
+            "queueing request"
+        );
 
         // Step 2: ask the client task to send it for us
         let (tx, rx) = tokio::sync::oneshot::channel();
         self.request_tx
             .send(TaskItem::QueueRequest(SendRequest {
-                buf: request_data,
+                buf: request_data.into(),
                 tx,
+                sent: None,
+                stream,
             }))
             .await
             .map_err(Error::generic)?;
+        Ok(PendingResponse {
+            rx,
+            request_tx: self.request_tx.clone(),
+            received: false,
+        })
+    }
 
-        // Step 3: wait for the client task to give us the response
-        let pdu_data = rx.await.map_err(Error::generic)?.map_err(Error::generic)?;
-
-        // Step 4: sniff for an error response in the deserialized data
-        use serde::Deserialize;
-        #[derive(Deserialize, Debug)]
-        struct MaybeError {
-            #[serde(default)]
-            error: Option<String>,
-        }
+    /// Wait for the response to a request queued by `queue_request`
+    pub(crate) async fn await_response<Request, Response>(
+        &self,
+        response: PendingResponse,
+        request: &Request,
+    ) -> Result<Response, Error>
+    where
+        Request: std::fmt::Debug,
+        Response: serde::de::DeserializeOwned,
+    {
+        let pdu_data = self.await_pdu(response, request).await?;
 
         // Step 5: deserialize into the caller-desired format
-        let maybe_err: MaybeError = bunser(&pdu_data)?;
-        if let Some(message) = maybe_err.error {
-            return Err(Error::WatchmanServerError {
-                message,
-                command: format!("{:#?}", request),
-            });
-        }
-
         let response: Response = bunser(&pdu_data)?;
         Ok(response)
     }
+
+    /// Wait for the PDU responding to a request queued by
+    /// `queue_request`, turning an error response into an `Error`
+    pub(crate) async fn await_pdu<Request>(
+        &self,
+        response: PendingResponse,
+        request: &Request,
+    ) -> Result<Bytes, Error>
+    where
+        Request: std::fmt::Debug,
+    {
+        await_pdu(&self.events, response, request).await
+    }
+}
+
+/// Wait for the PDU responding to a request, turning an error response
+/// into an `Error`, and reporting the server version to `events`
+async fn await_pdu<Request>(
+    events: &EventSink,
+    mut response: PendingResponse,
+    request: &Request,
+) -> Result<Bytes, Error>
+where
+    Request: std::fmt::Debug,
+{
+    // Step 3: wait for the client task to give us the response
+    let result = (&mut response.rx).await;
+    response.received = true;
+    let pdu_data = result.map_err(Error::generic)?.map_err(Error::generic)?;
+
+    // Step 4: sniff for an error response in the deserialized data
+    use serde::Deserialize;
+    #[derive(Deserialize, Debug)]
+    struct MaybeError {
+        #[serde(default)]
+        error: Option<String>,
+        #[serde(default)]
+        version: Option<String>,
+    }
+
+    let maybe_err: MaybeError = bunser(&pdu_data)?;
+    if let Some(version) = maybe_err.version.as_ref() {
+        events.server_version(version);
+    }
+    if let Some(message) = maybe_err.error {
+        return Err(Error::WatchmanServerError {
+            kind: ServerErrorKind::from_message(&message),
+            message,
+            command: format!("{:#?}", request),
+        });
+    }
+    Ok(pdu_data)
+}
+
+/// A response along with the PDU that it was decoded from.
+/// Returned by `Client::query_with_raw`, for callers that need fields
+/// that the typed response doesn't model, or that want to log exactly
+/// what the server said.
+#[derive(Debug, Clone)]
+pub struct WithRawPdu<T> {
+    /// The typed response
+    pub response: T,
+    /// The BSER encoded PDU
+    pub pdu: Vec<u8>,
+}
+
+impl<T> WithRawPdu<T> {
+    /// Decode the PDU into a `Value`, which holds every field sent by
+    /// the server
+    pub fn value(&self) -> Result<Value, Error> {
+        bunser(&self.pdu)
+    }
+}
+
+/// The result of `Client::sync_to_now`
+#[derive(Debug, Clone)]
+pub struct SyncedClock {
+    /// The clock once the server had observed every change made before
+    /// the sync began
+    pub clock: ClockSpec,
+    /// How long the sync took, from sending the request until the
+    /// response arrived
+    pub elapsed: Duration,
 }
 
 /// Returned by [Subscription::next](struct.Subscription.html#method.next)
 /// as events are observed by Watchman.
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum SubscriptionData<F>
 where
     F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
@@ -686,6 +2037,40 @@ where
     },
 }
 
+type DecodeErrorCallback = Arc<dyn Fn(&Error, &[u8]) + Send + Sync>;
+
+/// Controls what a `Subscription` does with a PDU that it cannot
+/// decode, such as one whose fields don't match the result type.
+#[derive(Clone, Default)]
+pub enum DecodeErrorPolicy {
+    /// Return the error from `Subscription::next`.  This is the default.
+    #[default]
+    Fail,
+    /// Discard the PDU and carry on waiting for the next one, after
+    /// passing the error and the raw PDU data to the callback.
+    /// Use `DecodeErrorPolicy::skip` to construct this.
+    Skip(DecodeErrorCallback),
+}
+
+impl DecodeErrorPolicy {
+    /// Skip undecodable PDUs, reporting each to `callback`
+    pub fn skip<C>(callback: C) -> Self
+    where
+        C: Fn(&Error, &[u8]) + Send + Sync + 'static,
+    {
+        Self::Skip(Arc::new(callback))
+    }
+}
+
+impl std::fmt::Debug for DecodeErrorPolicy {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Fail => fmt.write_str("Fail"),
+            Self::Skip(_) => fmt.write_str("Skip"),
+        }
+    }
+}
+
 /// A handle to a subscription initiated via `Client::subscribe`.
 /// Repeatedly call `Subscription::next().await` to yield the next
 /// set of subscription results.
@@ -699,10 +2084,31 @@ where
     name: String,
     inner: Arc<Mutex<ClientInner>>,
     root: ResolvedRoot,
-    responses: UnboundedReceiver<Vec<u8>>,
+    responses: PduReceiver,
+    decode_errors: DecodeErrorPolicy,
+    stats: SubscriptionStats,
+    asserted_states: BTreeSet<String>,
+    consolidation: Option<StateConsolidation>,
+    debounce: Option<Debounce>,
+    metrics: SharedMetrics,
+    /// The clock of the last `FilesChanged` result yielded by `next`,
+    /// or the `since` clock of the request if there hasn't been one
+    clock: Option<Clock>,
     _phantom: PhantomData<F>,
 }
 
+impl<F> std::fmt::Debug for Subscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Subscription")
+            .field("name", &self.name)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
 impl<F> Subscription<F>
 where
     F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
@@ -712,27 +2118,209 @@ where
         &self.name
     }
 
+    /// Returns statistics about the items delivered by this
+    /// subscription, including how long they waited to be delivered.
+    /// See the [subscription_stats](subscription_stats/index.html)
+    /// module.
+    pub fn stats(&self) -> &SubscriptionStats {
+        &self.stats
+    }
+
+    /// Returns the names of the states that are currently asserted for
+    /// the root, such as `hg.update` while the working copy is being
+    /// updated.  The set starts with the states that were asserted when
+    /// the subscription was initiated, and is updated as the
+    /// `StateEnter` and `StateLeave` events are yielded by `next`.
+    pub fn asserted_states(&self) -> &BTreeSet<String> {
+        &self.asserted_states
+    }
+
+    /// Returns true if the named state is currently asserted; see
+    /// `Subscription::asserted_states`
+    pub fn is_state_asserted(&self, state_name: &str) -> bool {
+        self.asserted_states.contains(state_name)
+    }
+
+    /// Returns the clock of the last `FilesChanged` result yielded by
+    /// `next`, or the `since` clock that the subscription was made with
+    /// if there hasn't been one yet.
+    /// Subscribing again with this clock as `since` reports the changes
+    /// that this subscription has yet to deliver; see
+    /// `Subscription::handle_info`.
+    pub fn clock(&self) -> Option<&Clock> {
+        self.clock.as_ref()
+    }
+
+    /// Consolidate the files changed while any of `states` is asserted,
+    /// such as `hg.update` during a checkout, into a single
+    /// `FilesChanged` result that is yielded once none of them is
+    /// asserted any more, right after the final `StateLeave`.
+    /// A file that changed several times appears once in the result,
+    /// with its most recent details.  The `StateEnter` and `StateLeave`
+    /// events are still yielded as they arrive.
+    /// If the subscription is canceled while a state is asserted, the
+    /// consolidated result is yielded before `Canceled`.
+    /// Calling this again replaces the set of states; an empty set
+    /// disables consolidation.
+    pub fn consolidate_states<I, S>(&mut self, states: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let consolidation = self.consolidation.get_or_insert_with(Default::default);
+        consolidation.set_states(states.into_iter().map(Into::into).collect());
+        if !consolidation.is_active(&self.asserted_states) {
+            consolidation.flush();
+        }
+    }
+
+    /// Merge the `FilesChanged` results that arrive within `window` of
+    /// each other into one, so that a burst of changes, such as an
+    /// editor saving several files, is yielded as a single result once
+    /// the subscription has been quiet for `window`.
+    /// A file that changed several times appears once in the merged
+    /// result, with its most recent details, and the result has the
+    /// clock of the last of them.  Any other event ends the burst, and
+    /// is yielded right after the merged result.
+    pub fn debounced(mut self, window: Duration) -> Self {
+        self.debounce = Some(Debounce::new(window));
+        self
+    }
+
+    /// Set how PDUs that cannot be decoded are handled.
+    /// The default is `DecodeErrorPolicy::Fail`.
+    pub fn set_decode_error_policy(&mut self, policy: DecodeErrorPolicy) {
+        self.decode_errors = policy;
+    }
+
     /// Yield the next set of subscription data.
     /// An error is generated if the subscription is disconnected
-    /// from the server.
+    /// from the server, or if a PDU cannot be decoded and the
+    /// `DecodeErrorPolicy` is `Fail`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(subscription = %self.name))
+    )]
     pub async fn next(&mut self) -> Result<SubscriptionData<F>, Error> {
-        let pdu = self
-            .responses
-            .recv()
+        tokio::future::poll_fn(|cx| self.poll_data(cx))
             .await
-            .ok_or_else(|| Error::generic("client was torn down"))?;
+            .unwrap_or_else(|| Err(Error::generic("client was torn down")))
+    }
+
+    /// Poll for the next set of subscription data, applying the
+    /// `DecodeErrorPolicy`.  Yields `None` once the client has been torn
+    /// down or the subscription has been canceled.
+    fn poll_data(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<SubscriptionData<F>, Error>>> {
+        use std::task::Poll;
+        loop {
+            // PDUs made ready by the debounce or the consolidation have
+            // already been through it, and skip it this time around.
+            // The debounce follows the consolidation, so its PDUs skip
+            // both.
+            let debounced = self.debounce.as_mut().and_then(|d| d.take_ready());
+            let consolidated = debounced
+                .is_none()
+                .then(|| self.consolidation.as_mut().and_then(|c| c.take_ready()))
+                .flatten();
+            let consolidate = debounced.is_none() && consolidated.is_none();
+            let debounce = debounced.is_none();
+            let pdu = match debounced.or(consolidated) {
+                Some(pdu) => pdu,
+                None => match self.responses.poll_recv(cx) {
+                    Poll::Ready(Some(Queued::Pdu(pdu))) => pdu,
+                    Poll::Ready(Some(Queued::Lagged(dropped))) => {
+                        if let Some(debounce) = &mut self.debounce {
+                            debounce.discard();
+                        }
+                        return Poll::Ready(Some(Err(Error::SubscriptionLagged { dropped })));
+                    }
+                    Poll::Ready(None) => match &mut self.debounce {
+                        Some(debounce) if debounce.is_buffering() => {
+                            debounce.flush();
+                            continue;
+                        }
+                        _ => return Poll::Ready(None),
+                    },
+                    Poll::Pending => {
+                        let quiet = self.debounce.as_mut().map(|d| d.poll_quiet(cx));
+                        match quiet {
+                            Some(Poll::Ready(())) => continue,
+                            _ => return Poll::Pending,
+                        }
+                    }
+                },
+            };
+            match (self.decode(&pdu.data), &self.decode_errors) {
+                (Err(err), DecodeErrorPolicy::Skip(callback)) => callback(&err, &pdu.data),
+                (result, _) => {
+                    let consolidation = self.consolidation.as_mut().filter(|_| consolidate);
+                    if let Some(consolidation) = consolidation {
+                        match &result {
+                            Ok(SubscriptionData::FilesChanged(_))
+                                if consolidation.is_active(&self.asserted_states) =>
+                            {
+                                consolidation.buffer(pdu);
+                                continue;
+                            }
+                            Ok(SubscriptionData::StateLeave { .. })
+                                if !consolidation.is_active(&self.asserted_states) =>
+                            {
+                                consolidation.flush();
+                            }
+                            Ok(SubscriptionData::Canceled) if consolidation.is_buffering() => {
+                                consolidation.flush();
+                                consolidation.push_ready(pdu);
+                                continue;
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let Some(debounce) = self.debounce.as_mut().filter(|_| debounce) {
+                        match &result {
+                            Ok(SubscriptionData::FilesChanged(_)) => {
+                                debounce.buffer(pdu);
+                                continue;
+                            }
+                            _ if debounce.is_buffering() => {
+                                debounce.flush();
+                                debounce.push_ready(pdu);
+                                continue;
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let Ok(SubscriptionData::FilesChanged(result)) = &result {
+                        self.clock = Some(result.clock.clone());
+                    }
+                    let delivered = Instant::now();
+                    if let Some(metrics) = &self.metrics {
+                        metrics.subscription_lag(&self.name, delivered - pdu.received);
+                    }
+                    self.stats.record(pdu.received, delivered);
+                    return Poll::Ready(Some(result));
+                }
+            }
+        }
+    }
 
-        let response: QueryResult<F> = bunser(&pdu)?;
+    /// Decode a PDU received for this subscription
+    fn decode(&mut self, pdu: &[u8]) -> Result<SubscriptionData<F>, Error> {
+        let response: QueryResult<F> = bunser(pdu)?;
 
         if response.subscription_canceled {
             self.responses.close();
             Ok(SubscriptionData::Canceled)
         } else if let Some(state_name) = response.state_enter {
+            self.asserted_states.insert(state_name.clone());
             Ok(SubscriptionData::StateEnter {
                 state_name,
                 metadata: response.state_metadata,
             })
         } else if let Some(state_name) = response.state_leave {
+            self.asserted_states.remove(&state_name);
             Ok(SubscriptionData::StateLeave {
                 state_name,
                 metadata: response.state_metadata,
@@ -751,6 +2339,11 @@ where
     /// will stop delivering data about it.
     pub async fn cancel(self) -> Result<(), Error> {
         let mut inner = self.inner.lock().await;
+        inner
+            .request_tx
+            .send(TaskItem::UnregisterSubscription(self.name.clone()))
+            .await
+            .map_err(Error::generic)?;
         let _: UnsubscribeResponse = inner
             .generic_request(Unsubscribe("unsubscribe", self.root.root, self.name))
             .await?;
@@ -759,6 +2352,23 @@ where
 }
 
 impl Client {
+    /// Set the default timeout that will be applied to requests made
+    /// via this client.  If the server doesn't respond to a request
+    /// within the timeout, the request will fail with `Error::Timeout`.
+    ///
+    /// The default is to wait indefinitely for the response.
+    ///
+    /// The timeout can be overridden for individual calls by using the
+    /// `_with_timeout` variants of the `query`, `subscribe` and `clock`
+    /// methods.
+    ///
+    /// Note that this is distinct from the `SyncTimeout` option which
+    /// controls how long the server will wait for a sync cookie.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
     /// This method will send a request to the watchman server
     /// and wait for its response.
     /// This is really an internal method, but it is made public in case a
@@ -773,11 +2383,83 @@ impl Client {
         Request: serde::Serialize + std::fmt::Debug,
         Response: serde::de::DeserializeOwned,
     {
-        let mut inner = self.inner.lock().await;
-        let response: Response = inner.generic_request(request).await?;
+        self.generic_request_with_timeout(request, self.request_timeout)
+            .await
+    }
+
+    /// Like `generic_request`, but waits at most `timeout` for the
+    /// response rather than using the client's default timeout.
+    /// If `timeout` is `None`, waits indefinitely.
+    #[doc(hidden)]
+    pub async fn generic_request_with_timeout<Request, Response>(
+        &self,
+        request: Request,
+        timeout: Option<Duration>,
+    ) -> Result<Response, Error>
+    where
+        Request: serde::Serialize + std::fmt::Debug,
+        Response: serde::de::DeserializeOwned,
+    {
+        let pdu_data = self.raw_request_with_timeout(&request, timeout).await?;
+        let response: Response = bunser(&pdu_data)?;
         Ok(response)
     }
 
+    /// Like `generic_request`, but also returns the PDU that the
+    /// response was decoded from.
+    #[doc(hidden)]
+    pub async fn generic_request_with_raw<Request, Response>(
+        &self,
+        request: Request,
+    ) -> Result<WithRawPdu<Response>, Error>
+    where
+        Request: serde::Serialize + std::fmt::Debug,
+        Response: serde::de::DeserializeOwned,
+    {
+        let pdu = self
+            .raw_request_with_timeout(&request, self.request_timeout)
+            .await?;
+        Ok(WithRawPdu {
+            response: bunser(&pdu)?,
+            pdu: pdu.to_vec(),
+        })
+    }
+
+    /// Send `request` and return the PDU that the server responded with
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "generic_request", level = "debug", skip_all)
+    )]
+    async fn raw_request_with_timeout<Request>(
+        &self,
+        request: &Request,
+        timeout: Option<Duration>,
+    ) -> Result<Bytes, Error>
+    where
+        Request: serde::Serialize + std::fmt::Debug,
+    {
+        let response = async {
+            // The lock is only held while queueing the request, as the
+            // client task delivers each response to its own requestor
+            let (response, events) = {
+                let mut inner = self.inner.lock().await;
+                (inner.queue_request(request).await?, inner.events.clone())
+            };
+            await_pdu(&events, response, request).await
+        };
+        match timeout {
+            None => response.await,
+            Some(duration) => tokio::time::timeout(duration, response)
+                .await
+                .map_err(|_| {
+                    trace_event!(debug, ?duration, "request timed out");
+                    Error::Timeout {
+                        command: format!("{:#?}", request),
+                    }
+                })?,
+        }
+    }
+
     /// This is typically the first method invoked on a client.
     /// Its purpose is to ensure that the watchman server is watching the specified
     /// path and to resolve it to a `ResolvedRoot` instance.
@@ -792,16 +2474,109 @@ impl Client {
     /// In other words, the worst case performance of this is
     /// `O(recursive-number-of-files)` and is impacted by the underlying storage
     /// device and its performance characteristics.
+    ///
+    /// If the client was connected using `Connector::cache_resolved_roots`,
+    /// paths that were previously resolved are not sent to the server again.
     pub async fn resolve_root(&self, path: CanonicalPath) -> Result<ResolvedRoot, Error> {
+        if let Some(root) = self.root_cache.as_ref().and_then(|c| c.get(&path.0)) {
+            return Ok(root);
+        }
+
         let response: WatchProjectResponse = self
             .generic_request(WatchProjectRequest("watch-project", path.0.clone()))
             .await?;
 
-        Ok(ResolvedRoot {
+        let root = ResolvedRoot {
             root: response.watch,
             relative: response.relative_path,
             watcher: response.watcher,
-        })
+        };
+        if let Some(cache) = &self.root_cache {
+            cache.insert(path.0, root.clone());
+        }
+        Ok(root)
+    }
+
+    /// Have the server stop watching `root`, canceling the
+    /// subscriptions and triggers on it.
+    /// If the client remembers resolved roots, `root` is forgotten.
+    pub async fn watch_del(&self, root: &ResolvedRoot) -> Result<(), Error> {
+        let _: WatchDelResponse = self
+            .generic_request(WatchDelRequest("watch-del", root.root.clone()))
+            .await?;
+        if let Some(cache) = &self.root_cache {
+            cache.invalidate(&root.root);
+        }
+        Ok(())
+    }
+
+    /// Returns the roots that the server is watching, in no particular
+    /// order.
+    /// See also the [server_info](server_info/index.html) module, which
+    /// reports these along with other details of the server.
+    pub async fn watch_list(&self) -> Result<Vec<PathBuf>, Error> {
+        let response: WatchListResponse = self.generic_request(WatchListRequest).await?;
+        Ok(response.roots)
+    }
+
+    /// Have the server stop watching every root, returning the roots
+    /// that were watched.
+    /// If the client remembers resolved roots, they are all forgotten.
+    pub async fn watch_del_all(&self) -> Result<Vec<PathBuf>, Error> {
+        let response: WatchDelAllResponse = self.generic_request(WatchDelAllRequest).await?;
+        self.forget_resolved_roots();
+        Ok(response.roots)
+    }
+
+    /// Register a trigger on `root`, which has the server run a command
+    /// whenever matching files change, even once this client has
+    /// disconnected.  The trigger persists until it is removed via
+    /// `remove_trigger` or the watch is removed.
+    /// See also the [triggers](triggers/index.html) module, which runs
+    /// actions in-process instead.
+    pub async fn register_trigger(
+        &self,
+        root: &ResolvedRoot,
+        mut definition: TriggerDefinition,
+    ) -> Result<TriggerDisposition, Error> {
+        if definition.relative_root.is_none() {
+            definition.relative_root = root.relative.clone();
+        }
+        let response: TriggerResponse = self
+            .generic_request(TriggerRequest("trigger", root.root.clone(), definition))
+            .await?;
+        Ok(response.disposition)
+    }
+
+    /// Returns the triggers registered on `root`
+    pub async fn list_triggers(&self, root: &ResolvedRoot) -> Result<Vec<TriggerInfo>, Error> {
+        let response: TriggerListResponse = self
+            .generic_request(TriggerListRequest("trigger-list", root.root.clone()))
+            .await?;
+        Ok(response.triggers)
+    }
+
+    /// Remove the trigger named `name` from `root`, returning true if
+    /// there was such a trigger
+    pub async fn remove_trigger(&self, root: &ResolvedRoot, name: &str) -> Result<bool, Error> {
+        let response: TriggerDelResponse = self
+            .generic_request(TriggerDelRequest(
+                "trigger-del",
+                root.root.clone(),
+                name.to_string(),
+            ))
+            .await?;
+        Ok(response.deleted)
+    }
+
+    /// Forget the roots remembered by `resolve_root`, so that they are
+    /// resolved by the server when they are next requested.
+    /// This affects every clone of this client, and has no effect unless
+    /// it was connected using `Connector::cache_resolved_roots`.
+    pub fn forget_resolved_roots(&self) {
+        if let Some(cache) = &self.root_cache {
+            cache.clear();
+        }
     }
 
     /// Perform a generic watchman query.
@@ -822,7 +2597,7 @@ impl Client {
     /// }
     ///
     /// async fn query(
-    ///    client: &mut Client,
+    ///    client: &Client,
     ///    resolved: &ResolvedRoot
     /// ) -> Result<(), Box<dyn std::error::Error>> {
     ///    let response: QueryResult<NameAndType> = client
@@ -837,12 +2612,25 @@ impl Client {
     ///    println!("response: {:#?}", response);
     ///    Ok(())
     /// }
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let server = watchman_client::test_support::MockServer::new();
+    /// # server.serve_files(&["src/lib.rs"]);
+    /// # let client = server.connect();
+    /// # let resolved = client
+    /// #     .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+    /// #     .await?;
+    /// # query(&client, &resolved).await
+    /// # }
     /// ```
     ///
     /// When constructing your result type, you can select from the
     /// following fields:
     ///
     /// * [CTimeAsFloatField](struct.CTimeAsFloatField.html)
+    /// * [CTimeAsMicrosField](struct.CTimeAsMicrosField.html)
+    /// * [CTimeAsMillisField](struct.CTimeAsMillisField.html)
+    /// * [CTimeAsNanosField](struct.CTimeAsNanosField.html)
     /// * [CTimeField](struct.CTimeField.html)
     /// * [ContentSha1HexField](struct.ContentSha1HexField.html)
     /// * [CreatedClockField](struct.CreatedClockField.html)
@@ -851,6 +2639,9 @@ impl Client {
     /// * [FileTypeField](struct.FileTypeField.html)
     /// * [InodeNumberField](struct.InodeNumberField.html)
     /// * [MTimeAsFloatField](struct.MTimeAsFloatField.html)
+    /// * [MTimeAsMicrosField](struct.MTimeAsMicrosField.html)
+    /// * [MTimeAsMillisField](struct.MTimeAsMillisField.html)
+    /// * [MTimeAsNanosField](struct.MTimeAsNanosField.html)
     /// * [MTimeField](struct.MTimeField.html)
     /// * [ModeAndPermissionsField](struct.ModeAndPermissionsField.html)
     /// * [NameField](struct.NameField.html)
@@ -865,6 +2656,10 @@ impl Client {
     /// (See [the fields module](fields/index.html) for a definitive list)
     ///
     /// The file names are all relative to the `root` parameter.
+    ///
+    /// Dropping the returned future abandons the query: if it hasn't
+    /// been sent yet then it never is, and if it has then the server's
+    /// response is discarded when it arrives.
     pub async fn query<F>(
         &self,
         root: &ResolvedRoot,
@@ -873,23 +2668,95 @@ impl Client {
     where
         F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
     {
-        let query = QueryRequest(
-            "query",
-            root.root.clone(),
-            QueryRequestCommon {
-                relative_root: root.relative.clone(),
-                fields: F::field_list(),
-                ..query
-            },
-        );
-
-        let response: QueryResult<F> = self.generic_request(query.clone()).await?;
-
-        Ok(response)
+        self.query_with_timeout(root, query, self.request_timeout)
+            .await
     }
 
-    /// Create a Subscription that will yield file changes as they occur in
-    /// real time.
+    /// Like `query`, but waits at most `timeout` for the server to
+    /// respond rather than using the default set via `request_timeout`.
+    /// If `timeout` is `None`, waits indefinitely.
+    pub async fn query_with_timeout<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+        timeout: Option<Duration>,
+    ) -> Result<QueryResult<F>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let query = self.query_request::<F>(root, query);
+        let response: QueryResult<F> = self.generic_request_with_timeout(query, timeout).await?;
+
+        Ok(response)
+    }
+
+    /// Returns the number of files matching `query`.
+    /// Only the names of the files are requested, and they are counted
+    /// as they are decoded, which is much cheaper than `query` for a
+    /// large number of matches.  Any `fields` in `query` are ignored.
+    pub async fn query_count(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+    ) -> Result<usize, Error> {
+        let query = self.query_request::<NameOnly>(root, query);
+        let response: QueryCountResponse = self
+            .generic_request_with_timeout(query, self.request_timeout)
+            .await?;
+        Ok(response.files)
+    }
+
+    /// Like `query`, but also returns the PDU that the results were
+    /// decoded from
+    pub async fn query_with_raw<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+    ) -> Result<WithRawPdu<QueryResult<F>>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let query = self.query_request::<F>(root, query);
+        self.generic_request_with_raw(query).await
+    }
+
+    /// Like `query`, but returns the response as the `Value` that it
+    /// was decoded from, without imposing a schema on it, for callers
+    /// that pass the results on rather than inspect them.
+    /// The `fields` of `query` are requested as given, or the server's
+    /// default fields if it is empty.
+    pub async fn query_raw(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+    ) -> Result<Value, Error> {
+        let fields = raw_fields(&query.fields);
+        let mut query = self.query_request::<Value>(root, query);
+        query.2.fields = fields;
+        self.generic_request_with_timeout(query, self.request_timeout)
+            .await
+    }
+
+    /// Build the request for querying `root` for `F`
+    fn query_request<F: QueryFieldList>(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+    ) -> QueryRequest {
+        QueryRequest(
+            "query",
+            root.root.clone(),
+            QueryRequestCommon {
+                relative_root: root.relative.clone(),
+                fields: F::field_list(),
+                sync_timeout: self.resolve_sync_timeout(query.sync_timeout),
+                ..query
+            },
+        )
+    }
+
+    /// Create a Subscription that will yield file changes as they occur in
+    /// real time.
     /// The `F` type is a struct defined by the
     /// [query_result_type!](macro.query_result_type.html) macro,
     /// or, if you want only the file name from the results, the
@@ -909,51 +2776,133 @@ impl Client {
     where
         F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
     {
-        let name = format!(
-            "sub-[{}]-{}",
-            std::env::args()
-                .nth(0)
-                .unwrap_or_else(|| "<no-argv-0>".to_string()),
-            SUB_ID.fetch_add(1, Ordering::Relaxed)
-        );
+        self.subscribe_with_timeout(root, query, self.request_timeout)
+            .await
+    }
+
+    /// Like `subscribe`, but waits at most `timeout` for the server to
+    /// respond to the subscribe request rather than using the default
+    /// set via `request_timeout`.
+    /// If `timeout` is `None`, waits indefinitely.
+    /// The timeout applies only to initiating the subscription; it
+    /// does not apply to `Subscription::next`.
+    pub async fn subscribe_with_timeout<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: SubscribeRequest,
+        timeout: Option<Duration>,
+    ) -> Result<(Subscription<F>, SubscribeResponse), Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        self.subscribe_with_fields(
+            root,
+            query,
+            subscription_name(),
+            F::field_list(),
+            timeout,
+            None,
+        )
+        .await
+    }
+
+    /// Like `subscribe`, but yields each file as the `Value` that it was
+    /// decoded from, without imposing a schema on it.
+    /// The `fields` of `query` are requested as given, or the server's
+    /// default fields if it is empty.
+    pub async fn subscribe_raw(
+        &self,
+        root: &ResolvedRoot,
+        query: SubscribeRequest,
+    ) -> Result<(Subscription<Value>, SubscribeResponse), Error> {
+        let fields = raw_fields(&query.fields);
+        self.subscribe_with_fields(
+            root,
+            query,
+            subscription_name(),
+            fields,
+            self.request_timeout,
+            None,
+        )
+        .await
+    }
 
+    /// Subscribe to `root` as `name`, requesting `fields` for each file.
+    /// The subscription's queue is limited by `bound`, if any.
+    pub(crate) async fn subscribe_with_fields<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: SubscribeRequest,
+        name: String,
+        fields: Vec<&'static str>,
+        timeout: Option<Duration>,
+        bound: Option<Bound>,
+    ) -> Result<(Subscription<F>, SubscribeResponse), Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let clock = query.since.clone();
         let query = SubscribeCommand(
             "subscribe",
             root.root.clone(),
             name.clone(),
             SubscribeRequest {
                 relative_root: root.relative.clone(),
-                fields: F::field_list(),
+                fields,
                 ..query
             },
         );
 
-        let (tx, responses) = tokio::sync::mpsc::unbounded_channel();
+        let (tx, responses) = backpressure::channel(bound);
 
         {
+            let command = serialize_request(&query)?;
             let mut inner = self.inner.lock().await;
             inner
                 .request_tx
-                .send(TaskItem::RegisterSubscription(name.clone(), tx))
+                .send(TaskItem::RegisterSubscription(name.clone(), tx, command))
                 .await
                 .map_err(Error::generic)?;
         }
 
-        let subscription = Subscription::<F> {
+        let mut subscription = Subscription::<F> {
             name,
             inner: Arc::clone(&self.inner),
             root: root.clone(),
             responses,
+            decode_errors: DecodeErrorPolicy::default(),
+            stats: SubscriptionStats::default(),
+            asserted_states: BTreeSet::new(),
+            consolidation: None,
+            debounce: None,
+            metrics: self.metrics.clone(),
+            clock,
             _phantom: PhantomData,
         };
 
-        let response: SubscribeResponse = self.generic_request(query).await?;
+        let response: SubscribeResponse =
+            match self.generic_request_with_timeout(query, timeout).await {
+                Ok(response) => response,
+                Err(err) => {
+                    // Don't leave the subscription to be re-established
+                    // after reconnecting
+                    let mut inner = self.inner.lock().await;
+                    inner
+                        .request_tx
+                        .send(TaskItem::UnregisterSubscription(subscription.name.clone()))
+                        .await
+                        .ok();
+                    return Err(err);
+                }
+            };
+        subscription.asserted_states = response.asserted_states.iter().cloned().collect();
 
         Ok((subscription, response))
     }
 
     /// Expand a set of globs into the set of matching file names.
-    /// The globs must be relative to the `root` parameter.
+    /// The globs must be relative to the `root` parameter, and may use
+    /// `{a,b}` alternatives, which are expanded by `expr::expand_braces`.
     /// The returned file names are all relative to the `root` parameter.
     pub async fn glob(&self, root: &ResolvedRoot, globs: &[&str]) -> Result<Vec<PathBuf>, Error> {
         let response: QueryResult<NameOnly> = self
@@ -968,7 +2917,68 @@ impl Client {
             .await?;
         Ok(response
             .files
-            .unwrap_or_else(Vec::new)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|f| f.name.into_inner())
+            .collect())
+    }
+
+    /// Returns the files beneath `root` whose names end in one of
+    /// `extensions`, which may be given with or without the leading `.`.
+    /// The candidates are produced by the `suffix` generator, which the
+    /// server evaluates efficiently, and then filtered by `expression`
+    /// if one is given.
+    /// The `F` type is a struct defined by the
+    /// [query_result_type!](macro.query_result_type.html) macro or
+    /// [NameOnly](struct.NameOnly.html), as for `query`.
+    pub async fn files_with_extension<F>(
+        &self,
+        root: &ResolvedRoot,
+        extensions: &[&str],
+        expression: Option<Expr>,
+    ) -> Result<Vec<F>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let suffixes = extensions
+            .iter()
+            .map(|ext| PathBuf::from(ext.strip_prefix('.').unwrap_or(ext)))
+            .collect();
+        let response: QueryResult<F> = self
+            .query(
+                root,
+                QueryRequestCommon {
+                    suffix: Some(suffixes),
+                    expression,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(response.files.unwrap_or_default())
+    }
+
+    /// Returns the names of the files beneath `root` that have changed
+    /// since the wall-clock `time`, including those that have been
+    /// deleted.  This is for tools that record checkpoints as points
+    /// in time rather than as clocks; see `ClockSpec::from_system_time`
+    /// for how `time` is rounded.
+    pub async fn changed_since_time(
+        &self,
+        root: &ResolvedRoot,
+        time: std::time::SystemTime,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let response: QueryResult<NameOnly> = self
+            .query(
+                root,
+                QueryRequestCommon {
+                    since: Some(Clock::Spec(ClockSpec::from_system_time(time))),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(response
+            .files
+            .unwrap_or_default()
             .into_iter()
             .map(|f| f.name.into_inner())
             .collect())
@@ -989,20 +2999,90 @@ impl Client {
     /// ## See also:
     ///  * <https://facebook.github.io/watchman/docs/cmd/clock.html>
     ///  * <https://facebook.github.io/watchman/docs/cookies.html>
-    pub async fn clock(
+    ///
+    /// `SyncTimeout::Default` is replaced by the timeout configured via
+    /// `Connector::sync_timeout`, if any.  A `Duration` may be passed in
+    /// place of a `SyncTimeout`.
+    pub async fn clock<T: Into<SyncTimeout>>(
         &self,
         root: &ResolvedRoot,
-        sync_timeout: SyncTimeout,
+        sync_timeout: T,
     ) -> Result<ClockSpec, Error> {
+        self.clock_with_timeout(root, sync_timeout, self.request_timeout)
+            .await
+    }
+
+    /// Like `clock`, but waits at most `timeout` for the server to
+    /// respond rather than using the default set via `request_timeout`.
+    /// If `timeout` is `None`, waits indefinitely.
+    pub async fn clock_with_timeout<T: Into<SyncTimeout>>(
+        &self,
+        root: &ResolvedRoot,
+        sync_timeout: T,
+        timeout: Option<Duration>,
+    ) -> Result<ClockSpec, Error> {
+        let sync_timeout = self.resolve_sync_timeout(sync_timeout.into());
         let response: ClockResponse = self
-            .generic_request(ClockRequest(
-                "clock",
-                root.root.clone(),
-                ClockRequestParams { sync_timeout },
-            ))
+            .generic_request_with_timeout(
+                ClockRequest(
+                    "clock",
+                    root.root.clone(),
+                    ClockRequestParams { sync_timeout },
+                ),
+                timeout,
+            )
             .await?;
         Ok(response.clock)
     }
+
+    /// Wait until the server has observed every filesystem change made
+    /// beneath `root` before this call, and return the clock at that
+    /// point.
+    ///
+    /// This writes a sync cookie file to the root and waits for up to
+    /// `timeout` for the server to observe it; if it doesn't the server
+    /// reports an error.  Passing the returned clock as the `since` of
+    /// a subsequent query, or querying with `SyncTimeout::DisableCookie`,
+    /// then sees everything that was written before `sync` was called
+    /// without paying for a second cookie.
+    ///
+    /// If a `request_timeout` is set that is shorter than `timeout`, it
+    /// is extended so that the server has a chance to report an expired
+    /// cookie.
+    pub async fn sync(&self, root: &ResolvedRoot, timeout: Duration) -> Result<ClockSpec, Error> {
+        Ok(self.sync_to_now(root, timeout).await?.clock)
+    }
+
+    /// Like `sync`, but also returns how long the sync took, which is
+    /// worth recording: it is usually a few milliseconds, and grows
+    /// when the server is busy or falling behind the filesystem.
+    pub async fn sync_to_now(
+        &self,
+        root: &ResolvedRoot,
+        timeout: Duration,
+    ) -> Result<SyncedClock, Error> {
+        // A zero duration would tell the server not to use a cookie
+        let sync_timeout = SyncTimeout::Duration(timeout.max(Duration::from_millis(1)));
+        let request_timeout = self
+            .request_timeout
+            .map(|request_timeout| request_timeout.max(timeout + Duration::from_secs(1)));
+        let start = Instant::now();
+        let clock = self
+            .clock_with_timeout(root, sync_timeout, request_timeout)
+            .await?;
+        let elapsed = start.elapsed();
+        trace_event!(debug, ?elapsed, "synced");
+        Ok(SyncedClock { clock, elapsed })
+    }
+
+    /// Replace `SyncTimeout::Default` by the timeout configured via
+    /// `Connector::sync_timeout`
+    fn resolve_sync_timeout(&self, sync_timeout: SyncTimeout) -> SyncTimeout {
+        match sync_timeout {
+            SyncTimeout::Default => self.sync_timeout,
+            sync_timeout => sync_timeout,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1014,4 +3094,1072 @@ mod tests {
         let builder = Connector::new().unix_domain_socket("/some/path");
         assert_eq!(builder.unix_domain, Some(PathBuf::from("/some/path")));
     }
+
+    #[test]
+    fn try_with_canonicalized_path() {
+        use std::convert::TryFrom;
+
+        assert!(CanonicalPath::try_with_canonicalized_path("relative".into()).is_err());
+        assert!(CanonicalPath::try_from(PathBuf::from("relative")).is_err());
+        let path = std::env::temp_dir();
+        let canonical = CanonicalPath::try_from(path.clone()).unwrap();
+        assert_eq!(canonical.as_path(), path);
+        assert_eq!(canonical.join("a"), path.join("a"));
+        assert_eq!(canonical.to_string(), path.display().to_string());
+        assert_eq!(PathBuf::from(canonical), path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn canonicalize_preserving_symlink() {
+        let dir = std::env::temp_dir().join(format!("watchman-symlink-{}", std::process::id()));
+        let target = dir.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let dir = std::fs::canonicalize(&dir).unwrap();
+
+        let preserved = CanonicalPath::canonicalize_preserving_symlink(&link).unwrap();
+        assert_eq!(preserved.into_path_buf(), dir.join("link"));
+        let resolved = CanonicalPath::canonicalize(&link).unwrap();
+        assert_eq!(resolved.into_path_buf(), dir.join("target"));
+        let parent = CanonicalPath::canonicalize_preserving_symlink(link.join("..")).unwrap();
+        assert_eq!(parent.into_path_buf(), dir);
+        assert!(CanonicalPath::canonicalize_preserving_symlink(dir.join("missing")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Reads the next request PDU sent by the client to the other
+    /// end of a `UnixStream::pair`
+    #[cfg(unix)]
+    async fn read_request(reader: &mut tokio::io::ReadHalf<UnixStream>) -> Value {
+        let pdu = PduBuffer::new().read_pdu(reader).await.unwrap();
+        bunser(&pdu).unwrap()
+    }
+
+    #[cfg(unix)]
+    fn clock_response(clock: &str) -> Vec<u8> {
+        serde_bser::ser::serialize(
+            Vec::new(),
+            maplit::hashmap! {
+                "version".to_string() => Value::from("fake"),
+                "clock".to_string() => Value::from(clock),
+            },
+        )
+        .unwrap()
+    }
+
+    #[cfg(unix)]
+    fn fake_root(path: &str) -> ResolvedRoot {
+        ResolvedRoot {
+            root: path.into(),
+            relative: None,
+            watcher: "fake".into(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pipelines_requests() {
+        let (stream, server) = UnixStream::pair().unwrap();
+        let client = Connector::new()
+            .max_outstanding_requests(2)
+            .spawn_client(Box::new(stream), None);
+        let (mut reader, mut writer) = tokio::io::split(server);
+
+        let clocks = tokio::spawn(async move {
+            let root = fake_root("/a");
+            let (a, b, c) = tokio::join!(
+                client.clock(&root, SyncTimeout::DisableCookie),
+                client.clock(&root, SyncTimeout::DisableCookie),
+                client.clock(&root, SyncTimeout::DisableCookie),
+            );
+            vec![a.unwrap(), b.unwrap(), c.unwrap()]
+        });
+
+        // Two requests are sent before either is responded to, and
+        // the third once there is room for it
+        read_request(&mut reader).await;
+        read_request(&mut reader).await;
+        writer.write_all(&clock_response("c:1")).await.unwrap();
+        read_request(&mut reader).await;
+        writer.write_all(&clock_response("c:2")).await.unwrap();
+        writer.write_all(&clock_response("c:3")).await.unwrap();
+
+        let clocks: Vec<String> = clocks
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|clock| match clock {
+                ClockSpec::StringClock(clock) => clock,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(clocks, vec!["c:1", "c:2", "c:3"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dropped_requests() {
+        let (stream, server) = UnixStream::pair().unwrap();
+        let client = Connector::new().spawn_client(Box::new(stream), None);
+        let (mut reader, mut writer) = tokio::io::split(server);
+        let short = Some(Duration::from_millis(10));
+
+        // Sent, but abandoned before the response arrives
+        client
+            .clock_with_timeout(&fake_root("/a"), SyncTimeout::DisableCookie, short)
+            .await
+            .unwrap_err();
+        // Queued behind the first, and abandoned before it is sent
+        client
+            .clock_with_timeout(&fake_root("/b"), SyncTimeout::DisableCookie, short)
+            .await
+            .unwrap_err();
+
+        let pending = tokio::spawn({
+            let inner = Arc::clone(&client.inner);
+            async move {
+                let client = Client {
+                    inner,
+                    endpoint: None,
+                    root_cache: None,
+                    sync_timeout: SyncTimeout::Default,
+                    request_timeout: None,
+                    server_version: Arc::default(),
+                    metrics: None,
+                };
+                client
+                    .clock(&fake_root("/c"), SyncTimeout::DisableCookie)
+                    .await
+            }
+        });
+
+        let request = read_request(&mut reader).await;
+        assert_eq!(
+            request,
+            Value::Array(vec![
+                "clock".into(),
+                "/a".into(),
+                Value::Object(HashMap::new())
+            ])
+        );
+        writer.write_all(&clock_response("c:a")).await.unwrap();
+
+        // The abandoned `/b` request is never sent
+        let request = read_request(&mut reader).await;
+        assert_eq!(
+            request,
+            Value::Array(vec![
+                "clock".into(),
+                "/c".into(),
+                Value::Object(HashMap::new())
+            ])
+        );
+        writer.write_all(&clock_response("c:c")).await.unwrap();
+
+        match pending.await.unwrap().unwrap() {
+            ClockSpec::StringClock(clock) => assert_eq!(clock, "c:c"),
+            clock => panic!("unexpected clock {:?}", clock),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn discards_abandoned_requests() {
+        #[derive(Default)]
+        struct QueueLength(std::sync::Mutex<Option<(usize, usize)>>);
+        impl ClientMetrics for QueueLength {
+            fn queue_length(&self, queued: usize, in_flight: usize) {
+                *self.0.lock().unwrap() = Some((queued, in_flight));
+            }
+        }
+
+        let (stream, server) = UnixStream::pair().unwrap();
+        let lengths = Arc::new(QueueLength::default());
+        let client = Connector::new()
+            .with_metrics(Arc::clone(&lengths))
+            .spawn_client(Box::new(stream), None);
+        let (mut reader, mut writer) = tokio::io::split(server);
+        let root = fake_root("/a");
+
+        let mut sent = Box::pin(client.clock(&root, SyncTimeout::DisableCookie));
+        let mut queued = Box::pin(client.clock(&root, SyncTimeout::DisableCookie));
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        assert!(sent.as_mut().poll(&mut cx).is_pending());
+        read_request(&mut reader).await;
+        assert!(queued.as_mut().poll(&mut cx).is_pending());
+        while *lengths.0.lock().unwrap() != Some((1, 1)) {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+
+        // The queued request is discarded as soon as it is dropped,
+        // without waiting for the one ahead of it to be answered
+        drop(queued);
+        while *lengths.0.lock().unwrap() != Some((0, 1)) {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+
+        // The response to the sent request is discarded, rather than
+        // being taken for the response to the next one
+        drop(sent);
+        let next = tokio::spawn({
+            let client = client.clone();
+            async move { client.clock(&root, SyncTimeout::DisableCookie).await }
+        });
+        writer.write_all(&clock_response("c:1")).await.unwrap();
+        read_request(&mut reader).await;
+        writer.write_all(&clock_response("c:2")).await.unwrap();
+        match next.await.unwrap().unwrap() {
+            ClockSpec::StringClock(clock) => assert_eq!(clock, "c:2"),
+            clock => panic!("unexpected clock {:?}", clock),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn closes_when_dropped() {
+        let (stream, server) = UnixStream::pair().unwrap();
+        let client = Connector::new().spawn_client(Box::new(stream), None);
+        let (mut reader, mut writer) = tokio::io::split(server);
+
+        let subscribe = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .subscribe::<NameOnly>(&fake_root("/a"), Default::default())
+                    .await
+            }
+        });
+        let request = read_request(&mut reader).await;
+        let response = serde_bser::ser::serialize(
+            Vec::new(),
+            maplit::hashmap! {
+                "version".to_string() => Value::from("fake"),
+                "subscribe".to_string() => crate::test_support::request_arg(&request, 2),
+                "clock".to_string() => Value::from("c:0"),
+            },
+        )
+        .unwrap();
+        writer.write_all(&response).await.unwrap();
+        let (subscription, _) = subscribe.await.unwrap().unwrap();
+
+        // The subscription keeps the connection open
+        drop(client);
+        let mut buf = [0u8; 1];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), reader.read(&mut buf))
+                .await
+                .is_err()
+        );
+
+        drop(subscription);
+        let read = tokio::time::timeout(Duration::from_secs(5), reader.read(&mut buf))
+            .await
+            .expect("the connection was closed");
+        assert_eq!(read.unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connect_retries() {
+        let failures = Arc::new(AtomicUsize::new(0));
+        let result = Connector::new()
+            .unix_domain_socket("/does/not/exist")
+            .connect_retries(2, Duration::from_millis(1))
+            .lifecycle_events({
+                let failures = Arc::clone(&failures);
+                move |event| {
+                    if let ConnectionEvent::ConnectFailed { .. } = event {
+                        failures.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+            .connect()
+            .await;
+        assert!(result.is_err());
+        assert_eq!(failures.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn pdu_buffer() {
+        let small = serialize_request(&("version",)).unwrap();
+        let large = serialize_request(&vec!["x".repeat(100); 200]).unwrap();
+        assert!(large.len() > PDU_BUFFER_SIZE);
+        let mut data = [small.clone(), large.clone()].concat();
+        for _ in 0..1000 {
+            data.extend_from_slice(&small);
+        }
+        let mut reader = &data[..];
+
+        let mut buf = PduBuffer::new();
+        let first = buf.read_pdu(&mut reader).await.unwrap();
+        assert_eq!(first, small);
+        assert_eq!(buf.read_pdu(&mut reader).await.unwrap(), large);
+
+        // Once the PDUs split from it are dropped, the storage is reused
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..1000 {
+            let pdu = buf.read_pdu(&mut reader).await.unwrap();
+            assert_eq!(pdu, small);
+            seen.insert(pdu.as_ptr());
+        }
+        assert!(seen.len() < 1000);
+        // Whereas a PDU that is still held is left alone
+        assert_eq!(first, small);
+
+        assert!(matches!(buf.read_pdu(&mut reader).await, Err(Error::Eof)));
+        let mut garbage = &b"garbage data that is not bser"[..];
+        assert!(matches!(
+            PduBuffer::new().read_pdu(&mut garbage).await,
+            Err(Error::Deserialize { .. })
+        ));
+    }
+
+    #[test]
+    fn command_name() {
+        let pdu = serialize_request(&WatchProjectRequest("watch-project", "/repo".into())).unwrap();
+        assert_eq!(super::command_name(&pdu).as_deref(), Some("watch-project"));
+        let pdu = serialize_request(&("version",)).unwrap();
+        assert_eq!(super::command_name(&pdu).as_deref(), Some("version"));
+        let pdu = serialize_request(&Value::from("not a command")).unwrap();
+        assert_eq!(super::command_name(&pdu), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connect_timeout() {
+        let result = Connector::new()
+            .unix_domain_socket("/does/not/exist")
+            .connect_retries(1000, Duration::from_millis(10))
+            .connect_timeout(Duration::from_millis(50))
+            .connect()
+            .await;
+        match result {
+            Err(Error::Timeout { command }) => assert_eq!(command, "connect"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn identifies_on_connect() {
+        let dir = std::env::temp_dir().join(format!("watchman-identity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sock_path = dir.join("sock");
+        std::fs::remove_file(&sock_path).ok();
+        let mut listener = tokio::net::UnixListener::bind(&sock_path).unwrap();
+
+        // The server never responds to the identity, which doesn't
+        // prevent connecting
+        let server = tokio::spawn(async move {
+            let (server, _) = listener.accept().await.unwrap();
+            let (mut reader, writer) = tokio::io::split(server);
+            let request = read_request(&mut reader).await;
+            (request, reader, writer)
+        });
+        let client = Connector::new()
+            .unix_domain_socket(&sock_path)
+            .identify(true)
+            .connect_timeout(Duration::from_secs(5))
+            .connect()
+            .await
+            .unwrap();
+        let (request, _reader, _writer) = server.await.unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        let identity = ClientIdentity::current();
+        assert_eq!(
+            request,
+            Value::Array(vec![
+                "log".into(),
+                "debug".into(),
+                format!("watchman_client: connected by {}", identity).into(),
+            ])
+        );
+        assert!(format!("{:?}", client).contains(&format!("pid {}", identity.pid)));
+    }
+
+    #[tokio::test]
+    async fn with_stream() {
+        use crate::test_support::{request_arg, MockServer};
+
+        let server = MockServer::new();
+        server.respond("log", |_| {
+            maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "logged".to_string() => Value::Bool(true),
+            }
+            .into()
+        });
+        let client = Connector::new().identify(true).with_stream(server.stream());
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        assert_eq!(root.project_root(), Path::new("/repo"));
+        // The identity is written in the background
+        while !server
+            .requests()
+            .iter()
+            .any(|r| request_arg(r, 0) == Value::from("log"))
+        {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn lifecycle_events() {
+        let dir = std::env::temp_dir().join(format!("watchman-lifecycle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sock_path = dir.join("sock");
+        std::fs::remove_file(&sock_path).ok();
+        let mut listener = tokio::net::UnixListener::bind(&sock_path).unwrap();
+
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = Connector::new()
+            .unix_domain_socket(&sock_path)
+            .lifecycle_events({
+                let events = Arc::clone(&events);
+                move |event| events.lock().unwrap().push(event.clone())
+            })
+            .connect()
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = tokio::io::split(server);
+
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                read_request(&mut reader).await;
+                writer.write_all(&clock_response("c:1")).await.unwrap();
+            }
+            // Dropping the reader and writer closes the connection
+        });
+        let root = fake_root("/a");
+        client
+            .clock(&root, SyncTimeout::DisableCookie)
+            .await
+            .unwrap();
+        client
+            .clock(&root, SyncTimeout::DisableCookie)
+            .await
+            .unwrap();
+        server.await.unwrap();
+
+        for _ in 0..100 {
+            if events.lock().unwrap().len() >= 4 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_dir_all(&dir).ok();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4, "{:?}", events);
+        match &events[0] {
+            ConnectionEvent::EndpointChosen {
+                endpoint,
+                discovered: false,
+                ..
+            } => assert_eq!(endpoint, &sock_path),
+            event => panic!("unexpected {:?}", event),
+        }
+        match &events[1] {
+            ConnectionEvent::Connected { endpoint } => assert_eq!(endpoint, &sock_path),
+            event => panic!("unexpected {:?}", event),
+        }
+        match &events[2] {
+            ConnectionEvent::ServerVersion { version } => assert_eq!(version, "fake"),
+            event => panic!("unexpected {:?}", event),
+        }
+        match &events[3] {
+            ConnectionEvent::Disconnected { reason } => {
+                assert_eq!(reason, &Error::Eof.to_string())
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn server_error_kind() {
+        use crate::test_support::{error_response, MockServer};
+
+        for (message, kind) in [
+            (
+                "RootResolveError: unable to resolve root /a: directory /a is not watched",
+                ServerErrorKind::RootNotWatched,
+            ),
+            (
+                "RootResolveError: unable to resolve root /a: realpath(/a) -> No such file or directory",
+                ServerErrorKind::RootResolveError,
+            ),
+            (
+                "RootResolveError: unable to resolve root /a: lstat: Permission denied \
+                 (this may be because you are not the process owner)",
+                ServerErrorKind::PermissionDenied,
+            ),
+            (
+                "query failed: synchronization failed: syncToNow: timed out waiting for \
+                 cookie file to be observed by watcher within 60000 milliseconds",
+                ServerErrorKind::SyncTimeout,
+            ),
+            (
+                "failed to parse query: unknown expression term 'bogus'",
+                ServerErrorKind::QueryParseError,
+            ),
+            (
+                "failed to validate command: unknown command bogus",
+                ServerErrorKind::InvalidCommand,
+            ),
+            ("something else", ServerErrorKind::Unknown),
+        ] {
+            assert_eq!(ServerErrorKind::from_message(message), kind, "{}", message);
+        }
+
+        let server = MockServer::new();
+        server.respond("watch-project", |_| {
+            error_response("RootResolveError: directory /repo is not watched")
+        });
+        let err = server
+            .connect()
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.server_error_kind(),
+            Some(ServerErrorKind::RootNotWatched)
+        );
+        assert_eq!(Error::Eof.server_error_kind(), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn request_timeout() {
+        // The server end of the pair never responds
+        let (stream, _server) = UnixStream::pair().unwrap();
+        let client = Connector::new()
+            .spawn_client(Box::new(stream), None)
+            .request_timeout(Duration::from_millis(10));
+        let root = fake_root("/some/path");
+
+        match client.clock(&root, SyncTimeout::DisableCookie).await {
+            Err(Error::Timeout { command }) => assert!(command.contains("clock")),
+            result => panic!("expected a timeout, got {:?}", result),
+        }
+
+        match client
+            .clock_with_timeout(
+                &root,
+                SyncTimeout::DisableCookie,
+                Some(Duration::from_millis(1)),
+            )
+            .await
+        {
+            Err(Error::Timeout { .. }) => {}
+            result => panic!("expected a timeout, got {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_uses_cookie() {
+        use crate::test_support::{request_arg, MockServer};
+        use serde_bser::value::Value;
+
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = fake_root("/repo");
+        client.sync(&root, Duration::from_secs(5)).await.unwrap();
+        client.sync(&root, Duration::from_secs(0)).await.unwrap();
+
+        let timeouts: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .filter(|r| request_arg(r, 0) == Value::from("clock"))
+            .map(|r| match request_arg(&r, 2) {
+                Value::Object(mut params) => params.remove("sync_timeout").unwrap(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(timeouts, vec![Value::from(5000i64), Value::from(1i64)]);
+
+        server.respond_once(
+            "clock",
+            maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "clock".to_string() => Value::from("c:0:9"),
+            }
+            .into(),
+        );
+        let synced = client
+            .sync_to_now(&root, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(synced.clock.to_string(), "c:0:9");
+        assert!(synced.elapsed < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn default_sync_timeout() {
+        use crate::test_support::{request_arg, MockServer};
+
+        let server = MockServer::new();
+        server.serve_files(&["a.rs"]);
+        let client = server.connect_with(Connector::new().sync_timeout(Duration::from_secs(0)));
+        let root = fake_root("/repo");
+        client.clock(&root, SyncTimeout::Default).await.unwrap();
+        client
+            .clock(&root, Duration::from_millis(1500))
+            .await
+            .unwrap();
+        client
+            .query::<NameOnly>(&root, QueryRequestCommon::default())
+            .await
+            .unwrap();
+        let sync_timeouts: Vec<Value> = server
+            .requests()
+            .iter()
+            .map(|request| match request_arg(request, 2) {
+                Value::Object(params) => params.get("sync_timeout").cloned().unwrap_or(Value::Null),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            sync_timeouts,
+            vec![Value::Null, Value::Integer(1500), Value::Integer(0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn debug_output() {
+        use crate::test_support::MockServer;
+
+        let connector = Connector::default()
+            .unix_domain_socket("/tmp/sock")
+            .lifecycle_events(|_| {});
+        let debug = format!("{:?}", connector);
+        assert!(debug.contains("\"/tmp/sock\""), "{}", debug);
+        assert!(debug.contains("lifecycle_events: true"), "{}", debug);
+
+        let server = MockServer::new();
+        let client = server.connect();
+        let clone = client.clone();
+        let debug = format!("{:?}", client);
+        assert!(debug.contains("endpoint: None"), "{}", debug);
+        assert!(debug.contains("handles: 2"), "{}", debug);
+        drop(clone);
+
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let (subscription, _) = client
+            .subscribe::<NameOnly>(&root, SubscribeRequest::default())
+            .await
+            .unwrap();
+        let debug = format!("{:?}", subscription);
+        assert!(debug.contains(subscription.name()), "{}", debug);
+        assert!(debug.contains("/repo"), "{}", debug);
+        assert!(format!("{:?}", client).contains("handles: 2"));
+
+        assert_eq!(ClockSpec::StringClock("c:0:1".into()).to_string(), "c:0:1");
+        assert_eq!(ClockSpec::unix_timestamp(1234).to_string(), "1234");
+    }
+
+    #[tokio::test]
+    async fn decode_error_policy() {
+        use crate::test_support::{subscription_pdu, MockServer};
+        use std::sync::Mutex as StdMutex;
+
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let (mut subscription, _) = client
+            .subscribe::<NameOnly>(&root, SubscribeRequest::default())
+            .await
+            .unwrap();
+        // Missing the required `clock` field
+        let name = subscription.name().to_string();
+        let malformed = || {
+            Value::from(maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "unilateral".to_string() => Value::Bool(true),
+                "subscription".to_string() => Value::from(name.as_str()),
+            })
+        };
+
+        server.push(malformed());
+        assert!(subscription.next().await.is_err());
+
+        let skipped = Arc::new(StdMutex::new(vec![]));
+        let record = Arc::clone(&skipped);
+        subscription.set_decode_error_policy(DecodeErrorPolicy::skip(move |err, pdu| {
+            assert!(matches!(err, Error::Deserialize { .. }));
+            record.lock().unwrap().push(pdu.to_vec());
+        }));
+        server.push(malformed());
+        server.push(subscription_pdu(
+            subscription.name(),
+            "c:0:1",
+            vec!["a.rs".into()],
+        ));
+        match subscription.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                assert_eq!(
+                    result.files.unwrap()[0].name.clone().into_inner(),
+                    PathBuf::from("a.rs")
+                )
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let skipped = skipped.lock().unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert!(bunser::<Value>(&skipped[0]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn asserted_states() {
+        use crate::test_support::{request_arg, MockServer};
+
+        let server = MockServer::new();
+        server.respond("subscribe", |request| {
+            maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "subscribe".to_string() => request_arg(request, 2),
+                "clock".to_string() => Value::from("c:0:1"),
+                "asserted-states".to_string() => Value::Array(vec!["hg.update".into()]),
+            }
+            .into()
+        });
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let (mut subscription, _) = client
+            .subscribe::<NameOnly>(&root, SubscribeRequest::default())
+            .await
+            .unwrap();
+        assert!(subscription.is_state_asserted("hg.update"));
+
+        let state = |key: &str, state_name: &str| {
+            Value::from(maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "clock".to_string() => Value::from("c:0:2"),
+                "unilateral".to_string() => Value::Bool(true),
+                "subscription".to_string() => Value::from(subscription.name()),
+                key.to_string() => Value::from(state_name),
+            })
+        };
+        server.push(state("state-enter", "build"));
+        server.push(state("state-leave", "hg.update"));
+        assert!(matches!(
+            subscription.next().await.unwrap(),
+            SubscriptionData::StateEnter { .. }
+        ));
+        assert_eq!(
+            subscription.asserted_states().iter().collect::<Vec<_>>(),
+            ["build", "hg.update"]
+        );
+        assert!(matches!(
+            subscription.next().await.unwrap(),
+            SubscriptionData::StateLeave { .. }
+        ));
+        assert_eq!(
+            subscription.asserted_states().iter().collect::<Vec<_>>(),
+            ["build"]
+        );
+    }
+
+    #[tokio::test]
+    async fn query_count() {
+        use crate::test_support::{request_arg, MockServer};
+
+        let server = MockServer::new();
+        server.serve_files(&["a.rs", "b.rs", "c/d.rs"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let count = client
+            .query_count(&root, QueryRequestCommon::default())
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+        let request = server.requests().pop().unwrap();
+        match request_arg(&request, 2) {
+            Value::Object(query) => {
+                assert_eq!(query["fields"], Value::Array(vec!["name".into()]))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn trigger_management() {
+        use crate::test_support::{request_arg, MockServer};
+
+        let server = MockServer::new();
+        server.respond("trigger-list", |_| {
+            maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "triggers".to_string() => Value::Array(vec![maplit::hashmap! {
+                    "name".to_string() => Value::from("assets"),
+                    "command".to_string() => Value::Array(vec!["make".into()]),
+                    "append_files".to_string() => Value::Bool(true),
+                }
+                .into()]),
+            }
+            .into()
+        });
+        server.respond("trigger-del", |request| {
+            maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "deleted".to_string() => Value::Bool(true),
+                "trigger".to_string() => request_arg(request, 2),
+            }
+            .into()
+        });
+        server.respond("trigger", |request| {
+            let name = match request_arg(request, 2) {
+                Value::Object(definition) => definition["name"].clone(),
+                other => other,
+            };
+            maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "triggerid".to_string() => name,
+                "disposition".to_string() => Value::from("created"),
+            }
+            .into()
+        });
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let disposition = client
+            .register_trigger(
+                &root,
+                TriggerDefinition::new("assets", &["make", "assets"])
+                    .expression(Expr::Suffix(vec!["css".into()]))
+                    .append_files(true)
+                    .stdin(TriggerStdin::fields_of::<NameOnly>())
+                    .stdout("/tmp/out", true)
+                    .env("A", "1"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(disposition, TriggerDisposition::Created);
+        match request_arg(&server.requests().pop().unwrap(), 2) {
+            Value::Object(definition) => {
+                assert_eq!(definition["name"], Value::from("assets"));
+                assert_eq!(
+                    definition["command"],
+                    Value::Array(vec![
+                        "env".into(),
+                        "A=1".into(),
+                        "make".into(),
+                        "assets".into()
+                    ])
+                );
+                assert_eq!(definition["append_files"], Value::Bool(true));
+                assert_eq!(definition["stdin"], Value::Array(vec!["name".into()]));
+                assert_eq!(definition["stdout"], Value::from(">>/tmp/out"));
+                assert!(!definition.contains_key("stderr"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let triggers = client.list_triggers(&root).await.unwrap();
+        assert_eq!(triggers.len(), 1);
+        assert_eq!(triggers[0].name, "assets");
+        assert!(triggers[0].append_files);
+
+        assert!(client.remove_trigger(&root, "assets").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn query_with_raw() {
+        use crate::test_support::MockServer;
+
+        let server = MockServer::new();
+        server.respond("query", |_| {
+            maplit::hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "clock".to_string() => Value::from("c:0:1"),
+                "files".to_string() => Value::Array(vec!["a.rs".into()]),
+                "debug".to_string() => Value::from("extra"),
+            }
+            .into()
+        });
+        let client = server.connect();
+        let root = fake_root("/repo");
+        let raw = client
+            .query_with_raw::<NameOnly>(&root, QueryRequestCommon::default())
+            .await
+            .unwrap();
+        assert_eq!(raw.response.files.as_ref().map(Vec::len), Some(1));
+        match raw.value().unwrap() {
+            Value::Object(fields) => assert_eq!(fields["debug"], Value::from("extra")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_query_and_subscription() {
+        use crate::test_support::{request_arg, subscription_pdu, MockServer};
+
+        let server = MockServer::new();
+        server.serve_files(&["a.rs"]);
+        let client = server.connect();
+        let root = fake_root("/repo");
+        let fields_of = |command: &str| {
+            let request = server
+                .requests()
+                .into_iter()
+                .rev()
+                .find(|r| request_arg(r, 0) == Value::from(command))
+                .unwrap();
+            let index = if command == "query" { 2 } else { 3 };
+            match request_arg(&request, index) {
+                Value::Object(mut query) => query.remove("fields").unwrap(),
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+
+        let response = client
+            .query_raw(&root, QueryRequestCommon::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            fields_of("query"),
+            Value::Array(vec![
+                "name".into(),
+                "exists".into(),
+                "new".into(),
+                "size".into(),
+                "mode".into()
+            ])
+        );
+        match response {
+            Value::Object(mut response) => match response.remove("files") {
+                Some(Value::Array(files)) => match &files[0] {
+                    Value::Object(file) => assert_eq!(file["mode"], Value::Integer(0o100644)),
+                    other => panic!("unexpected {:?}", other),
+                },
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let (mut sub, _) = client
+            .subscribe_raw(
+                &root,
+                SubscribeRequest {
+                    fields: vec!["name", "size"],
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            fields_of("subscribe"),
+            Value::Array(vec!["name".into(), "size".into()])
+        );
+        let file: Value = maplit::hashmap! {
+            "name".to_string() => Value::from("a.rs"),
+            "size".to_string() => Value::Integer(3),
+        }
+        .into();
+        server.push(subscription_pdu(sub.name(), "c:0:2", vec![file.clone()]));
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => assert_eq!(result.files, Some(vec![file])),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn files_with_extension() {
+        use crate::test_support::{request_arg, MockServer};
+
+        let server = MockServer::new();
+        server.serve_files(&["a.rs"]);
+        let client = server.connect();
+        let root = fake_root("/repo");
+        let files: Vec<NameOnly> = client
+            .files_with_extension(&root, &[".rs", "toml"], Some(Expr::Exists))
+            .await
+            .unwrap();
+        assert_eq!(files[0].name.clone().into_inner(), PathBuf::from("a.rs"));
+        match request_arg(&server.requests()[0], 2) {
+            Value::Object(params) => {
+                assert_eq!(
+                    params["suffix"],
+                    Value::Array(vec!["rs".into(), "toml".into()])
+                );
+                assert_eq!(params["expression"], Value::from("exists"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn changed_since_time() {
+        use crate::test_support::{request_arg, MockServer};
+
+        let server = MockServer::new();
+        server.serve_files(&["a.rs"]);
+        let client = server.connect();
+        let root = fake_root("/repo");
+        let time = std::time::UNIX_EPOCH + Duration::from_millis(1_600_000_000_500);
+        let files = client.changed_since_time(&root, time).await.unwrap();
+        assert_eq!(files, vec![PathBuf::from("a.rs")]);
+        match request_arg(&server.requests()[0], 2) {
+            Value::Object(params) => {
+                assert_eq!(params["since"], Value::Integer(1_600_000_000))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn clones_share_connection() {
+        use crate::test_support::{request_arg, MockServer};
+        use serde_bser::value::Value;
+
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = fake_root("/repo");
+        let clone = client.clone().request_timeout(Duration::from_secs(10));
+        assert_eq!(client.request_timeout, None);
+
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let client = clone.clone();
+                let root = root.clone();
+                tokio::spawn(async move { client.clock(&root, SyncTimeout::DisableCookie).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        drop(clone);
+        client
+            .clock(&root, SyncTimeout::DisableCookie)
+            .await
+            .unwrap();
+        let clocks = server
+            .requests()
+            .iter()
+            .filter(|r| request_arg(r, 0) == Value::from("clock"))
+            .count();
+        assert_eq!(clocks, 5);
+    }
 }
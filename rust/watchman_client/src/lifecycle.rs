@@ -0,0 +1,117 @@
+//! Structured events describing the lifecycle of a connection.
+//!
+//! Register a callback using `Connector::lifecycle_events` to be told
+//! what the client is doing as it discovers, connects to and
+//! disconnects from the server.  This is intended to help operators
+//! reconstruct the behavior of a client after the fact, so the
+//! callback is invoked synchronously as each event happens and may
+//! be used to timestamp and record the events.
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Describes something that happened to a connection
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ConnectionEvent {
+    /// The watchman CLI is being invoked to discover the server endpoint
+    DiscoveryStarted { watchman_path: PathBuf },
+
+    /// Discovery via the watchman CLI failed; the connection attempt
+    /// will fail with the same reason.
+    DiscoveryFailed { reason: String },
+
+    /// The endpoint that will be used to connect to the server has been
+    /// determined.  `discovered` is true if the endpoint was obtained by
+    /// invoking the watchman CLI, and false if it was configured on the
+    /// Connector or via the `WATCHMAN_SOCK` environment variable.
+    EndpointChosen {
+        endpoint: PathBuf,
+        discovered: bool,
+        elapsed: Duration,
+    },
+
+    /// A connection to the server was established
+    Connected { endpoint: PathBuf },
+
+    /// Connecting to the endpoint failed
+    ConnectFailed { endpoint: PathBuf, reason: String },
+
+    /// The version of the server was learned, either during discovery
+    /// or from the first response to a request.
+    /// This is reported once per connection.
+    ServerVersion { version: String },
+
+    /// The connection was closed.
+    /// This is reported once per connection.
+    Disconnected { reason: String },
+
+    /// The connection was lost, and the client will make its
+    /// `attempt`th attempt to reconnect after waiting for `delay`.
+    /// This is only reported when `Connector::reconnect` is enabled,
+    /// in place of `Disconnected`.
+    Reconnecting {
+        attempt: usize,
+        delay: Duration,
+        reason: String,
+    },
+
+    /// The client reconnected, after `attempts` attempts, and
+    /// re-established its subscriptions
+    Reconnected { attempts: usize },
+
+    /// Writing to the traffic log at `path` failed, so nothing more
+    /// is written to it.
+    /// This is reported at most once per client.
+    TrafficLogFailed { path: PathBuf, reason: String },
+}
+
+type Callback = Arc<dyn Fn(&ConnectionEvent) + Send + Sync>;
+
+/// Dispatches events to the registered callback, if any.
+/// This is shared between the Connector, the client and its tasks.
+#[derive(Clone, Default)]
+pub(crate) struct EventSink {
+    callback: Option<Callback>,
+    version_reported: Arc<AtomicBool>,
+    disconnected: Arc<AtomicBool>,
+}
+
+impl EventSink {
+    pub(crate) fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&ConnectionEvent) + Send + Sync + 'static,
+    {
+        Self {
+            callback: Some(Arc::new(callback)),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn has_callback(&self) -> bool {
+        self.callback.is_some()
+    }
+
+    pub(crate) fn emit(&self, event: ConnectionEvent) {
+        if let Some(callback) = self.callback.as_ref() {
+            callback(&event);
+        }
+    }
+
+    /// Report the server version, if it hasn't already been reported
+    pub(crate) fn server_version(&self, version: &str) {
+        if self.callback.is_some() && !self.version_reported.swap(true, Ordering::SeqCst) {
+            self.emit(ConnectionEvent::ServerVersion {
+                version: version.to_string(),
+            });
+        }
+    }
+
+    /// Report the disconnection, if it hasn't already been reported
+    pub(crate) fn disconnected(&self, reason: String) {
+        if !self.disconnected.swap(true, Ordering::SeqCst) {
+            self.emit(ConnectionEvent::Disconnected { reason });
+        }
+    }
+}
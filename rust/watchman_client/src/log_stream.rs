@@ -0,0 +1,145 @@
+//! Receive the server's log messages.
+//!
+//! The server sends its log messages to each connection that has
+//! asked for them using the `log-level` command.  This is handy for
+//! surfacing diagnostics in a tool that is built on watchman, such as
+//! an editor plugin:
+//!
+//! ```
+//! use serde_bser::value::Value;
+//! use tokio::stream::StreamExt;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//! # use maplit::hashmap;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let mut logs = client.log_stream().await?;
+//! client.set_log_level(LogLevel::Error).await?;
+//! # server.push(hashmap! {
+//! #     "unilateral".to_string() => Value::Bool(true),
+//! #     "level".to_string() => Value::from("error"),
+//! #     "log".to_string() => Value::from("1970-01-01T00:00:00,000: [io] failed\n"),
+//! # }.into());
+//! while let Some(message) = logs.next().await {
+//!     eprint!("watchman: {}", message?.log);
+//! #   break;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Calling `log_stream` before `set_log_level` ensures that no messages
+//! are missed.  The server forgets the log level of a connection when
+//! it is lost, so a client that reconnects needs to set it again.
+use crate::pdu::{LogLevel, LogLevelRequest, LogLevelResponse, LogMessage};
+use crate::prelude::*;
+use crate::{bunser, Error, ReceivedPdu, TaskItem};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::stream::Stream;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// The log messages sent by the server to a client.
+/// Returned by `Client::log_stream`.
+///
+/// The stream ends once the connection to the server is closed, and
+/// yields an error for a message that cannot be decoded.
+#[derive(Debug)]
+pub struct LogStream {
+    messages: UnboundedReceiver<ReceivedPdu>,
+}
+
+impl Stream for LogStream {
+    type Item = Result<LogMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages
+            .poll_recv(cx)
+            .map(|pdu| pdu.map(|pdu| bunser(&pdu.data)))
+    }
+}
+
+impl Client {
+    /// Choose which of the server's log messages are sent to this
+    /// client.  They are received using `log_stream`.
+    /// The default is `LogLevel::Off`.
+    pub async fn set_log_level(&self, level: LogLevel) -> Result<(), Error> {
+        let _: LogLevelResponse = self
+            .generic_request(LogLevelRequest("log-level", level))
+            .await?;
+        Ok(())
+    }
+
+    /// Returns a stream of the log messages sent to this client by the
+    /// server; use `set_log_level` to have the server send them.
+    /// Each stream receives every message that arrives after it was
+    /// created.
+    pub async fn log_stream(&self) -> Result<LogStream, Error> {
+        let (tx, messages) = tokio::sync::mpsc::unbounded_channel();
+        self.inner
+            .lock()
+            .await
+            .request_tx
+            .send(TaskItem::RegisterLogStream(tx))
+            .await
+            .map_err(Error::generic)?;
+        Ok(LogStream { messages })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use tokio::stream::StreamExt;
+
+    fn log_pdu(level: &str, log: &str) -> Value {
+        hashmap! {
+            "unilateral".to_string() => Value::Bool(true),
+            "level".to_string() => Value::from(level),
+            "log".to_string() => Value::from(log),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn streams_logs() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let mut logs = client.log_stream().await.unwrap();
+        client.set_log_level(LogLevel::Debug).await.unwrap();
+        let request = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("log-level"))
+            .unwrap();
+        assert_eq!(request_arg(&request, 1), Value::from("debug"));
+
+        server.push(log_pdu("debug", "one\n"));
+        server.push(log_pdu("error", "two\n"));
+        assert_eq!(
+            logs.next().await.unwrap().unwrap(),
+            LogMessage {
+                level: LogLevel::Debug,
+                log: "one\n".to_string(),
+            }
+        );
+        assert_eq!(logs.next().await.unwrap().unwrap().level, LogLevel::Error);
+
+        // A log PDU doesn't get mistaken for the response to a request
+        server.push(log_pdu("debug", "three\n"));
+        client.set_log_level(LogLevel::Off).await.unwrap();
+        assert_eq!(logs.next().await.unwrap().unwrap().log, "three\n");
+
+        server.disconnect_all();
+        assert!(logs.next().await.is_none());
+    }
+}
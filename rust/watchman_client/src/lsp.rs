@@ -0,0 +1,334 @@
+//! Back the Language Server Protocol `workspace/didChangeWatchedFiles`
+//! notification with a watchman subscription.
+//!
+//! This module is available when the `lsp-types` feature is enabled.
+//! A language server registers interest in files using a list of
+//! `FileSystemWatcher` glob patterns; `WatchedFilesRegistration`
+//! converts those into a subscription expression and converts the
+//! resulting subscription data into the `FileEvent` lists that the
+//! client expects:
+//!
+//! ```no_run
+//! use lsp_types::{FileSystemWatcher, GlobPattern};
+//! use watchman_client::lsp::{WatchedFile, WatchedFilesRegistration};
+//! use watchman_client::prelude::*;
+//!
+//! # async fn example(client: &Client) -> Result<(), watchman_client::Error> {
+//! let resolved = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let registration = WatchedFilesRegistration::new(
+//!     &resolved,
+//!     vec![FileSystemWatcher {
+//!         glob_pattern: GlobPattern::String("**/*.{rs,toml}".to_string()),
+//!         kind: None,
+//!     }],
+//! );
+//! let (mut sub, _) = client
+//!     .subscribe::<WatchedFile>(&resolved, registration.subscribe_request())
+//!     .await?;
+//! loop {
+//!     if let Some(params) = registration.did_change_watched_files(&sub.next().await?) {
+//!         // Send params to the language server
+//!         println!("{:?}", params.changes);
+//!     }
+//! }
+//! # }
+//! ```
+#![allow(deprecated)]
+// NewField is deprecated because it is easy to misinterpret; we use it
+// here only to distinguish creation from modification, which is all
+// that it can be relied upon to indicate.
+
+use crate::expr::expand_braces;
+use crate::prelude::*;
+use crate::SubscriptionData;
+use lsp_types::{
+    DidChangeWatchedFilesParams, FileChangeType, FileEvent, FileSystemWatcher, GlobPattern, OneOf,
+    Url, WatchKind,
+};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+// The fields required to produce `FileEvent`s
+query_result_type! {
+    pub struct WatchedFile {
+        pub name: NameField,
+        pub exists: ExistsField,
+        pub new: NewField,
+    }
+}
+
+/// A set of `FileSystemWatcher`s registered by a language server,
+/// mapped onto a watched root.
+///
+/// Watchman doesn't report which term of an expression matched a given
+/// file, so when the watchers specify different `kind`s, every matching
+/// file is reported using the union of those kinds.
+#[derive(Debug, Clone)]
+pub struct WatchedFilesRegistration {
+    root: PathBuf,
+    globs: Vec<String>,
+    kind: WatchKind,
+}
+
+impl WatchedFilesRegistration {
+    /// Map `watchers` onto `root`.
+    /// Watchers whose patterns are anchored outside of the root can
+    /// never match, and are ignored.
+    pub fn new(root: &ResolvedRoot, watchers: Vec<FileSystemWatcher>) -> Self {
+        let root = root.path();
+        let mut globs = vec![];
+        let mut kind = WatchKind::empty();
+        for watcher in watchers {
+            if let Some(glob) = relative_glob(&root, &watcher.glob_pattern) {
+                globs.extend(expand_braces(&glob));
+                // The protocol specifies that an omitted kind means
+                // all kinds
+                kind |= watcher.kind.unwrap_or_else(WatchKind::all);
+            }
+        }
+        Self { root, globs, kind }
+    }
+
+    /// Returns the expression that matches the registered patterns
+    pub fn expression(&self) -> Expr {
+        Expr::Any(
+            self.globs
+                .iter()
+                .map(|glob| {
+                    Expr::Match(MatchTerm {
+                        glob: glob.clone(),
+                        wholename: true,
+                        include_dot_files: true,
+                        ..Default::default()
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns a request for a subscription that reports changes to
+    /// the registered patterns.
+    /// The initial fresh instance result is empty, as the language
+    /// server is only interested in subsequent changes.
+    pub fn subscribe_request(&self) -> SubscribeRequest {
+        SubscribeRequest {
+            expression: Some(self.expression()),
+            empty_on_fresh_instance: true,
+            ..Default::default()
+        }
+    }
+
+    /// Convert a subscription result into a list of `FileEvent`s.
+    /// Changes to kinds that were not registered are omitted.
+    pub fn file_events(&self, result: &QueryResult<WatchedFile>) -> Vec<FileEvent> {
+        result
+            .files
+            .iter()
+            .flatten()
+            .filter_map(|file| {
+                let (typ, kind) = if !*file.exists {
+                    (FileChangeType::DELETED, WatchKind::Delete)
+                } else if *file.new {
+                    (FileChangeType::CREATED, WatchKind::Create)
+                } else {
+                    (FileChangeType::CHANGED, WatchKind::Change)
+                };
+                if !self.kind.contains(kind) {
+                    return None;
+                }
+                let uri = Url::from_file_path(self.root.join(&*file.name)).ok()?;
+                Some(FileEvent { uri, typ })
+            })
+            .collect()
+    }
+
+    /// Convert subscription data into the parameters of a
+    /// `workspace/didChangeWatchedFiles` notification.
+    /// Returns `None` if there is nothing to report; this includes
+    /// state transitions and fresh instance results, which don't
+    /// describe individual changes.
+    pub fn did_change_watched_files(
+        &self,
+        data: &SubscriptionData<WatchedFile>,
+    ) -> Option<DidChangeWatchedFilesParams> {
+        match data {
+            SubscriptionData::FilesChanged(result) if !result.is_fresh_instance => {
+                let changes = self.file_events(result);
+                if changes.is_empty() {
+                    None
+                } else {
+                    Some(DidChangeWatchedFilesParams { changes })
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Returns `pattern` as a glob relative to `root`, or `None` if the
+/// pattern is anchored outside of `root`
+fn relative_glob(root: &Path, pattern: &GlobPattern) -> Option<String> {
+    let (base, pattern) = match pattern {
+        GlobPattern::String(pattern) => {
+            if Path::new(pattern).is_absolute() {
+                return strip_root(root, pattern);
+            }
+            return Some(pattern.clone());
+        }
+        GlobPattern::Relative(relative) => {
+            let base = match &relative.base_uri {
+                OneOf::Left(folder) => &folder.uri,
+                OneOf::Right(uri) => uri,
+            };
+            (base.to_file_path().ok()?, &relative.pattern)
+        }
+    };
+    match base.strip_prefix(root) {
+        Ok(prefix) if prefix.as_os_str().is_empty() => Some(pattern.clone()),
+        Ok(prefix) => Some(format!("{}/{}", prefix.to_str()?, pattern)),
+        // The base may instead be a parent of the root, in which case
+        // the pattern is anchored above it
+        Err(_) => strip_root(root, &format!("{}/{}", base.to_str()?, pattern)),
+    }
+}
+
+/// Strips `root` from the absolute glob `pattern`
+fn strip_root(root: &Path, pattern: &str) -> Option<String> {
+    let root = root.to_str()?.trim_end_matches('/');
+    pattern
+        .strip_prefix(root)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bunser;
+    use crate::test_support::MOCK_VERSION;
+    use lsp_types::{RelativePattern, WorkspaceFolder};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    fn root() -> ResolvedRoot {
+        ResolvedRoot {
+            root: "/repo".into(),
+            relative: Some("src".into()),
+            watcher: "mock".to_string(),
+        }
+    }
+
+    fn watcher(glob_pattern: GlobPattern, kind: Option<WatchKind>) -> FileSystemWatcher {
+        FileSystemWatcher { glob_pattern, kind }
+    }
+
+    fn file(name: &str, exists: bool, new: bool) -> Value {
+        hashmap! {
+            "name".to_string() => Value::from(name),
+            "exists".to_string() => Value::Bool(exists),
+            "new".to_string() => Value::Bool(new),
+        }
+        .into()
+    }
+
+    fn result(files: Vec<Value>, is_fresh_instance: bool) -> QueryResult<WatchedFile> {
+        let pdu: Value = hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "clock".to_string() => Value::from("c:0:1"),
+            "is_fresh_instance".to_string() => Value::Bool(is_fresh_instance),
+            "files".to_string() => Value::Array(files),
+        }
+        .into();
+        bunser(&serde_bser::ser::serialize(Vec::new(), pdu).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn patterns() {
+        let uri = |path: &str| Url::from_file_path(path).unwrap();
+        let registration = WatchedFilesRegistration::new(
+            &root(),
+            vec![
+                watcher(GlobPattern::String("**/*.{rs,toml}".to_string()), None),
+                watcher(GlobPattern::String("/repo/src/lib/*.c".to_string()), None),
+                watcher(GlobPattern::String("/elsewhere/*.c".to_string()), None),
+                watcher(
+                    GlobPattern::Relative(RelativePattern {
+                        base_uri: OneOf::Right(uri("/repo/src/gen")),
+                        pattern: "*.h".to_string(),
+                    }),
+                    None,
+                ),
+                watcher(
+                    GlobPattern::Relative(RelativePattern {
+                        base_uri: OneOf::Left(WorkspaceFolder {
+                            uri: uri("/repo"),
+                            name: "repo".to_string(),
+                        }),
+                        pattern: "src/*.md".to_string(),
+                    }),
+                    None,
+                ),
+            ],
+        );
+        assert_eq!(
+            registration.globs,
+            vec!["**/*.rs", "**/*.toml", "lib/*.c", "gen/*.h", "*.md"]
+        );
+        match registration.expression() {
+            Expr::Any(terms) => assert_eq!(terms.len(), 5),
+            expr => panic!("unexpected {:?}", expr),
+        }
+        assert!(registration.subscribe_request().empty_on_fresh_instance);
+    }
+
+    #[test]
+    fn events() {
+        let registration = WatchedFilesRegistration::new(
+            &root(),
+            vec![watcher(
+                GlobPattern::String("*".to_string()),
+                Some(WatchKind::Create | WatchKind::Delete),
+            )],
+        );
+        let files = vec![
+            file("created", true, true),
+            file("changed", true, false),
+            file("deleted", false, false),
+        ];
+
+        let params = registration
+            .did_change_watched_files(&SubscriptionData::FilesChanged(result(
+                files.clone(),
+                false,
+            )))
+            .unwrap();
+        assert_eq!(
+            params.changes,
+            vec![
+                FileEvent {
+                    uri: Url::from_file_path("/repo/src/created").unwrap(),
+                    typ: FileChangeType::CREATED,
+                },
+                FileEvent {
+                    uri: Url::from_file_path("/repo/src/deleted").unwrap(),
+                    typ: FileChangeType::DELETED,
+                },
+            ]
+        );
+
+        assert!(registration
+            .did_change_watched_files(&SubscriptionData::FilesChanged(result(files, true)))
+            .is_none());
+        assert!(registration
+            .did_change_watched_files(&SubscriptionData::FilesChanged(result(
+                vec![file("changed", true, false)],
+                false
+            )))
+            .is_none());
+        assert!(registration
+            .did_change_watched_files(&SubscriptionData::Canceled)
+            .is_none());
+    }
+}
@@ -0,0 +1,168 @@
+//! Export measurements of how a client is performing.
+//!
+//! Services that export metrics, for example to Prometheus, can pass
+//! an implementation of `ClientMetrics` to `Connector::with_metrics` to
+//! be told about each request as it completes, the traffic exchanged
+//! with the server, how many requests are waiting to be sent, and how
+//! far behind the server each subscription is:
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::time::Duration;
+//! use watchman_client::metrics::ClientMetrics;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! #[derive(Default)]
+//! struct Counters {
+//!     requests: AtomicUsize,
+//!     bytes_read: AtomicUsize,
+//! }
+//!
+//! impl ClientMetrics for Counters {
+//!     fn request_completed(&self, _command: &str, _latency: Duration) {
+//!         self.requests.fetch_add(1, Ordering::Relaxed);
+//!     }
+//!
+//!     fn bytes_read(&self, bytes: usize) {
+//!         self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+//!     }
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let counters = std::sync::Arc::new(Counters::default());
+//! # let server = MockServer::new();
+//! # let client = server.connect_with(Connector::new().with_metrics(std::sync::Arc::clone(&counters)));
+//! # /*
+//! let client = Connector::new()
+//!     .with_metrics(std::sync::Arc::clone(&counters))
+//!     .connect()
+//!     .await?;
+//! # */
+//! client.version(&[], &[]).await?;
+//! assert_eq!(counters.requests.load(Ordering::Relaxed), 1);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The methods are invoked synchronously by the tasks that service the
+//! connection, so they should be cheap: incrementing a counter or
+//! recording into a histogram is fine, but anything slower holds up the
+//! connection.
+//! Sizes are those of the BSER PDUs, whichever `Codec` is in use.
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Receives measurements from a client; see `Connector::with_metrics`.
+/// Every method does nothing unless it is overridden.
+pub trait ClientMetrics: Send + Sync + 'static {
+    /// The server responded to a `command` request, `latency` after it
+    /// was sent
+    fn request_completed(&self, _command: &str, _latency: Duration) {}
+
+    /// A PDU of `bytes` bytes was read from the server
+    fn bytes_read(&self, _bytes: usize) {}
+
+    /// A PDU of `bytes` bytes was written to the server
+    fn bytes_written(&self, _bytes: usize) {}
+
+    /// `queued` requests are waiting to be sent, and `in_flight` have
+    /// been sent and are waiting for their responses.
+    /// This is reported whenever either number may have changed.
+    fn queue_length(&self, _queued: usize, _in_flight: usize) {}
+
+    /// A result was yielded by the `Subscription` named `subscription`,
+    /// `lag` after it was read from the server
+    fn subscription_lag(&self, _subscription: &str, _lag: Duration) {}
+}
+
+impl<T: ClientMetrics> ClientMetrics for Arc<T> {
+    fn request_completed(&self, command: &str, latency: Duration) {
+        (**self).request_completed(command, latency)
+    }
+
+    fn bytes_read(&self, bytes: usize) {
+        (**self).bytes_read(bytes)
+    }
+
+    fn bytes_written(&self, bytes: usize) {
+        (**self).bytes_written(bytes)
+    }
+
+    fn queue_length(&self, queued: usize, in_flight: usize) {
+        (**self).queue_length(queued, in_flight)
+    }
+
+    fn subscription_lag(&self, subscription: &str, lag: Duration) {
+        (**self).subscription_lag(subscription, lag)
+    }
+}
+
+/// The metrics of a client, shared by its tasks and subscriptions
+pub(crate) type SharedMetrics = Option<Arc<dyn ClientMetrics>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_support::{subscription_pdu, MockServer};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        requests: Mutex<Vec<String>>,
+        bytes_read: Mutex<usize>,
+        bytes_written: Mutex<usize>,
+        queue_lengths: Mutex<Vec<(usize, usize)>>,
+        lagging: Mutex<Vec<String>>,
+    }
+
+    impl ClientMetrics for Recorder {
+        fn request_completed(&self, command: &str, _latency: Duration) {
+            self.requests.lock().unwrap().push(command.to_string());
+        }
+
+        fn bytes_read(&self, bytes: usize) {
+            *self.bytes_read.lock().unwrap() += bytes;
+        }
+
+        fn bytes_written(&self, bytes: usize) {
+            *self.bytes_written.lock().unwrap() += bytes;
+        }
+
+        fn queue_length(&self, queued: usize, in_flight: usize) {
+            self.queue_lengths.lock().unwrap().push((queued, in_flight));
+        }
+
+        fn subscription_lag(&self, subscription: &str, _lag: Duration) {
+            self.lagging.lock().unwrap().push(subscription.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn records_metrics() {
+        let server = MockServer::new();
+        let recorder = Arc::new(Recorder::default());
+        let client = server.connect_with(Connector::new().with_metrics(Arc::clone(&recorder)));
+
+        client.version(&[], &[]).await.unwrap();
+        assert_eq!(*recorder.requests.lock().unwrap(), vec!["version"]);
+        assert!(*recorder.bytes_read.lock().unwrap() > 0);
+        assert!(*recorder.bytes_written.lock().unwrap() > 0);
+        assert_eq!(recorder.queue_lengths.lock().unwrap().last(), Some(&(0, 0)));
+
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let (mut sub, _) = client
+            .subscribe::<NameOnly>(&root, Default::default())
+            .await
+            .unwrap();
+        assert!(recorder.requests.lock().unwrap().len() > 1);
+        server.push(subscription_pdu(sub.name(), "c:0:2", vec!["a.rs".into()]));
+        sub.next().await.unwrap();
+        assert_eq!(*recorder.lagging.lock().unwrap(), vec![sub.name()]);
+    }
+}
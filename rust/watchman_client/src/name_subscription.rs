@@ -0,0 +1,208 @@
+//! Subscribe to the names of the files that change.
+//!
+//! Many consumers of subscriptions only need to know which paths
+//! changed.  `Client::subscribe_names` spares them from defining a
+//! result type and from matching on `SubscriptionData`, yielding a
+//! `Stream` of batches of changed paths:
+//!
+//! ```
+//! use tokio::stream::StreamExt;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::{subscription_pdu, MockServer};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let mut names = client
+//!     .subscribe_names(&root, SubscribeRequest::default())
+//!     .await?;
+//! # server.push(subscription_pdu(names.name(), "c:0:1", vec!["src/lib.rs".into()]));
+//! while let Some(paths) = names.next().await {
+//!     println!("changed: {:?}", paths?);
+//! #   break;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::subscription_stats::SubscriptionStats;
+use crate::{DecodeErrorPolicy, Error, Subscription, SubscriptionData};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::stream::Stream;
+
+/// A subscription that yields the names of the changed files.
+/// Returned by `Client::subscribe_names`.
+///
+/// Each item is a batch of paths relative to the root, as reported
+/// together by the server.  Empty batches and state transitions are
+/// not reported.  The stream ends once the subscription has been
+/// canceled by the server, and yields an error if the connection
+/// fails.
+#[derive(Debug)]
+pub struct NameSubscription {
+    subscription: Subscription<NameOnly>,
+    done: bool,
+}
+
+impl NameSubscription {
+    /// Returns the assigned name for this subscription instance
+    pub fn name(&self) -> &str {
+        self.subscription.name()
+    }
+
+    /// Returns statistics about the delivered PDUs, including the
+    /// empty batches and state transitions that are not yielded; see
+    /// `Subscription::stats`
+    pub fn stats(&self) -> &SubscriptionStats {
+        self.subscription.stats()
+    }
+
+    /// Returns the names of the currently asserted states; see
+    /// `Subscription::asserted_states`
+    pub fn asserted_states(&self) -> &BTreeSet<String> {
+        self.subscription.asserted_states()
+    }
+
+    /// Consolidate the names changed while any of `states` is asserted
+    /// into a single batch; see `Subscription::consolidate_states`
+    pub fn consolidate_states<I, S>(&mut self, states: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.subscription.consolidate_states(states);
+    }
+
+    /// Set how PDUs that cannot be decoded are handled; see
+    /// `Subscription::set_decode_error_policy`
+    pub fn set_decode_error_policy(&mut self, policy: DecodeErrorPolicy) {
+        self.subscription.set_decode_error_policy(policy);
+    }
+
+    /// Gracefully cancel this subscription; see `Subscription::cancel`
+    pub async fn cancel(self) -> Result<(), Error> {
+        self.subscription.cancel().await
+    }
+
+    /// Returns the underlying subscription
+    pub fn into_inner(self) -> Subscription<NameOnly> {
+        self.subscription
+    }
+}
+
+impl Stream for NameSubscription {
+    type Item = Result<Vec<PathBuf>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while !self.done {
+            let data = match self.subscription.poll_data(cx) {
+                Poll::Ready(Some(data)) => data,
+                Poll::Ready(None) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(Error::generic("client was torn down"))));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            match data {
+                Ok(SubscriptionData::FilesChanged(result)) => {
+                    let names: Vec<PathBuf> = result
+                        .files
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|file| file.name.into_inner())
+                        .collect();
+                    if !names.is_empty() {
+                        return Poll::Ready(Some(Ok(names)));
+                    }
+                }
+                Ok(SubscriptionData::StateEnter { .. })
+                | Ok(SubscriptionData::StateLeave { .. }) => {}
+                Ok(SubscriptionData::Canceled) => self.done = true,
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl Client {
+    /// Subscribe to the names of the files beneath `root` that change.
+    /// This is the same as `subscribe::<NameOnly>` except that the
+    /// results are yielded by a `Stream` of batches of paths.
+    ///
+    /// Unless `request.empty_on_fresh_instance` is set, the first batch
+    /// lists every matching file.
+    pub async fn subscribe_names(
+        &self,
+        root: &ResolvedRoot,
+        request: SubscribeRequest,
+    ) -> Result<NameSubscription, Error> {
+        let (subscription, _) = self.subscribe::<NameOnly>(root, request).await?;
+        Ok(NameSubscription {
+            subscription,
+            done: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{subscription_pdu, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use tokio::stream::StreamExt;
+
+    #[tokio::test]
+    async fn yields_batches() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let mut names = client
+            .subscribe_names(&root, SubscribeRequest::default())
+            .await
+            .unwrap();
+
+        server.push(subscription_pdu(names.name(), "c:0:1", vec![]));
+        server.push(Value::from(hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "unilateral".to_string() => Value::Bool(true),
+            "subscription".to_string() => Value::from(names.name()),
+            "clock".to_string() => Value::from("c:0:2"),
+            "state-enter".to_string() => Value::from("hg.update"),
+        }));
+        server.push(subscription_pdu(
+            names.name(),
+            "c:0:3",
+            vec!["a.rs".into(), "b.rs".into()],
+        ));
+        assert_eq!(
+            names.next().await.unwrap().unwrap(),
+            vec![PathBuf::from("a.rs"), PathBuf::from("b.rs")]
+        );
+        assert_eq!(names.stats().batches, 3);
+
+        server.push(Value::from(hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "unilateral".to_string() => Value::Bool(true),
+            "subscription".to_string() => Value::from(names.name()),
+            "clock".to_string() => Value::from("c:0:4"),
+            "canceled".to_string() => Value::Bool(true),
+        }));
+        assert!(names.next().await.is_none());
+        assert!(names.next().await.is_none());
+    }
+}
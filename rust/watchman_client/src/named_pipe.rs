@@ -1,109 +1,130 @@
 #![cfg(windows)]
 use crate::Error;
-use std::io::prelude::*;
 use std::io::Error as IoError;
 use std::os::windows::ffi::OsStrExt;
 use std::os::windows::io::FromRawHandle;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::PollEvented;
 use tokio::prelude::*;
-use tokio_net::util::PollEvented;
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::winerror::ERROR_PIPE_BUSY;
 use winapi::um::fileapi::*;
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::namedpipeapi::WaitNamedPipeW;
 use winapi::um::winbase::*;
 use winapi::um::winnt::*;
 
+/// How long `NamedPipe::connect` waits for an instance of a busy pipe
+/// to become available before giving up
+const PIPE_BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest that a single wait for a busy pipe occupies a thread of
+/// the blocking pool, which bounds how long the thread lingers once the
+/// connection attempt has been abandoned
+const PIPE_BUSY_WAIT_SLICE: Duration = Duration::from_millis(250);
+
 /// Spiritually similar in intent to the tokio-named-pipes crate
 /// equivalent, but this implementation works with pipe clients
 /// rather than servers, and works with the new async/await
-/// futures impl
+/// futures impl.
+/// The pipe is opened for overlapped IO and registered with the
+/// reactor, so reads and writes don't tie up a thread.
 pub struct NamedPipe {
     io: PollEvented<mio_named_pipes::NamedPipe>,
 }
 
 impl NamedPipe {
+    /// Connect to the pipe at `path`.
+    /// The server creates a limited number of instances of its pipe,
+    /// which may all be in use when it is under load; in that case this
+    /// waits for one to become available, for up to
+    /// `PIPE_BUSY_TIMEOUT`, rather than failing with `ERROR_PIPE_BUSY`
+    /// straight away.
     pub async fn connect(path: PathBuf) -> Result<Self, Error> {
         let win_path = path
             .as_os_str()
             .encode_wide()
             .chain(Some(0))
             .collect::<Vec<_>>();
+        let connect_error = |source: IoError| Error::Connect {
+            endpoint: path.clone(),
+            source: Box::new(source),
+        };
 
-        let handle = unsafe {
-            CreateFileW(
-                win_path.as_ptr(),
-                GENERIC_READ | GENERIC_WRITE,
-                0,
-                std::ptr::null_mut(),
-                OPEN_EXISTING,
-                FILE_FLAG_OVERLAPPED,
-                std::ptr::null_mut(),
-            )
+        let deadline = Instant::now() + PIPE_BUSY_TIMEOUT;
+        let pipe = loop {
+            let err = match open_pipe(&win_path) {
+                Ok(pipe) => break pipe,
+                Err(err) => err,
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if err.raw_os_error() != Some(ERROR_PIPE_BUSY as i32) || remaining == Duration::ZERO {
+                return Err(connect_error(err));
+            }
+            // Whether or not the wait sees an instance become available,
+            // trying again tells us whether we can have it, as another
+            // client may have beaten us to it
+            let wait = remaining.min(PIPE_BUSY_WAIT_SLICE).as_millis() as DWORD;
+            let win_path = win_path.clone();
+            tokio::task::spawn_blocking(move || unsafe {
+                WaitNamedPipeW(win_path.as_ptr(), wait);
+            })
+            .await
+            .map_err(|err| connect_error(IoError::other(err)))?;
         };
-        if handle.is_null() {
-            let err = IoError::last_os_error();
-            return Err(Error::Connect {
-                endpoint: path,
-                source: Box::new(err),
-            });
-        }
 
-        let pipe = unsafe { mio_named_pipes::NamedPipe::from_raw_handle(handle) };
-        let io = PollEvented::new(pipe);
+        let io = PollEvented::new(pipe).map_err(connect_error)?;
         Ok(Self { io })
     }
 }
 
+/// Open the client end of the pipe named by the nul terminated
+/// `win_path`
+fn open_pipe(win_path: &[u16]) -> Result<mio_named_pipes::NamedPipe, IoError> {
+    let handle = unsafe {
+        CreateFileW(
+            win_path.as_ptr(),
+            GENERIC_READ | GENERIC_WRITE,
+            0,
+            std::ptr::null_mut(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            std::ptr::null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(IoError::last_os_error());
+    }
+    Ok(unsafe { mio_named_pipes::NamedPipe::from_raw_handle(handle) })
+}
+
 impl AsyncRead for NamedPipe {
     fn poll_read(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         ctx: &mut Context,
         buf: &mut [u8],
     ) -> Poll<Result<usize, IoError>> {
-        match self.io.poll_read_ready(ctx, mio::Ready::readable()) {
-            Poll::Ready(res) => res?,
-            Poll::Pending => return Poll::Pending,
-        };
-
-        match self.io.get_ref().read(buf) {
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                self.io.clear_read_ready(ctx, mio::Ready::readable())?;
-                Poll::Pending
-            }
-
-            x => Poll::Ready(x),
-        }
+        Pin::new(&mut self.io).poll_read(ctx, buf)
     }
 }
 
 impl AsyncWrite for NamedPipe {
     fn poll_write(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         ctx: &mut Context,
         buf: &[u8],
     ) -> Poll<Result<usize, IoError>> {
-        match self.io.poll_write_ready(ctx) {
-            Poll::Ready(res) => res?,
-            Poll::Pending => return Poll::Pending,
-        };
-
-        match self.io.get_ref().write(buf) {
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                self.io.clear_write_ready(ctx)?;
-                Poll::Pending
-            }
-
-            x => Poll::Ready(x),
-        }
+        Pin::new(&mut self.io).poll_write(ctx, buf)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<Result<(), IoError>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.io).poll_flush(ctx)
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<Result<(), IoError>> {
-        Poll::Ready(Ok(()))
+    fn poll_shutdown(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.io).poll_shutdown(ctx)
     }
 }
-
-impl crate::ReadWriteStream for NamedPipe {}
@@ -0,0 +1,542 @@
+//! An implementation of the `notify` crate's `Watcher` trait that is
+//! backed by watchman.
+//!
+//! This module is available when the `notify` feature is enabled.
+//! It allows code written against `notify` to switch to watchman by
+//! changing the type of its watcher:
+//!
+//! ```no_run
+//! use notify::{RecursiveMode, Watcher};
+//! use watchman_client::notify_compat::WatchmanWatcher;
+//!
+//! # fn main() -> notify::Result<()> {
+//! let (tx, rx) = std::sync::mpsc::channel();
+//! let mut watcher = WatchmanWatcher::new(tx, notify::Config::default())?;
+//! watcher.watch(std::path::Path::new("."), RecursiveMode::Recursive)?;
+//! for event in rx {
+//!     println!("{:?}", event?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each watched path is backed by a subscription.
+//! Watchman reports changes in terms of the state of a file rather
+//! than the operation that was performed on it, so the events are
+//! necessarily coarser than those produced by the native backends:
+//!
+//! * A file that no longer exists is reported as `EventKind::Remove`
+//! * A file that was created since the last notification is reported
+//!   as `EventKind::Create`
+//! * Any other change is reported as `EventKind::Modify(ModifyKind::Any)`
+//!
+//! If the server has to recrawl the watched tree, and thus may have
+//! missed changes, an `EventKind::Other` event carrying `Flag::Rescan`
+//! is delivered, matching the behavior of the native backends.
+//!
+//! Code that manages its own subscriptions can translate their results
+//! into the same events with `to_events`, by subscribing with the
+//! `NotifyFile` result type:
+//!
+//! ```
+//! use watchman_client::notify_compat::{to_events, NotifyFile};
+//! use watchman_client::prelude::*;
+//! use watchman_client::SubscriptionData;
+//! # use watchman_client::test_support::{subscription_pdu, MockServer};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+//!     .await?;
+//! let (mut sub, _) = client
+//!     .subscribe::<NotifyFile>(&root, Default::default())
+//!     .await?;
+//! # server.push(subscription_pdu(sub.name(), "c:0:1", vec![]));
+//! if let SubscriptionData::FilesChanged(result) = sub.next().await? {
+//!     for event in to_events(&root.path(), &result) {
+//!         println!("{:?}", event);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+#![allow(deprecated)]
+// NewField is deprecated because it is easy to misinterpret; we use it
+// here only to distinguish creation from modification, which is all
+// that it can be relied upon to indicate.
+
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use notify::event::{CreateKind, Event, EventKind, Flag, ModifyKind, RemoveKind};
+use notify::{Config, EventHandler, RecursiveMode, WatcherKind};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+query_result_type! {
+    pub struct NotifyFile {
+        pub name: NameField,
+        pub exists: ExistsField,
+        pub new: NewField,
+        pub file_type: FileTypeField,
+    }
+}
+
+type ConnectFuture = Pin<Box<dyn Future<Output = Result<Client, Error>>>>;
+type ConnectFn = Box<dyn FnOnce() -> ConnectFuture + Send>;
+type SharedHandler = Arc<Mutex<dyn EventHandler>>;
+type Reply = std::sync::mpsc::Sender<notify::Result<()>>;
+/// Cancels a watch, replying once the subscription has been removed
+type Cancel = oneshot::Sender<Option<Reply>>;
+
+enum Command {
+    Watch {
+        path: PathBuf,
+        recursive_mode: RecursiveMode,
+        reply: Reply,
+    },
+    Unwatch {
+        path: PathBuf,
+        reply: Reply,
+    },
+}
+
+/// A `notify::Watcher` that delivers events from watchman subscriptions.
+///
+/// The watcher owns a background thread that runs the client; it is
+/// shut down when the watcher is dropped.
+pub struct WatchmanWatcher {
+    commands: UnboundedSender<Command>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for WatchmanWatcher {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("WatchmanWatcher").finish()
+    }
+}
+
+fn notify_error<E: std::fmt::Display>(err: E) -> notify::Error {
+    notify::Error::generic(&err.to_string())
+}
+
+impl WatchmanWatcher {
+    /// Create a watcher that connects to the server using the
+    /// supplied Connector
+    pub fn with_connector<F: EventHandler>(
+        event_handler: F,
+        connector: Connector,
+    ) -> notify::Result<Self> {
+        Self::spawn(
+            event_handler,
+            Box::new(move || Box::pin(async move { connector.connect().await })),
+        )
+    }
+
+    pub(crate) fn spawn<F: EventHandler>(
+        event_handler: F,
+        connect: ConnectFn,
+    ) -> notify::Result<Self> {
+        let handler: SharedHandler = Arc::new(Mutex::new(event_handler));
+        let (commands, rx) = unbounded_channel();
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+
+        let thread = std::thread::Builder::new()
+            .name("watchman-notify".to_string())
+            .spawn(move || {
+                let mut runtime = match tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        ready_tx.send(Err(notify::Error::io(err))).ok();
+                        return;
+                    }
+                };
+                runtime.block_on(async move {
+                    let client = match connect().await {
+                        Ok(client) => client,
+                        Err(err) => {
+                            ready_tx.send(Err(notify_error(err))).ok();
+                            return;
+                        }
+                    };
+                    ready_tx.send(Ok(())).ok();
+                    run(client, handler, rx).await;
+                });
+            })
+            .map_err(notify::Error::io)?;
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(Self {
+                commands,
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(notify::Error::generic("watchman watcher thread panicked")),
+        }
+    }
+
+    fn send(&self, make_command: impl FnOnce(Reply) -> Command) -> notify::Result<()> {
+        let (reply, result) = std::sync::mpsc::channel();
+        self.commands
+            .send(make_command(reply))
+            .map_err(|_| notify::Error::generic("watchman watcher thread has exited"))?;
+        result
+            .recv()
+            .map_err(|_| notify::Error::generic("watchman watcher thread has exited"))?
+    }
+}
+
+impl notify::Watcher for WatchmanWatcher {
+    /// Create a watcher that connects to the server that is discovered
+    /// via the watchman CLI.
+    /// The `Config` is ignored: watchman doesn't poll, and always
+    /// reports content changes.
+    fn new<F: EventHandler>(event_handler: F, _config: Config) -> notify::Result<Self> {
+        Self::with_connector(event_handler, Connector::new())
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        let path = path.to_path_buf();
+        self.send(|reply| Command::Watch {
+            path,
+            recursive_mode,
+            reply,
+        })
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        let path = path.to_path_buf();
+        self.send(|reply| Command::Unwatch { path, reply })
+    }
+
+    /// `notify` has no kind for watchers that delegate to an external
+    /// service, so this reports `NullWatcher`
+    fn kind() -> WatcherKind {
+        WatcherKind::NullWatcher
+    }
+}
+
+impl Drop for WatchmanWatcher {
+    fn drop(&mut self) {
+        // Closing the command channel causes the thread to exit
+        let (commands, _) = unbounded_channel();
+        drop(std::mem::replace(&mut self.commands, commands));
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Services commands until the watcher is dropped
+async fn run(client: Client, handler: SharedHandler, mut commands: UnboundedReceiver<Command>) {
+    // Maps the watched path to the means of canceling its subscription
+    let mut watches: HashMap<PathBuf, Cancel> = HashMap::new();
+
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::Watch {
+                path,
+                recursive_mode,
+                reply,
+            } => {
+                if let Some(cancel) = watches.remove(&path) {
+                    cancel.send(None).ok();
+                }
+                let result = watch(&client, &path, recursive_mode, Arc::clone(&handler)).await;
+                reply
+                    .send(result.map(|cancel| {
+                        watches.insert(path, cancel);
+                    }))
+                    .ok();
+            }
+            Command::Unwatch { path, reply } => {
+                match watches.remove(&path) {
+                    Some(cancel) => {
+                        // If the subscription has already ended there
+                        // is nothing left to remove
+                        if let Err(Some(reply)) = cancel.send(Some(reply)) {
+                            reply.send(Ok(())).ok();
+                        }
+                    }
+                    None => {
+                        reply
+                            .send(Err(notify::Error::watch_not_found().add_path(path)))
+                            .ok();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Establish a subscription for `path` and spawn a task to deliver
+/// its events.  Returns the means to cancel the subscription.
+async fn watch(
+    client: &Client,
+    path: &Path,
+    recursive_mode: RecursiveMode,
+    handler: SharedHandler,
+) -> notify::Result<Cancel> {
+    let canonical = std::fs::canonicalize(path)
+        .map_err(|_| notify::Error::path_not_found().add_path(path.to_path_buf()))?;
+
+    // Watchman can only watch directories; a file is watched via
+    // its parent
+    let (dir, file_name) = if canonical.is_dir() {
+        (canonical, None)
+    } else {
+        match (canonical.parent(), canonical.file_name()) {
+            (Some(parent), Some(file_name)) => {
+                (parent.to_path_buf(), Some(PathBuf::from(file_name)))
+            }
+            _ => return Err(notify::Error::path_not_found().add_path(path.to_path_buf())),
+        }
+    };
+
+    let resolved = client
+        .resolve_root(CanonicalPath::with_canonicalized_path(dir))
+        .await
+        .map_err(notify_error)?;
+
+    let shallow = Expr::Match(MatchTerm {
+        glob: "*".to_string(),
+        wholename: true,
+        include_dot_files: true,
+        ..Default::default()
+    });
+    let expression = match (file_name, recursive_mode) {
+        (Some(file_name), _) => Some(Expr::All(vec![
+            shallow,
+            Expr::Name(NameTerm {
+                paths: vec![file_name],
+                wholename: false,
+            }),
+        ])),
+        (None, RecursiveMode::NonRecursive) => Some(shallow),
+        (None, RecursiveMode::Recursive) => None,
+    };
+
+    let (subscription, _) = client
+        .subscribe::<NotifyFile>(
+            &resolved,
+            SubscribeRequest {
+                expression,
+                empty_on_fresh_instance: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(notify_error)?;
+
+    let (cancel, canceled) = oneshot::channel();
+    let watched = path.to_path_buf();
+    tokio::spawn(async move {
+        deliver(subscription, watched, handler, canceled).await;
+    });
+    Ok(cancel)
+}
+
+fn dispatch(handler: &SharedHandler, event: notify::Result<Event>) {
+    let mut handler = match handler.lock() {
+        Ok(handler) => handler,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    handler.handle_event(event);
+}
+
+/// Pump events from the subscription to the handler until it is
+/// canceled or fails
+async fn deliver(
+    mut subscription: Subscription<NotifyFile>,
+    watched: PathBuf,
+    handler: SharedHandler,
+    mut canceled: oneshot::Receiver<Option<Reply>>,
+) {
+    // The initial result is an empty fresh instance because we set
+    // empty_on_fresh_instance; any subsequent fresh instance means
+    // that the server recrawled and we may have missed changes
+    let watched_is_dir = watched.is_dir();
+    let mut initial = true;
+    loop {
+        let data = tokio::select! {
+            reply = &mut canceled => {
+                let result = subscription.cancel().await.map_err(notify_error);
+                if let Ok(Some(reply)) = reply {
+                    reply.send(result).ok();
+                }
+                return;
+            }
+            data = subscription.next() => data,
+        };
+        match data {
+            Ok(SubscriptionData::FilesChanged(result)) => {
+                if std::mem::replace(&mut initial, false) && result.is_fresh_instance {
+                    continue;
+                }
+                for mut event in to_events(&watched, &result) {
+                    if !watched_is_dir {
+                        event.paths = vec![watched.clone()];
+                    }
+                    dispatch(&handler, Ok(event));
+                }
+            }
+            Ok(SubscriptionData::StateEnter { .. }) | Ok(SubscriptionData::StateLeave { .. }) => {}
+            Ok(SubscriptionData::Canceled) => {
+                dispatch(
+                    &handler,
+                    Err(
+                        notify::Error::generic("the watch was canceled by the server")
+                            .add_path(watched),
+                    ),
+                );
+                return;
+            }
+            Err(err) => {
+                dispatch(&handler, Err(notify_error(err).add_path(watched)));
+                return;
+            }
+        }
+    }
+}
+
+/// Returns the events that describe a result of a subscription to the
+/// files beneath `root`: one for each file, whose path is joined to
+/// `root`, preceded by a rescan event if the result is a fresh instance
+pub fn to_events(root: &Path, result: &QueryResult<NotifyFile>) -> Vec<Event> {
+    let mut events = vec![];
+    if result.is_fresh_instance {
+        events.push(
+            Event::new(EventKind::Other)
+                .add_path(root.to_path_buf())
+                .set_flag(Flag::Rescan),
+        );
+    }
+    for file in result.files.iter().flatten() {
+        events.push(Event::new(event_kind(file)).add_path(root.join(&*file.name)));
+    }
+    events
+}
+
+fn event_kind(file: &NotifyFile) -> EventKind {
+    let file_type = *file.file_type;
+    if !*file.exists {
+        EventKind::Remove(match file_type {
+            FileType::Regular => RemoveKind::File,
+            FileType::Directory => RemoveKind::Folder,
+            _ => RemoveKind::Other,
+        })
+    } else if *file.new {
+        EventKind::Create(match file_type {
+            FileType::Regular => CreateKind::File,
+            FileType::Directory => CreateKind::Folder,
+            _ => CreateKind::Other,
+        })
+    } else {
+        EventKind::Modify(ModifyKind::Any)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer};
+    use maplit::hashmap;
+    use notify::Watcher;
+    use serde_bser::value::Value;
+    use std::time::Duration;
+
+    fn file(name: &str, exists: bool, new: bool, file_type: &str) -> Value {
+        hashmap! {
+            "name".to_string() => Value::from(name),
+            "exists".to_string() => Value::Bool(exists),
+            "new".to_string() => Value::Bool(new),
+            "type".to_string() => Value::from(file_type),
+        }
+        .into()
+    }
+
+    fn subscription_name(server: &MockServer) -> String {
+        server
+            .requests()
+            .iter()
+            .rev()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .map(|r| match request_arg(r, 2) {
+                Value::Utf8String(name) => name,
+                other => panic!("unexpected subscription name {:?}", other),
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn watch_and_unwatch() {
+        let dir = std::env::temp_dir();
+        let server = Arc::new(MockServer::new());
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mock = Arc::clone(&server);
+        let mut watcher = WatchmanWatcher::spawn(
+            tx,
+            Box::new(move || Box::pin(async move { Ok(mock.connect()) })),
+        )
+        .unwrap();
+
+        assert!(watcher
+            .watch(Path::new("/does/not/exist"), RecursiveMode::Recursive)
+            .is_err());
+
+        watcher.watch(&dir, RecursiveMode::Recursive).unwrap();
+        let name = subscription_name(&server);
+        let fresh_instance = |clock| {
+            let mut pdu = subscription_pdu(&name, clock, vec![]);
+            if let Value::Object(pdu) = &mut pdu {
+                pdu.insert("is_fresh_instance".to_string(), Value::Bool(true));
+            }
+            pdu
+        };
+        // The initial result doesn't produce an event
+        server.push(fresh_instance("c:0:0"));
+        server.push(subscription_pdu(
+            &name,
+            "c:0:1",
+            vec![
+                file("created", true, true, "f"),
+                file("changed", true, false, "f"),
+                file("gone", false, false, "d"),
+            ],
+        ));
+
+        let timeout = Duration::from_secs(10);
+        let next = || rx.recv_timeout(timeout).unwrap().unwrap();
+        let event = next();
+        assert_eq!(event.kind, EventKind::Create(CreateKind::File));
+        assert_eq!(event.paths, vec![dir.join("created")]);
+        assert_eq!(next().kind, EventKind::Modify(ModifyKind::Any));
+        assert_eq!(next().kind, EventKind::Remove(RemoveKind::Folder));
+
+        server.push(fresh_instance("c:0:2"));
+        assert!(next().need_rescan());
+
+        watcher.unwatch(&dir).unwrap();
+        assert!(watcher.unwatch(&dir).is_err());
+        drop(watcher);
+        assert!(server
+            .requests()
+            .iter()
+            .any(|r| request_arg(r, 0) == Value::from("unsubscribe")));
+    }
+}
@@ -1,21 +1,132 @@
 //! This module defines the request and response PDU types used by the
 //! watchman protocol.
 
-use crate::expr::Expr;
+use crate::expr::{expand_braces, Expr};
 use serde::{Deserialize, Serialize};
 use serde_bser::value::Value;
 use std::path::PathBuf;
 
 /// The `get-sockname` command response
 #[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct GetSockNameResponse {
     pub version: String,
     pub sockname: Option<PathBuf>,
     pub error: Option<String>,
 }
 
+/// The `get-pid` command response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct GetPidResponse {
+    pub version: String,
+    pub pid: u32,
+}
+
+/// The `shutdown-server` command response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ShutdownServerResponse {
+    pub version: String,
+    /// True if the server is exiting
+    #[serde(rename = "shutdown-server")]
+    pub shutdown_server: bool,
+}
+
+/// The `list-capabilities` command response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ListCapabilitiesResponse {
+    pub version: String,
+    pub capabilities: Vec<String>,
+}
+
+/// The `get-config` command request.
+/// You should use `Client::get_config` rather than directly
+/// constructing this type.
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct GetConfigRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "get-config"))] pub &'static str,
+    pub PathBuf,
+);
+
+/// The `get-config` command response
+#[derive(Deserialize, Debug)]
+pub struct GetConfigResponse {
+    pub version: String,
+    /// The contents of the root's `.watchmanconfig` file, as loaded by
+    /// the server when the root was watched.  This is empty if the
+    /// root has no such file.
+    #[serde(default)]
+    pub config: crate::watchman_config::WatchmanConfig,
+}
+
+/// The `version` command request.
+/// You should use `Client::version` rather than directly constructing
+/// this type.
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct VersionRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "version"))] pub &'static str,
+    pub VersionRequestParams,
+);
+
+/// The capabilities to ask about with the `version` command
+#[derive(Serialize, Debug, Default, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct VersionRequestParams {
+    /// The server fails the request if it lacks any of these
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
+    /// The server reports whether it has each of these
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub optional: Vec<String>,
+}
+
+/// The `version` command response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct VersionResponse {
+    /// The watchman server version
+    pub version: String,
+    /// Details of how the server was built, if it was built with them
+    #[serde(default)]
+    pub buildinfo: Option<String>,
+    /// Whether the server has each of the capabilities that were
+    /// asked about
+    #[serde(default)]
+    pub capabilities: std::collections::BTreeMap<String, bool>,
+}
+
+/// The `watch-list` command request.
+/// You should use `Client::watch_list` rather than directly
+/// constructing this type.
+#[derive(Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct WatchListRequest;
+
+impl Serialize for WatchListRequest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ["watch-list"].serialize(serializer)
+    }
+}
+
+/// The `watch-list` command response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct WatchListResponse {
+    pub version: String,
+    /// The roots that the server is watching.  The server reports
+    /// only their paths; `Client::resolve_root` reports the watcher
+    /// that is used for a root.
+    #[serde(default)]
+    pub roots: Vec<PathBuf>,
+}
+
 /// The `clock` command response
 #[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct ClockResponse {
     pub version: String,
     pub clock: ClockSpec,
@@ -23,9 +134,15 @@ pub struct ClockResponse {
 
 /// The `clock` command request.
 #[derive(Serialize, Debug)]
-pub struct ClockRequest(pub &'static str, pub PathBuf, pub ClockRequestParams);
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ClockRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "clock"))] pub &'static str,
+    pub PathBuf,
+    pub ClockRequestParams,
+);
 
 #[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct ClockRequestParams {
     #[serde(skip_serializing_if = "SyncTimeout::is_disabled", default)]
     pub sync_timeout: SyncTimeout,
@@ -35,10 +152,15 @@ pub struct ClockRequestParams {
 /// You should use `Client::resolve_root` rather than directly
 /// constructing this type.
 #[derive(Serialize, Debug)]
-pub struct WatchProjectRequest(pub &'static str, pub PathBuf);
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct WatchProjectRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "watch-project"))] pub &'static str,
+    pub PathBuf,
+);
 
 /// The `watch-project` response
 #[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct WatchProjectResponse {
     /// The watchman server version
     pub version: String,
@@ -51,10 +173,101 @@ pub struct WatchProjectResponse {
     pub watcher: String,
 }
 
+/// The `watch-del` command request.
+/// You should use `Client::watch_del` rather than directly
+/// constructing this type.
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct WatchDelRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "watch-del"))] pub &'static str,
+    pub PathBuf,
+);
+
+/// The `watch-del` response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct WatchDelResponse {
+    /// The watchman server version
+    pub version: String,
+    /// True if the watch was removed
+    #[serde(rename = "watch-del")]
+    pub watch_del: bool,
+    /// The root of the project that is no longer watched
+    pub root: PathBuf,
+}
+
+/// The `watch-del-all` command request.
+/// You should use `Client::watch_del_all` rather than directly
+/// constructing this type.
+#[derive(Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct WatchDelAllRequest;
+
+impl Serialize for WatchDelAllRequest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ["watch-del-all"].serialize(serializer)
+    }
+}
+
+/// The `watch-del-all` response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct WatchDelAllResponse {
+    /// The watchman server version
+    pub version: String,
+    /// The roots that are no longer watched
+    #[serde(default)]
+    pub roots: Vec<PathBuf>,
+}
+
+/// The `find` command request.
+/// You should use `Client::find` rather than directly constructing
+/// this type.
+/// <https://facebook.github.io/watchman/docs/cmd/find.html>
+#[derive(Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FindRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "find"))] pub &'static str,
+    pub PathBuf,
+    /// The patterns to match, in the legacy pattern syntax; these are
+    /// sent as individual arguments following the root
+    pub Vec<String>,
+);
+
+impl Serialize for FindRequest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(Some(2 + self.2.len()))?;
+        seq.serialize_element(self.0)?;
+        seq.serialize_element(&self.1)?;
+        for pattern in &self.2 {
+            seq.serialize_element(pattern)?;
+        }
+        seq.end()
+    }
+}
+
+/// The `find` response
+#[derive(Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FindResponse<F>
+where
+    F: std::fmt::Debug + Clone,
+{
+    /// The watchman server version
+    pub version: String,
+    /// The clock at the time that the files were found
+    pub clock: ClockSpec,
+    /// The matching files
+    #[serde(default = "Vec::new")]
+    pub files: Vec<F>,
+}
+
 /// When using the `path` generator, this specifies a path to be
 /// examined.
 /// <https://facebook.github.io/watchman/docs/file-query.html#path-generator>
 #[derive(Serialize, Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(untagged)]
 pub enum PathGeneratorElement {
     RecursivePath(PathBuf),
@@ -63,17 +276,29 @@ pub enum PathGeneratorElement {
 
 /// The `query` request
 #[derive(Serialize, Clone, Debug)]
-pub struct QueryRequest(pub &'static str, pub PathBuf, pub QueryRequestCommon);
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct QueryRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "query"))] pub &'static str,
+    pub PathBuf,
+    pub QueryRequestCommon,
+);
 
 #[allow(clippy::trivially_copy_pass_by_ref)]
 fn is_false(v: &bool) -> bool {
     !*v
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_true(v: &bool) -> bool {
+    *v
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(into = "i64")]
 pub enum SyncTimeout {
     /// Use the default cookie synchronization timeout
+    #[default]
     Default,
     /// Disable the use of a sync cookie.
     /// This can save ~15ms of latency, but may result in
@@ -90,25 +315,13 @@ pub enum SyncTimeout {
     Duration(std::time::Duration),
 }
 
-impl Default for SyncTimeout {
-    fn default() -> Self {
-        Self::Default
-    }
-}
-
 impl SyncTimeout {
     fn is_default(&self) -> bool {
-        match self {
-            Self::Default => true,
-            _ => false,
-        }
+        matches!(self, Self::Default)
     }
 
     fn is_disabled(&self) -> bool {
-        match self {
-            Self::DisableCookie => true,
-            _ => false,
-        }
+        matches!(self, Self::DisableCookie)
     }
 }
 
@@ -123,9 +336,16 @@ impl From<std::time::Duration> for SyncTimeout {
     }
 }
 
-impl Into<i64> for SyncTimeout {
-    fn into(self) -> i64 {
-        match self {
+/// `None` is the default timeout
+impl From<Option<std::time::Duration>> for SyncTimeout {
+    fn from(duration: Option<std::time::Duration>) -> Self {
+        duration.map_or(Self::Default, Self::from)
+    }
+}
+
+impl From<SyncTimeout> for i64 {
+    fn from(timeout: SyncTimeout) -> i64 {
+        match timeout {
             // This is only really here because the `ClockRequestParams` PDU
             // treats a missing sync_timeout as `DisableCookie`, whereas
             // the `QueryRequestCommon` PDU treats it as `Default`.
@@ -135,9 +355,9 @@ impl Into<i64> for SyncTimeout {
             // default behavior, we use the current default sync timeout here.
             // We're honestly not likely to change this, so this should be fine.
             // The server uses 1 minute; the value here is expressed in milliseconds.
-            Self::Default => 60_000,
-            Self::DisableCookie => 0,
-            Self::Duration(d) => d.as_millis() as i64,
+            SyncTimeout::Default => 60_000,
+            SyncTimeout::DisableCookie => 0,
+            SyncTimeout::Duration(d) => d.as_millis() as i64,
         }
     }
 }
@@ -161,11 +381,17 @@ impl Into<i64> for SyncTimeout {
 /// The filtration and expression syntax is explained here:
 /// <https://facebook.github.io/watchman/docs/file-query.html#expressions>
 #[derive(Serialize, Default, Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct QueryRequestCommon {
     /// If set, enables the glob generator and specifies a set of globs
     /// that will be expanded into a list of file names and then filtered
     /// according to the expression field.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// The globs may use `{a,b}` alternatives, which are expanded by
+    /// `expr::expand_braces` when the query is sent.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "expand_globs"
+    )]
     pub glob: Option<Vec<String>>,
 
     /// If using the glob generator and set to true, do not treat the backslash
@@ -230,6 +456,10 @@ pub struct QueryRequestCommon {
     /// In general, avoid querying `size` and `mode` fields and instead prefer to
     /// query `content.sha1hex` and `type` instead to avoid materializing inodes
     /// in a virtualized filesystem.
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::field_list)
+    )]
     pub fields: Vec<&'static str>,
 
     /// If true you indicate that you know how to 100% correctly deal with a fresh
@@ -305,6 +535,7 @@ pub struct QueryRequestCommon {
 /// The `F` should deserialize the list of fields in your QueryRequestCommon
 /// struct.
 #[derive(Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct QueryResult<F>
 where
     F: std::fmt::Debug + Clone,
@@ -342,10 +573,25 @@ where
     #[doc(hidden)]
     pub state_leave: Option<String>,
     #[serde(rename = "metadata")]
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::optional_value)
+    )]
     pub state_metadata: Option<Value>,
+
+    /// When using source control aware queries with saved
+    /// state configuration, this field holds metadata from
+    /// the save state storage engine.
+    #[serde(rename = "saved-state-info")]
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::optional_value)
+    )]
+    pub saved_state_info: Option<Value>,
 }
 
-#[derive(Serialize, Default, Clone, Debug)]
+#[derive(Serialize, Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct SubscribeRequest {
     /// If set, enables the use of the `since` generator and specifies the last
     /// time you queried the server and for which you wish to receive a delta of
@@ -383,6 +629,10 @@ pub struct SubscribeRequest {
     /// In general, avoid querying `size` and `mode` fields and instead prefer to
     /// query `content.sha1hex` and `type` instead to avoid materializing inodes
     /// in a virtualized filesystem.
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::field_list)
+    )]
     pub fields: Vec<&'static str>,
 
     /// If true you indicate that you know how to 100% correctly deal with a fresh
@@ -399,21 +649,112 @@ pub struct SubscribeRequest {
     /// <https://facebook.github.io/watchman/docs/cmd/query.html#case-sensitivity>
     #[serde(default, skip_serializing_if = "is_false")]
     pub case_sensitive: bool,
+
+    /// The names of states during which notifications are deferred.
+    /// The changes made while one of these states is asserted on the
+    /// root are delivered together once it has been left.
+    /// <https://facebook.github.io/watchman/docs/cmd/subscribe.html#defer>
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub defer: Vec<String>,
+
+    /// The names of states during which notifications are dropped.
+    /// The changes made while one of these states is asserted on the
+    /// root are never delivered; the subscription resumes from the
+    /// point at which the state was left.
+    /// <https://facebook.github.io/watchman/docs/cmd/subscribe.html#drop>
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drop: Vec<String>,
+
+    /// If true, notifications are deferred while the server observes a
+    /// version control operation in progress, such as the lock files
+    /// held by `git` or `hg`.  This defaults to true, as it does in the
+    /// server.
+    /// <https://facebook.github.io/watchman/docs/cmd/subscribe.html#defer_vcs>
+    #[serde(skip_serializing_if = "is_true")]
+    pub defer_vcs: bool,
+}
+
+impl Default for SubscribeRequest {
+    fn default() -> Self {
+        Self {
+            since: None,
+            relative_root: None,
+            expression: None,
+            fields: vec![],
+            empty_on_fresh_instance: false,
+            case_sensitive: false,
+            defer: vec![],
+            drop: vec![],
+            defer_vcs: true,
+        }
+    }
 }
 
 #[derive(Serialize, Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct SubscribeCommand(
-    pub &'static str,
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "subscribe"))] pub &'static str,
     pub PathBuf,
     pub String,
     pub SubscribeRequest,
 );
 
+/// The response to a query, reduced to the number of matching files so
+/// that the files themselves needn't be decoded into a `Vec`.
+/// Used by `Client::query_count`.
+#[derive(Deserialize, Debug)]
+pub(crate) struct QueryCountResponse {
+    #[serde(default, deserialize_with = "count_elements")]
+    pub files: usize,
+}
+
+/// Serialize `globs` with their `{a,b}` alternatives expanded, as the
+/// server doesn't support them
+fn expand_globs<S>(globs: &Option<Vec<String>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let expanded: Option<Vec<String>> = globs
+        .as_ref()
+        .map(|globs| globs.iter().flat_map(|glob| expand_braces(glob)).collect());
+    expanded.serialize(serializer)
+}
+
+fn count_elements<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct CountVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for CountVisitor {
+        type Value = usize;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("an array")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<usize, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            let mut count = 0;
+            while seq.next_element::<serde::de::IgnoredAny>()?.is_some() {
+                count += 1;
+            }
+            Ok(count)
+        }
+    }
+
+    deserializer.deserialize_seq(CountVisitor)
+}
+
 /// Returns information about the state of the watch at the time the
 /// subscription was initiated.
 #[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct SubscribeResponse {
     pub version: String,
+    #[allow(dead_code)]
     subscribe: String,
 
     /// The clock at initiation time.
@@ -430,18 +771,511 @@ pub struct SubscribeResponse {
     /// state configuration, this field holds metadata from
     /// the save state storage engine.
     #[serde(rename = "saved-state-info")]
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::optional_value)
+    )]
     pub saved_state_info: Option<Value>,
 }
 
 #[derive(Serialize, Debug)]
-pub struct Unsubscribe(pub &'static str, pub PathBuf, pub String);
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Unsubscribe(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "unsubscribe"))] pub &'static str,
+    pub PathBuf,
+    pub String,
+);
 
 #[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct UnsubscribeResponse {
     pub version: String,
     pub unsubscribe: String,
 }
 
+/// The `flush-subscriptions` command
+/// <https://facebook.github.io/watchman/docs/cmd/flush-subscriptions.html>
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FlushSubscriptionsRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "flush-subscriptions"))] pub &'static str,
+    pub PathBuf,
+    pub FlushSubscriptionsParams,
+);
+
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FlushSubscriptionsParams {
+    /// How long the server waits, in milliseconds, for its view of the
+    /// filesystem to catch up before flushing the subscriptions
+    pub sync_timeout: i64,
+    /// The names of the subscriptions to flush.  If omitted, all of the
+    /// subscriptions on the root that were made by this connection are
+    /// flushed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscriptions: Option<Vec<String>>,
+}
+
+/// The `flush-subscriptions` response
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FlushSubscriptionsResponse {
+    pub version: String,
+    /// The subscriptions that had pending changes, which were delivered
+    /// ahead of this response
+    #[serde(default)]
+    pub synced: Vec<String>,
+    /// The subscriptions that were already up to date
+    #[serde(default)]
+    pub no_sync_needed: Vec<String>,
+    /// The subscriptions whose pending changes were dropped rather than
+    /// delivered, because the root is in a state that the subscription
+    /// was asked to drop changes for
+    #[serde(default)]
+    pub dropped: Vec<String>,
+}
+
+/// What is fed to the standard input of a trigger's command
+/// <https://facebook.github.io/watchman/docs/cmd/trigger.html#controlling-input>
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum TriggerStdin {
+    /// The command reads from `/dev/null`.  This is the default.
+    DevNull,
+    /// The command reads the names of the changed files, one per line
+    NamePerLine,
+    /// The command reads a JSON array of objects describing the changed
+    /// files, with the given fields
+    Fields(Vec<String>),
+}
+
+impl TriggerStdin {
+    /// The command reads a JSON array of objects with the fields of `F`,
+    /// a type defined using `query_result_type!`
+    pub fn fields_of<F: crate::fields::QueryFieldList>() -> Self {
+        Self::Fields(F::field_list().into_iter().map(String::from).collect())
+    }
+}
+
+impl Serialize for TriggerStdin {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::DevNull => serializer.serialize_str("/dev/null"),
+            Self::NamePerLine => serializer.serialize_str("NAME_PER_LINE"),
+            Self::Fields(fields) => fields.serialize(serializer),
+        }
+    }
+}
+
+/// The definition of a trigger, which has the server run a command
+/// when files matching an expression change.
+/// Register it using `Client::register_trigger`.
+/// <https://facebook.github.io/watchman/docs/cmd/trigger.html>
+///
+/// ```
+/// use watchman_client::prelude::*;
+///
+/// let trigger = TriggerDefinition::new("assets", &["make", "assets"])
+///     .expression(Expr::Suffix(vec!["css".into()]))
+///     .stdin(TriggerStdin::NamePerLine)
+///     .stdout("/tmp/assets.log", true)
+///     .env("MAKEFLAGS", "-j4");
+/// ```
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TriggerDefinition {
+    pub(crate) name: String,
+    command: Vec<String>,
+    expression: Option<Expr>,
+    append_files: bool,
+    stdin: Option<TriggerStdin>,
+    stdout: Option<String>,
+    stderr: Option<String>,
+    max_files_stdin: Option<u64>,
+    chdir: Option<PathBuf>,
+    pub(crate) relative_root: Option<PathBuf>,
+    env: std::collections::BTreeMap<String, String>,
+}
+
+impl TriggerDefinition {
+    /// Define a trigger named `name` that runs `command`, whose first
+    /// element is the program and the rest its arguments.
+    /// Registering a trigger replaces any trigger with the same name
+    /// on the root.
+    pub fn new<S: AsRef<str>>(name: &str, command: &[S]) -> Self {
+        Self {
+            name: name.to_string(),
+            command: command.iter().map(|s| s.as_ref().to_string()).collect(),
+            expression: None,
+            append_files: false,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            max_files_stdin: None,
+            chdir: None,
+            relative_root: None,
+            env: Default::default(),
+        }
+    }
+
+    /// Returns the name of the trigger
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Only run the command for changes to files matching `expression`.
+    /// By default, changes to any file run it.
+    pub fn expression(mut self, expression: Expr) -> Self {
+        self.expression = Some(expression);
+        self
+    }
+
+    /// Append the names of the changed files to the command line, as
+    /// many as fit.  The default is not to.
+    pub fn append_files(mut self, enable: bool) -> Self {
+        self.append_files = enable;
+        self
+    }
+
+    /// Set what the command reads from its standard input
+    pub fn stdin(mut self, stdin: TriggerStdin) -> Self {
+        self.stdin = Some(stdin);
+        self
+    }
+
+    /// Limit the number of files written to the standard input of the
+    /// command.  The default is not to limit them.
+    pub fn max_files_stdin(mut self, max_files: u64) -> Self {
+        self.max_files_stdin = Some(max_files);
+        self
+    }
+
+    /// Redirect the standard output of the command to `path`, which is
+    /// truncated first unless `append` is true.
+    /// Appending is not supported by servers on Windows.
+    pub fn stdout<P: AsRef<std::path::Path>>(mut self, path: P, append: bool) -> Self {
+        self.stdout = Some(redirection(path.as_ref(), append));
+        self
+    }
+
+    /// Redirect the standard error of the command; see `stdout`
+    pub fn stderr<P: AsRef<std::path::Path>>(mut self, path: P, append: bool) -> Self {
+        self.stderr = Some(redirection(path.as_ref(), append));
+        self
+    }
+
+    /// Run the command in `dir`, relative to the root.  The default is
+    /// to run it in the root.
+    pub fn chdir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.chdir = Some(dir.into());
+        self
+    }
+
+    /// Only consider the files beneath `dir`, relative to the project
+    /// root, and report their names relative to it.  The default is the
+    /// relative path of the `ResolvedRoot` that the trigger is
+    /// registered on.
+    pub fn relative_root<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.relative_root = Some(dir.into());
+        self
+    }
+
+    /// Set the environment variable `key` to `value` for the command.
+    /// The server doesn't support this directly, so the command is run
+    /// via `env(1)`, which must be installed on the server's host.
+    /// The server itself sets `WATCHMAN_ROOT`, `WATCHMAN_SOCK` and
+    /// `WATCHMAN_TRIGGER`.
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.env.insert(key.into(), value.into());
+        self
+    }
+}
+
+fn redirection(path: &std::path::Path, append: bool) -> String {
+    format!(
+        "{}{}",
+        if append { ">>" } else { ">" },
+        path.to_string_lossy()
+    )
+}
+
+impl Serialize for TriggerDefinition {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Definition<'a> {
+            name: &'a str,
+            command: Vec<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            expression: Option<&'a Expr>,
+            #[serde(skip_serializing_if = "is_false")]
+            append_files: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            stdin: Option<&'a TriggerStdin>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            stdout: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            stderr: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            max_files_stdin: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            chdir: Option<&'a PathBuf>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            relative_root: Option<&'a PathBuf>,
+        }
+
+        let command = if self.env.is_empty() {
+            self.command.clone()
+        } else {
+            std::iter::once("env".to_string())
+                .chain(
+                    self.env
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value)),
+                )
+                .chain(self.command.iter().cloned())
+                .collect()
+        };
+        Definition {
+            name: &self.name,
+            command,
+            expression: self.expression.as_ref(),
+            append_files: self.append_files,
+            stdin: self.stdin.as_ref(),
+            stdout: self.stdout.as_deref(),
+            stderr: self.stderr.as_deref(),
+            max_files_stdin: self.max_files_stdin,
+            chdir: self.chdir.as_ref(),
+            relative_root: self.relative_root.as_ref(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The `trigger` command request.
+/// You should use `Client::register_trigger` rather than directly
+/// constructing this type.
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TriggerRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "trigger"))] pub &'static str,
+    pub PathBuf,
+    pub TriggerDefinition,
+);
+
+/// What the server did with a trigger definition
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerDisposition {
+    /// There was no trigger with the same name
+    Created,
+    /// The definition replaced a different one with the same name
+    Replaced,
+    /// An identical definition was already registered
+    AlreadyDefined,
+}
+
+/// The `trigger` response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TriggerResponse {
+    pub version: String,
+    /// The name of the trigger
+    pub triggerid: String,
+    pub disposition: TriggerDisposition,
+}
+
+/// The `trigger-list` command request.
+/// You should use `Client::list_triggers` rather than directly
+/// constructing this type.
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TriggerListRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "trigger-list"))] pub &'static str,
+    pub PathBuf,
+);
+
+/// A trigger registered on a root, as reported by `trigger-list`.
+/// The fields are those of the registered `TriggerDefinition`.
+#[derive(Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TriggerInfo {
+    pub name: String,
+    pub command: Vec<String>,
+    #[serde(default)]
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::optional_value)
+    )]
+    pub expression: Option<Value>,
+    #[serde(default)]
+    pub append_files: bool,
+    #[serde(default)]
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::optional_value)
+    )]
+    pub stdin: Option<Value>,
+    #[serde(default)]
+    pub stdout: Option<String>,
+    #[serde(default)]
+    pub stderr: Option<String>,
+    #[serde(default)]
+    pub max_files_stdin: Option<u64>,
+    #[serde(default)]
+    pub chdir: Option<PathBuf>,
+    #[serde(default)]
+    pub relative_root: Option<PathBuf>,
+}
+
+/// The `trigger-list` response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TriggerListResponse {
+    pub version: String,
+    #[serde(default)]
+    pub triggers: Vec<TriggerInfo>,
+}
+
+/// The `trigger-del` command request.
+/// You should use `Client::remove_trigger` rather than directly
+/// constructing this type.
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TriggerDelRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "trigger-del"))] pub &'static str,
+    pub PathBuf,
+    pub String,
+);
+
+/// The `trigger-del` response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TriggerDelResponse {
+    pub version: String,
+    /// True if a trigger was removed
+    pub deleted: bool,
+    /// The name of the trigger
+    pub trigger: String,
+}
+
+/// The `state-enter` and `state-leave` requests, whose first element is
+/// the command.
+/// You should use `Client::state_enter` and `Client::state_leave`
+/// rather than directly constructing this type.
+/// <https://facebook.github.io/watchman/docs/cmd/state-enter.html>
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct StateRequest<M: Serialize>(
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::state_command)
+    )]
+    pub &'static str,
+    pub PathBuf,
+    pub StateRequestParams<M>,
+);
+
+/// The parameters of a `StateRequest`
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct StateRequestParams<M: Serialize> {
+    /// The name of the state
+    pub name: String,
+    /// Passed to the subscribers that observe the state transition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<M>,
+    /// How long the server waits for its view of the filesystem to
+    /// catch up before broadcasting the transition, so that the
+    /// subscribers observe the changes made before it in order
+    #[serde(skip_serializing_if = "SyncTimeout::is_default", default)]
+    pub sync_timeout: SyncTimeout,
+}
+
+/// The `state-enter` response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct StateEnterResponse {
+    pub version: String,
+    pub root: PathBuf,
+    /// The name of the state that was entered
+    #[serde(rename = "state-enter")]
+    pub state_enter: String,
+}
+
+/// The `state-leave` response
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct StateLeaveResponse {
+    pub version: String,
+    pub root: PathBuf,
+    /// The name of the state that was left
+    #[serde(rename = "state-leave")]
+    pub state_leave: String,
+}
+
+/// The `log` request, which writes a message to the server's log at
+/// the level given by the second element: `debug` or `error`
+/// <https://facebook.github.io/watchman/docs/cmd/log.html>
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct LogRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "log"))] pub &'static str,
+    pub String,
+    pub String,
+);
+
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct LogResponse {
+    pub version: String,
+    #[serde(default)]
+    pub logged: bool,
+}
+
+/// Which of the server's log messages are sent to a client
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// No log messages
+    Off,
+    /// Only error messages
+    Error,
+    /// Both error and debug messages
+    Debug,
+}
+
+/// The `log-level` request, which chooses the log messages that the
+/// server sends to this connection as unilateral PDUs
+/// <https://facebook.github.io/watchman/docs/cmd/log-level.html>
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct LogLevelRequest(
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = "log-level"))] pub &'static str,
+    pub LogLevel,
+);
+
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct LogLevelResponse {
+    pub version: String,
+    pub log_level: LogLevel,
+}
+
+/// A log message sent by the server as a unilateral PDU, following a
+/// `log-level` request
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct LogMessage {
+    /// The level that the message was logged at; either `Error` or
+    /// `Debug`
+    pub level: LogLevel,
+    /// The text of the message, which begins with a timestamp and the
+    /// name of the thread that logged it, and ends with a newline
+    pub log: String,
+}
+
 /// A `Clock` is used to refer to a logical point in time.
 /// Internally, watchman maintains a monotonically increasing tick counter
 /// along with some additional data to detect A-B-A style situations if
@@ -454,6 +1288,7 @@ pub struct UnsubscribeResponse {
 /// control to work with source control aware queries:
 /// <https://facebook.github.io/watchman/docs/scm-query.html>
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(untagged)]
 pub enum Clock {
     /// Just a basic ClockSpec
@@ -462,6 +1297,12 @@ pub enum Clock {
     ScmAware(FatClockData),
 }
 
+impl From<ClockSpec> for Clock {
+    fn from(spec: ClockSpec) -> Self {
+        Self::Spec(spec)
+    }
+}
+
 /// The fundamental clock specifier string.
 /// The contents of the string should be considered to be opaque to
 /// the client as the server occasionally evolves the meaning of
@@ -472,6 +1313,7 @@ pub enum Clock {
 ///
 /// <https://facebook.github.io/watchman/docs/clockspec.html>
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(untagged)]
 pub enum ClockSpec {
     StringClock(String),
@@ -529,13 +1371,118 @@ impl ClockSpec {
     pub fn unix_timestamp(time_t: i64) -> Self {
         Self::UnixTimestamp(time_t)
     }
+
+    /// A clock specified as a unix timestamp, taken from `time` rounded
+    /// down to the second.  Rounding down means that the changes made
+    /// during the second containing `time` are reported, rather than
+    /// missed.  The caveats of `unix_timestamp` apply.
+    pub fn from_system_time(time: std::time::SystemTime) -> Self {
+        let time_t = match time.duration_since(std::time::UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(err) => {
+                let before = err.duration();
+                -(before.as_secs() as i64) - i64::from(before.subsec_nanos() > 0)
+            }
+        };
+        Self::UnixTimestamp(time_t)
+    }
+
+    /// Parse a clockspec from the form produced by its `Display`
+    /// implementation, which is also the form accepted by the watchman
+    /// CLI: a clock string such as `c:1629898887:3180:1:42`, a named
+    /// cursor such as `n:my-tool`, or a unix timestamp.
+    ///
+    /// Only the outline of clock strings is validated, since their
+    /// format is not a stable API: they must consist of `c:` followed
+    /// by two or more colon separated decimal numbers.
+    pub fn parse(clock: &str) -> Result<Self, crate::Error> {
+        let invalid = |reason: &str| {
+            crate::Error::generic(format!("invalid clockspec {:?}: {}", clock, reason))
+        };
+        if let Some(fields) = clock.strip_prefix("c:") {
+            let fields: Vec<&str> = fields.split(':').collect();
+            if fields.len() < 2 {
+                return Err(invalid("expected two or more fields after `c:`"));
+            }
+            if fields
+                .iter()
+                .any(|field| field.is_empty() || !field.bytes().all(|b| b.is_ascii_digit()))
+            {
+                return Err(invalid("clock fields must be decimal numbers"));
+            }
+            Ok(Self::StringClock(clock.to_string()))
+        } else if let Some(name) = clock.strip_prefix("n:") {
+            if name.is_empty() {
+                return Err(invalid("the cursor name is empty"));
+            }
+            Ok(Self::StringClock(clock.to_string()))
+        } else if let Ok(time_t) = clock.parse() {
+            Ok(Self::UnixTimestamp(time_t))
+        } else {
+            Err(invalid(
+                "expected a clock string, a named cursor or a unix timestamp",
+            ))
+        }
+    }
+
+    /// Returns true if this is a clock string produced by the server,
+    /// including the null clock, rather than a named cursor or a
+    /// timestamp
+    pub fn is_string_clock(&self) -> bool {
+        matches!(self, Self::StringClock(clock) if clock.starts_with("c:"))
+    }
+
+    /// Returns true if this is the null clock
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::StringClock(clock) if clock == "c:0:0")
+    }
+
+    /// Returns true if this is a named cursor
+    pub fn is_named_cursor(&self) -> bool {
+        self.cursor_name().is_some()
+    }
+
+    /// Returns the name of the cursor if this is a named cursor
+    pub fn cursor_name(&self) -> Option<&str> {
+        match self {
+            Self::StringClock(clock) => clock.strip_prefix("n:"),
+            Self::UnixTimestamp(_) => None,
+        }
+    }
+
+    /// Returns the unix timestamp if this clock is a timestamp
+    pub fn as_epoch(&self) -> Option<i64> {
+        match self {
+            Self::UnixTimestamp(time_t) => Some(*time_t),
+            Self::StringClock(_) => None,
+        }
+    }
 }
 
-impl Into<Value> for ClockSpec {
-    fn into(self) -> Value {
+/// Equivalent to `ClockSpec::parse`
+impl std::str::FromStr for ClockSpec {
+    type Err = crate::Error;
+
+    fn from_str(clock: &str) -> Result<Self, Self::Err> {
+        Self::parse(clock)
+    }
+}
+
+/// Formats the clock as it would be passed to the watchman CLI
+impl std::fmt::Display for ClockSpec {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Self::StringClock(st) => Value::Utf8String(st),
-            Self::UnixTimestamp(ts) => Value::Integer(ts),
+            Self::StringClock(clock) => fmt.write_str(clock),
+            Self::UnixTimestamp(time_t) => write!(fmt, "{}", time_t),
+        }
+    }
+}
+
+impl From<ClockSpec> for Value {
+    fn from(clock: ClockSpec) -> Value {
+        match clock {
+            ClockSpec::StringClock(st) => Value::Utf8String(st),
+            ClockSpec::UnixTimestamp(ts) => Value::Integer(ts),
         }
     }
 }
@@ -544,6 +1491,7 @@ impl Into<Value> for ClockSpec {
 /// query metadata.
 /// <https://facebook.github.io/watchman/docs/scm-query.html>
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct FatClockData {
     pub clock: ClockSpec,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -553,14 +1501,23 @@ pub struct FatClockData {
 /// Holds extended clock data that includes source control aware
 /// query metadata.
 /// <https://facebook.github.io/watchman/docs/scm-query.html>
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct ScmAwareClockData {
+    /// The merge base of the working copy and `mergebase_with` as of
+    /// the clock.  This is filled in by the server; a query passes back
+    /// the merge base from its previous result so that the server can
+    /// tell whether it has since changed.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mergebase: Option<String>,
+    /// The revision, such as `master`, whose merge base with the
+    /// working copy the changes are reported relative to
     #[serde(rename = "mergebase-with")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mergebase_with: Option<String>,
 
+    /// Where to look for a saved state to start from when the merge
+    /// base changes
     #[serde(rename = "saved-state")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub saved_state: Option<SavedStateClockData>,
@@ -569,22 +1526,82 @@ pub struct ScmAwareClockData {
 /// Holds extended clock data that includes source control aware
 /// query metadata.
 /// <https://facebook.github.io/watchman/docs/scm-query.html>
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct SavedStateClockData {
+    /// The saved state storage engine, such as `local`
     #[serde(skip_serializing_if = "Option::is_none")]
     pub storage: Option<String>,
+    /// The commit of the saved state that was used.  This is filled in
+    /// by the server.
     #[serde(rename = "commit-id")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub commit: Option<String>,
+    /// The configuration of the storage engine; see
+    /// `LocalSavedStateConfig` for the `local` engine
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = crate::arbitrary_impls::optional_value)
+    )]
     pub config: Option<Value>,
 }
 
+impl SavedStateClockData {
+    /// Look for saved states with the `local` storage engine
+    pub fn local(config: LocalSavedStateConfig) -> Self {
+        Self {
+            storage: Some("local".to_string()),
+            commit: None,
+            config: Some(config.into()),
+        }
+    }
+}
+
+/// The configuration of the `local` saved state storage engine, which
+/// looks for a saved state for each commit in a directory named after
+/// it beneath `local_storage_path/project`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalSavedStateConfig {
+    /// The directory holding the saved states of each project
+    pub local_storage_path: PathBuf,
+    /// The project, relative to `local_storage_path`
+    pub project: String,
+    /// Distinguishes different kinds of saved state for the same
+    /// project
+    pub project_metadata: Option<String>,
+    /// How many commits prior to the merge base to look for a saved
+    /// state in.  The server defaults to 10.
+    pub max_commits: Option<u32>,
+}
+
+impl From<LocalSavedStateConfig> for Value {
+    fn from(config: LocalSavedStateConfig) -> Self {
+        let mut map = std::collections::HashMap::new();
+        map.insert(
+            "local-storage-path".to_string(),
+            Value::from(config.local_storage_path.to_string_lossy().into_owned()),
+        );
+        map.insert("project".to_string(), Value::from(config.project));
+        if let Some(metadata) = config.project_metadata {
+            map.insert("project-metadata".to_string(), Value::from(metadata));
+        }
+        if let Some(max_commits) = config.max_commits {
+            map.insert(
+                "max-commits".to_string(),
+                Value::Integer(i64::from(max_commits)),
+            );
+        }
+        Value::Object(map)
+    }
+}
+
 /// Reports the content SHA1 hash for a file.
 /// Since computing the hash can fail, this struct can also represent
-/// the error that happened during hash computation.
-#[derive(Deserialize, Debug, Clone, PartialEq)]
-#[serde(untagged)]
+/// the error that happened during hash computation; use `into_result`
+/// to handle it as a `Result`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum ContentSha1Hex {
     /// The 40-hex-digit SHA1 content hash of the file contents
     Hash(String),
@@ -598,6 +1615,107 @@ pub enum ContentSha1Hex {
     None,
 }
 
+impl ContentSha1Hex {
+    /// Returns the hash, if it was computed
+    pub fn hash(&self) -> Option<&str> {
+        match self {
+            Self::Hash(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// Returns the hash, or `None` if the file was deleted, failing if
+    /// the server was unable to compute the hash
+    pub fn into_result(self) -> Result<Option<String>, ContentHashError> {
+        match self {
+            Self::Hash(hash) => Ok(Some(hash)),
+            Self::Error { error } => Err(ContentHashError { message: error }),
+            Self::None => Ok(None),
+        }
+    }
+}
+
+/// The error reported by the server in place of a content hash; see
+/// `ContentSha1Hex::into_result`
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("unable to hash the file contents: {message}")]
+pub struct ContentHashError {
+    /// The reason that the server gave for being unable to compute
+    /// the hash
+    pub message: String,
+}
+
+/// The server reports the hash as a string, the failure to compute it
+/// as an object whose `error` is the reason, and a deleted file as
+/// null
+impl<'de> Deserialize<'de> for ContentSha1Hex {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct HashVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for HashVisitor {
+            type Value = ContentSha1Hex;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a sha1 hash, an object describing an error, or null")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, hash: &str) -> Result<Self::Value, E> {
+                Ok(ContentSha1Hex::Hash(hash.to_string()))
+            }
+
+            fn visit_string<E: serde::de::Error>(self, hash: String) -> Result<Self::Value, E> {
+                Ok(ContentSha1Hex::Hash(hash))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, hash: &[u8]) -> Result<Self::Value, E> {
+                match std::str::from_utf8(hash) {
+                    Ok(hash) => self.visit_str(hash),
+                    Err(_) => Err(E::invalid_value(serde::de::Unexpected::Bytes(hash), &self)),
+                }
+            }
+
+            fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+                Ok(ContentSha1Hex::None)
+            }
+
+            fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+                Ok(ContentSha1Hex::None)
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                deserializer.deserialize_any(self)
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                // Any other keys that the server includes are ignored
+                let mut error = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == "error" {
+                        error = Some(map.next_value::<String>()?);
+                    } else {
+                        map.next_value::<serde::de::IgnoredAny>()?;
+                    }
+                }
+                match error {
+                    Some(error) => Ok(ContentSha1Hex::Error { error }),
+                    None => Err(serde::de::Error::missing_field("error")),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(HashVisitor)
+    }
+}
+
 /// Encodes the file type field returned in query results and
 /// specified in expression terms.
 ///
@@ -615,7 +1733,8 @@ pub enum ContentSha1Hex {
 ///    file_type: FileType,
 /// }
 /// ```
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[serde(from = "String", into = "String")]
 pub enum FileType {
     BlockSpecial,
@@ -628,9 +1747,10 @@ pub enum FileType {
     SolarisDoor,
 }
 
-impl std::string::ToString for FileType {
-    fn to_string(&self) -> String {
-        (*self).into()
+impl std::fmt::Display for FileType {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s: String = (*self).into();
+        fmt.write_str(&s)
     }
 }
 
@@ -650,17 +1770,17 @@ impl From<String> for FileType {
     }
 }
 
-impl Into<String> for FileType {
-    fn into(self) -> String {
-        match self {
-            Self::BlockSpecial => "b",
-            Self::CharSpecial => "c",
-            Self::Directory => "d",
-            Self::Regular => "f",
-            Self::Fifo => "p",
-            Self::Symlink => "l",
-            Self::Socket => "s",
-            Self::SolarisDoor => "D",
+impl From<FileType> for String {
+    fn from(file_type: FileType) -> String {
+        match file_type {
+            FileType::BlockSpecial => "b",
+            FileType::CharSpecial => "c",
+            FileType::Directory => "d",
+            FileType::Regular => "f",
+            FileType::Fifo => "p",
+            FileType::Symlink => "l",
+            FileType::Socket => "s",
+            FileType::SolarisDoor => "D",
         }
         .to_string()
     }
@@ -681,6 +1801,79 @@ mod tests {
         bunser(&binary).unwrap()
     }
 
+    #[test]
+    fn test_sync_timeout_conversions() {
+        use std::time::Duration;
+        assert_eq!(
+            SyncTimeout::from(Duration::from_secs(2)),
+            SyncTimeout::Duration(Duration::from_secs(2))
+        );
+        assert_eq!(
+            SyncTimeout::from(Duration::from_micros(10)),
+            SyncTimeout::DisableCookie
+        );
+        assert_eq!(SyncTimeout::from(None), SyncTimeout::Default);
+        assert_eq!(
+            SyncTimeout::from(Some(Duration::from_secs(2))),
+            SyncTimeout::Duration(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn test_glob_expansion() {
+        let query = QueryRequestCommon {
+            glob: Some(vec!["src/*.{rs,toml}".to_string(), "*.md".to_string()]),
+            ..Default::default()
+        };
+        let pdu = serde_bser::ser::serialize(Vec::new(), &query).unwrap();
+        let value: Value = bunser(&pdu).unwrap();
+        match value {
+            Value::Object(query) => assert_eq!(
+                query["glob"],
+                Value::Array(vec!["src/*.rs".into(), "src/*.toml".into(), "*.md".into()])
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_clockspec_parse() {
+        for clock in &["c:0:0", "c:1629898887:3180:1:42", "n:my-tool", "1629898887"] {
+            assert_eq!(ClockSpec::parse(clock).unwrap().to_string(), *clock);
+        }
+        for clock in &["", "c:", "c:1", "c:1:", "c:1:x", "n:", "x:1:2", "3.5"] {
+            assert!(ClockSpec::parse(clock).is_err(), "{}", clock);
+        }
+
+        let clock: ClockSpec = "c:0:0".parse().unwrap();
+        assert!(clock.is_string_clock() && clock.is_null() && !clock.is_named_cursor());
+        let cursor = ClockSpec::parse("n:my-tool").unwrap();
+        assert!(!cursor.is_string_clock() && cursor.is_named_cursor());
+        assert_eq!(cursor.cursor_name(), Some("my-tool"));
+        let epoch = ClockSpec::parse("-5").unwrap();
+        assert_eq!(epoch.as_epoch(), Some(-5));
+        assert!(!epoch.is_string_clock() && !epoch.is_named_cursor());
+        assert_eq!(cursor.as_epoch(), None);
+    }
+
+    #[test]
+    fn test_clockspec_from_system_time() {
+        use std::time::{Duration, UNIX_EPOCH};
+        let at = |millis: i64| {
+            let offset = Duration::from_millis(millis.unsigned_abs());
+            if millis < 0 {
+                UNIX_EPOCH - offset
+            } else {
+                UNIX_EPOCH + offset
+            }
+        };
+        let epoch = |millis| ClockSpec::from_system_time(at(millis)).as_epoch();
+        assert_eq!(epoch(1_600_000_000_999), Some(1_600_000_000));
+        assert_eq!(epoch(0), Some(0));
+        assert_eq!(epoch(-1000), Some(-1));
+        assert_eq!(epoch(-1500), Some(-2));
+    }
+
     #[test]
     fn test_content_sha1hex_hash() {
         let value: ContentSha1Hex =
@@ -709,5 +1902,78 @@ mod tests {
     fn test_content_sha1hex_none() {
         let value: ContentSha1Hex = convert_bser_value(Value::Null);
         assert_eq!(value, ContentSha1Hex::None);
+        assert_eq!(value.into_result(), Ok(None));
+    }
+
+    #[test]
+    fn test_content_sha1hex_error_payload() {
+        // The reason is all that is kept of the error object
+        let error_obj: HashMap<String, Value> = maplit::hashmap! {
+            "error".to_string() => Value::ByteString(b"No such file or directory".to_vec().into()),
+            "errno".to_string() => Value::Integer(2),
+        };
+        let value: ContentSha1Hex = convert_bser_value(error_obj.into());
+        let err = value.clone().into_result().unwrap_err();
+        assert_eq!(err.message, "No such file or directory");
+        assert_eq!(
+            err.to_string(),
+            "unable to hash the file contents: No such file or directory"
+        );
+        assert_eq!(value.hash(), None);
+
+        let hash: ContentSha1Hex = convert_bser_value(Value::ByteString(
+            b"e820c2c600a36f05ba905cf1bf32c4834e804e22".to_vec().into(),
+        ));
+        assert_eq!(
+            hash.hash(),
+            Some("e820c2c600a36f05ba905cf1bf32c4834e804e22")
+        );
+
+        // An object without a reason isn't mistaken for a hash
+        let binary = serde_bser::ser::serialize(
+            Vec::new(),
+            Value::from(maplit::hashmap! { "errno".to_string() => Value::Integer(2) }),
+        )
+        .unwrap();
+        let err = bunser::<ContentSha1Hex>(&binary).unwrap_err();
+        assert!(err.to_string().contains("error"), "{}", err);
+        let binary = serde_bser::ser::serialize(Vec::new(), Value::Integer(2)).unwrap();
+        assert!(bunser::<ContentSha1Hex>(&binary).is_err());
+    }
+
+    #[test]
+    fn test_content_sha1hex_in_results() {
+        use crate::prelude::*;
+
+        query_result_type! {
+            struct NameAndHash {
+                name: NameField,
+                hash: ContentSha1HexField,
+            }
+        }
+
+        let files = Value::Array(vec![
+            maplit::hashmap! {
+                "name".to_string() => Value::from("a.rs"),
+                "content.sha1hex".to_string() =>
+                    Value::from("e820c2c600a36f05ba905cf1bf32c4834e804e22"),
+            }
+            .into(),
+            maplit::hashmap! {
+                "name".to_string() => Value::from("b.rs"),
+                "content.sha1hex".to_string() => maplit::hashmap! {
+                    "error".to_string() => Value::from("file deleted while hashing"),
+                }
+                .into(),
+            }
+            .into(),
+        ]);
+        let files: Vec<NameAndHash> = convert_bser_value(files);
+        assert_eq!(*files[0].name, PathBuf::from("a.rs"));
+        assert!(files[0].hash.hash().is_some());
+        assert_eq!(
+            (*files[1].hash).clone().into_result().unwrap_err().message,
+            "file deleted while hashing"
+        );
     }
 }
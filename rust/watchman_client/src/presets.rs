@@ -0,0 +1,217 @@
+//! Reusable expressions for common filtering needs.
+//!
+//! Most tools want to ignore version control metadata and build
+//! output, and only care about the source files of a handful of
+//! languages.  The functions in this module produce expressions for
+//! those needs that can be combined with each other and with your own
+//! terms using `Expr::All`:
+//!
+//! ```
+//! use watchman_client::presets::{self, Language};
+//! use watchman_client::prelude::*;
+//!
+//! let expression = Expr::All(vec![
+//!     presets::source_files(&[Language::Rust]),
+//!     presets::exclude_vcs_dirs(),
+//!     presets::exclude_build_dirs(),
+//! ]);
+//! let query = QueryRequestCommon {
+//!     expression: Some(expression),
+//!     ..Default::default()
+//! };
+//! ```
+//!
+//! Note that the server only evaluates expressions against the files
+//! that it has crawled: if you never want to see the contents of a
+//! directory, adding it to `ignore_dirs` in the `.watchmanconfig` of
+//! the project is much cheaper, as the server then doesn't watch it at
+//! all.  These expressions are for directories that some tools sharing
+//! the watch do want to see.
+use crate::expr::{Expr, MatchTerm};
+use crate::pdu::FileType;
+
+/// The names of the metadata directories of common version control
+/// systems
+pub const VCS_DIRS: &[&str] = &[".git", ".hg", ".sl", ".svn", ".bzr", "_darcs", "CVS"];
+
+/// The names of directories commonly holding build output, caches or
+/// installed dependencies
+pub const BUILD_DIRS: &[&str] = &[
+    "target",
+    "node_modules",
+    "buck-out",
+    "bazel-out",
+    "__pycache__",
+    ".gradle",
+    ".mypy_cache",
+    ".pytest_cache",
+    ".tox",
+];
+
+/// Evaluates as true for any file beneath a directory named one of
+/// `names`, at any depth, and for those directories themselves
+pub fn in_dirs<I, S>(names: I) -> Expr
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let glob = |glob: String| {
+        Expr::Match(MatchTerm {
+            glob,
+            wholename: true,
+            include_dot_files: true,
+            // The names are escaped so that they match literally
+            no_escape: false,
+        })
+    };
+    Expr::Any(
+        names
+            .into_iter()
+            .flat_map(|name| {
+                let name = escape_glob(name.as_ref());
+                vec![
+                    glob(format!("**/{}", name)),
+                    glob(format!("**/{}/**", name)),
+                ]
+            })
+            .collect(),
+    )
+}
+
+/// Evaluates as true for any file that is not beneath a directory named
+/// one of `names`
+pub fn exclude_dirs<I, S>(names: I) -> Expr
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    Expr::Not(Box::new(in_dirs(names)))
+}
+
+/// Excludes the metadata directories of the systems in `VCS_DIRS`
+pub fn exclude_vcs_dirs() -> Expr {
+    exclude_dirs(VCS_DIRS)
+}
+
+/// Excludes the build output directories in `BUILD_DIRS`
+pub fn exclude_build_dirs() -> Expr {
+    exclude_dirs(BUILD_DIRS)
+}
+
+/// A programming language, identified by the suffixes of its source
+/// files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    C,
+    Cpp,
+    CSharp,
+    Go,
+    Java,
+    JavaScript,
+    Kotlin,
+    ObjectiveC,
+    Python,
+    Ruby,
+    Rust,
+    Swift,
+    TypeScript,
+}
+
+impl Language {
+    /// Returns the suffixes of source files in this language, without
+    /// the leading `.`.  Headers are included for languages that have
+    /// them.
+    pub fn suffixes(self) -> &'static [&'static str] {
+        match self {
+            Self::C => &["c", "h"],
+            Self::Cpp => &[
+                "cpp", "cc", "cxx", "c++", "hpp", "hh", "hxx", "h", "ipp", "tcc",
+            ],
+            Self::CSharp => &["cs"],
+            Self::Go => &["go"],
+            Self::Java => &["java"],
+            Self::JavaScript => &["js", "jsx", "mjs", "cjs"],
+            Self::Kotlin => &["kt", "kts"],
+            Self::ObjectiveC => &["m", "mm", "h"],
+            Self::Python => &["py", "pyi"],
+            Self::Ruby => &["rb"],
+            Self::Rust => &["rs"],
+            Self::Swift => &["swift"],
+            Self::TypeScript => &["ts", "tsx", "mts", "cts"],
+        }
+    }
+}
+
+/// Evaluates as true for regular files that are source files of any of
+/// `languages`.  This uses a single `suffix` term, which the server
+/// evaluates efficiently.
+pub fn source_files(languages: &[Language]) -> Expr {
+    let mut suffixes: Vec<&str> = languages
+        .iter()
+        .flat_map(|language| language.suffixes().iter().copied())
+        .collect();
+    suffixes.sort_unstable();
+    suffixes.dedup();
+    Expr::All(vec![
+        Expr::FileType(FileType::Regular),
+        Expr::Suffix(suffixes.into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Escape the characters that are special in a glob
+fn escape_glob(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bser::value::Value;
+
+    fn matches(expr: &Value, index: usize) -> Value {
+        match expr {
+            Value::Array(terms) => match &terms[index] {
+                Value::Array(term) => term[1].clone(),
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn presets() {
+        let expr: Value = in_dirs(["target", "a*b"]).into();
+        assert_eq!(matches(&expr, 1), Value::from("**/target"));
+        assert_eq!(matches(&expr, 2), Value::from("**/target/**"));
+        assert_eq!(matches(&expr, 4), Value::from("**/a\\*b/**"));
+
+        match exclude_vcs_dirs() {
+            Expr::Not(expr) => match *expr {
+                Expr::Any(terms) => assert_eq!(terms.len(), VCS_DIRS.len() * 2),
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match source_files(&[Language::C, Language::ObjectiveC]) {
+            Expr::All(terms) => match &terms[1] {
+                Expr::Suffix(suffixes) => assert_eq!(
+                    suffixes,
+                    &["c", "h", "m", "mm"]
+                        .iter()
+                        .map(std::path::PathBuf::from)
+                        .collect::<Vec<_>>()
+                ),
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Choose how the server should produce the candidates of a query.
+//!
+//! A query is evaluated in two stages: a generator produces candidate
+//! files, and the expression filters them.  The same result can be
+//! obtained in several ways, whose costs differ by orders of magnitude
+//! in a large tree: a `since` generator only looks at the files that
+//! changed, while an expression alone looks at every file.
+//! `QueryPlanner` takes a description of the wanted files and picks the
+//! cheapest generator for it, turning the remaining constraints into
+//! the expression:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use watchman_client::query_planner::{Generator, QueryPlanner};
+//!
+//! let plan = QueryPlanner::new()
+//!     .scope("src")
+//!     .extension("rs")
+//!     .plan();
+//! assert_eq!(plan.generator, Generator::Path);
+//! println!("{}", plan.explain());
+//! let query = plan.into_query();
+//! ```
+//!
+//! All of the constraints must be met for a file to be included.  By
+//! default the planner assumes a server that supports every generator;
+//! pass the capabilities reported by `Client::server_info` to
+//! `QueryPlanner::capabilities` to plan for an older server.
+use crate::expr::{expand_braces, DirNameTerm, Expr, MatchTerm};
+use crate::pdu::{Clock, PathGeneratorElement, QueryRequestCommon};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The generators that a plan can use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Generator {
+    /// The files that changed since a clock
+    Since,
+    /// The files matching a set of globs
+    Glob,
+    /// The files beneath a set of directories
+    Path,
+    /// The files with a set of suffixes
+    Suffix,
+    /// Every file in the tree
+    AllFiles,
+}
+
+impl fmt::Display for Generator {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            Self::Since => "since",
+            Self::Glob => "glob",
+            Self::Path => "path",
+            Self::Suffix => "suffix",
+            Self::AllFiles => "all files",
+        })
+    }
+}
+
+/// Describes the files wanted from a query, and plans it
+#[derive(Debug, Clone, Default)]
+pub struct QueryPlanner {
+    scopes: Vec<PathBuf>,
+    extensions: Vec<PathBuf>,
+    globs: Vec<String>,
+    since: Option<Clock>,
+    expression: Option<Expr>,
+    capabilities: Option<Vec<String>>,
+}
+
+impl QueryPlanner {
+    /// Create a planner for a query matching every file
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only include files beneath the directory `path`, relative to the
+    /// root.  If called more than once, files beneath any of the
+    /// directories are included.
+    pub fn scope<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.scopes.push(path.into());
+        self
+    }
+
+    /// Only include files with the extension `ext`, given with or
+    /// without the leading `.`.  If called more than once, files with
+    /// any of the extensions are included.
+    pub fn extension(mut self, ext: &str) -> Self {
+        self.extensions
+            .push(ext.strip_prefix('.').unwrap_or(ext).into());
+        self
+    }
+
+    /// Only include files whose path relative to the root matches the
+    /// glob `pattern`, which may contain brace alternatives.  If called
+    /// more than once, files matching any of the patterns are included.
+    pub fn glob(mut self, pattern: &str) -> Self {
+        self.globs.extend(expand_braces(pattern));
+        self
+    }
+
+    /// Only include files that changed since `clock`
+    pub fn since(mut self, clock: Clock) -> Self {
+        self.since = Some(clock);
+        self
+    }
+
+    /// Only include files that match `expression`
+    pub fn expression(mut self, expression: Expr) -> Self {
+        self.expression = Some(expression);
+        self
+    }
+
+    /// Plan for a server with the given capabilities, as returned by
+    /// `Client::server_info`
+    pub fn capabilities<I, S>(mut self, capabilities: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.capabilities = Some(capabilities.into_iter().map(Into::into).collect());
+        self
+    }
+
+    fn supports(&self, capability: &str) -> bool {
+        match &self.capabilities {
+            Some(capabilities) => capabilities.iter().any(|c| c == capability),
+            None => true,
+        }
+    }
+
+    /// Choose the generator and build the query
+    pub fn plan(&self) -> QueryPlan {
+        let mut notes = vec![];
+        let mut query = QueryRequestCommon::default();
+        let glob_generator = self.supports("glob_generator");
+
+        let generator = if let Some(since) = &self.since {
+            query.since = Some(since.clone());
+            notes.push(
+                "only the files that changed since the clock are examined, \
+                 unless the server can't describe the changes since it"
+                    .to_string(),
+            );
+            Generator::Since
+        } else if !self.globs.is_empty() && glob_generator {
+            query.glob = Some(if self.scopes.is_empty() {
+                self.globs.clone()
+            } else {
+                notes.push("the globs are applied within each scope".to_string());
+                self.scopes
+                    .iter()
+                    .flat_map(|scope| {
+                        let scope = scope.to_string_lossy().trim_end_matches('/').to_string();
+                        self.globs
+                            .iter()
+                            .map(move |glob| format!("{}/{}", scope, glob))
+                    })
+                    .collect()
+            });
+            notes.push("the globs are matched against the directory tree".to_string());
+            Generator::Glob
+        } else if !self.scopes.is_empty() {
+            query.path = Some(
+                self.scopes
+                    .iter()
+                    .cloned()
+                    .map(PathGeneratorElement::RecursivePath)
+                    .collect(),
+            );
+            notes.push("only the files beneath the scopes are examined".to_string());
+            Generator::Path
+        } else if !self.extensions.is_empty() {
+            query.suffix = Some(self.extensions.clone());
+            notes.push("the server's suffix index is used".to_string());
+            Generator::Suffix
+        } else {
+            notes.push("there is nothing to narrow the candidates with".to_string());
+            Generator::AllFiles
+        };
+        if !self.globs.is_empty() && !glob_generator {
+            notes.push("the server has no glob generator".to_string());
+        }
+
+        let mut terms = vec![];
+        let filter = |what: &str, notes: &mut Vec<String>| {
+            notes.push(format!("the {} are checked by the expression", what));
+        };
+        if !self.scopes.is_empty() && matches!(generator, Generator::Since | Generator::Suffix) {
+            filter("scopes", &mut notes);
+            terms.push(any(self.scopes.iter().map(|scope| self.scope_term(scope))));
+        }
+        if !self.extensions.is_empty() && generator != Generator::Suffix {
+            filter("extensions", &mut notes);
+            terms.push(Expr::Suffix(self.extensions.clone()));
+        }
+        if !self.globs.is_empty() && generator != Generator::Glob {
+            filter("globs", &mut notes);
+            terms.push(any(self.globs.iter().map(|glob| {
+                Expr::Match(MatchTerm {
+                    glob: glob.clone(),
+                    wholename: true,
+                    ..Default::default()
+                })
+            })));
+        }
+        if let Some(expression) = &self.expression {
+            terms.push(expression.clone());
+        }
+        query.expression = match terms.len() {
+            0 => None,
+            1 => terms.pop(),
+            _ => Some(Expr::All(terms)),
+        };
+
+        QueryPlan {
+            generator,
+            query,
+            notes,
+        }
+    }
+
+    fn scope_term(&self, scope: &Path) -> Expr {
+        if self.supports("term-dirname") {
+            Expr::DirName(DirNameTerm {
+                path: scope.to_path_buf(),
+                depth: None,
+            })
+        } else {
+            Expr::Match(MatchTerm {
+                glob: format!("{}/**", scope.to_string_lossy().trim_end_matches('/')),
+                wholename: true,
+                include_dot_files: true,
+                ..Default::default()
+            })
+        }
+    }
+}
+
+/// Returns an expression matching any of `terms`, avoiding a redundant
+/// `anyof` for a single term
+fn any<I: Iterator<Item = Expr>>(terms: I) -> Expr {
+    let mut terms: Vec<Expr> = terms.collect();
+    if terms.len() == 1 {
+        terms.pop().unwrap()
+    } else {
+        Expr::Any(terms)
+    }
+}
+
+/// A query planned by `QueryPlanner::plan`
+#[derive(Debug, Clone)]
+pub struct QueryPlan {
+    /// The chosen generator
+    pub generator: Generator,
+    /// The query, ready to pass to `Client::query`
+    pub query: QueryRequestCommon,
+    notes: Vec<String>,
+}
+
+impl QueryPlan {
+    /// Describe the plan and the reasons for it, one point per line
+    pub fn explain(&self) -> String {
+        let mut explanation = format!("generator: {}", self.generator);
+        for note in &self.notes {
+            explanation.push_str("\n- ");
+            explanation.push_str(note);
+        }
+        explanation
+    }
+
+    /// Returns the query
+    pub fn into_query(self) -> QueryRequestCommon {
+        self.query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdu::ClockSpec;
+    use serde_bser::value::Value;
+
+    fn expression(plan: &QueryPlan) -> Value {
+        plan.query.expression.clone().unwrap().into()
+    }
+
+    #[test]
+    fn chooses_generators() {
+        let since = Clock::Spec(ClockSpec::StringClock("c:0:1".into()));
+        let plan = QueryPlanner::new()
+            .since(since)
+            .scope("src")
+            .glob("**/*.{rs,toml}")
+            .plan();
+        assert_eq!(plan.generator, Generator::Since);
+        assert!(plan.query.glob.is_none());
+        match expression(&plan) {
+            Value::Array(terms) => {
+                assert_eq!(terms[0], Value::from("allof"));
+                assert_eq!(terms.len(), 3);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let plan = QueryPlanner::new()
+            .scope("src/")
+            .scope("tests")
+            .glob("**/*.rs")
+            .plan();
+        assert_eq!(plan.generator, Generator::Glob);
+        assert_eq!(
+            plan.query.glob,
+            Some(vec!["src/**/*.rs".to_string(), "tests/**/*.rs".to_string()])
+        );
+        assert!(plan.query.expression.is_none());
+
+        let plan = QueryPlanner::new()
+            .glob("**/*.rs")
+            .scope("src")
+            .capabilities(vec!["relative_root"])
+            .plan();
+        assert_eq!(plan.generator, Generator::Path);
+        assert!(plan.query.path.is_some());
+        assert!(
+            plan.explain().contains("no glob generator"),
+            "{}",
+            plan.explain()
+        );
+
+        let plan = QueryPlanner::new().extension(".rs").plan();
+        assert_eq!(plan.generator, Generator::Suffix);
+        assert_eq!(plan.query.suffix, Some(vec![PathBuf::from("rs")]));
+
+        let plan = QueryPlanner::new()
+            .extension("rs")
+            .scope("src")
+            .capabilities(Vec::<String>::new())
+            .plan();
+        assert_eq!(plan.generator, Generator::Path);
+        assert_eq!(
+            expression(&plan),
+            Value::from(Expr::Suffix(vec!["rs".into()]))
+        );
+
+        let plan = QueryPlanner::new()
+            .extension("rs")
+            .since(Clock::Spec(ClockSpec::null()))
+            .scope("src")
+            .capabilities(Vec::<String>::new())
+            .plan();
+        match expression(&plan) {
+            Value::Array(terms) => assert_eq!(
+                terms[1],
+                Value::Array(vec![
+                    "match".into(),
+                    "src/**".into(),
+                    "wholename".into(),
+                    Value::from(maplit::hashmap! {
+                        "includedotfiles".to_string() => Value::Bool(true),
+                        "noescape".to_string() => Value::Bool(false),
+                    }),
+                ])
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let plan = QueryPlanner::new().plan();
+        assert_eq!(plan.generator, Generator::AllFiles);
+        assert!(plan.query.expression.is_none());
+        assert!(plan.explain().starts_with("generator: all files"));
+    }
+}
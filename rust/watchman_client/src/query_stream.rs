@@ -0,0 +1,961 @@
+//! Consume the results of a large query in batches.
+//!
+//! `Client::query` decodes every file in the result before returning
+//! any of them, which means holding the whole response, and then every
+//! decoded file, in memory.  `Client::query_stream` instead has the
+//! reader task split the `files` array of the response into batches as
+//! it is read from the connection, so that only the batches that have
+//! yet to be consumed are held in memory:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use tokio::stream::StreamExt;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["a.rs", "b.rs", "c.rs"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let mut files = client
+//!     .query_stream::<NameOnly>(&root, Default::default(), 1000)
+//!     .await?;
+//! let mut count = 0;
+//! while let Some(batch) = files.next().await {
+//!     count += batch?.len();
+//! }
+//! println!("{} files as of {:?}", count, files.clock());
+//! # assert_eq!(count, 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The reader task only reads ahead of the consumer by one batch, so
+//! nothing else is read from the connection while a stream is left
+//! unconsumed: responses to other requests and subscription updates
+//! wait until it has been consumed or dropped.  Stream large queries
+//! over a connection of their own if that is a problem.
+//!
+//! There are two cases in which the response is read in full before
+//! its files are streamed:
+//!
+//! * Once a subscription has been made on the connection, as a
+//!   subscription update can't be told apart from the response until
+//!   all of its fields have been read.
+//! * With `Codec::Json`, whose PDUs are translated to BSER whole.
+use crate::codec::Codec;
+use crate::fields::QueryFieldList;
+use crate::pdu::{Clock, QueryRequestCommon};
+use crate::{bunser, Client, Error, PduBuffer, ResolvedRoot, PDU_BUFFER_SIZE};
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_bser::de::{Bunser, SliceRead};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::AsyncRead;
+use tokio::stream::{Stream, StreamExt};
+use tokio::sync::mpsc;
+
+const BSER_ARRAY: u8 = 0x00;
+const BSER_OBJECT: u8 = 0x01;
+const BSER_BYTESTRING: u8 = 0x02;
+const BSER_INT8: u8 = 0x03;
+const BSER_INT16: u8 = 0x04;
+const BSER_INT32: u8 = 0x05;
+const BSER_INT64: u8 = 0x06;
+const BSER_REAL: u8 = 0x07;
+const BSER_TRUE: u8 = 0x08;
+const BSER_FALSE: u8 = 0x09;
+const BSER_NULL: u8 = 0x0a;
+const BSER_TEMPLATE: u8 = 0x0b;
+const BSER_SKIP: u8 = 0x0c;
+const BSER_UTF8STRING: u8 = 0x0d;
+
+/// The magic and (empty) capabilities that start each BSER v2 PDU
+const PDU_PREFIX: &[u8] = b"\x00\x02\x00\x00\x00\x00";
+
+/// How deeply values may be nested in a PDU that is being streamed
+const MAX_DEPTH: usize = 128;
+
+/// Where the reader task sends the files of a streamed response, each
+/// batch encoded as a BSER array
+#[derive(Clone)]
+pub(crate) struct RowSink {
+    tx: mpsc::Sender<Bytes>,
+    batch_size: usize,
+}
+
+/// What the client task tells the reader task of a connection about
+/// the requests that it has sent
+#[derive(Default)]
+pub(crate) struct Streams {
+    state: Mutex<StreamState>,
+}
+
+pub(crate) type SharedStreams = Arc<Streams>;
+
+#[derive(Default)]
+struct StreamState {
+    /// For each request that has been sent, in the order that the
+    /// server responds to them, where the files of its response are
+    /// streamed to
+    pending: VecDeque<Option<RowSink>>,
+    /// Set once a subscription has been made on the connection
+    subscribed: bool,
+}
+
+impl Streams {
+    fn state(&self) -> std::sync::MutexGuard<'_, StreamState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Called just before a request is written to the server
+    pub(crate) fn sent(&self, stream: Option<RowSink>) {
+        self.state().pending.push_back(stream);
+    }
+
+    /// Called if writing the request passed to `sent` failed
+    pub(crate) fn unsent(&self) {
+        self.state().pending.pop_back();
+    }
+
+    /// Called when the requests that were sent will never see their
+    /// responses
+    pub(crate) fn clear(&self) {
+        self.state().pending.clear();
+    }
+
+    /// Called when a subscription is registered
+    pub(crate) fn subscribed(&self) {
+        self.state().subscribed = true;
+    }
+
+    /// Returns where the files of the next response are streamed to,
+    /// and whether the connection has had subscriptions
+    fn next_response(&self) -> (Option<RowSink>, bool) {
+        let state = self.state();
+        (state.pending.front().cloned().flatten(), state.subscribed)
+    }
+
+    /// Called once the response to the oldest pending request has been
+    /// read
+    fn responded(&self) {
+        self.state().pending.pop_front();
+    }
+}
+
+/// Read the next PDU for the reader task, returning it BSER encoded
+/// along with the number of bytes that it took up on the connection.
+/// The files of a response to `Client::query_stream` are sent to its
+/// stream as they are read, leaving them out of the returned PDU.
+pub(crate) async fn read_pdu<R>(
+    streams: &Streams,
+    buf: &mut PduBuffer,
+    reader: &mut R,
+) -> Result<(Bytes, usize), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    if let Codec::Json = buf.codec {
+        let pdu = buf.read_json_pdu(reader).await?;
+        if !is_unilateral(&pdu) {
+            streams.responded();
+        }
+        let len = pdu.len();
+        return Ok((pdu, len));
+    }
+
+    // The request is known to the `streams` before the server could
+    // have started to respond to it
+    let (header, total) = buf.read_bser_header(reader).await?;
+    let (sink, subscribed) = streams.next_response();
+
+    let sink = match sink {
+        Some(sink) if !subscribed => sink,
+        sink => {
+            while buf.buf.len() < total {
+                buf.read_up_to(reader, total).await?;
+            }
+            match sink {
+                Some(sink) if !is_unilateral(&buf.buf) => {
+                    // Read in full, so there is nothing more to read
+                    // from the connection
+                    let summary = Scanner::new(buf, &mut tokio::io::empty(), header, total)
+                        .stream_files(sink)
+                        .await?;
+                    streams.responded();
+                    return Ok((summary, total));
+                }
+                _ => {
+                    let pdu = buf.buf.split().freeze();
+                    if !is_unilateral(&pdu) {
+                        streams.responded();
+                    }
+                    return Ok((pdu, total));
+                }
+            }
+        }
+    };
+
+    let mut scanner = Scanner::new(buf, reader, header, total);
+    let summary = scanner.stream_files(sink).await?;
+    if !scanner.unilateral {
+        streams.responded();
+    }
+    Ok((summary, total))
+}
+
+/// Returns true if the PDU `pdu` has a `unilateral` field, as
+/// subscription updates and logs do
+fn is_unilateral(pdu: &[u8]) -> bool {
+    let header = match Bunser::new(SliceRead::new(pdu)).read_pdu() {
+        Ok(info) => info.start as usize,
+        Err(_) => return false,
+    };
+    let data = &pdu[header..];
+    let (count, mut pos) = match (data.first(), parse_int(&data[1.min(data.len())..])) {
+        (Some(&BSER_OBJECT), Ok(Some((count, len)))) => (count, 1 + len),
+        _ => return false,
+    };
+    for _ in 0..count {
+        let key = match value_len(&data[pos..], 0) {
+            Ok(Some(len)) => &data[pos..pos + len],
+            _ => return false,
+        };
+        if string_value(key) == Some(b"unilateral") {
+            return true;
+        }
+        pos += key.len();
+        match value_len(&data[pos..], 0) {
+            Ok(Some(len)) => pos += len,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Reads a PDU whose header is at the start of `buf`, discarding what
+/// has been scanned so that only the value being scanned is buffered.
+/// `pos` is relative to the start of `buf`.
+struct Scanner<'a, R> {
+    buf: &'a mut PduBuffer,
+    reader: &'a mut R,
+    pos: usize,
+    /// The bytes of the PDU that have yet to be read into `buf`
+    unread: usize,
+    /// Set once the PDU is found to have a `unilateral` field
+    unilateral: bool,
+}
+
+impl<'a, R: AsyncRead + Unpin> Scanner<'a, R> {
+    fn new(buf: &'a mut PduBuffer, reader: &'a mut R, header: usize, total: usize) -> Self {
+        let unread = total - buf.buf.len();
+        Self {
+            buf,
+            reader,
+            pos: header,
+            unread,
+            unilateral: false,
+        }
+    }
+
+    /// Read more of the PDU into the buffer
+    async fn fill(&mut self) -> Result<(), Error> {
+        if self.unread == 0 {
+            return Err(malformed("the PDU ended part way through a value"));
+        }
+        let len = self.buf.buf.len();
+        self.buf
+            .read_up_to(self.reader, len + self.unread.min(PDU_BUFFER_SIZE))
+            .await?;
+        self.unread -= self.buf.buf.len() - len;
+        Ok(())
+    }
+
+    /// Drop the part of the buffer that has been scanned
+    fn discard(&mut self) {
+        drop(self.buf.buf.split_to(self.pos));
+        self.pos = 0;
+    }
+
+    /// Returns the next byte, without consuming it
+    async fn peek(&mut self) -> Result<u8, Error> {
+        while self.buf.buf.len() <= self.pos {
+            self.fill().await?;
+        }
+        Ok(self.buf.buf[self.pos])
+    }
+
+    /// Consume an integer
+    async fn take_int(&mut self) -> Result<i64, Error> {
+        loop {
+            if let Some((value, len)) = parse_int(&self.buf.buf[self.pos..])? {
+                self.pos += len;
+                return Ok(value);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Consume a count of items, which can't be negative
+    async fn take_count(&mut self) -> Result<usize, Error> {
+        let count = self.take_int().await?;
+        usize::try_from(count).map_err(|_| malformed("a negative count"))
+    }
+
+    /// Consume a whole value, returning where it is in the buffer
+    async fn take_value(&mut self) -> Result<std::ops::Range<usize>, Error> {
+        loop {
+            if let Some(len) = value_len(&self.buf.buf[self.pos..], 0)? {
+                let start = self.pos;
+                self.pos += len;
+                return Ok(start..self.pos);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Send the files of the PDU to `sink`, returning the PDU without
+    /// them.  If the PDU turns out to be unilateral, the files are
+    /// left in.
+    async fn stream_files(&mut self, sink: RowSink) -> Result<Bytes, Error> {
+        if self.peek().await? != BSER_OBJECT {
+            return Err(malformed("the PDU isn't an object"));
+        }
+        self.pos += 1;
+        let count = self.take_count().await?;
+        self.discard();
+
+        let mut batcher = Batcher::new(sink);
+        let mut fields = Vec::new();
+        let mut kept = 0;
+        for _ in 0..count {
+            let key = self.take_value().await?;
+            let key = self.buf.buf[key].to_vec();
+            match string_value(&key) {
+                Some(b"files") if !self.unilateral => {
+                    self.stream_rows(&mut batcher).await?;
+                    batcher.flush().await;
+                    continue;
+                }
+                Some(b"unilateral") => self.unilateral = true,
+                _ => {}
+            }
+            let value = self.take_value().await?;
+            fields.extend_from_slice(&key);
+            fields.extend_from_slice(&self.buf.buf[value]);
+            kept += 1;
+            self.discard();
+        }
+        Ok(encode_pdu(BSER_OBJECT, kept, &fields))
+    }
+
+    /// Consume the `files` array, passing each file to `batcher`.
+    /// Templated arrays are turned back into arrays of objects.
+    async fn stream_rows(&mut self, batcher: &mut Batcher) -> Result<(), Error> {
+        match self.peek().await? {
+            BSER_ARRAY => {
+                self.pos += 1;
+                let count = self.take_count().await?;
+                self.discard();
+                for _ in 0..count {
+                    let row = self.take_value().await?;
+                    batcher.push(&self.buf.buf[row]).await;
+                    self.discard();
+                }
+            }
+            BSER_TEMPLATE => {
+                self.pos += 1;
+                let keys = self.take_value().await?;
+                let keys = template_keys(&self.buf.buf[keys])?;
+                let count = self.take_count().await?;
+                self.discard();
+                let mut row = Vec::new();
+                for _ in 0..count {
+                    row.clear();
+                    let mut present = 0;
+                    for key in &keys {
+                        if self.peek().await? == BSER_SKIP {
+                            self.pos += 1;
+                            continue;
+                        }
+                        let value = self.take_value().await?;
+                        row.extend_from_slice(key);
+                        row.extend_from_slice(&self.buf.buf[value]);
+                        present += 1;
+                    }
+                    self.discard();
+                    let mut object = vec![BSER_OBJECT];
+                    put_int(&mut object, present);
+                    object.extend_from_slice(&row);
+                    batcher.push(&object).await;
+                }
+            }
+            _ => return Err(malformed("the files aren't an array")),
+        }
+        Ok(())
+    }
+}
+
+/// Collects files into batches and sends them to the stream
+struct Batcher {
+    /// `None` once the stream has been dropped; the rest of the files
+    /// are then discarded
+    tx: Option<mpsc::Sender<Bytes>>,
+    batch_size: usize,
+    rows: Vec<u8>,
+    count: usize,
+}
+
+impl Batcher {
+    fn new(sink: RowSink) -> Self {
+        Self {
+            tx: Some(sink.tx),
+            batch_size: sink.batch_size,
+            rows: Vec::new(),
+            count: 0,
+        }
+    }
+
+    async fn push(&mut self, row: &[u8]) {
+        if self.tx.is_some() {
+            self.rows.extend_from_slice(row);
+            self.count += 1;
+            if self.count == self.batch_size {
+                self.flush().await;
+            }
+        }
+    }
+
+    /// Send the files collected so far.
+    /// This waits while the stream has an unconsumed batch.
+    async fn flush(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+        let batch = encode_pdu(BSER_ARRAY, self.count, &self.rows);
+        self.rows.clear();
+        self.count = 0;
+        if let Some(tx) = &mut self.tx {
+            if tx.send(batch).await.is_err() {
+                self.tx = None;
+            }
+        }
+    }
+}
+
+fn malformed(reason: &str) -> Error {
+    Error::generic(format!("malformed query response: {}", reason))
+}
+
+/// Encode a PDU holding an array or object of `count` items, whose
+/// encoded items are `body`
+fn encode_pdu(tag: u8, count: usize, body: &[u8]) -> Bytes {
+    let mut pdu = Vec::with_capacity(PDU_PREFIX.len() + 19 + body.len());
+    pdu.extend_from_slice(PDU_PREFIX);
+    put_int(&mut pdu, 10 + body.len());
+    pdu.push(tag);
+    put_int(&mut pdu, count);
+    pdu.extend_from_slice(body);
+    pdu.into()
+}
+
+/// Append `value` as a 64 bit BSER integer, which takes 9 bytes
+fn put_int(buf: &mut Vec<u8>, value: usize) {
+    buf.push(BSER_INT64);
+    buf.extend_from_slice(&(value as i64).to_ne_bytes());
+}
+
+/// Parse the integer at the start of `data`, returning it along with
+/// its encoded length, or `None` if `data` stops short of its end
+fn parse_int(data: &[u8]) -> Result<Option<(i64, usize)>, Error> {
+    let size = match data.first() {
+        None => return Ok(None),
+        Some(&BSER_INT8) => 1,
+        Some(&BSER_INT16) => 2,
+        Some(&BSER_INT32) => 4,
+        Some(&BSER_INT64) => 8,
+        Some(_) => return Err(malformed("expected an integer")),
+    };
+    let bytes = match data.get(1..1 + size) {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    let value = match size {
+        1 => bytes[0] as i8 as i64,
+        2 => i16::from_ne_bytes([bytes[0], bytes[1]]) as i64,
+        4 => i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+        _ => i64::from_ne_bytes([
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+        ]),
+    };
+    Ok(Some((value, 1 + size)))
+}
+
+/// Returns the encoded length of the value at the start of `data`, or
+/// `None` if `data` stops short of its end
+fn value_len(data: &[u8], depth: usize) -> Result<Option<usize>, Error> {
+    if depth > MAX_DEPTH {
+        return Err(malformed("values are nested too deeply"));
+    }
+    let tag = match data.first() {
+        Some(&tag) => tag,
+        None => return Ok(None),
+    };
+    // Parses a count that follows the first `offset` bytes
+    let count_at = |offset: usize| -> Result<Option<(usize, usize)>, Error> {
+        match parse_int(data.get(offset..).unwrap_or_default())? {
+            Some((count, len)) => usize::try_from(count)
+                .map(|count| Some((count, offset + len)))
+                .map_err(|_| malformed("a negative count")),
+            None => Ok(None),
+        }
+    };
+    let len = match tag {
+        BSER_ARRAY | BSER_OBJECT => {
+            let (count, mut len) = match count_at(1)? {
+                Some(count) => count,
+                None => return Ok(None),
+            };
+            let items = if tag == BSER_OBJECT { 2 * count } else { count };
+            for _ in 0..items {
+                match value_len(&data[len..], depth + 1)? {
+                    Some(item) => len += item,
+                    None => return Ok(None),
+                }
+            }
+            len
+        }
+        BSER_BYTESTRING | BSER_UTF8STRING => match count_at(1)? {
+            Some((count, len)) => len + count,
+            None => return Ok(None),
+        },
+        BSER_INT8 | BSER_INT16 | BSER_INT32 | BSER_INT64 => match parse_int(data)? {
+            Some((_, len)) => len,
+            None => return Ok(None),
+        },
+        BSER_REAL => 9,
+        BSER_TRUE | BSER_FALSE | BSER_NULL | BSER_SKIP => 1,
+        BSER_TEMPLATE => {
+            let keys = match value_len(&data[1..], depth + 1)? {
+                Some(keys) => keys,
+                None => return Ok(None),
+            };
+            let nkeys = match count_at(2)? {
+                Some((nkeys, _)) if data[1] == BSER_ARRAY => nkeys,
+                _ => return Err(malformed("the keys of a template aren't an array")),
+            };
+            let (count, mut len) = match count_at(1 + keys)? {
+                Some(count) => count,
+                None => return Ok(None),
+            };
+            for _ in 0..count.saturating_mul(nkeys) {
+                match data.get(len) {
+                    Some(&BSER_SKIP) => len += 1,
+                    Some(_) => match value_len(&data[len..], depth + 1)? {
+                        Some(item) => len += item,
+                        None => return Ok(None),
+                    },
+                    None => return Ok(None),
+                }
+            }
+            len
+        }
+        _ => return Err(malformed("unknown value type")),
+    };
+    Ok(if data.len() < len { None } else { Some(len) })
+}
+
+/// Returns the contents of the string encoded in `value`
+fn string_value(value: &[u8]) -> Option<&[u8]> {
+    match value.first() {
+        Some(&BSER_BYTESTRING) | Some(&BSER_UTF8STRING) => {
+            let (_, len) = parse_int(&value[1..]).ok()??;
+            value.get(1 + len..)
+        }
+        _ => None,
+    }
+}
+
+/// Split the encoded keys `array` of a template into its keys
+fn template_keys(array: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let (count, mut pos) = match parse_int(&array[1..])? {
+        Some((count, len)) if array[0] == BSER_ARRAY => (count, 1 + len),
+        _ => return Err(malformed("the keys of a template aren't an array")),
+    };
+    let mut keys = vec![];
+    for _ in 0..count {
+        let len = value_len(&array[pos..], 0)?
+            .ok_or_else(|| malformed("the PDU ended part way through a value"))?;
+        keys.push(array[pos..pos + len].to_vec());
+        pos += len;
+    }
+    Ok(keys)
+}
+
+/// The fields of the response that are left once its files have been
+/// streamed.
+/// `files` is only present if they couldn't be streamed.
+#[derive(Deserialize)]
+struct QueryEnd<F> {
+    #[serde(default)]
+    clock: Option<Clock>,
+    #[serde(default)]
+    is_fresh_instance: bool,
+    #[serde(default = "Vec::new")]
+    files: Vec<F>,
+}
+
+type PendingEnd<F> = Pin<Box<dyn Future<Output = Result<QueryEnd<F>, Error>> + Send>>;
+
+/// The files matching a query, yielded in batches by
+/// `Client::query_stream`.
+/// Once the stream is exhausted, `clock` and `is_fresh_instance`
+/// describe the result as a whole.
+pub struct QueryStream<F> {
+    batches: mpsc::Receiver<Bytes>,
+    /// Set until the batches run out
+    streaming: bool,
+    /// Resolves to the rest of the response once the batches have run
+    /// out
+    end: Option<PendingEnd<F>>,
+    /// The first batch, which `query_stream` waits for
+    first: Option<Vec<F>>,
+    /// Files that were left in the response, to be yielded in batches
+    unstreamed: std::vec::IntoIter<F>,
+    batch_size: usize,
+    clock: Option<Clock>,
+    is_fresh_instance: bool,
+    done: bool,
+}
+
+// The fields are never pinned
+impl<F> Unpin for QueryStream<F> {}
+
+impl<F> std::fmt::Debug for QueryStream<F> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("QueryStream")
+            .field("clock", &self.clock)
+            .field("is_fresh_instance", &self.is_fresh_instance)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<F> QueryStream<F> {
+    /// Returns the clock of the result, once the stream is exhausted
+    pub fn clock(&self) -> Option<&Clock> {
+        self.clock.as_ref()
+    }
+
+    /// Returns true if the result is a fresh instance, as described
+    /// by `QueryResult::is_fresh_instance`.  This is only known once
+    /// the stream is exhausted.
+    pub fn is_fresh_instance(&self) -> bool {
+        self.is_fresh_instance
+    }
+}
+
+impl<F: DeserializeOwned> Stream for QueryStream<F> {
+    type Item = Result<Vec<F>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(batch) = self.first.take() {
+            return Poll::Ready(Some(Ok(batch)));
+        }
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+            if self.streaming {
+                match self.batches.poll_recv(cx) {
+                    Poll::Ready(Some(batch)) => return Poll::Ready(Some(bunser(&batch))),
+                    Poll::Ready(None) => self.streaming = false,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            let batch_size = self.batch_size;
+            let batch: Vec<F> = self.unstreamed.by_ref().take(batch_size).collect();
+            if !batch.is_empty() {
+                return Poll::Ready(Some(Ok(batch)));
+            }
+            let end = match self.end.as_mut() {
+                Some(end) => end,
+                None => {
+                    self.done = true;
+                    continue;
+                }
+            };
+            let result = match end.as_mut().poll(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.end = None;
+            match result {
+                Ok(end) => {
+                    self.clock = end.clock;
+                    self.is_fresh_instance = end.is_fresh_instance;
+                    self.unstreamed = end.files.into_iter();
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Perform a query as `query` does, yielding the matching files in
+    /// batches of up to `batch_size` files as they are read from the
+    /// server.
+    /// This returns once the first batch has arrived, and is subject
+    /// to the client's `request_timeout` while waiting for it, but not
+    /// while consuming the stream.
+    /// See the `query_stream` module for how the stream affects the
+    /// rest of the connection.
+    pub async fn query_stream<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+        batch_size: usize,
+    ) -> Result<QueryStream<F>, Error>
+    where
+        F: DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList + Send + 'static,
+    {
+        let request = self.query_request::<F>(root, query);
+        let batch_size = batch_size.max(1);
+        let (tx, batches) = mpsc::channel(1);
+
+        // The lock is only held while queueing the request, as the
+        // client task delivers the response to the stream
+        let (response, events) = {
+            let mut inner = self.inner.lock().await;
+            let sink = RowSink { tx, batch_size };
+            (
+                inner
+                    .queue_request_with_stream(&request, Some(sink))
+                    .await?,
+                inner.events.clone(),
+            )
+        };
+        let end = {
+            let request = request.clone();
+            async move {
+                let pdu = crate::await_pdu(&events, response, &request).await?;
+                bunser::<QueryEnd<F>>(&pdu)
+            }
+        };
+        let mut stream = QueryStream {
+            batches,
+            streaming: true,
+            end: Some(Box::pin(end)),
+            first: None,
+            unstreamed: Vec::new().into_iter(),
+            batch_size,
+            clock: None,
+            is_fresh_instance: false,
+            done: false,
+        };
+
+        // Errors from the server are returned from here rather than
+        // from the stream
+        let first = match self.request_timeout {
+            None => stream.next().await,
+            Some(duration) => tokio::time::timeout(duration, stream.next())
+                .await
+                .map_err(|_| Error::Timeout {
+                    command: format!("{:#?}", request),
+                })?,
+        };
+        stream.first = first.transpose()?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_support::{subscription_pdu, MockServer};
+    use crate::SubscriptionData;
+    use serde_bser::value::Value;
+
+    query_result_type! {
+        struct NameSize {
+            name: NameField,
+            size: SizeField,
+        }
+    }
+
+    async fn collect<F: DeserializeOwned>(stream: &mut QueryStream<F>) -> Vec<Vec<F>> {
+        let mut batches = vec![];
+        while let Some(batch) = stream.next().await {
+            batches.push(batch.unwrap());
+        }
+        batches
+    }
+
+    fn file_names(batch: &[NameSize]) -> Vec<String> {
+        batch
+            .iter()
+            .map(|f| f.name.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streams_batches() {
+        let server = MockServer::new();
+        let names: Vec<String> = (0..10).map(|i| format!("f{}", i)).collect();
+        server.serve_files(&names);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let mut stream = client
+            .query_stream::<NameSize>(&root, Default::default(), 4)
+            .await
+            .unwrap();
+        let batches = collect(&mut stream).await;
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+        assert!(batches.iter().flatten().all(|f| *f.size == 0));
+        assert_eq!(batches.concat().len(), 10);
+        assert_eq!(file_names(&batches.concat()), names);
+        assert!(stream.is_fresh_instance());
+        assert!(stream.clock().is_some());
+        assert!(stream.next().await.is_none());
+
+        // Dropping the stream part way through discards the rest of
+        // the files without holding up the connection
+        let mut stream = client
+            .query_stream::<NameOnly>(&root, Default::default(), 1)
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().len(), 1);
+        drop(stream);
+        client.clock(&root, SyncTimeout::Default).await.unwrap();
+
+        // Once there are subscriptions on the connection, subscription
+        // updates are told apart from the response
+        let (mut subscription, _) = client
+            .subscribe::<NameOnly>(&root, Default::default())
+            .await
+            .unwrap();
+        server.push(subscription_pdu(
+            subscription.name(),
+            "c:1",
+            vec![Value::from("f0")],
+        ));
+        let mut stream = client
+            .query_stream::<NameSize>(&root, Default::default(), 4)
+            .await
+            .unwrap();
+        assert_eq!(file_names(&collect(&mut stream).await.concat()), names);
+        match subscription.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                assert_eq!(result.files.unwrap().len(), 1)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    /// Encode `value` as a BSER string
+    fn string(value: &str) -> Vec<u8> {
+        let mut buf = vec![BSER_UTF8STRING];
+        put_int(&mut buf, value.len());
+        buf.extend_from_slice(value.as_bytes());
+        buf
+    }
+
+    fn int(value: usize) -> Vec<u8> {
+        let mut buf = vec![];
+        put_int(&mut buf, value);
+        buf
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn streams_files_as_they_arrive() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::UnixStream;
+
+        let (stream, server) = UnixStream::pair().unwrap();
+        let client = Connector::new().spawn_client(Box::new(stream), None);
+        let (mut reader, mut writer) = tokio::io::split(server);
+        let root = ResolvedRoot {
+            root: "/repo".into(),
+            relative: None,
+            watcher: "fake".into(),
+        };
+        let query = tokio::spawn(async move {
+            client
+                .query_stream::<NameSize>(&root, Default::default(), 2)
+                .await
+        });
+        PduBuffer::new().read_pdu(&mut reader).await.unwrap();
+
+        // A templated files array, whose rows skip an unused field
+        let mut body = vec![BSER_OBJECT];
+        body.extend(int(3));
+        body.extend(string("files"));
+        body.push(BSER_TEMPLATE);
+        body.push(BSER_ARRAY);
+        body.extend(int(3));
+        for key in &["name", "size", "mode"] {
+            body.extend(string(key));
+        }
+        body.extend(int(3));
+        for (name, size) in &[("a", 1), ("b", 2)] {
+            body.extend(string(name));
+            body.extend(int(*size));
+            body.push(BSER_SKIP);
+        }
+        let first = body.len();
+        body.extend(string("c"));
+        body.extend(int(3));
+        body.extend(int(0o644));
+        body.extend(string("clock"));
+        body.extend(string("c:1"));
+        body.extend(string("is_fresh_instance"));
+        body.push(BSER_TRUE);
+        let mut pdu = PDU_PREFIX.to_vec();
+        pdu.extend(int(body.len()));
+        let first = pdu.len() + first;
+        pdu.extend(body);
+
+        // The first batch is yielded before the rest of the PDU has
+        // been written
+        writer.write_all(&pdu[..first]).await.unwrap();
+        let mut stream = query.await.unwrap().unwrap();
+        let batch = stream.next().await.unwrap().unwrap();
+        assert_eq!(file_names(&batch), vec!["a", "b"]);
+        assert_eq!(*batch[1].size, 2);
+
+        writer.write_all(&pdu[first..]).await.unwrap();
+        let batches = collect(&mut stream).await;
+        assert_eq!(file_names(&batches.concat()), vec!["c"]);
+        match stream.clock() {
+            Some(Clock::Spec(ClockSpec::StringClock(clock))) => assert_eq!(clock, "c:1"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(stream.is_fresh_instance());
+    }
+}
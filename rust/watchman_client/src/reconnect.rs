@@ -0,0 +1,633 @@
+//! Transparently re-establish a lost connection.
+//!
+//! By default a client is unusable once its connection to the server
+//! has been lost, for example because the server was restarted, and
+//! every subscription made with it fails.  `Connector::reconnect`
+//! enables a mode in which the client instead connects again, waiting
+//! between the attempts as described by a `ReconnectPolicy`, and then
+//! re-establishes its subscriptions:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use watchman_client::reconnect::ReconnectPolicy;
+//! use std::time::Duration;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! let connector = Connector::new().reconnect(
+//!     ReconnectPolicy::new()
+//!         .initial_delay(Duration::from_millis(50))
+//!         .max_delay(Duration::from_secs(2))
+//!         .max_attempts(10),
+//! );
+//! # let client = server.connect_with(connector);
+//! # /*
+//! let client = connector.connect().await?;
+//! # */
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Once connected again, the client issues `watch-project` for the
+//! roots of its subscriptions, and then resubscribes each of them with
+//! the same query, passing the clock of the last result that it
+//! delivered as `since`.  The subscriptions resume where they left off:
+//! a server that kept running reports just the changes that were
+//! missed, while a restarted server doesn't recognize the clock and
+//! reports a fresh instance.  States that were asserted when the
+//! connection was lost and are no longer asserted are reported as
+//! having been left, so that code waiting for them to be left doesn't
+//! wait forever.  A subscription that can't be re-established, for
+//! example because its root no longer exists, fails as it would have
+//! without reconnection.
+//!
+//! Requests that were queued but not yet sent when the connection was
+//! lost are sent once connected again.  The requests that were waiting
+//! for responses fail, as the client can't tell whether the server
+//! acted upon them.  The remembered roots returned by
+//! `Client::resolve_root` are forgotten, since they may no longer be
+//! watched.
+//!
+//! The client connects to the same endpoint again rather than
+//! performing discovery.  Reconnection is abandoned once
+//! `ReconnectPolicy::max_attempts` have failed, or as soon as every
+//! handle to the client and its subscriptions has been dropped.
+//! Each attempt is reported via `ConnectionEvent::Reconnecting`, and
+//! success via `ConnectionEvent::Reconnected`; see the
+//! [lifecycle](../lifecycle/index.html) module.
+use crate::lifecycle::{ConnectionEvent, EventSink};
+use crate::{
+    bunser, log_traffic, serialize_request, ClientInner, ClientTask, Error, PduBuffer,
+    ReadWriteStream, ReaderTask, ReceivedPdu, UnilateralPdu,
+};
+use serde::Deserialize;
+use serde_bser::value::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::sync::Mutex;
+
+/// Describes how long to wait between attempts to reconnect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<usize>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Create a policy that waits 100ms before the first attempt,
+    /// doubling the delay for each subsequent attempt up to 5 seconds,
+    /// and that never gives up
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the delay before the first attempt
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Set the longest delay between two attempts
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Give up after `attempts` failed attempts
+    pub fn max_attempts(mut self, attempts: usize) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Returns the delay before `attempt`, counting from 1
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let factor = 1u32 << attempt.saturating_sub(1).min(31);
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+type DialFuture = Pin<Box<dyn Future<Output = Result<Box<dyn ReadWriteStream>, Error>> + Send>>;
+
+/// Opens a new connection to the server
+pub(crate) type Dialer = Arc<dyn Fn() -> DialFuture + Send + Sync>;
+
+/// What the client task needs to know in order to re-establish a
+/// subscription
+struct Resubscribe {
+    root: Value,
+    /// The `subscribe` command, as a `Value::Array`
+    command: Value,
+    /// The clock of the last result delivered to the subscription
+    clock: Option<Value>,
+    asserted_states: BTreeSet<String>,
+}
+
+/// The state that the client task keeps in order to reconnect
+pub(crate) struct Reconnector {
+    policy: ReconnectPolicy,
+    dialer: Dialer,
+    events: EventSink,
+    /// Used to tell whether anything is still using the connection
+    client: Weak<Mutex<ClientInner>>,
+    subscriptions: HashMap<String, Resubscribe>,
+}
+
+impl Reconnector {
+    pub fn new(
+        policy: ReconnectPolicy,
+        dialer: Dialer,
+        events: EventSink,
+        client: &Arc<Mutex<ClientInner>>,
+    ) -> Self {
+        Self {
+            policy,
+            dialer,
+            events,
+            client: Arc::downgrade(client),
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    /// Remember the serialized `subscribe` command for the subscription
+    /// `name`
+    pub fn register(&mut self, name: &str, command: &[u8]) {
+        let command: Value = match bunser(command) {
+            Ok(command) => command,
+            Err(_) => return,
+        };
+        let root = match &command {
+            Value::Array(args) if args.len() == 4 => args[1].clone(),
+            _ => return,
+        };
+        self.subscriptions.insert(
+            name.to_string(),
+            Resubscribe {
+                root,
+                command,
+                clock: None,
+                asserted_states: BTreeSet::new(),
+            },
+        );
+    }
+
+    /// Track the clock and states reported by a PDU destined for a
+    /// subscription
+    pub fn observe(&mut self, pdu: &UnilateralPdu) {
+        if pdu.canceled {
+            self.subscriptions.remove(&pdu.subscription);
+            return;
+        }
+        let subscription = match self.subscriptions.get_mut(&pdu.subscription) {
+            Some(subscription) => subscription,
+            None => return,
+        };
+        if let Some(state) = &pdu.state_enter {
+            subscription.asserted_states.insert(state.clone());
+        } else if let Some(state) = &pdu.state_leave {
+            subscription.asserted_states.remove(state);
+        } else if pdu.files.is_some() {
+            // The clocks of the state transitions don't imply that
+            // the preceding changes have been delivered
+            subscription.clock = pdu.clock.clone();
+        }
+    }
+
+    pub fn forget(&mut self, name: &str) {
+        self.subscriptions.remove(name);
+    }
+}
+
+#[derive(Deserialize)]
+struct HandshakeResponse {
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    clock: Option<Value>,
+    #[serde(default)]
+    asserted_states: Vec<String>,
+}
+
+impl ClientTask {
+    /// Reconnect after the connection was lost with `err`.
+    /// Returns false if nothing is using the connection any more, and
+    /// fails once the policy gives up.
+    pub(crate) async fn reconnect(&mut self, err: Error) -> Result<bool, Error> {
+        for request in self.in_flight.drain(..) {
+            request
+                .respond(Err(format!(
+                    "{}; the connection was lost before the server responded",
+                    err
+                )))
+                .ok();
+        }
+        self.streams.clear();
+        self.broken = true;
+        if let Some(cache) = &self.root_cache {
+            cache.clear();
+        }
+
+        let reconnector = self.reconnect.as_ref().expect("reconnect is enabled");
+        let policy = reconnector.policy;
+        let dialer = Arc::clone(&reconnector.dialer);
+        let events = reconnector.events.clone();
+        let client = Weak::clone(&reconnector.client);
+
+        // Carried over to the new reader task once the subscriptions
+        // have been re-established
+        let mut buf = PduBuffer::with_codec(self.codec);
+        let mut attempt = 0;
+        let stream = loop {
+            if client.strong_count() == 0 {
+                return Ok(false);
+            }
+            attempt += 1;
+            if policy.max_attempts.is_some_and(|max| attempt > max) {
+                return Err(err);
+            }
+            let delay = policy.delay(attempt);
+            events.emit(ConnectionEvent::Reconnecting {
+                attempt,
+                delay,
+                reason: err.to_string(),
+            });
+            tokio::time::delay_for(delay).await;
+            let mut stream = match dialer().await {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            if self.resubscribe(&mut stream, &mut buf).await.is_ok() {
+                break stream;
+            }
+        };
+
+        let (reader, writer) = tokio::io::split(stream);
+        let (reader_shutdown, shutdown) = tokio::sync::oneshot::channel();
+        let mut reader_task = ReaderTask {
+            reader,
+            buf,
+            request_tx: self.reader_tx.clone(),
+            shutdown,
+            streams: Arc::clone(&self.streams),
+            traffic_logger: self.traffic_logger.clone(),
+            metrics: self.metrics.clone(),
+        };
+        let reader_events = events.clone();
+        tokio::spawn(async move {
+            if let Err(err) = reader_task.run().await {
+                eprintln!("watchman reader task failed: {}", err);
+                reader_events.disconnected(err.to_string());
+            }
+        });
+        self.writer = writer;
+        self.reader_shutdown = reader_shutdown;
+        self.broken = false;
+        events.emit(ConnectionEvent::Reconnected { attempts: attempt });
+
+        self.send_next_request().await?;
+        Ok(true)
+    }
+
+    /// Re-establish the subscriptions over the new connection `stream`
+    async fn resubscribe(
+        &mut self,
+        stream: &mut Box<dyn ReadWriteStream>,
+        buf: &mut PduBuffer,
+    ) -> Result<(), Error> {
+        // Subscriptions whose `Subscription` has been dropped, or that
+        // were canceled by the server, don't need to be re-established
+        self.subscriptions.retain(|_, sender| !sender.is_closed());
+        let subscriptions = &self.subscriptions;
+        let reconnector = self.reconnect.as_mut().expect("reconnect is enabled");
+        reconnector
+            .subscriptions
+            .retain(|name, _| subscriptions.contains_key(name));
+
+        let mut roots: Vec<Value> = vec![];
+        for subscription in reconnector.subscriptions.values() {
+            if !roots.contains(&subscription.root) {
+                roots.push(subscription.root.clone());
+            }
+        }
+        for root in roots {
+            let response = self
+                .handshake(stream, buf, &serialize_request(&("watch-project", &root))?)
+                .await?;
+            if response.error.is_some() {
+                self.drop_subscriptions(|subscription| subscription.root == root);
+            }
+        }
+
+        let reconnector = self.reconnect.as_ref().expect("reconnect is enabled");
+        let mut commands = vec![];
+        for (name, subscription) in &reconnector.subscriptions {
+            let mut command = subscription.command.clone();
+            if let (Value::Array(args), Some(clock)) = (&mut command, &subscription.clock) {
+                if let Some(Value::Object(query)) = args.get_mut(3) {
+                    query.insert("since".to_string(), clock.clone());
+                }
+            }
+            commands.push((name.clone(), serialize_request(&command)?));
+        }
+
+        for (name, command) in commands {
+            let response = self.handshake(stream, buf, &command).await?;
+            if response.error.is_some() {
+                self.drop_subscriptions(|subscription| {
+                    matches!(&subscription.command, Value::Array(args)
+                        if args.get(2) == Some(&Value::from(name.as_str())))
+                });
+                continue;
+            }
+            self.reconcile_states(&name, response);
+        }
+        Ok(())
+    }
+
+    /// Tell the subscription `name` about the states that changed
+    /// while the connection was down
+    fn reconcile_states(&mut self, name: &str, response: HandshakeResponse) {
+        let reconnector = self.reconnect.as_mut().expect("reconnect is enabled");
+        let subscription = match reconnector.subscriptions.get_mut(name) {
+            Some(subscription) => subscription,
+            None => return,
+        };
+        let asserted: BTreeSet<String> = response.asserted_states.into_iter().collect();
+        let mut transitions = vec![];
+        for state in subscription.asserted_states.difference(&asserted) {
+            transitions.push(("state-leave", state.clone()));
+        }
+        for state in asserted.difference(&subscription.asserted_states) {
+            transitions.push(("state-enter", state.clone()));
+        }
+        subscription.asserted_states = asserted;
+
+        let sender = match self.subscriptions.get(name) {
+            Some(sender) => sender,
+            None => return,
+        };
+        for (key, state) in transitions {
+            let mut pdu = HashMap::new();
+            pdu.insert(
+                "version".to_string(),
+                Value::from(response.version.clone().unwrap_or_default()),
+            );
+            pdu.insert("unilateral".to_string(), Value::Bool(true));
+            pdu.insert("subscription".to_string(), Value::from(name));
+            pdu.insert(
+                "clock".to_string(),
+                response.clock.clone().unwrap_or(Value::Null),
+            );
+            pdu.insert(key.to_string(), Value::from(state));
+            if let Ok(data) = serialize_request(&Value::Object(pdu)) {
+                sender
+                    .send(ReceivedPdu {
+                        received: Instant::now(),
+                        data: data.into(),
+                    })
+                    .ok();
+            }
+        }
+    }
+
+    /// Stop delivering to the subscriptions for which `matches` returns
+    /// true, which fails them
+    fn drop_subscriptions<F: Fn(&Resubscribe) -> bool>(&mut self, matches: F) {
+        let reconnector = self.reconnect.as_mut().expect("reconnect is enabled");
+        let names: Vec<String> = reconnector
+            .subscriptions
+            .iter()
+            .filter(|(_, subscription)| matches(subscription))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            reconnector.forget(&name);
+            self.subscriptions.remove(&name);
+        }
+    }
+
+    /// Send `request` over `stream` and wait for its response,
+    /// delivering any subscription PDUs that arrive in the meantime
+    async fn handshake(
+        &mut self,
+        stream: &mut Box<dyn ReadWriteStream>,
+        buf: &mut PduBuffer,
+        request: &[u8],
+    ) -> Result<HandshakeResponse, Error> {
+        log_traffic(&self.traffic_logger, crate::Direction::Send, request);
+        stream.write_all(&self.codec.encode(request)?).await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_written(request.len());
+        }
+        loop {
+            let pdu = buf.read_pdu(stream).await?;
+            log_traffic(&self.traffic_logger, crate::Direction::Receive, &pdu);
+            if let Some(metrics) = &self.metrics {
+                metrics.bytes_read(pdu.len());
+            }
+            if !self.dispatch_unilateral(&pdu).await {
+                return bunser(&pdu);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_support::{error_response, request_arg, subscription_pdu, MockServer};
+    use crate::SubscriptionData;
+    use std::path::PathBuf;
+
+    #[test]
+    fn backoff() {
+        let policy = ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(10))
+            .max_delay(Duration::from_millis(50));
+        assert_eq!(policy.delay(1), Duration::from_millis(10));
+        assert_eq!(policy.delay(2), Duration::from_millis(20));
+        assert_eq!(policy.delay(3), Duration::from_millis(40));
+        assert_eq!(policy.delay(4), Duration::from_millis(50));
+        assert_eq!(policy.delay(100), Duration::from_millis(50));
+    }
+
+    /// Returns the requests for `command`
+    fn requests(server: &MockServer, command: &str) -> Vec<Value> {
+        server
+            .requests()
+            .into_iter()
+            .filter(|r| request_arg(r, 0) == Value::from(command))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resubscribes() {
+        let server = MockServer::new();
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let connector = Connector::new()
+            .reconnect(ReconnectPolicy::new().initial_delay(Duration::from_millis(1)))
+            .lifecycle_events({
+                let events = Arc::clone(&events);
+                move |event| events.lock().unwrap().push(event.clone())
+            });
+        let client = server.connect_with(connector);
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let (mut sub, _) = client
+            .subscribe::<NameOnly>(&root, Default::default())
+            .await
+            .unwrap();
+        let name = sub.name().to_string();
+
+        server.push(subscription_pdu(&name, "c:0:1", vec!["a".into()]));
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::FilesChanged(_)
+        ));
+        let mut enter = subscription_pdu(&name, "c:0:2", vec![]);
+        if let Value::Object(pdu) = &mut enter {
+            pdu.remove("files");
+            pdu.insert("state-enter".to_string(), Value::from("hg.update"));
+        }
+        server.push(enter);
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::StateEnter { .. }
+        ));
+        assert!(sub.is_state_asserted("hg.update"));
+
+        server.disconnect_all();
+
+        // The state was left by the restart
+        match sub.next().await.unwrap() {
+            SubscriptionData::StateLeave { state_name, .. } => assert_eq!(state_name, "hg.update"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(!sub.is_state_asserted("hg.update"));
+
+        let subscribes = requests(&server, "subscribe");
+        assert_eq!(subscribes.len(), 2);
+        assert_eq!(request_arg(&subscribes[1], 2), Value::from(name.as_str()));
+        match request_arg(&subscribes[1], 3) {
+            Value::Object(query) => assert_eq!(query.get("since"), Some(&Value::from("c:0:1"))),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(requests(&server, "watch-project").len(), 2);
+
+        // The subscription and the client continue to work
+        server.push(subscription_pdu(&name, "c:0:3", vec!["b".into()]));
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                assert_eq!(*result.files.unwrap()[0].name, PathBuf::from("b"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        client
+            .clock(&root, SyncTimeout::DisableCookie)
+            .await
+            .unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ConnectionEvent::Reconnecting { attempt: 1, .. })));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ConnectionEvent::Reconnected { attempts: 1 })));
+    }
+
+    #[tokio::test]
+    async fn fails_lost_subscriptions() {
+        let server = MockServer::new();
+        let client = server.connect_with(
+            Connector::new()
+                .reconnect(ReconnectPolicy::new().initial_delay(Duration::from_millis(1))),
+        );
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let (mut sub, _) = client
+            .subscribe::<NameOnly>(&root, Default::default())
+            .await
+            .unwrap();
+
+        // The restarted server can't watch the root any more
+        server.respond("watch-project", |_| error_response("no such directory"));
+        server.disconnect_all();
+        assert!(sub.next().await.is_err());
+        client
+            .clock(&root, SyncTimeout::DisableCookie)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn forgets_dead_subscriptions() {
+        let server = MockServer::new();
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let connector = Connector::new()
+            .reconnect(ReconnectPolicy::new().initial_delay(Duration::from_millis(1)))
+            .lifecycle_events({
+                let events = Arc::clone(&events);
+                move |event| events.lock().unwrap().push(event.clone())
+            });
+        let client = server.connect_with(connector);
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let (canceled, _) = client
+            .subscribe::<NameOnly>(&root, Default::default())
+            .await
+            .unwrap();
+        canceled.cancel().await.unwrap();
+        server.respond_once("subscribe", error_response("unable to subscribe"));
+        assert!(client
+            .subscribe::<NameOnly>(&root, Default::default())
+            .await
+            .is_err());
+
+        server.disconnect_all();
+        while !events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|event| matches!(event, ConnectionEvent::Reconnected { .. }))
+        {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        client
+            .clock(&root, SyncTimeout::DisableCookie)
+            .await
+            .unwrap();
+
+        // Neither subscription was re-established
+        assert_eq!(requests(&server, "subscribe").len(), 2);
+    }
+}
@@ -0,0 +1,179 @@
+//! Resume a subscription where it left off.
+//!
+//! A daemon that is restarted, perhaps after a crash, can pick its
+//! subscriptions up from the last result that it dealt with rather than
+//! starting again from scratch.  `Subscription::handle_info` returns the
+//! name of the subscription and the clock of the last result that it
+//! delivered, which can be persisted and passed to `Client::resubscribe`
+//! after the restart:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use watchman_client::resume::SubscriptionHandleInfo;
+//! use watchman_client::SubscriptionData;
+//! # use watchman_client::test_support::{subscription_pdu, MockServer};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # let state = std::env::temp_dir().join(format!("watchman-doc-resume-{}", std::process::id()));
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let (mut sub, _) = match std::fs::read(&state) {
+//!     Ok(saved) => {
+//!         let info: SubscriptionHandleInfo = serde_json::from_slice(&saved)?;
+//!         client
+//!             .resubscribe::<NameOnly>(&root, SubscribeRequest::default(), info)
+//!             .await?
+//!     }
+//!     Err(_) => client.subscribe::<NameOnly>(&root, Default::default()).await?,
+//! };
+//! # server.push(subscription_pdu(sub.name(), "c:0:1", vec!["a.rs".into()]));
+//! if let SubscriptionData::FilesChanged(result) = sub.next().await? {
+//!     // ... deal with the changes ...
+//! }
+//! // Only once the changes have been dealt with
+//! std::fs::write(&state, serde_json::to_vec(&sub.handle_info())?)?;
+//! # std::fs::remove_file(&state)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The clock is only meaningful for the root that the subscription was
+//! made on, and the request passed to `resubscribe` should match the
+//! one that the subscription was made with.  If the server has been
+//! restarted in the meantime it doesn't recognize the clock, and the
+//! first result is a fresh instance listing every file.
+use crate::pdu::{Clock, SubscribeRequest, SubscribeResponse};
+use crate::prelude::*;
+use crate::{Error, Subscription};
+use serde::{Deserialize, Serialize};
+
+/// What is needed to resume a subscription: its name and the clock of
+/// the last result that it delivered.
+/// Returned by `Subscription::handle_info`; it can be serialized to
+/// persist it between runs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubscriptionHandleInfo {
+    /// The name of the subscription
+    pub name: String,
+    /// The clock of the last result delivered by the subscription.
+    /// This is `None` if it was made with no `since` clock and has yet
+    /// to deliver a result.
+    pub clock: Option<Clock>,
+}
+
+impl<F> Subscription<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    /// Returns the name of this subscription and the clock of the last
+    /// result that it delivered, for passing to `Client::resubscribe`
+    pub fn handle_info(&self) -> SubscriptionHandleInfo {
+        SubscriptionHandleInfo {
+            name: self.name().to_string(),
+            clock: self.clock().cloned(),
+        }
+    }
+}
+
+impl SubscribeRequest {
+    /// Report the changes since `clock`, rather than starting with a
+    /// result listing every matching file
+    pub fn with_since<C: Into<Clock>>(mut self, clock: C) -> Self {
+        self.since = Some(clock.into());
+        self
+    }
+
+    /// Report the changes since the last result delivered by the
+    /// subscription described by `info`.
+    /// If it had yet to deliver one, `since` is left as it is.
+    pub fn resuming(mut self, info: &SubscriptionHandleInfo) -> Self {
+        if let Some(clock) = &info.clock {
+            self.since = Some(clock.clone());
+        }
+        self
+    }
+}
+
+impl Client {
+    /// Subscribe to `root` again, under the name of the subscription
+    /// described by `prev` and from the last result that it delivered.
+    /// `query` should match the request that subscription was made with.
+    pub async fn resubscribe<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: SubscribeRequest,
+        prev: SubscriptionHandleInfo,
+    ) -> Result<(Subscription<F>, SubscribeResponse), Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let query = query.resuming(&prev);
+        self.subscribe_with_fields(
+            root,
+            query,
+            prev.name,
+            F::field_list(),
+            self.request_timeout,
+            None,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer};
+    use crate::SubscriptionData;
+    use serde_bser::value::Value;
+
+    #[tokio::test]
+    async fn resubscribes() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let request =
+            SubscribeRequest::default().with_since(ClockSpec::StringClock("c:0:1".into()));
+        let (mut sub, _) = client.subscribe::<NameOnly>(&root, request).await.unwrap();
+        assert!(
+            matches!(sub.clock(), Some(Clock::Spec(ClockSpec::StringClock(c))) if c == "c:0:1")
+        );
+
+        server.push(subscription_pdu(sub.name(), "c:0:5", vec!["a.rs".into()]));
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::FilesChanged(_)
+        ));
+        let saved = serde_json::to_string(&sub.handle_info()).unwrap();
+        let name = sub.name().to_string();
+        drop(sub);
+
+        let info: SubscriptionHandleInfo = serde_json::from_str(&saved).unwrap();
+        let (sub, _) = client
+            .resubscribe::<NameOnly>(&root, SubscribeRequest::default(), info)
+            .await
+            .unwrap();
+        assert_eq!(sub.name(), name);
+        let request = server
+            .requests()
+            .into_iter()
+            .rev()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .unwrap();
+        assert_eq!(request_arg(&request, 2), Value::from(name));
+        match request_arg(&request, 3) {
+            Value::Object(query) => assert_eq!(query["since"], Value::from("c:0:5")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
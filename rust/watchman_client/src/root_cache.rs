@@ -0,0 +1,139 @@
+//! Memoizes `Client::resolve_root`; see `Connector::cache_resolved_roots`.
+use crate::ResolvedRoot;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Maps the canonical paths passed to `resolve_root` to the roots
+/// that they resolved to.  This is shared by the clones of a client
+/// and the task that reads from its connection, so that the latter
+/// can invalidate roots as the server reports that they were removed.
+#[derive(Clone, Default)]
+pub(crate) struct RootCache(Arc<Mutex<HashMap<PathBuf, ResolvedRoot>>>);
+
+impl RootCache {
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, ResolvedRoot>> {
+        // The map is always consistent, even if a holder panicked
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn get(&self, path: &Path) -> Option<ResolvedRoot> {
+        self.lock().get(path).cloned()
+    }
+
+    pub(crate) fn insert(&self, path: PathBuf, root: ResolvedRoot) {
+        self.lock().insert(path, root);
+    }
+
+    /// Forget every path that resolved to the watch at `root`
+    pub(crate) fn invalidate(&self, root: &Path) {
+        self.lock().retain(|_, resolved| resolved.root != root);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_support::{request_arg, MockServer, MOCK_VERSION};
+    use crate::SubscriptionData;
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    fn resolutions(server: &MockServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|r| request_arg(r, 0) == Value::from("watch-project"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn invalidated_by_cancel() {
+        let server = MockServer::new();
+        let client = server.connect_with(Connector::new().cache_resolved_roots(true));
+        let path = || CanonicalPath::with_canonicalized_path("/repo/sub".into());
+
+        let root = client.resolve_root(path()).await.unwrap();
+        let again = client.clone().resolve_root(path()).await.unwrap();
+        assert_eq!(again.path(), root.path());
+        assert_eq!(again.project_relative_path(), root.project_relative_path());
+        assert_eq!(resolutions(&server), 1);
+
+        // The server cancels the subscriptions on a root when it stops
+        // watching it
+        let (mut subscription, _) = client
+            .subscribe::<NameOnly>(&root, SubscribeRequest::default())
+            .await
+            .unwrap();
+        server.push(Value::from(hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "unilateral".to_string() => Value::Bool(true),
+            "subscription".to_string() => Value::from(subscription.name()),
+            "root".to_string() => Value::from(root.path().to_string_lossy().into_owned()),
+            "canceled".to_string() => Value::Bool(true),
+            "clock".to_string() => Value::from("c:0:1"),
+        }));
+        assert!(matches!(
+            subscription.next().await.unwrap(),
+            SubscriptionData::Canceled
+        ));
+        client.resolve_root(path()).await.unwrap();
+        assert_eq!(resolutions(&server), 2);
+
+        client.forget_resolved_roots();
+        client.resolve_root(path()).await.unwrap();
+        assert_eq!(resolutions(&server), 3);
+
+        let uncached = server.connect();
+        uncached.resolve_root(path()).await.unwrap();
+        uncached.resolve_root(path()).await.unwrap();
+        assert_eq!(resolutions(&server), 5);
+    }
+
+    #[tokio::test]
+    async fn invalidated_by_watch_del() {
+        let server = MockServer::new();
+        server.respond("watch-del", |request| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "watch-del".to_string() => Value::Bool(true),
+                "root".to_string() => request_arg(request, 1),
+            }
+            .into()
+        });
+        server.respond("watch-del-all", |_| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "roots".to_string() => Value::Array(vec!["/repo".into()]),
+            }
+            .into()
+        });
+        let client = server.connect_with(Connector::new().cache_resolved_roots(true));
+        let path = || CanonicalPath::with_canonicalized_path("/repo/sub".into());
+
+        let root = client.resolve_root(path()).await.unwrap();
+        client.watch_del(&root).await.unwrap();
+        assert_eq!(
+            server.requests().last().unwrap(),
+            &Value::Array(vec![
+                "watch-del".into(),
+                root.project_root().to_str().unwrap().into()
+            ])
+        );
+        client.resolve_root(path()).await.unwrap();
+        assert_eq!(resolutions(&server), 2);
+
+        let roots = client.watch_del_all().await.unwrap();
+        assert_eq!(roots, vec![std::path::PathBuf::from("/repo")]);
+        assert_eq!(
+            server.requests().last().unwrap(),
+            &Value::Array(vec!["watch-del-all".into()])
+        );
+        client.resolve_root(path()).await.unwrap();
+        assert_eq!(resolutions(&server), 3);
+    }
+}
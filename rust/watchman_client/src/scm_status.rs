@@ -0,0 +1,571 @@
+//! Summarize the working copy of a source control checkout.
+//!
+//! `ScmStatusTracker` combines source control aware queries, which
+//! report the files that changed relative to the merge base of the
+//! working copy and a given revision, with the `hg.update` style states
+//! that source control tools assert around operations that rewrite the
+//! working copy.  Each call to `ScmStatusTracker::status` produces a
+//! typed `WorkingCopyStatus`:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use watchman_client::scm_status::ScmStatusTracker;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["src/main.rs"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let mut tracker = ScmStatusTracker::new(&client, &root, "master").await?;
+//! let status = tracker.status(&client).await?;
+//! if status.updating {
+//!     println!("the working copy is being updated; try again later");
+//! } else {
+//!     println!("{} files differ from master", status.changed.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `Client::query_since_mergebase` makes a single source control aware
+//! query, optionally asking the server to look for a saved state to
+//! start from when the merge base changes, for tools that manage the
+//! clock themselves.
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use serde_bser::value::Value;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::oneshot;
+
+/// The states asserted by source control tools while they rewrite the
+/// working copy
+pub const UPDATE_STATES: &[&str] = &["hg.update", "hg.transaction"];
+
+/// The state of the working copy, as reported by
+/// `ScmStatusTracker::status`
+#[derive(Debug, Clone)]
+pub struct WorkingCopyStatus {
+    /// The merge base of the working copy and `mergebase_with`, if the
+    /// server was able to determine it
+    pub mergebase: Option<String>,
+    /// The revision that the working copy is compared with
+    pub mergebase_with: String,
+    /// True if the merge base differs from that of the previous
+    /// status, for example because a commit was made or the working
+    /// copy was rebased
+    pub mergebase_changed: bool,
+    /// The files, relative to the root, that may differ from the merge
+    /// base.  This includes both committed and uncommitted changes, as
+    /// well as deleted files.
+    pub changed: Vec<PathBuf>,
+    /// The files that changed since the previous status, or all of
+    /// `changed` for the first status and whenever the merge base
+    /// changes
+    pub changed_since_last_status: Vec<PathBuf>,
+    /// True if one of the `UPDATE_STATES` is asserted, meaning that the
+    /// working copy is in the middle of being rewritten and the status
+    /// is likely to be transient
+    pub updating: bool,
+    /// All of the states that are currently asserted for the root
+    pub asserted_states: Vec<String>,
+    /// The clock of the status
+    pub clock: Clock,
+}
+
+/// The result of `Client::query_since_mergebase`
+#[derive(Debug, Clone)]
+pub struct MergebaseResult<F> {
+    /// The files that changed since the clock, or, if the merge base
+    /// changed, since the new merge base or the saved state
+    pub files: Vec<F>,
+    /// True if the files are relative to a new merge base or saved
+    /// state rather than to the clock, in which case any state derived
+    /// from previous results should be discarded
+    pub is_fresh_instance: bool,
+    /// The merge base of the working copy and `mergebase_with`, if the
+    /// server was able to determine it
+    pub mergebase: Option<String>,
+    /// The saved state that the files are relative to, if a saved state
+    /// was asked for and the merge base changed
+    pub saved_state: Option<SavedStateInfo>,
+    /// The clock to pass to the next `query_since_mergebase`
+    pub clock: Clock,
+}
+
+/// Describes the saved state chosen by the server's storage engine
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedStateInfo {
+    /// The commit that the saved state was made for
+    pub commit_id: Option<String>,
+    /// Where the `local` storage engine found the saved state
+    pub local_path: Option<PathBuf>,
+    /// Why no saved state could be used, in which case the files are
+    /// relative to the merge base
+    pub error: Option<String>,
+    /// Everything reported by the storage engine, including any fields
+    /// particular to it
+    pub info: Value,
+}
+
+impl SavedStateInfo {
+    fn from_value(info: Value) -> Self {
+        let string = |key: &str| match &info {
+            Value::Object(map) => match map.get(key) {
+                Some(Value::Utf8String(s)) => Some(s.clone()),
+                Some(Value::ByteString(s)) => Some(s.as_escaped_string()),
+                _ => None,
+            },
+            _ => None,
+        };
+        let commit_id = string("commit-id");
+        let local_path = string("local-path").map(PathBuf::from);
+        let error = string("error");
+        Self {
+            commit_id,
+            local_path,
+            error,
+            info,
+        }
+    }
+}
+
+impl Client {
+    /// Returns the files beneath `root` that match `expression`, or all
+    /// files if it is `None`, that changed since `since` in a source
+    /// control aware way: relative to the merge base of the working
+    /// copy and the revision `mergebase_with`, such as `master`.
+    /// Pass the `clock` of the previous result as `since`, so that only
+    /// the changes since then are reported while the merge base stays
+    /// the same; `None` reports everything that differs from the merge
+    /// base.
+    /// If `saved_state` is given, then whenever the merge base changes
+    /// the server looks for a saved state made for it, or for one of
+    /// the commits before it, and reports the files relative to that
+    /// instead.
+    pub async fn query_since_mergebase<F>(
+        &self,
+        root: &ResolvedRoot,
+        since: Option<Clock>,
+        mergebase_with: &str,
+        saved_state: Option<SavedStateClockData>,
+        expression: Option<Expr>,
+    ) -> Result<MergebaseResult<F>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let (clock, mergebase) = match since {
+            None => (ClockSpec::null(), None),
+            Some(Clock::Spec(clock)) => (clock, None),
+            Some(Clock::ScmAware(FatClockData { clock, scm })) => {
+                (clock, scm.and_then(|scm| scm.mergebase))
+            }
+        };
+        let since = Clock::ScmAware(FatClockData {
+            clock,
+            scm: Some(ScmAwareClockData {
+                mergebase,
+                mergebase_with: Some(mergebase_with.to_string()),
+                saved_state,
+            }),
+        });
+        let result: QueryResult<F> = self
+            .query(
+                root,
+                QueryRequestCommon {
+                    since: Some(since),
+                    expression,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let mergebase = match &result.clock {
+            Clock::ScmAware(FatClockData { scm: Some(scm), .. }) => scm.mergebase.clone(),
+            _ => None,
+        };
+        Ok(MergebaseResult {
+            files: result.files.unwrap_or_default(),
+            is_fresh_instance: result.is_fresh_instance,
+            mergebase,
+            saved_state: result.saved_state_info.map(SavedStateInfo::from_value),
+            clock: result.clock,
+        })
+    }
+}
+
+/// Produces `WorkingCopyStatus` reports for a root.
+pub struct ScmStatusTracker {
+    root: ResolvedRoot,
+    mergebase_with: String,
+    clock: Clock,
+    mergebase: Option<String>,
+    changed: BTreeSet<PathBuf>,
+    states: Arc<Mutex<BTreeSet<String>>>,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+impl std::fmt::Debug for ScmStatusTracker {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("ScmStatusTracker")
+            .field("root", &self.root)
+            .field("mergebase_with", &self.mergebase_with)
+            .field("mergebase", &self.mergebase)
+            .finish()
+    }
+}
+
+fn lock(states: &Mutex<BTreeSet<String>>) -> MutexGuard<'_, BTreeSet<String>> {
+    match states.lock() {
+        Ok(states) => states,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl ScmStatusTracker {
+    /// Start tracking the working copy at `root`, comparing it with
+    /// the revision `mergebase_with`, such as `master`
+    pub async fn new(
+        client: &Client,
+        root: &ResolvedRoot,
+        mergebase_with: &str,
+    ) -> Result<Self, Error> {
+        let clock = client.clock(root, SyncTimeout::Default).await?;
+
+        // Watch for state transitions without receiving any files
+        let (subscription, response) = client
+            .subscribe::<NameOnly>(
+                root,
+                SubscribeRequest {
+                    expression: Some(Expr::False),
+                    empty_on_fresh_instance: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let states = Arc::new(Mutex::new(response.asserted_states.into_iter().collect()));
+        let (cancel, canceled) = oneshot::channel();
+        tokio::spawn(track_states(subscription, Arc::clone(&states), canceled));
+
+        Ok(Self {
+            root: root.clone(),
+            mergebase_with: mergebase_with.to_string(),
+            clock: Clock::Spec(clock),
+            mergebase: None,
+            changed: BTreeSet::new(),
+            states,
+            cancel: Some(cancel),
+        })
+    }
+
+    /// Returns true if one of the `UPDATE_STATES` is asserted
+    pub fn is_updating(&self) -> bool {
+        lock(&self.states)
+            .iter()
+            .any(|state| UPDATE_STATES.contains(&state.as_str()))
+    }
+
+    /// Query the server for the current state of the working copy
+    pub async fn status(&mut self, client: &Client) -> Result<WorkingCopyStatus, Error> {
+        // The clock carries the merge base of the previous status
+        let result = client
+            .query_since_mergebase::<NameOnly>(
+                &self.root,
+                Some(self.clock.clone()),
+                &self.mergebase_with,
+                None,
+                None,
+            )
+            .await?;
+
+        let mergebase = result.mergebase;
+        let first = self.mergebase.is_none();
+        let mergebase_changed = !first && mergebase != self.mergebase;
+        let recent: Vec<PathBuf> = result
+            .files
+            .into_iter()
+            .map(|file| file.name.into_inner())
+            .collect();
+        // When the merge base changes, or the server can't produce a
+        // delta, the result lists everything relative to the new
+        // merge base
+        if first || mergebase_changed || result.is_fresh_instance {
+            self.changed.clear();
+        }
+        self.changed.extend(recent.iter().cloned());
+        let changed_since_last_status = if first || mergebase_changed || result.is_fresh_instance {
+            self.changed.iter().cloned().collect()
+        } else {
+            recent
+        };
+
+        self.mergebase = mergebase;
+        self.clock = result.clock.clone();
+        let asserted_states: Vec<String> = lock(&self.states).iter().cloned().collect();
+        Ok(WorkingCopyStatus {
+            mergebase: self.mergebase.clone(),
+            mergebase_with: self.mergebase_with.clone(),
+            mergebase_changed,
+            changed: self.changed.iter().cloned().collect(),
+            changed_since_last_status,
+            updating: asserted_states
+                .iter()
+                .any(|state| UPDATE_STATES.contains(&state.as_str())),
+            asserted_states,
+            clock: result.clock,
+        })
+    }
+}
+
+impl Drop for ScmStatusTracker {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.send(()).ok();
+        }
+    }
+}
+
+/// Maintain the set of asserted states until the tracker is dropped
+async fn track_states(
+    mut subscription: Subscription<NameOnly>,
+    states: Arc<Mutex<BTreeSet<String>>>,
+    mut canceled: oneshot::Receiver<()>,
+) {
+    loop {
+        let data = tokio::select! {
+            _ = &mut canceled => {
+                subscription.cancel().await.ok();
+                return;
+            }
+            data = subscription.next() => data,
+        };
+        match data {
+            Ok(SubscriptionData::StateEnter { state_name, .. }) => {
+                lock(&states).insert(state_name);
+            }
+            Ok(SubscriptionData::StateLeave { state_name, .. }) => {
+                lock(&states).remove(&state_name);
+            }
+            Ok(SubscriptionData::FilesChanged(_)) => {}
+            Ok(SubscriptionData::Canceled) | Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn scm_result(mergebase: &str, files: &[&str], is_fresh_instance: bool) -> Value {
+        hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "clock".to_string() => Value::from(hashmap! {
+                "clock".to_string() => Value::from("c:0:2"),
+                "scm".to_string() => Value::from(hashmap! {
+                    "mergebase".to_string() => Value::from(mergebase),
+                    "mergebase-with".to_string() => Value::from("master"),
+                }),
+            }),
+            "is_fresh_instance".to_string() => Value::Bool(is_fresh_instance),
+            "files".to_string() => Value::Array(files.iter().map(|&f| Value::from(f)).collect()),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn tracks_mergebase_and_states() {
+        let server = MockServer::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        {
+            let count = Arc::clone(&count);
+            server.respond("query", move |_| {
+                match count.fetch_add(1, Ordering::SeqCst) {
+                    0 => scm_result("abc", &["a.rs", "b.rs"], true),
+                    1 => scm_result("abc", &["c.rs"], false),
+                    _ => scm_result("def", &["d.rs"], true),
+                }
+            });
+        }
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let mut tracker = ScmStatusTracker::new(&client, &root, "master")
+            .await
+            .unwrap();
+
+        let status = tracker.status(&client).await.unwrap();
+        assert_eq!(status.mergebase.as_deref(), Some("abc"));
+        assert!(!status.mergebase_changed);
+        assert_eq!(status.changed.len(), 2);
+        assert_eq!(status.changed_since_last_status.len(), 2);
+        assert!(!status.updating);
+
+        server.push(
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "unilateral".to_string() => Value::Bool(true),
+                "subscription".to_string() => subscription_name(&server),
+                "clock".to_string() => Value::from("c:0:3"),
+                "state-enter".to_string() => Value::from("hg.update"),
+            }
+            .into(),
+        );
+        while !tracker.is_updating() {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+
+        let status = tracker.status(&client).await.unwrap();
+        assert!(status.updating);
+        assert_eq!(status.asserted_states, vec!["hg.update".to_string()]);
+        assert_eq!(
+            status.changed,
+            vec![
+                PathBuf::from("a.rs"),
+                PathBuf::from("b.rs"),
+                PathBuf::from("c.rs")
+            ]
+        );
+        assert_eq!(
+            status.changed_since_last_status,
+            vec![PathBuf::from("c.rs")]
+        );
+
+        // The second query continues from the clock and merge base of
+        // the first
+        let queries: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .filter(|r| request_arg(r, 0) == Value::from("query"))
+            .collect();
+        match request_arg(&queries[1], 2) {
+            Value::Object(query) => {
+                let since = format!("{:?}", query["since"]);
+                assert!(since.contains("abc"), "{}", since);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let status = tracker.status(&client).await.unwrap();
+        assert!(status.mergebase_changed);
+        assert_eq!(status.changed, vec![PathBuf::from("d.rs")]);
+    }
+
+    #[tokio::test]
+    async fn query_since_mergebase() {
+        let server = MockServer::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        {
+            let count = Arc::clone(&count);
+            server.respond("query", move |_| {
+                match count.fetch_add(1, Ordering::SeqCst) {
+                    0 => {
+                        let mut result = match scm_result("abc", &["a.rs"], true) {
+                            Value::Object(result) => result,
+                            _ => unreachable!(),
+                        };
+                        result.insert(
+                            "saved-state-info".to_string(),
+                            Value::from(hashmap! {
+                                "local-path".to_string() => Value::from("/states/proj/abc"),
+                                "commit-id".to_string() => Value::from("abc"),
+                            }),
+                        );
+                        result.into()
+                    }
+                    _ => scm_result("abc", &["b.rs"], false),
+                }
+            });
+        }
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let saved_state = SavedStateClockData::local(LocalSavedStateConfig {
+            local_storage_path: "/states".into(),
+            project: "proj".to_string(),
+            project_metadata: None,
+            max_commits: Some(5),
+        });
+        let first = client
+            .query_since_mergebase::<NameOnly>(&root, None, "master", Some(saved_state), None)
+            .await
+            .unwrap();
+        assert!(first.is_fresh_instance);
+        assert_eq!(first.mergebase.as_deref(), Some("abc"));
+        let saved_state = first.saved_state.unwrap();
+        assert_eq!(saved_state.commit_id.as_deref(), Some("abc"));
+        assert_eq!(
+            saved_state.local_path,
+            Some(PathBuf::from("/states/proj/abc"))
+        );
+        assert_eq!(saved_state.error, None);
+
+        let second = client
+            .query_since_mergebase::<NameOnly>(&root, Some(first.clock), "master", None, None)
+            .await
+            .unwrap();
+        assert!(!second.is_fresh_instance);
+        assert!(second.saved_state.is_none());
+        assert_eq!(second.files.len(), 1);
+
+        let since: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .filter(|r| request_arg(r, 0) == Value::from("query"))
+            .map(|r| match request_arg(&r, 2) {
+                Value::Object(mut query) => query.remove("since").unwrap(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            since,
+            vec![
+                Value::from(hashmap! {
+                    "clock".to_string() => Value::from("c:0:0"),
+                    "scm".to_string() => Value::from(hashmap! {
+                        "mergebase-with".to_string() => Value::from("master"),
+                        "saved-state".to_string() => Value::from(hashmap! {
+                            "storage".to_string() => Value::from("local"),
+                            "config".to_string() => Value::from(hashmap! {
+                                "local-storage-path".to_string() => Value::from("/states"),
+                                "project".to_string() => Value::from("proj"),
+                                "max-commits".to_string() => Value::Integer(5),
+                            }),
+                        }),
+                    }),
+                }),
+                Value::from(hashmap! {
+                    "clock".to_string() => Value::from("c:0:2"),
+                    "scm".to_string() => Value::from(hashmap! {
+                        "mergebase".to_string() => Value::from("abc"),
+                        "mergebase-with".to_string() => Value::from("master"),
+                    }),
+                }),
+            ]
+        );
+    }
+
+    fn subscription_name(server: &MockServer) -> Value {
+        server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .map(|r| request_arg(&r, 2))
+            .unwrap()
+    }
+}
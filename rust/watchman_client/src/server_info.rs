@@ -0,0 +1,345 @@
+//! Describe the server in a single call.
+//!
+//! Diagnostics panels and bug report tools typically want to show
+//! everything about the server that a client is connected to.
+//! `Client::server_info` gathers it with one call, queueing the
+//! requests for the individual pieces of information together so that
+//! each is sent as soon as the server has responded to the previous
+//! one:
+//!
+//! ```no_run
+//! use watchman_client::prelude::*;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = Connector::new().connect().await?;
+//! let info = client.server_info().await?;
+//! println!("watchman {} (pid {})", info.version, info.pid);
+//! for root in &info.roots {
+//!     println!("watching {}", root.display());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A program that depends on particular features of the server can
+//! check for them when it starts with `Client::version`, which fails
+//! with `Error::MissingCapability` if any of the required capabilities
+//! are absent, and reports which of the optional ones are present:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let capabilities = client
+//!     .version(&["relative_root"], &["scm-since"])
+//!     .await?;
+//! if capabilities.has("scm-since") {
+//!     // ... use source control aware queries ...
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::pdu::{
+    GetPidResponse, GetSockNameResponse, ListCapabilitiesResponse, VersionRequest,
+    VersionRequestParams, VersionResponse, WatchListRequest, WatchListResponse,
+};
+use crate::{await_pdu, bunser, Client, Error};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+/// The information returned by `Client::server_info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// The version of the server
+    pub version: String,
+    /// The capabilities that the server supports, sorted
+    pub capabilities: Vec<String>,
+    /// The process id of the server
+    pub pid: u32,
+    /// The path to the server's socket, if it reported one
+    pub sockname: Option<PathBuf>,
+    /// The roots that the server is watching, sorted
+    pub roots: Vec<PathBuf>,
+}
+
+impl ServerInfo {
+    /// Returns true if the server supports `capability`
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities
+            .binary_search_by(|c| c.as_str().cmp(capability))
+            .is_ok()
+    }
+}
+
+/// The result of `Client::version`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The version of the server
+    pub version: String,
+    /// Details of how the server was built, if it reported them
+    pub buildinfo: Option<String>,
+    /// Whether the server has each of the capabilities that were
+    /// asked about
+    pub capabilities: BTreeMap<String, bool>,
+}
+
+impl Capabilities {
+    /// Returns true if the server has `capability`.  Capabilities that
+    /// were not asked about are reported as absent.
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.get(capability).copied().unwrap_or(false)
+    }
+
+    /// Returns the capabilities that were asked about and that the
+    /// server doesn't have
+    pub fn missing(&self) -> impl Iterator<Item = &str> {
+        self.capabilities
+            .iter()
+            .filter(|(_, &have)| !have)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+impl Client {
+    /// Returns the version of the server, along with whether it has
+    /// each of the `required` and `optional` capabilities.
+    /// Fails with `Error::MissingCapability`, naming all of them, if
+    /// any of the `required` capabilities are absent.
+    /// Servers that predate capabilities report none of them.
+    pub async fn version(
+        &self,
+        required: &[&str],
+        optional: &[&str],
+    ) -> Result<Capabilities, Error> {
+        // Everything is asked about as optional, since the server fails
+        // the request at the first absent required capability, without
+        // reporting on the others
+        let response: VersionResponse = self
+            .generic_request(VersionRequest(
+                "version",
+                VersionRequestParams {
+                    required: vec![],
+                    optional: required
+                        .iter()
+                        .chain(optional)
+                        .map(|c| c.to_string())
+                        .collect(),
+                },
+            ))
+            .await?;
+        let mut capabilities = Capabilities {
+            version: response.version,
+            buildinfo: response.buildinfo,
+            capabilities: response.capabilities,
+        };
+        for capability in required.iter().chain(optional) {
+            capabilities
+                .capabilities
+                .entry(capability.to_string())
+                .or_insert(false);
+        }
+        let missing: Vec<String> = required
+            .iter()
+            .filter(|c| !capabilities.has(c))
+            .map(|c| c.to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::MissingCapability {
+                version: capabilities.version,
+                missing,
+            });
+        }
+        Ok(capabilities)
+    }
+
+    /// Returns all of the capabilities that the server supports.
+    /// Prefer `version` to check for particular capabilities, as it
+    /// also reports on those that the server doesn't have.
+    pub async fn list_capabilities(&self) -> Result<HashSet<String>, Error> {
+        let response: ListCapabilitiesResponse =
+            self.generic_request(&["list-capabilities"]).await?;
+        Ok(response.capabilities.into_iter().collect())
+    }
+
+    /// Returns the version, capabilities, process id, socket path and
+    /// watched roots of the server.
+    /// This is subject to the client's `request_timeout`, which applies
+    /// to all of the requests together.
+    pub async fn server_info(&self) -> Result<ServerInfo, Error> {
+        let info = async {
+            // The lock is only held while queueing the requests, as the
+            // client task delivers each response to its own requestor
+            let (capabilities, pid, sockname, roots, events) = {
+                let mut inner = self.inner.lock().await;
+                (
+                    inner.queue_request(&["list-capabilities"]).await?,
+                    inner.queue_request(&["get-pid"]).await?,
+                    inner.queue_request(&["get-sockname"]).await?,
+                    inner.queue_request(&WatchListRequest).await?,
+                    inner.events.clone(),
+                )
+            };
+
+            let capabilities: ListCapabilitiesResponse =
+                bunser(&await_pdu(&events, capabilities, &["list-capabilities"]).await?)?;
+            let pid: GetPidResponse = bunser(&await_pdu(&events, pid, &["get-pid"]).await?)?;
+            let sockname: GetSockNameResponse =
+                bunser(&await_pdu(&events, sockname, &["get-sockname"]).await?)?;
+            let roots: WatchListResponse =
+                bunser(&await_pdu(&events, roots, &WatchListRequest).await?)?;
+
+            let mut info = ServerInfo {
+                version: capabilities.version,
+                capabilities: capabilities.capabilities,
+                pid: pid.pid,
+                sockname: sockname.sockname,
+                roots: roots.roots,
+            };
+            info.capabilities.sort();
+            info.roots.sort();
+            Ok(info)
+        };
+        match self.request_timeout {
+            None => info.await,
+            Some(duration) => {
+                tokio::time::timeout(duration, info)
+                    .await
+                    .map_err(|_| Error::Timeout {
+                        command: "server_info".to_string(),
+                    })?
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{error_response, request_arg, MockServer, MOCK_VERSION};
+    use crate::Error;
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn server_info() {
+        let server = MockServer::new();
+        server.respond("list-capabilities", |_| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "capabilities".to_string() => Value::Array(vec![
+                    "relative_root".into(),
+                    "glob_generator".into(),
+                ]),
+            }
+            .into()
+        });
+        server.respond("get-pid", |_| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "pid".to_string() => Value::Integer(1234),
+            }
+            .into()
+        });
+        server.respond("get-sockname", |_| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "sockname".to_string() => Value::from("/tmp/sock"),
+            }
+            .into()
+        });
+        server.respond("watch-list", |_| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "roots".to_string() => Value::Array(vec!["/b".into(), "/a".into()]),
+            }
+            .into()
+        });
+        let client = server.connect();
+
+        let info = client.server_info().await.unwrap();
+        assert_eq!(info.version, MOCK_VERSION);
+        assert_eq!(info.capabilities, vec!["glob_generator", "relative_root"]);
+        assert!(info.has_capability("relative_root"));
+        assert!(!info.has_capability("scm-since"));
+        assert_eq!(info.pid, 1234);
+        assert_eq!(info.sockname, Some(PathBuf::from("/tmp/sock")));
+        assert_eq!(info.roots, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+
+        server.respond_once("get-pid", error_response("no pid"));
+        assert!(client.server_info().await.is_err());
+        // The responses to the other requests of the failed call are not
+        // mistaken for responses to later requests
+        assert_eq!(client.server_info().await.unwrap(), info);
+
+        let capabilities = client.list_capabilities().await.unwrap();
+        assert_eq!(capabilities.len(), 2);
+        assert!(capabilities.contains("glob_generator"));
+
+        assert_eq!(
+            client.watch_list().await.unwrap(),
+            vec![PathBuf::from("/b"), PathBuf::from("/a")]
+        );
+    }
+
+    #[tokio::test]
+    async fn version() {
+        let server = MockServer::new();
+        server.respond("version", |request| {
+            let asked = match request_arg(request, 1) {
+                Value::Object(mut params) => match params.remove("optional") {
+                    Some(Value::Array(asked)) => asked,
+                    _ => vec![],
+                },
+                _ => vec![],
+            };
+            let capabilities = asked
+                .into_iter()
+                .filter_map(|c| match c {
+                    Value::Utf8String(c) => {
+                        let have = c != "scm-since" && c != "suffix-set";
+                        Some((c, Value::Bool(have)))
+                    }
+                    _ => None,
+                })
+                .collect::<std::collections::HashMap<_, _>>();
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "capabilities".to_string() => capabilities.into(),
+            }
+            .into()
+        });
+        let client = server.connect();
+
+        let capabilities = client
+            .version(&["relative_root"], &["scm-since", "glob_generator"])
+            .await
+            .unwrap();
+        assert_eq!(capabilities.version, MOCK_VERSION);
+        assert!(capabilities.has("relative_root"));
+        assert!(capabilities.has("glob_generator"));
+        assert!(!capabilities.has("scm-since"));
+        assert!(!capabilities.has("never-asked"));
+        assert_eq!(
+            capabilities.missing().collect::<Vec<_>>(),
+            vec!["scm-since"]
+        );
+
+        match client
+            .version(&["scm-since", "relative_root", "suffix-set"], &[])
+            .await
+        {
+            Err(Error::MissingCapability { missing, .. }) => {
+                assert_eq!(missing, vec!["scm-since", "suffix-set"])
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
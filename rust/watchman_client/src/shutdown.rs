@@ -0,0 +1,134 @@
+//! Stop the server.
+//!
+//! Test harnesses and administrative tools that manage their own
+//! server can stop it without shelling out to the CLI.
+//! `Client::shutdown_server` returns once the server has agreed to
+//! exit, and `Client::shutdown_server_and_wait` waits until it has
+//! closed the connection as well, which it does as it exits:
+//!
+//! ```
+//! use std::time::Duration;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::{MockServer, MOCK_VERSION};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = std::sync::Arc::new(MockServer::new());
+//! # let client = server.connect();
+//! # server.respond_once("shutdown-server", maplit::hashmap! {
+//! #     "version".to_string() => MOCK_VERSION.into(),
+//! #     "shutdown-server".to_string() => true.into(),
+//! # }.into());
+//! # let exiting = std::sync::Arc::clone(&server);
+//! # tokio::spawn(async move {
+//! #     tokio::time::delay_for(Duration::from_millis(20)).await;
+//! #     exiting.disconnect_all();
+//! # });
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! client
+//!     .shutdown_server_and_wait(Duration::from_secs(10))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every client connected to the server, including this one, loses its
+//! connection as the server exits.  If `Connector::reconnect` is
+//! enabled the client goes on to reconnect, which succeeds if something
+//! starts the server again.
+use crate::pdu::ShutdownServerResponse;
+use crate::{Client, Error};
+use serde_bser::value::Value;
+use std::time::Duration;
+
+/// How often `shutdown_server_and_wait` checks whether the server has
+/// closed the connection
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl Client {
+    /// Ask the server to exit, returning once it has agreed to
+    pub async fn shutdown_server(&self) -> Result<(), Error> {
+        let response: ShutdownServerResponse = self.generic_request(&["shutdown-server"]).await?;
+        if response.shutdown_server {
+            Ok(())
+        } else {
+            Err(Error::WatchmanResponseError {
+                message: "the server declined to shut down".to_string(),
+            })
+        }
+    }
+
+    /// Ask the server to exit, and wait at most `timeout` for it to
+    /// close this connection.
+    /// Fails with `Error::Timeout` if the connection is still open
+    /// once `timeout` has passed.
+    pub async fn shutdown_server_and_wait(&self, timeout: Duration) -> Result<(), Error> {
+        let wait = async {
+            self.shutdown_server().await?;
+            // Until the server goes away it carries on answering
+            // requests, albeit perhaps with an error
+            loop {
+                match self.generic_request::<_, Value>(&["get-pid"]).await {
+                    Ok(_) | Err(Error::WatchmanServerError { .. }) => {}
+                    Err(_) => return Ok(()),
+                }
+                tokio::time::delay_for(POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| Error::Timeout {
+                command: "shutdown-server".to_string(),
+            })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{error_response, request_arg, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use std::sync::Arc;
+
+    fn acknowledged() -> Value {
+        hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "shutdown-server".to_string() => Value::Bool(true),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn shuts_down() {
+        let server = Arc::new(MockServer::new());
+        let client = server.connect();
+        server.respond_once("shutdown-server", acknowledged());
+        client.shutdown_server().await.unwrap();
+        assert!(server
+            .requests()
+            .iter()
+            .any(|r| request_arg(r, 0) == Value::from("shutdown-server")));
+
+        // The server stays up, so the wait times out
+        server.respond_once("shutdown-server", acknowledged());
+        let err = client
+            .shutdown_server_and_wait(Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout { .. }), "{}", err);
+
+        server.respond_once("shutdown-server", acknowledged());
+        server.respond("get-pid", |_| error_response("still exiting"));
+        let exiting = Arc::clone(&server);
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            exiting.disconnect_all();
+        });
+        client
+            .shutdown_server_and_wait(Duration::from_secs(10))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+//! Verify that a checkout still matches a manifest.
+//!
+//! CI systems often provision a checkout, record its manifest, and
+//! start the build some time later.  `Client::verify_snapshot` checks
+//! that nothing mutated the checkout in between, by comparing the files
+//! that changed since a clock taken when the checkout was provisioned
+//! against the manifest:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use watchman_client::snapshot_verify::{Manifest, ManifestEntry};
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files::<&str>(&[]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! // When provisioning
+//! let provisioned = client.clock(&root, SyncTimeout::Default).await?;
+//! let mut manifest = Manifest::new();
+//! manifest.insert("src/lib.rs", ManifestEntry::with_size(1024));
+//! // When the build starts
+//! let report = client
+//!     .verify_snapshot(&root, &manifest, provisioned, None)
+//!     .await?;
+//! for divergence in &report.divergences {
+//!     eprintln!("checkout was modified: {:?}", divergence);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Files that haven't changed since the clock are assumed to match the
+//! manifest, so the clock must be taken after the manifest was
+//! recorded.  If the server can't describe the changes since the clock,
+//! for example because it was restarted, every file is compared.
+use crate::prelude::*;
+use crate::Error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+query_result_type! {
+    struct ManifestFile {
+        name: NameField,
+        exists: ExistsField,
+        size: SizeField,
+    }
+}
+
+query_result_type! {
+    struct HashedManifestFile {
+        name: NameField,
+        exists: ExistsField,
+        size: SizeField,
+        hash: ContentSha1HexField,
+    }
+}
+
+/// What a manifest expects of a file.
+/// Only the properties that are set are compared.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The size of the file in bytes
+    pub size: Option<u64>,
+    /// The SHA1 hash of the file contents, as 40 hex digits
+    pub sha1hex: Option<String>,
+}
+
+impl ManifestEntry {
+    /// A file of `size` bytes
+    pub fn with_size(size: u64) -> Self {
+        Self {
+            size: Some(size),
+            sha1hex: None,
+        }
+    }
+
+    /// A file whose contents have the SHA1 hash `sha1hex`
+    pub fn with_sha1hex<S: Into<String>>(sha1hex: S) -> Self {
+        Self {
+            size: None,
+            sha1hex: Some(sha1hex.into()),
+        }
+    }
+}
+
+/// The files expected to be present in a checkout, keyed by their
+/// path relative to the root
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Create an empty manifest
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the output of `sha1sum`, which lists a hash and a path on
+    /// each line, separated by whitespace.  A `*` marking the path as
+    /// having been read in binary mode is ignored.
+    pub fn from_sha1sum(listing: &str) -> Result<Self, Error> {
+        let mut manifest = Self::new();
+        for line in listing.lines().filter(|line| !line.trim().is_empty()) {
+            let (hash, path) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| Error::generic(format!("invalid sha1sum line {:?}", line)))?;
+            let path = path.trim_start();
+            let path = path.strip_prefix('*').unwrap_or(path);
+            if hash.len() != 40 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) || path.is_empty() {
+                return Err(Error::generic(format!("invalid sha1sum line {:?}", line)));
+            }
+            manifest.insert(path, ManifestEntry::with_sha1hex(hash.to_ascii_lowercase()));
+        }
+        Ok(manifest)
+    }
+
+    /// Add or replace the entry for `path`
+    pub fn insert<P: Into<PathBuf>>(&mut self, path: P, entry: ManifestEntry) {
+        self.entries.insert(path.into(), entry);
+    }
+
+    /// Returns the entry for `path`
+    pub fn get(&self, path: &Path) -> Option<&ManifestEntry> {
+        self.entries.get(path)
+    }
+
+    /// Returns the number of files in the manifest
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the manifest lists no files
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn has_hashes(&self) -> bool {
+        self.entries.values().any(|entry| entry.sha1hex.is_some())
+    }
+}
+
+impl<P: Into<PathBuf>> std::iter::FromIterator<(P, ManifestEntry)> for Manifest {
+    fn from_iter<I: IntoIterator<Item = (P, ManifestEntry)>>(iter: I) -> Self {
+        Self {
+            entries: iter
+                .into_iter()
+                .map(|(path, entry)| (path.into(), entry))
+                .collect(),
+        }
+    }
+}
+
+/// A way in which the checkout differs from the manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// A file in the manifest no longer exists
+    Missing(PathBuf),
+    /// A file that isn't in the manifest exists
+    Unexpected(PathBuf),
+    /// A file has a different size than the manifest expects
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// The contents of a file have a different hash than the manifest
+    /// expects
+    HashMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    /// The server couldn't hash a file whose hash is in the manifest
+    Unhashable { path: PathBuf, error: String },
+}
+
+impl Divergence {
+    /// Returns the path of the file that diverged
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Missing(path) | Self::Unexpected(path) => path,
+            Self::SizeMismatch { path, .. }
+            | Self::HashMismatch { path, .. }
+            | Self::Unhashable { path, .. } => path,
+        }
+    }
+}
+
+/// The result of `Client::verify_snapshot`
+#[derive(Debug, Clone)]
+pub struct SnapshotReport {
+    /// The ways in which the checkout differs from the manifest,
+    /// sorted by path
+    pub divergences: Vec<Divergence>,
+    /// The number of files that were compared against the manifest
+    pub checked: usize,
+    /// True if the server couldn't describe the changes since the
+    /// clock, so every file was compared
+    pub is_fresh_instance: bool,
+    /// The clock at which the checkout was verified
+    pub clock: ClockSpec,
+}
+
+impl SnapshotReport {
+    /// Returns true if the checkout matches the manifest
+    pub fn is_clean(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+/// A file reported by the server, with its hash if it was requested
+struct Observed {
+    name: PathBuf,
+    exists: bool,
+    size: u64,
+    hash: Option<ContentSha1Hex>,
+}
+
+impl Client {
+    /// Compare the files beneath `root` that changed since `since`
+    /// against `manifest`, reporting the differences.
+    ///
+    /// If `expression` is provided, only the files that match it are
+    /// considered; this is useful to ignore build output that isn't
+    /// part of the checkout.  Directories are never considered.
+    /// File content hashes are only requested from the server if the
+    /// manifest contains some, as they can be expensive to compute.
+    pub async fn verify_snapshot(
+        &self,
+        root: &ResolvedRoot,
+        manifest: &Manifest,
+        since: ClockSpec,
+        expression: Option<Expr>,
+    ) -> Result<SnapshotReport, Error> {
+        let not_dir = Expr::Not(Box::new(Expr::FileType(FileType::Directory)));
+        let query = QueryRequestCommon {
+            since: Some(Clock::Spec(since)),
+            expression: Some(match expression {
+                Some(expression) => Expr::All(vec![not_dir, expression]),
+                None => not_dir,
+            }),
+            relative_root: root.relative.clone(),
+            ..Default::default()
+        };
+
+        let (files, clock, is_fresh_instance) = if manifest.has_hashes() {
+            let result: QueryResult<HashedManifestFile> = self.query(root, query).await?;
+            let files: Vec<Observed> = result
+                .files
+                .unwrap_or_default()
+                .into_iter()
+                .map(|file| Observed {
+                    name: file.name.into_inner(),
+                    exists: *file.exists,
+                    size: *file.size as u64,
+                    hash: Some(file.hash.into_inner()),
+                })
+                .collect();
+            (files, result.clock, result.is_fresh_instance)
+        } else {
+            let result: QueryResult<ManifestFile> = self.query(root, query).await?;
+            let files = result
+                .files
+                .unwrap_or_default()
+                .into_iter()
+                .map(|file| Observed {
+                    name: file.name.into_inner(),
+                    exists: *file.exists,
+                    size: *file.size as u64,
+                    hash: None,
+                })
+                .collect();
+            (files, result.clock, result.is_fresh_instance)
+        };
+
+        let mut divergences = vec![];
+        let mut seen = std::collections::HashSet::new();
+        for file in &files {
+            seen.insert(file.name.as_path());
+            match (manifest.get(&file.name), file.exists) {
+                (Some(_), false) => divergences.push(Divergence::Missing(file.name.clone())),
+                (Some(expected), true) => compare(file, expected, &mut divergences),
+                (None, true) => divergences.push(Divergence::Unexpected(file.name.clone())),
+                // Created and deleted again since the clock
+                (None, false) => {}
+            }
+        }
+        if is_fresh_instance {
+            // Only the files that exist are listed
+            divergences.extend(
+                manifest
+                    .entries
+                    .keys()
+                    .filter(|path| !seen.contains(path.as_path()))
+                    .map(|path| Divergence::Missing(path.clone())),
+            );
+        }
+        divergences.sort_by(|a, b| a.path().cmp(b.path()));
+
+        Ok(SnapshotReport {
+            divergences,
+            checked: files.len(),
+            is_fresh_instance,
+            clock: match clock {
+                Clock::Spec(clock) | Clock::ScmAware(FatClockData { clock, .. }) => clock,
+            },
+        })
+    }
+}
+
+/// Compare an existing file against its manifest entry
+fn compare(file: &Observed, expected: &ManifestEntry, divergences: &mut Vec<Divergence>) {
+    if let Some(size) = expected.size {
+        if size != file.size {
+            divergences.push(Divergence::SizeMismatch {
+                path: file.name.clone(),
+                expected: size,
+                actual: file.size,
+            });
+            return;
+        }
+    }
+    if let (Some(expected), Some(hash)) = (&expected.sha1hex, &file.hash) {
+        match hash {
+            ContentSha1Hex::Hash(actual) if actual.eq_ignore_ascii_case(expected) => {}
+            ContentSha1Hex::Hash(actual) => divergences.push(Divergence::HashMismatch {
+                path: file.name.clone(),
+                expected: expected.clone(),
+                actual: actual.clone(),
+            }),
+            ContentSha1Hex::Error { error } => divergences.push(Divergence::Unhashable {
+                path: file.name.clone(),
+                error: error.clone(),
+            }),
+            ContentSha1Hex::None => divergences.push(Divergence::Missing(file.name.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    const HASH_A: &str = "e820c2c600a36f05ba905cf1bf32c4834e804e22";
+    const HASH_B: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+
+    fn serve(server: &MockServer, is_fresh_instance: bool, files: Vec<(&'static str, bool, i64)>) {
+        server.respond("query", move |_| {
+            let files = files
+                .iter()
+                .map(|&(name, exists, size)| {
+                    Value::from(hashmap! {
+                        "name".to_string() => Value::from(name),
+                        "exists".to_string() => Value::Bool(exists),
+                        "size".to_string() => Value::Integer(size),
+                        "content.sha1hex".to_string() => Value::from(HASH_A),
+                    })
+                })
+                .collect();
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "clock".to_string() => Value::from("c:123:1:30"),
+                "is_fresh_instance".to_string() => Value::Bool(is_fresh_instance),
+                "files".to_string() => Value::Array(files),
+            }
+            .into()
+        });
+    }
+
+    #[tokio::test]
+    async fn reports_divergences() {
+        let server = MockServer::new();
+        serve(
+            &server,
+            false,
+            vec![
+                ("same", true, 10),
+                ("resized", true, 11),
+                ("rehashed", true, 10),
+                ("deleted", false, 0),
+                ("added", true, 1),
+                ("transient", false, 0),
+            ],
+        );
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let manifest: Manifest = vec![
+            ("same", ManifestEntry::with_size(10)),
+            ("resized", ManifestEntry::with_size(10)),
+            ("rehashed", ManifestEntry::with_sha1hex(HASH_B)),
+            ("deleted", ManifestEntry::with_size(3)),
+            ("untouched", ManifestEntry::with_size(3)),
+        ]
+        .into_iter()
+        .collect();
+
+        let report = client
+            .verify_snapshot(
+                &root,
+                &manifest,
+                ClockSpec::StringClock("c:123:1:5".into()),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            report.divergences,
+            vec![
+                Divergence::Unexpected("added".into()),
+                Divergence::Missing("deleted".into()),
+                Divergence::HashMismatch {
+                    path: "rehashed".into(),
+                    expected: HASH_B.into(),
+                    actual: HASH_A.into(),
+                },
+                Divergence::SizeMismatch {
+                    path: "resized".into(),
+                    expected: 10,
+                    actual: 11,
+                },
+            ]
+        );
+        assert_eq!(report.checked, 6);
+        assert!(!report.is_clean());
+
+        serve(&server, true, vec![("same", true, 10)]);
+        let report = client
+            .verify_snapshot(&root, &manifest, ClockSpec::null(), None)
+            .await
+            .unwrap();
+        assert!(report.is_fresh_instance);
+        let missing: Vec<&Path> = report.divergences.iter().map(|d| d.path()).collect();
+        assert_eq!(
+            missing,
+            ["deleted", "rehashed", "resized", "untouched"]
+                .iter()
+                .map(Path::new)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn from_sha1sum() {
+        let manifest =
+            Manifest::from_sha1sum(&format!("{}  src/lib.rs\n{} *bin/tool\n\n", HASH_A, HASH_B))
+                .unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(
+            manifest.get(Path::new("bin/tool")),
+            Some(&ManifestEntry::with_sha1hex(HASH_B))
+        );
+        assert!(Manifest::from_sha1sum("abc  src/lib.rs").is_err());
+        assert!(Manifest::from_sha1sum(HASH_A).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Buffers the subscription PDUs delivered while a source control
+//! state is asserted, and merges them into a single PDU when the state
+//! is left; see `Subscription::consolidate_states`.
+use crate::{bunser, Error, ReceivedPdu};
+use serde_bser::value::Value;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+#[derive(Default)]
+pub(crate) struct StateConsolidation {
+    states: BTreeSet<String>,
+    buffered: Vec<ReceivedPdu>,
+    ready: VecDeque<ReceivedPdu>,
+}
+
+impl StateConsolidation {
+    /// Replace the set of states during which PDUs are buffered
+    pub fn set_states(&mut self, states: BTreeSet<String>) {
+        self.states = states;
+    }
+
+    /// Returns true if PDUs should currently be buffered
+    pub fn is_active(&self, asserted_states: &BTreeSet<String>) -> bool {
+        self.states
+            .iter()
+            .any(|state| asserted_states.contains(state))
+    }
+
+    /// Returns true if any PDUs are buffered
+    pub fn is_buffering(&self) -> bool {
+        !self.buffered.is_empty()
+    }
+
+    pub fn buffer(&mut self, pdu: ReceivedPdu) {
+        self.buffered.push(pdu);
+    }
+
+    /// Returns the next PDU to be decoded ahead of those received from
+    /// the server
+    pub fn take_ready(&mut self) -> Option<ReceivedPdu> {
+        self.ready.pop_front()
+    }
+
+    /// Queue `pdu` to be decoded after those already made ready
+    pub fn push_ready(&mut self, pdu: ReceivedPdu) {
+        self.ready.push_back(pdu);
+    }
+
+    /// Merge the buffered PDUs into one and make it ready.  Should the
+    /// PDUs not be mergeable, they are made ready as they are.
+    pub fn flush(&mut self) {
+        if self.buffered.is_empty() {
+            return;
+        }
+        let buffered = std::mem::take(&mut self.buffered);
+        match merge(&buffered) {
+            Ok(data) => self.ready.push_back(ReceivedPdu {
+                received: buffered.last().unwrap().received,
+                data: data.into(),
+            }),
+            Err(_) => self.ready.extend(buffered),
+        }
+    }
+}
+
+/// Returns the key by which two entries of a `files` array are taken to
+/// describe the same file.  An entry is either a name, for a `NameOnly`
+/// field list, or an object with a `name` field.
+fn file_key(file: &Value) -> Option<Vec<u8>> {
+    let name = match file {
+        Value::Object(fields) => fields.get("name")?,
+        name => name,
+    };
+    match name {
+        Value::Utf8String(name) => Some(name.as_bytes().to_vec()),
+        Value::ByteString(name) => Some(name.as_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+/// Merge subscription PDUs that each describe a set of changed files
+/// into one that describes all of them.  When a file appears in more
+/// than one PDU, its most recent entry is kept.  The other fields are
+/// taken from the last PDU, except that the result is a fresh instance
+/// if any of the PDUs was.
+pub(crate) fn merge(pdus: &[ReceivedPdu]) -> Result<Vec<u8>, Error> {
+    let mut files: Vec<Value> = vec![];
+    let mut positions: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut is_fresh_instance = false;
+    let mut merged = None;
+    for pdu in pdus {
+        let mut fields = match bunser::<Value>(&pdu.data)? {
+            Value::Object(fields) => fields,
+            _ => return Err(Error::generic("subscription PDU is not an object")),
+        };
+        if let Some(Value::Bool(true)) = fields.get("is_fresh_instance") {
+            is_fresh_instance = true;
+        }
+        if let Some(Value::Array(pdu_files)) = fields.remove("files") {
+            for file in pdu_files {
+                match file_key(&file) {
+                    Some(key) => match positions.get(&key) {
+                        Some(&position) => files[position] = file,
+                        None => {
+                            positions.insert(key, files.len());
+                            files.push(file);
+                        }
+                    },
+                    None => files.push(file),
+                }
+            }
+        }
+        merged = Some(fields);
+    }
+    let mut merged = merged.ok_or_else(|| Error::generic("no PDUs to merge"))?;
+    merged.insert("files".to_string(), Value::Array(files));
+    merged.insert(
+        "is_fresh_instance".to_string(),
+        Value::Bool(is_fresh_instance),
+    );
+    serde_bser::ser::serialize(Vec::new(), Value::Object(merged)).map_err(Error::generic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_support::fake_subscription;
+    use crate::SubscriptionData;
+    use serde::Deserialize;
+
+    query_result_type! {
+        struct NameExists {
+            name: NameField,
+            exists: ExistsField,
+        }
+    }
+
+    fn file(name: &str, exists: bool) -> Value {
+        Value::from(maplit::hashmap! {
+            "name".to_string() => Value::from(name),
+            "exists".to_string() => Value::Bool(exists),
+        })
+    }
+
+    #[tokio::test]
+    async fn consolidates_states() {
+        let (mut sub, feed) = fake_subscription::<NameExists>("/repo");
+        sub.consolidate_states(vec!["hg.update"]);
+
+        feed.files_changed(vec![file("before", true)]);
+        feed.state_enter("hg.update", None);
+        feed.files_changed(vec![file("a", true), file("b", true)]);
+        feed.state_enter("build", None);
+        feed.files_changed(vec![file("a", false), file("c", true)]);
+        feed.state_leave("build", None);
+        feed.files_changed(vec![file("b", false)]);
+        feed.state_leave("hg.update", None);
+        feed.files_changed(vec![file("after", true)]);
+
+        let mut events = vec![];
+        for _ in 0..7 {
+            events.push(match sub.next().await.unwrap() {
+                SubscriptionData::FilesChanged(result) => result
+                    .files
+                    .unwrap()
+                    .into_iter()
+                    .map(|f| format!("{}{}", if *f.exists { "+" } else { "-" }, f.name.display()))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                SubscriptionData::StateEnter { state_name, .. } => format!("enter {}", state_name),
+                SubscriptionData::StateLeave { state_name, .. } => format!("leave {}", state_name),
+                SubscriptionData::Canceled => "canceled".to_string(),
+            });
+        }
+        assert_eq!(
+            events,
+            vec![
+                "+before",
+                "enter hg.update",
+                "enter build",
+                "leave build",
+                "leave hg.update",
+                "-a -b +c",
+                "+after",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn flushes_before_cancel() {
+        let (mut sub, feed) = fake_subscription::<NameOnly>("/repo");
+        sub.consolidate_states(vec!["hg.update"]);
+        feed.state_enter("hg.update", None);
+        feed.fresh_instance(vec!["a".into(), "b".into()]);
+        feed.files_changed(vec!["a".into()]);
+        feed.canceled();
+
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::StateEnter { .. }
+        ));
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                assert!(result.is_fresh_instance);
+                assert_eq!(result.files.unwrap().len(), 2);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::Canceled
+        ));
+    }
+}
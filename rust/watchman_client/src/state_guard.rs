@@ -0,0 +1,255 @@
+//! Assert named states on a root.
+//!
+//! A client can broadcast that a root is in a named state, such as a
+//! source control tool's `hg.update`, for the duration of an operation.
+//! The subscribers on the root observe the transitions as
+//! `SubscriptionData::StateEnter` and `SubscriptionData::StateLeave`,
+//! and may choose to hold off processing changes until the state has
+//! been left:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use serde::Serialize;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! #[derive(Serialize, Debug)]
+//! struct Update {
+//!     rev: String,
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let guard = client
+//!     .state_guard(
+//!         &root,
+//!         "mytool.update",
+//!         Some(Update { rev: "abc123".into() }),
+//!         SyncTimeout::Default,
+//!     )
+//!     .await?;
+//! // ... update the files ...
+//! guard.leave(None::<()>).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A state is owned by the connection that entered it: the server
+//! leaves it on behalf of a client that disconnects without doing so,
+//! and reports the transition to the subscribers without metadata.
+use crate::pdu::{StateEnterResponse, StateLeaveResponse, StateRequest, StateRequestParams};
+use crate::prelude::*;
+use crate::Error;
+use serde::Serialize;
+
+impl Client {
+    /// Enter the state `name` on `root`, passing `metadata` to the
+    /// subscribers that observe the transition.
+    /// The server waits for up to `sync_timeout` for its view of the
+    /// filesystem to catch up before broadcasting the transition;
+    /// `SyncTimeout::Default` is replaced by the timeout configured via
+    /// `Connector::sync_timeout`.
+    /// Use `None::<()>` to pass no metadata.
+    pub async fn state_enter<M: Serialize + std::fmt::Debug>(
+        &self,
+        root: &ResolvedRoot,
+        name: &str,
+        metadata: Option<M>,
+        sync_timeout: SyncTimeout,
+    ) -> Result<(), Error> {
+        let _: StateEnterResponse = self
+            .generic_request(self.state_request("state-enter", root, name, metadata, sync_timeout))
+            .await?;
+        Ok(())
+    }
+
+    /// Leave the state `name` on `root`, which this client entered via
+    /// `state_enter`; see `state_enter` for the parameters
+    pub async fn state_leave<M: Serialize + std::fmt::Debug>(
+        &self,
+        root: &ResolvedRoot,
+        name: &str,
+        metadata: Option<M>,
+        sync_timeout: SyncTimeout,
+    ) -> Result<(), Error> {
+        let _: StateLeaveResponse = self
+            .generic_request(self.state_request("state-leave", root, name, metadata, sync_timeout))
+            .await?;
+        Ok(())
+    }
+
+    /// Enter the state `name` on `root` as `state_enter` does, returning
+    /// a guard that leaves it when dropped
+    pub async fn state_guard<M: Serialize + std::fmt::Debug>(
+        &self,
+        root: &ResolvedRoot,
+        name: &str,
+        metadata: Option<M>,
+        sync_timeout: SyncTimeout,
+    ) -> Result<StateGuard, Error> {
+        self.state_enter(root, name, metadata, sync_timeout).await?;
+        Ok(StateGuard {
+            client: self.clone(),
+            root: root.clone(),
+            name: name.to_string(),
+            sync_timeout,
+            entered: true,
+        })
+    }
+
+    fn state_request<M: Serialize>(
+        &self,
+        command: &'static str,
+        root: &ResolvedRoot,
+        name: &str,
+        metadata: Option<M>,
+        sync_timeout: SyncTimeout,
+    ) -> StateRequest<M> {
+        StateRequest(
+            command,
+            root.root.clone(),
+            StateRequestParams {
+                name: name.to_string(),
+                metadata,
+                sync_timeout: self.resolve_sync_timeout(sync_timeout),
+            },
+        )
+    }
+}
+
+/// A state entered via `Client::state_guard`.
+/// Dropping the guard leaves the state in the background, without
+/// metadata; use `StateGuard::leave` to pass metadata or to find out
+/// whether leaving the state succeeded.
+/// The guard keeps the connection to the server open.
+#[derive(Debug)]
+#[must_use = "the state is left as soon as the guard is dropped"]
+pub struct StateGuard {
+    client: Client,
+    root: ResolvedRoot,
+    name: String,
+    sync_timeout: SyncTimeout,
+    entered: bool,
+}
+
+impl StateGuard {
+    /// Returns the name of the state
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Leave the state, passing `metadata` to the subscribers that
+    /// observe the transition
+    pub async fn leave<M: Serialize + std::fmt::Debug>(
+        mut self,
+        metadata: Option<M>,
+    ) -> Result<(), Error> {
+        self.entered = false;
+        self.client
+            .state_leave(&self.root, &self.name, metadata, self.sync_timeout)
+            .await
+    }
+}
+
+impl Drop for StateGuard {
+    fn drop(&mut self) {
+        if !self.entered {
+            return;
+        }
+        // Without a runtime, the state is left by the server once the
+        // connection is closed
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let client = self.client.clone();
+            let root = self.root.clone();
+            let name = std::mem::take(&mut self.name);
+            let sync_timeout = self.sync_timeout;
+            handle.spawn(async move {
+                client
+                    .state_leave(&root, &name, None::<()>, sync_timeout)
+                    .await
+                    .ok();
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use std::time::Duration;
+
+    /// Returns the parameters of the requests for `command`
+    fn requests(server: &MockServer, command: &str) -> Vec<Value> {
+        server
+            .requests()
+            .iter()
+            .filter(|r| request_arg(r, 0) == Value::from(command))
+            .map(|r| request_arg(r, 2))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn enters_and_leaves() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let guard = client
+            .state_guard(
+                &root,
+                "build",
+                Some(hashmap! { "rev" => "abc" }),
+                SyncTimeout::Duration(Duration::from_millis(500)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(guard.name(), "build");
+        assert_eq!(
+            requests(&server, "state-enter"),
+            vec![Value::from(hashmap! {
+                "name".to_string() => Value::from("build"),
+                "metadata".to_string() => Value::from(hashmap! {
+                    "rev".to_string() => Value::from("abc"),
+                }),
+                "sync_timeout".to_string() => Value::Integer(500),
+            })]
+        );
+        guard.leave(None::<()>).await.unwrap();
+        assert_eq!(
+            requests(&server, "state-leave"),
+            vec![Value::from(hashmap! {
+                "name".to_string() => Value::from("build"),
+                "sync_timeout".to_string() => Value::Integer(500),
+            })]
+        );
+
+        // Dropping the guard leaves the state in the background
+        let guard = client
+            .state_guard(&root, "build", None::<()>, SyncTimeout::Default)
+            .await
+            .unwrap();
+        drop(guard);
+        while requests(&server, "state-leave").len() < 2 {
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+        }
+        assert_eq!(
+            requests(&server, "state-leave")[1],
+            Value::from(hashmap! {
+                "name".to_string() => Value::from("build"),
+            })
+        );
+    }
+}
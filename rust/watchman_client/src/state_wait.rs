@@ -0,0 +1,210 @@
+//! Wait for a root to enter or leave a named state.
+//!
+//! Source control tools broadcast states such as `hg.update` for the
+//! duration of an operation that touches many files.  A tool that
+//! queries in the middle of one sees a half-updated tree, so it is
+//! usually better to wait for the state to be left first:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use std::time::Duration;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files::<&str>(&[]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! client
+//!     .wait_for_state_leave(&root, "hg.update", Some(Duration::from_secs(60)))
+//!     .await?;
+//! let files = client.glob(&root, &["**/*.rs"]).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The wait is built on a subscription whose expression matches no
+//! files, so that the server only sends it state transitions.  The
+//! subscription is canceled once the wait is over.
+use crate::prelude::*;
+use crate::{Error, SubscriptionData};
+use serde_bser::value::Value;
+use std::time::{Duration, Instant};
+
+impl Client {
+    /// Wait until `state_name` is no longer asserted for `root`.
+    /// Returns immediately if the state isn't asserted.  Otherwise
+    /// returns the metadata of the `StateLeave` event, which is `None`
+    /// if the client that entered the state disconnected without
+    /// leaving it.
+    /// Fails with `Error::Timeout` if the state is still asserted once
+    /// `timeout` has elapsed; `None` waits indefinitely.
+    pub async fn wait_for_state_leave(
+        &self,
+        root: &ResolvedRoot,
+        state_name: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Value>, Error> {
+        self.wait_for_state(root, state_name, false, timeout).await
+    }
+
+    /// Wait until `state_name` is asserted for `root`.
+    /// Returns immediately if the state is already asserted.  Otherwise
+    /// returns the metadata of the `StateEnter` event.
+    /// Fails with `Error::Timeout` if the state hasn't been entered
+    /// once `timeout` has elapsed; `None` waits indefinitely.
+    pub async fn wait_for_state_enter(
+        &self,
+        root: &ResolvedRoot,
+        state_name: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Value>, Error> {
+        self.wait_for_state(root, state_name, true, timeout).await
+    }
+
+    async fn wait_for_state(
+        &self,
+        root: &ResolvedRoot,
+        state_name: &str,
+        enter: bool,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Value>, Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let (mut subscription, _) = self
+            .subscribe_with_timeout::<NameOnly>(
+                root,
+                SubscribeRequest {
+                    expression: Some(Expr::False),
+                    empty_on_fresh_instance: true,
+                    ..Default::default()
+                },
+                timeout,
+            )
+            .await?;
+
+        let result = if subscription.is_state_asserted(state_name) == enter {
+            Ok(None)
+        } else {
+            let transition = async {
+                loop {
+                    match subscription.next().await? {
+                        SubscriptionData::StateEnter {
+                            state_name: name,
+                            metadata,
+                        } if enter && name == state_name => return Ok(metadata),
+                        SubscriptionData::StateLeave {
+                            state_name: name,
+                            metadata,
+                        } if !enter && name == state_name => return Ok(metadata),
+                        SubscriptionData::Canceled => {
+                            return Err(Error::generic("the subscription was canceled"))
+                        }
+                        _ => {}
+                    }
+                }
+            };
+            match deadline {
+                None => transition.await,
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    tokio::time::timeout(remaining, transition)
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(Error::Timeout {
+                                command: format!(
+                                    "wait_for_state_{} {}",
+                                    if enter { "enter" } else { "leave" },
+                                    state_name
+                                ),
+                            })
+                        })
+                }
+            }
+        };
+
+        // If the wait failed then so may the cancellation, for the same
+        // reason, and the original error is the more useful one
+        let canceled = subscription.cancel().await;
+        let metadata = result?;
+        canceled?;
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockServer;
+    use maplit::hashmap;
+
+    fn state_pdu(subscription: &str, key: &str, state_name: &str) -> Value {
+        Value::from(hashmap! {
+            "version".to_string() => Value::from("mock"),
+            "clock".to_string() => Value::from("c:0:2"),
+            "unilateral".to_string() => Value::Bool(true),
+            "subscription".to_string() => Value::from(subscription),
+            key.to_string() => Value::from(state_name),
+            "metadata".to_string() => Value::from("rev"),
+        })
+    }
+
+    /// Waits for the server to receive its `index`th subscribe request,
+    /// returning the name of the subscription
+    async fn subscription_name(server: &MockServer, index: usize) -> String {
+        loop {
+            let mut names = server
+                .requests()
+                .into_iter()
+                .filter_map(|request| match request {
+                    Value::Array(args) if args[0] == Value::from("subscribe") => match &args[2] {
+                        Value::Utf8String(name) => Some(name.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                });
+            if let Some(name) = names.nth(index) {
+                return name;
+            }
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_states() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        // Not asserted, so there is nothing to wait for
+        let metadata = client
+            .wait_for_state_leave(&root, "hg.update", Some(Duration::from_millis(50)))
+            .await
+            .unwrap();
+        assert_eq!(metadata, None);
+
+        let (metadata, _) = tokio::join!(
+            client.wait_for_state_enter(&root, "hg.update", None),
+            async {
+                let name = subscription_name(&server, 1).await;
+                server.push(state_pdu(&name, "state-enter", "build"));
+                server.push(state_pdu(&name, "state-enter", "hg.update"));
+            }
+        );
+        assert_eq!(metadata.unwrap(), Some(Value::from("rev")));
+
+        let err = client
+            .wait_for_state_enter(&root, "hg.update", Some(Duration::from_millis(50)))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout { .. }), "{:?}", err);
+    }
+}
@@ -0,0 +1,244 @@
+//! Configure a subscription step by step.
+//!
+//! `Client::subscription_builder` is an alternative to filling in a
+//! `SubscribeRequest` by hand, and in particular to remembering how its
+//! fields interact with the states asserted on a root.  A subscription
+//! that rebuilds a project might want to wait for a source control
+//! update to finish rather than rebuilding part way through it, and to
+//! ignore the churn of a code generator entirely:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let (mut subscription, _) = client
+//!     .subscription_builder(&root)
+//!     .expression(Expr::Suffix(vec!["rs".into()]))
+//!     .defer_during("hg.update")
+//!     .drop_during("mytool.codegen")
+//!     .subscribe::<NameOnly>()
+//!     .await?;
+//! # /*
+//! loop {
+//!     match subscription.next().await? {
+//!         SubscriptionData::FilesChanged(result) => {
+//!             // ... rebuild ...
+//!         }
+//!         _ => {}
+//!     }
+//! }
+//! # */
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Deferred changes are delivered together once the state has been
+//! left, whereas dropped changes are never delivered.  In both cases
+//! the subscription still observes the transitions themselves, as
+//! `SubscriptionData::StateEnter` and `SubscriptionData::StateLeave`.
+use crate::backpressure::{Bound, OverflowPolicy};
+use crate::pdu::{Clock, SubscribeRequest, SubscribeResponse};
+use crate::prelude::*;
+use crate::{Error, Subscription};
+use std::time::Duration;
+
+impl Client {
+    /// Returns a builder for a subscription to changes beneath `root`
+    pub fn subscription_builder<'a>(&'a self, root: &'a ResolvedRoot) -> SubscriptionBuilder<'a> {
+        SubscriptionBuilder {
+            client: self,
+            root,
+            request: SubscribeRequest::default(),
+            timeout: None,
+            bound: None,
+        }
+    }
+}
+
+/// Configures a subscription; see `Client::subscription_builder`
+#[derive(Debug)]
+#[must_use = "the subscription is only made by `subscribe`"]
+pub struct SubscriptionBuilder<'a> {
+    client: &'a Client,
+    root: &'a ResolvedRoot,
+    request: SubscribeRequest,
+    /// `None` uses the client's `request_timeout`
+    timeout: Option<Option<Duration>>,
+    bound: Option<Bound>,
+}
+
+impl<'a> SubscriptionBuilder<'a> {
+    /// Only report the files that match `expression`
+    pub fn expression(mut self, expression: Expr) -> Self {
+        self.request.expression = Some(expression);
+        self
+    }
+
+    /// Report the changes since `clock`, rather than starting with a
+    /// listing of every matching file
+    pub fn since<C: Into<Clock>>(mut self, clock: C) -> Self {
+        self.request.since = Some(clock.into());
+        self
+    }
+
+    /// Defer notifications while the state `name` is asserted on the
+    /// root, delivering the accumulated changes once it has been left.
+    /// May be called more than once to defer during several states.
+    pub fn defer_during<S: Into<String>>(mut self, name: S) -> Self {
+        self.request.defer.push(name.into());
+        self
+    }
+
+    /// Drop notifications while the state `name` is asserted on the
+    /// root, so that the changes made during it are never delivered.
+    /// May be called more than once to drop during several states.
+    pub fn drop_during<S: Into<String>>(mut self, name: S) -> Self {
+        self.request.drop.push(name.into());
+        self
+    }
+
+    /// Set whether notifications are deferred while the server observes
+    /// a version control operation in progress.  This is true unless
+    /// set otherwise.
+    pub fn defer_vcs(mut self, defer_vcs: bool) -> Self {
+        self.request.defer_vcs = defer_vcs;
+        self
+    }
+
+    /// If true, the initial listing of every matching file, and the
+    /// results of any subsequent recrawl, are delivered without files;
+    /// see `SubscribeRequest::empty_on_fresh_instance`
+    pub fn empty_on_fresh_instance(mut self, empty: bool) -> Self {
+        self.request.empty_on_fresh_instance = empty;
+        self
+    }
+
+    /// If true, treat file names as case sensitive even on filesystems
+    /// that appear to be case insensitive
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.request.case_sensitive = case_sensitive;
+        self
+    }
+
+    /// Wait at most `timeout` for the server to respond to the
+    /// subscribe request, as `Client::subscribe_with_timeout` does,
+    /// rather than using the client's `request_timeout`
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Queue at most `capacity` results that have yet to be taken by
+    /// `Subscription::next`, applying `policy` to those that arrive
+    /// while the queue is full, rather than queueing without limit.
+    /// See the [backpressure](../backpressure/index.html) module.
+    pub fn bounded(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+        self.bound = Some(Bound {
+            capacity: capacity.max(1),
+            policy,
+        });
+        self
+    }
+
+    /// Returns the request that `subscribe` makes.  The `fields` and
+    /// `relative_root` are filled in by `subscribe`.
+    pub fn request(&self) -> &SubscribeRequest {
+        &self.request
+    }
+
+    /// Create the subscription, as `Client::subscribe` does
+    pub async fn subscribe<F>(self) -> Result<(Subscription<F>, SubscribeResponse), Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let timeout = self.timeout.unwrap_or(self.client.request_timeout);
+        self.client
+            .subscribe_with_fields(
+                self.root,
+                self.request,
+                crate::subscription_name(),
+                F::field_list(),
+                timeout,
+                self.bound,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, MockServer};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    #[tokio::test]
+    async fn serializes_request() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let (subscription, _) = client
+            .subscription_builder(&root)
+            .expression(Expr::Exists)
+            .since(ClockSpec::StringClock("c:1:2".into()))
+            .defer_during("hg.update")
+            .defer_during("hg.transaction")
+            .drop_during("codegen")
+            .defer_vcs(false)
+            .empty_on_fresh_instance(true)
+            .subscribe::<NameOnly>()
+            .await
+            .unwrap();
+        let subscribe = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .unwrap();
+        assert_eq!(request_arg(&subscribe, 2), Value::from(subscription.name()));
+        assert_eq!(
+            request_arg(&subscribe, 3),
+            Value::from(hashmap! {
+                "since".to_string() => Value::from("c:1:2"),
+                "expression".to_string() => Value::from("exists"),
+                "fields".to_string() => Value::Array(vec!["name".into()]),
+                "empty_on_fresh_instance".to_string() => Value::Bool(true),
+                "defer".to_string() => Value::Array(vec!["hg.update".into(), "hg.transaction".into()]),
+                "drop".to_string() => Value::Array(vec!["codegen".into()]),
+                "defer_vcs".to_string() => Value::Bool(false),
+            })
+        );
+
+        // The defaults leave the state interactions to the server
+        let builder = client.subscription_builder(&root);
+        assert!(builder.request().defer.is_empty());
+        assert!(builder.request().defer_vcs);
+        let (subscription, _) = builder.subscribe::<NameOnly>().await.unwrap();
+        let subscribe = server
+            .requests()
+            .into_iter()
+            .filter(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .nth(1)
+            .unwrap();
+        assert_eq!(request_arg(&subscribe, 2), Value::from(subscription.name()));
+        assert_eq!(
+            request_arg(&subscribe, 3),
+            Value::from(hashmap! {
+                "fields".to_string() => Value::Array(vec!["name".into()]),
+            })
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! Consume several subscriptions as one stream.
+//!
+//! Workspace-level tools often watch many projects at once, each with
+//! its own subscription.  A `SubscriptionGroup` owns a set of
+//! subscriptions, possibly on different roots, and merges them into a
+//! single `Stream` whose items are tagged with the subscription that
+//! produced them:
+//!
+//! ```
+//! use tokio::stream::StreamExt;
+//! use watchman_client::prelude::*;
+//! use watchman_client::subscription_group::SubscriptionGroup;
+//! # use watchman_client::test_support::{subscription_pdu, MockServer};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let mut group = SubscriptionGroup::<NameOnly>::new();
+//! for project in &["/src/app", "/src/lib"] {
+//!     let root = client
+//!         .resolve_root(CanonicalPath::with_canonicalized_path(project.into()))
+//!         .await?;
+//!     group
+//!         .subscribe(&client, &root, SubscribeRequest::default())
+//!         .await?;
+//! }
+//! # let name = group.names().next().unwrap().to_string();
+//! # server.push(subscription_pdu(&name, "c:0:1", vec!["main.rs".into()]));
+//! while let Some(item) = group.next().await {
+//!     println!("{}: {:?}", item.root.project_root().display(), item.data?);
+//! #   break;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::subscription_stats::SubscriptionStats;
+use crate::{Error, QueryFieldList, Subscription, SubscriptionData};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::stream::Stream;
+
+/// An item yielded by a `SubscriptionGroup`
+#[derive(Debug)]
+pub struct GroupItem<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    /// The name of the subscription that produced the item
+    pub name: String,
+    /// The root of the subscription that produced the item
+    pub root: ResolvedRoot,
+    /// The data, as it would have been returned by `Subscription::next`
+    pub data: Result<SubscriptionData<F>, Error>,
+}
+
+/// Owns a set of subscriptions and merges their data into one
+/// `Stream` of `GroupItem`s.
+///
+/// A subscription is removed from the group once it has ended: that
+/// is, after the item reporting `SubscriptionData::Canceled`, or the
+/// error reporting that its client was torn down, has been yielded.
+/// The stream ends when the group has no subscriptions left.
+/// The subscriptions are polled in turn, so that a busy subscription
+/// cannot starve the others.
+#[derive(Debug)]
+pub struct SubscriptionGroup<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    members: Vec<Subscription<F>>,
+    next_member: usize,
+}
+
+// The members are never pinned, so the group can be moved freely
+// regardless of `F`
+impl<F> Unpin for SubscriptionGroup<F> where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList
+{
+}
+
+impl<F> Default for SubscriptionGroup<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F> SubscriptionGroup<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    /// Create an empty group
+    pub fn new() -> Self {
+        Self {
+            members: vec![],
+            next_member: 0,
+        }
+    }
+
+    /// Add an existing subscription to the group
+    pub fn insert(&mut self, subscription: Subscription<F>) {
+        self.members.push(subscription);
+    }
+
+    /// Subscribe to `root` using `client` and add the subscription to
+    /// the group.  Returns the server's response to the subscription
+    /// request.
+    pub async fn subscribe(
+        &mut self,
+        client: &Client,
+        root: &ResolvedRoot,
+        request: SubscribeRequest,
+    ) -> Result<SubscribeResponse, Error> {
+        let (subscription, response) = client.subscribe::<F>(root, request).await?;
+        self.insert(subscription);
+        Ok(response)
+    }
+
+    /// Returns the number of subscriptions in the group
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Returns true if the group has no subscriptions
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Returns the names of the subscriptions in the group
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(|member| member.name())
+    }
+
+    /// Returns the statistics of the subscription named `name`; see
+    /// `Subscription::stats`
+    pub fn stats(&self, name: &str) -> Option<&SubscriptionStats> {
+        self.members
+            .iter()
+            .find(|member| member.name == name)
+            .map(|member| member.stats())
+    }
+
+    /// Remove the subscription named `name` from the group and return
+    /// it, without canceling it
+    pub fn remove(&mut self, name: &str) -> Option<Subscription<F>> {
+        let index = self.members.iter().position(|member| member.name == name)?;
+        Some(self.remove_member(index))
+    }
+
+    /// Remove the subscription named `name` from the group and cancel
+    /// it.  Returns false if there was no such subscription.
+    pub async fn cancel(&mut self, name: &str) -> Result<bool, Error> {
+        match self.remove(name) {
+            Some(subscription) => {
+                subscription.cancel().await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Cancel all of the subscriptions in the group, leaving it empty.
+    /// All of the subscriptions are canceled even if some fail to be;
+    /// the first error is returned.
+    pub async fn cancel_all(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        self.next_member = 0;
+        for subscription in self.members.drain(..) {
+            if let Err(err) = subscription.cancel().await {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+
+    fn remove_member(&mut self, index: usize) -> Subscription<F> {
+        if index < self.next_member {
+            self.next_member -= 1;
+        }
+        self.members.remove(index)
+    }
+}
+
+impl<F> Stream for SubscriptionGroup<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    type Item = GroupItem<F>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let count = self.members.len();
+        for offset in 0..count {
+            let index = (self.next_member + offset) % count;
+            let member = &mut self.members[index];
+            let (data, ended) = match member.poll_data(cx) {
+                Poll::Ready(Some(data)) => {
+                    let ended = matches!(data, Ok(SubscriptionData::Canceled));
+                    (data, ended)
+                }
+                Poll::Ready(None) => (Err(Error::generic("client was torn down")), true),
+                Poll::Pending => continue,
+            };
+            let item = GroupItem {
+                name: member.name.clone(),
+                root: member.root.clone(),
+                data,
+            };
+            self.next_member = index + 1;
+            if ended {
+                self.remove_member(index);
+            }
+            if self.next_member >= self.members.len() {
+                self.next_member = 0;
+            }
+            return Poll::Ready(Some(item));
+        }
+        if self.members.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{subscription_pdu, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use std::path::{Path, PathBuf};
+    use tokio::stream::StreamExt;
+
+    fn names(item: GroupItem<NameOnly>) -> Vec<PathBuf> {
+        match item.data.unwrap() {
+            SubscriptionData::FilesChanged(result) => result
+                .files
+                .unwrap_or_default()
+                .into_iter()
+                .map(|file| file.name.into_inner())
+                .collect(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn merges_members() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let mut group = SubscriptionGroup::<NameOnly>::new();
+        for project in &["/a", "/b"] {
+            let root = client
+                .resolve_root(CanonicalPath::with_canonicalized_path(project.into()))
+                .await
+                .unwrap();
+            group
+                .subscribe(&client, &root, SubscribeRequest::default())
+                .await
+                .unwrap();
+        }
+        let member_names: Vec<String> = group.names().map(String::from).collect();
+        assert_eq!(group.len(), 2);
+
+        // Busy members don't starve the others
+        for clock in 1..=3 {
+            let clock = format!("c:0:{}", clock);
+            server.push(subscription_pdu(&member_names[0], &clock, vec!["x".into()]));
+        }
+        server.push(subscription_pdu(
+            &member_names[1],
+            "c:0:4",
+            vec!["y".into()],
+        ));
+        tokio::time::delay_for(std::time::Duration::from_millis(50)).await;
+        let first = group.next().await.unwrap();
+        assert_eq!(first.name, member_names[0]);
+        assert_eq!(first.root.project_root(), Path::new("/a"));
+        assert_eq!(names(first), vec![PathBuf::from("x")]);
+        let second = group.next().await.unwrap();
+        assert_eq!(second.root.project_root(), Path::new("/b"));
+        assert_eq!(names(second), vec![PathBuf::from("y")]);
+        assert_eq!(group.stats(&member_names[1]).unwrap().batches, 1);
+
+        // Canceled members are removed once the cancellation is reported
+        server.push(Value::from(hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "unilateral".to_string() => Value::Bool(true),
+            "subscription".to_string() => Value::from(member_names[1].as_str()),
+            "clock".to_string() => Value::from("c:0:5"),
+            "canceled".to_string() => Value::Bool(true),
+        }));
+        loop {
+            let item = group.next().await.unwrap();
+            if item.name == member_names[1] {
+                assert!(matches!(item.data, Ok(SubscriptionData::Canceled)));
+                break;
+            }
+        }
+        assert_eq!(
+            group.names().collect::<Vec<_>>(),
+            vec![member_names[0].as_str()]
+        );
+
+        assert!(group.cancel(&member_names[0]).await.unwrap());
+        assert!(!group.cancel(&member_names[0]).await.unwrap());
+        assert!(group.is_empty());
+        assert!(group.next().await.is_none());
+    }
+}
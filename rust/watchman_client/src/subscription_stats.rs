@@ -0,0 +1,78 @@
+//! Measure how far behind a subscription is running.
+//!
+//! Each `Subscription` keeps `SubscriptionStats` describing the items
+//! that it has delivered.  The lag of an item is the time between the
+//! client reading its PDU from the connection and the consumer
+//! receiving it from `Subscription::next`.  A growing lag means that the
+//! consumer is not keeping up with the rate of change; a subscription
+//! whose items arrive late but with little lag points at the server
+//! instead.
+//!
+//! The server doesn't timestamp the PDUs that it sends for
+//! subscriptions, and its clocks are not wall-clock times, so the lag
+//! doesn't include the time taken by the server to produce an item.
+use std::time::{Duration, Instant};
+
+/// Statistics about the items delivered by a subscription.
+/// Returned by `Subscription::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubscriptionStats {
+    /// The number of items delivered, including state transitions and
+    /// the cancellation of the subscription
+    pub batches: u64,
+    /// When the PDU of the most recently delivered item was read from
+    /// the connection
+    pub last_received: Option<Instant>,
+    /// The lag of the most recently delivered item
+    pub last_lag: Option<Duration>,
+    /// The greatest lag of any delivered item
+    pub max_lag: Duration,
+    /// The sum of the lags of all of the delivered items
+    pub total_lag: Duration,
+}
+
+impl SubscriptionStats {
+    /// Returns the mean lag of the delivered items, or `None` if no
+    /// items have been delivered
+    pub fn mean_lag(&self) -> Option<Duration> {
+        if self.batches == 0 {
+            return None;
+        }
+        let nanos = self.total_lag.as_nanos() / u128::from(self.batches);
+        Some(Duration::from_nanos(nanos as u64))
+    }
+
+    /// Record the delivery at `delivered` of an item whose PDU was read
+    /// at `received`
+    pub(crate) fn record(&mut self, received: Instant, delivered: Instant) {
+        let lag = delivered.saturating_duration_since(received);
+        self.batches += 1;
+        self.last_received = Some(received);
+        self.last_lag = Some(lag);
+        self.max_lag = self.max_lag.max(lag);
+        self.total_lag += lag;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let mut stats = SubscriptionStats::default();
+        assert_eq!(stats.mean_lag(), None);
+
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        stats.record(start, start + ms(30));
+        stats.record(start + ms(10), start + ms(20));
+        // Clocks that appear to run backwards don't produce a negative lag
+        stats.record(start + ms(50), start + ms(40));
+        assert_eq!(stats.batches, 3);
+        assert_eq!(stats.last_received, Some(start + ms(50)));
+        assert_eq!(stats.last_lag, Some(ms(0)));
+        assert_eq!(stats.max_lag, ms(30));
+        assert_eq!(stats.mean_lag(), Some(ms(40) / 3));
+    }
+}
@@ -0,0 +1,188 @@
+//! Detect symlinks that point outside of the watched root.
+//!
+//! Sync and backup tools built on watchman usually only mean to
+//! operate on the files of a project, and following a symlink whose
+//! target lies outside of the project can leak or clobber unrelated
+//! files.  The functions in this module resolve the targets of the
+//! symlinks in a set of results and flag those that escape the root:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files::<&str>(&[]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! for escape in client.symlink_escapes(&root).await? {
+//!     eprintln!(
+//!         "refusing to follow {} -> {}",
+//!         escape.name.display(),
+//!         escape.target.display()
+//!     );
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Targets are resolved lexically, without consulting the filesystem,
+//! so that dangling links are handled too.  A link that escapes by way
+//! of another link inside the root is not flagged itself, but the other
+//! link is, provided that it is part of the results being checked.
+use crate::prelude::*;
+use crate::Error;
+use serde::Deserialize;
+use std::path::{Component, Path, PathBuf};
+
+query_result_type! {
+    struct SymlinkFile {
+        name: NameField,
+        target: SymlinkTargetField,
+    }
+}
+
+/// A symlink whose target lies outside of the watched root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymlinkEscape {
+    /// The name of the symlink, as it appeared in the results
+    pub name: PathBuf,
+    /// The target of the symlink, as returned by readlink(2)
+    pub target: PathBuf,
+    /// The absolute path that the target resolves to
+    pub resolved: PathBuf,
+}
+
+/// Check whether the symlink `name`, whose target is `target`, points
+/// outside of the project root of `root`.
+/// `name` is relative to `root`, as are the names in query results.
+pub fn check_symlink(root: &ResolvedRoot, name: &Path, target: &Path) -> Option<SymlinkEscape> {
+    let link = root.path().join(name);
+    let base = link.parent().unwrap_or_else(|| root.project_root());
+    let resolved = normalize(&base.join(target));
+    if resolved.starts_with(normalize(root.project_root())) {
+        None
+    } else {
+        Some(SymlinkEscape {
+            name: name.to_path_buf(),
+            target: target.to_path_buf(),
+            resolved,
+        })
+    }
+}
+
+/// Check the `(name, symlink_target)` pairs of a set of query results,
+/// returning the symlinks that point outside of the project root of
+/// `root`.  Files without a target, which are not symlinks, are
+/// skipped.
+pub fn find_escapes<I, P, T>(root: &ResolvedRoot, files: I) -> Vec<SymlinkEscape>
+where
+    I: IntoIterator<Item = (P, Option<T>)>,
+    P: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    files
+        .into_iter()
+        .filter_map(|(name, target)| {
+            let target = target?;
+            check_symlink(root, name.as_ref(), target.as_ref())
+        })
+        .collect()
+}
+
+/// Remove the `.` and `..` components of an absolute path without
+/// consulting the filesystem.  `..` at the root stays at the root, as
+/// it does when resolving paths.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
+
+impl Client {
+    /// Returns the symlinks beneath `root` whose targets lie outside of
+    /// its project root.
+    /// See the [symlink_escape](symlink_escape/index.html) module.
+    pub async fn symlink_escapes(&self, root: &ResolvedRoot) -> Result<Vec<SymlinkEscape>, Error> {
+        let result: QueryResult<SymlinkFile> = self
+            .query(
+                root,
+                QueryRequestCommon {
+                    expression: Some(Expr::All(vec![
+                        Expr::Exists,
+                        Expr::FileType(FileType::Symlink),
+                    ])),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(find_escapes(
+            root,
+            result
+                .files
+                .unwrap_or_default()
+                .into_iter()
+                .map(|file| (file.name.into_inner(), file.target.into_inner())),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(relative: Option<&str>) -> ResolvedRoot {
+        ResolvedRoot {
+            root: "/repo".into(),
+            relative: relative.map(PathBuf::from),
+            watcher: "mock".into(),
+        }
+    }
+
+    #[test]
+    fn flags_escapes() {
+        let root = root(None);
+        let escapes = find_escapes(
+            &root,
+            vec![
+                ("src/lib.rs", None),
+                ("src/inside", Some("../README.md")),
+                ("src/dot", Some("./a/../b")),
+                ("src/parent", Some("../../etc/passwd")),
+                ("absolute_in", Some("/repo/docs")),
+                ("absolute_out", Some("/etc")),
+                ("sneaky", Some("/repo/../repo2/x")),
+                ("deep", Some("../../../../..")),
+            ],
+        );
+        let names: Vec<&Path> = escapes.iter().map(|e| e.name.as_path()).collect();
+        assert_eq!(
+            names,
+            ["src/parent", "absolute_out", "sneaky", "deep"]
+                .iter()
+                .map(Path::new)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(escapes[0].resolved, PathBuf::from("/etc/passwd"));
+        assert_eq!(escapes[3].resolved, PathBuf::from("/"));
+
+        // Names are relative to the resolved path
+        let root = self::root(Some("src"));
+        assert!(check_symlink(&root, Path::new("link"), Path::new("../lib")).is_none());
+        assert!(check_symlink(&root, Path::new("link"), Path::new("../../lib")).is_some());
+    }
+}
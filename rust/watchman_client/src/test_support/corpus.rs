@@ -0,0 +1,283 @@
+//! A corpus of realistic server PDUs.
+use crate::{bunser, Error};
+use serde_bser::value::Value;
+use std::collections::HashMap;
+
+/// The object keys whose string values the server encodes as BSER
+/// byte strings rather than UTF-8 strings, because they hold paths
+const BYTESTRING_KEYS: &[&str] = &["name", "root", "watch", "relative_path", "symlink_target"];
+
+macro_rules! corpus {
+    ($($name:literal),* $(,)?) => {
+        &[$(
+            GoldenPdu {
+                name: $name,
+                json: include_str!(concat!("../../testdata/pdus/", $name, ".json")),
+            },
+        )*]
+    };
+}
+
+const CORPUS: &[GoldenPdu] = corpus![
+    "clock",
+    "clock_scm",
+    "error_query_syntax",
+    "error_unable_to_resolve_root",
+    "query_large",
+    "query_scm_since",
+    "subscribe",
+    "subscription_canceled",
+    "subscription_files",
+    "subscription_fresh_instance",
+    "subscription_state_enter",
+    "subscription_state_leave",
+    "subscription_state_leave_abandoned",
+    "unsubscribe",
+    "version",
+    "watch_project",
+];
+
+/// A PDU from the golden corpus.
+///
+/// The corpus holds PDUs modelled on those produced by real servers:
+/// large query results, subscription data including fresh instance,
+/// state and cancellation events, and error responses.
+/// It is intended to validate deserialization against realistic data
+/// rather than minimal synthetic examples.
+///
+/// The PDUs are stored as JSON in the `testdata/pdus` directory of
+/// this crate; paths are converted to BSER byte strings when the PDU
+/// is encoded, as the server does.
+///
+/// ```
+/// use serde::Deserialize;
+/// use watchman_client::prelude::*;
+/// use watchman_client::test_support::golden_pdu;
+///
+/// query_result_type! {
+///     struct NameAndHash {
+///         name: NameField,
+///         hash: ContentSha1HexField,
+///     }
+/// }
+///
+/// let pdu = golden_pdu("query_large").unwrap();
+/// let result: QueryResult<NameAndHash> = pdu.decode().unwrap();
+/// assert!(result.is_fresh_instance);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct GoldenPdu {
+    name: &'static str,
+    json: &'static str,
+}
+
+impl GoldenPdu {
+    /// Returns the name of the PDU; this is the name of its file in
+    /// the corpus, without the `.json` extension
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the PDU as a `Value`
+    pub fn value(&self) -> Value {
+        let json: serde_json::Value = serde_json::from_str(self.json)
+            .unwrap_or_else(|err| panic!("golden PDU {} is not valid JSON: {}", self.name, err));
+        json_to_value(json, false)
+    }
+
+    /// Returns the PDU encoded as BSER, exactly as it would be read
+    /// from the server connection
+    pub fn bser(&self) -> Vec<u8> {
+        serde_bser::ser::serialize(Vec::new(), self.value()).expect("serializing Value")
+    }
+
+    /// Decode the PDU as `T`
+    pub fn decode<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        bunser(&self.bser())
+    }
+}
+
+fn json_to_value(json: serde_json::Value, bytestring: bool) -> Value {
+    match json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Bool(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) if bytestring => Value::ByteString(s.into()),
+        serde_json::Value::String(s) => Value::Utf8String(s),
+        serde_json::Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|v| json_to_value(v, bytestring))
+                .collect(),
+        ),
+        serde_json::Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| {
+                    let bytestring = BYTESTRING_KEYS.contains(&k.as_str());
+                    (k, json_to_value(v, bytestring))
+                })
+                .collect::<HashMap<_, _>>(),
+        ),
+    }
+}
+
+/// Returns all of the PDUs in the golden corpus
+pub fn golden_pdus() -> impl Iterator<Item = GoldenPdu> {
+    CORPUS.iter().copied()
+}
+
+/// Returns the PDU named `name` from the golden corpus
+pub fn golden_pdu(name: &str) -> Option<GoldenPdu> {
+    golden_pdus().find(|pdu| pdu.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdu::*;
+    use crate::prelude::*;
+    use crate::test_support::{fake_subscription, FakeTransport};
+    use crate::{CanonicalPath, SubscriptionData};
+    use serde::Deserialize;
+
+    query_result_type! {
+        struct Details {
+            name: NameField,
+            exists: ExistsField,
+            file_type: FileTypeField,
+            hash: ContentSha1HexField,
+        }
+    }
+
+    fn golden(name: &str) -> GoldenPdu {
+        golden_pdu(name).unwrap_or_else(|| panic!("no golden PDU named {}", name))
+    }
+
+    #[test]
+    fn all_valid() {
+        assert!(golden_pdus().count() >= CORPUS.len());
+        for pdu in golden_pdus() {
+            let value: Value = pdu.decode().unwrap();
+            match value {
+                Value::Object(map) => assert!(map.contains_key("version"), "{}", pdu.name()),
+                _ => panic!("{} is not an object", pdu.name()),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_responses() {
+        let result: QueryResult<Details> = golden("query_large").decode().unwrap();
+        let files = result.files.unwrap();
+        assert_eq!(files.len(), 1500);
+        assert!(files.iter().all(|f| f.name.is_relative()));
+        assert!(files
+            .iter()
+            .any(|f| matches!(*f.file_type, FileType::Directory)));
+        assert!(files
+            .iter()
+            .any(|f| matches!(*f.hash, ContentSha1Hex::Error { .. })));
+        assert!(files
+            .iter()
+            .any(|f| !*f.exists && *f.hash == ContentSha1Hex::None));
+
+        let result: QueryResult<Details> = golden("query_scm_since").decode().unwrap();
+        match result.clock {
+            Clock::ScmAware(FatClockData { scm: Some(scm), .. }) => {
+                assert_eq!(scm.mergebase_with.as_deref(), Some("remote/master"));
+                assert!(scm.saved_state.unwrap().config.is_some());
+            }
+            clock => panic!("unexpected clock {:?}", clock),
+        }
+
+        let response: WatchProjectResponse = golden("watch_project").decode().unwrap();
+        assert_eq!(response.watch, std::path::Path::new("/home/user/repo"));
+        assert_eq!(
+            response.relative_path.as_deref(),
+            Some(std::path::Path::new("src/core"))
+        );
+        let response: ClockResponse = golden("clock").decode().unwrap();
+        assert!(matches!(response.clock, ClockSpec::StringClock(_)));
+        let response: SubscribeResponse = golden("subscribe").decode().unwrap();
+        assert_eq!(response.asserted_states, vec!["hg.update".to_string()]);
+        let _: UnsubscribeResponse = golden("unsubscribe").decode().unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscription_events() {
+        let (mut sub, feed) = fake_subscription::<Details>("/home/user/repo");
+        for name in &[
+            "subscription_fresh_instance",
+            "subscription_files",
+            "subscription_state_enter",
+            "subscription_state_leave",
+            "subscription_state_leave_abandoned",
+            "subscription_canceled",
+        ] {
+            feed.send_pdu(golden(name).value());
+        }
+
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                assert!(result.is_fresh_instance);
+                assert!(!result.files.unwrap().is_empty());
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                assert!(!result.is_fresh_instance);
+                assert_eq!(result.files.unwrap().len(), 2);
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+        match sub.next().await.unwrap() {
+            SubscriptionData::StateEnter {
+                state_name,
+                metadata,
+            } => {
+                assert_eq!(state_name, "hg.update");
+                assert!(metadata.is_some());
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::StateLeave {
+                metadata: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::StateLeave { metadata: None, .. }
+        ));
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::Canceled
+        ));
+    }
+
+    #[tokio::test]
+    async fn error_responses() {
+        let transport = FakeTransport::new()
+            .expect_command("watch-project")
+            .respond(golden("error_unable_to_resolve_root").value());
+        let client = transport.connect();
+        match client
+            .resolve_root(CanonicalPath::with_canonicalized_path(
+                "/home/user/missing".into(),
+            ))
+            .await
+        {
+            Err(crate::Error::WatchmanServerError { message, .. }) => {
+                assert!(message.starts_with("unable to resolve root"))
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        transport.assert_finished();
+    }
+}
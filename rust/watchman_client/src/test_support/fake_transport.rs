@@ -0,0 +1,403 @@
+//! A transport that plays back a scripted conversation.
+use crate::{bunser, Client, Connector};
+use serde_bser::de::{Bunser, SliceRead};
+use serde_bser::value::Value;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::prelude::*;
+
+type Check = Box<dyn Fn(&Value) -> Result<(), String> + Send>;
+
+enum Step {
+    /// Wait for the client to send a request and check it
+    Expect(Check),
+    /// Deliver these bytes to the client
+    Send(Vec<u8>),
+    /// Close the connection
+    Eof,
+}
+
+#[derive(Default)]
+struct Script {
+    steps: VecDeque<Step>,
+    /// Bytes written by the client that don't yet form a complete PDU
+    written: Vec<u8>,
+    /// Bytes from a `Send` step that haven't yet been read by the client
+    readable: VecDeque<u8>,
+    closed: bool,
+    read_waker: Option<Waker>,
+    failures: Vec<String>,
+    requests: usize,
+}
+
+impl Script {
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Check a complete request PDU against the script
+    fn process_request(&mut self, pdu: &[u8]) {
+        self.requests += 1;
+        let request = match bunser::<Value>(pdu) {
+            Ok(request) => request,
+            Err(err) => {
+                self.fail(format!(
+                    "request {} is not valid BSER: {}",
+                    self.requests, err
+                ));
+                return;
+            }
+        };
+        match self.steps.pop_front() {
+            Some(Step::Expect(check)) => {
+                if let Err(reason) = check(&request) {
+                    self.fail(format!("request {}: {}", self.requests, reason));
+                }
+            }
+            Some(step) => {
+                self.steps.push_front(step);
+                self.fail(format!(
+                    "unexpected request {}: {:?}",
+                    self.requests, request
+                ));
+            }
+            None => self.fail(format!(
+                "unexpected request {} after the end of the script: {:?}",
+                self.requests, request
+            )),
+        }
+    }
+
+    /// Record a failure and close the connection so that the client
+    /// doesn't wait for a response that will never arrive
+    fn fail(&mut self, reason: String) {
+        self.failures.push(reason);
+        self.closed = true;
+    }
+}
+
+/// A transport that delivers a scripted sequence of server output
+/// to a Client and checks the requests that the Client sends.
+///
+/// Unlike the [MockServer](struct.MockServer.html), the script is
+/// followed exactly: each `expect_*` step waits for the client to send
+/// a request and then checks it, and each `respond*` step delivers
+/// data to the client.  This allows simulating situations that a real
+/// server wouldn't normally produce, such as truncated or malformed PDUs.
+///
+/// ```
+/// use watchman_client::prelude::*;
+/// use watchman_client::test_support::{serialize_pdu, FakeTransport};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let truncated = serialize_pdu(serde_bser::value::Value::from("truncated"));
+/// let transport = FakeTransport::new()
+///     .expect_command("watch-project")
+///     .respond_raw(truncated[..truncated.len() - 2].to_vec())
+///     .eof();
+///
+/// let client = transport.connect();
+/// let result = client
+///     .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+///     .await;
+/// assert!(result.is_err());
+/// transport.assert_finished();
+/// # }
+/// ```
+///
+/// Requests that don't match the script are recorded and the connection
+/// is closed; use `assert_finished` at the end of the test to check that
+/// the whole script was consumed without any failures.
+pub struct FakeTransport {
+    script: Arc<Mutex<Script>>,
+}
+
+impl Default for FakeTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeTransport {
+    /// Create an empty script
+    pub fn new() -> Self {
+        Self {
+            script: Arc::new(Mutex::new(Script::default())),
+        }
+    }
+
+    fn script(&self) -> std::sync::MutexGuard<'_, Script> {
+        match self.script.lock() {
+            Ok(script) => script,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn step(self, step: Step) -> Self {
+        self.script().steps.push_back(step);
+        self
+    }
+
+    /// Wait for the next request and check it using `check`, which
+    /// returns an error describing the problem if the request is not
+    /// acceptable.
+    pub fn expect_with<F>(self, check: F) -> Self
+    where
+        F: Fn(&Value) -> Result<(), String> + Send + 'static,
+    {
+        self.step(Step::Expect(Box::new(check)))
+    }
+
+    /// Wait for the next request and check that it is exactly `expected`
+    pub fn expect_request(self, expected: Value) -> Self {
+        self.expect_with(move |request| {
+            if *request == expected {
+                Ok(())
+            } else {
+                Err(format!("expected {:?} but got {:?}", expected, request))
+            }
+        })
+    }
+
+    /// Wait for the next request and check that it is for `command`
+    pub fn expect_command(self, command: &str) -> Self {
+        let command = command.to_string();
+        self.expect_with(move |request| match request {
+            Value::Array(args) if args.first() == Some(&Value::from(command.as_str())) => Ok(()),
+            _ => Err(format!(
+                "expected a `{}` command but got {:?}",
+                command, request
+            )),
+        })
+    }
+
+    /// Deliver `pdu` to the client
+    pub fn respond(self, pdu: Value) -> Self {
+        self.respond_raw(serialize_pdu(pdu))
+    }
+
+    /// Deliver `data` to the client verbatim.
+    /// The data need not be a valid or complete PDU.
+    pub fn respond_raw(self, data: Vec<u8>) -> Self {
+        self.step(Step::Send(data))
+    }
+
+    /// Close the connection
+    pub fn eof(self) -> Self {
+        self.step(Step::Eof)
+    }
+
+    /// Returns the list of problems observed with the requests
+    /// received so far
+    pub fn failures(&self) -> Vec<String> {
+        self.script().failures.clone()
+    }
+
+    /// Panics if any requests failed to match the script, or if there
+    /// are steps in the script that have not yet been performed
+    pub fn assert_finished(&self) {
+        let script = self.script();
+        assert!(
+            script.failures.is_empty(),
+            "FakeTransport failures: {:#?}",
+            script.failures
+        );
+        assert!(
+            script.steps.is_empty(),
+            "FakeTransport has {} unperformed steps",
+            script.steps.len()
+        );
+    }
+
+    /// Returns a Client connected to this transport.
+    /// The script is shared with the returned client, so steps can
+    /// continue to be inspected via this FakeTransport.
+    pub fn connect(&self) -> Client {
+        self.connect_with(Connector::new())
+    }
+
+    /// Returns a Client connected to this transport, configured
+    /// using the supplied Connector.  The endpoint settings of the
+    /// Connector are ignored.
+    pub fn connect_with(&self, connector: Connector) -> Client {
+        connector.spawn_client(Box::new(self.stream()), None)
+    }
+
+    pub(crate) fn stream(&self) -> FakeStream {
+        FakeStream {
+            script: Arc::clone(&self.script),
+        }
+    }
+}
+
+/// Serialize a value as a BSER PDU.  This is useful together with
+/// `FakeTransport::respond_raw` to produce truncated PDUs.
+pub fn serialize_pdu(pdu: Value) -> Vec<u8> {
+    serde_bser::ser::serialize(Vec::new(), pdu).expect("serializing Value")
+}
+
+/// The stream end of a FakeTransport
+pub(crate) struct FakeStream {
+    script: Arc<Mutex<Script>>,
+}
+
+impl FakeStream {
+    fn script(&self) -> std::sync::MutexGuard<'_, Script> {
+        match self.script.lock() {
+            Ok(script) => script,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl AsyncRead for FakeStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let mut script = self.script();
+        loop {
+            if !script.readable.is_empty() {
+                let len = buf.len().min(script.readable.len());
+                for (dest, src) in buf.iter_mut().zip(script.readable.drain(..len)) {
+                    *dest = src;
+                }
+                return Poll::Ready(Ok(len));
+            }
+            if script.closed {
+                return Poll::Ready(Ok(0));
+            }
+            match script.steps.front() {
+                Some(Step::Send(_)) => {
+                    if let Some(Step::Send(data)) = script.steps.pop_front() {
+                        script.readable.extend(data);
+                    }
+                }
+                Some(Step::Eof) => {
+                    script.steps.pop_front();
+                    script.closed = true;
+                }
+                Some(Step::Expect(_)) | None => {
+                    script.read_waker = Some(ctx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for FakeStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _ctx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let mut script = self.script();
+        if script.closed {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        script.written.extend_from_slice(buf);
+
+        loop {
+            let total = match Bunser::new(SliceRead::new(&script.written)).read_pdu() {
+                Ok(info) => (info.start + info.len) as usize,
+                // We don't have a complete header yet
+                Err(_) => break,
+            };
+            if script.written.len() < total {
+                break;
+            }
+            let pdu: Vec<u8> = script.written.drain(..total).collect();
+            script.process_request(&pdu);
+        }
+
+        script.wake_reader();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::Error;
+    use maplit::hashmap;
+
+    fn root() -> CanonicalPath {
+        CanonicalPath::with_canonicalized_path("/repo".into())
+    }
+
+    fn watch_project_response() -> Value {
+        hashmap! {
+            "version".to_string() => Value::from("fake"),
+            "watch".to_string() => Value::from("/repo"),
+            "watcher".to_string() => Value::from("fake"),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn scripted_conversation() {
+        let transport = FakeTransport::new()
+            .expect_request(vec!["watch-project".into(), "/repo".into()].into())
+            .respond(watch_project_response());
+        let client = transport.connect();
+        let resolved = client.resolve_root(root()).await.unwrap();
+        assert_eq!(resolved.watcher(), "fake");
+        transport.assert_finished();
+    }
+
+    #[tokio::test]
+    async fn eof_mid_response() {
+        let response = serialize_pdu(watch_project_response());
+        let transport = FakeTransport::new()
+            .expect_command("watch-project")
+            .respond_raw(response[..response.len() / 2].to_vec())
+            .eof();
+        let client = transport.connect();
+        match client.resolve_root(root()).await {
+            Err(Error::Generic(message)) => assert_eq!(message, Error::Eof.to_string()),
+            result => panic!("unexpected {:?}", result),
+        }
+        transport.assert_finished();
+
+        // The client is no longer usable
+        assert!(client.resolve_root(root()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_frame() {
+        let transport = FakeTransport::new()
+            .expect_command("watch-project")
+            .respond_raw(b"not a bser pdu at all".to_vec());
+        let client = transport.connect();
+        assert!(client.resolve_root(root()).await.is_err());
+        transport.assert_finished();
+    }
+
+    #[tokio::test]
+    async fn unexpected_request() {
+        let transport = FakeTransport::new()
+            .expect_command("clock")
+            .respond(watch_project_response());
+        let client = transport.connect();
+        assert!(client.resolve_root(root()).await.is_err());
+        let failures = transport.failures();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].contains("expected a `clock` command"));
+    }
+}
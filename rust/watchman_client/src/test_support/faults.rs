@@ -0,0 +1,312 @@
+//! Deterministic fault injection for client connections.
+use super::mock_server::MockServer;
+use crate::{Client, Connector, ReadWriteStream};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::prelude::*;
+use tokio::time::Delay;
+
+/// Describes the faults to inject into a connection.
+///
+/// Faults are applied to the client's end of the connection: reads
+/// see data as it arrives from the server and writes see the requests
+/// sent by the client.
+/// Randomized faults are driven by a seeded generator, so a given
+/// `Faults` configuration produces the same sequence of faults each
+/// time it is used, provided the traffic is the same.
+///
+/// ```
+/// use watchman_client::prelude::*;
+/// use watchman_client::test_support::{Faults, MockServer};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let server = MockServer::new();
+/// // Close the connection just after the first response starts to arrive
+/// let client = server.connect_with_faults(Connector::new(), Faults::new().eof_after(10));
+/// let result = client
+///     .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+///     .await;
+/// assert!(result.is_err());
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Faults {
+    seed: u64,
+    max_write: Option<usize>,
+    read_delay: Option<Duration>,
+    eof_after: Option<u64>,
+    eof_probability: f64,
+    corrupt_at: Vec<u64>,
+    corrupt_probability: f64,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Faults {
+    /// Create a configuration that injects no faults
+    pub fn new() -> Self {
+        Self {
+            seed: 0x9e37_79b9_7f4a_7c15,
+            max_write: None,
+            read_delay: None,
+            eof_after: None,
+            eof_probability: 0.0,
+            corrupt_at: vec![],
+            corrupt_probability: 0.0,
+        }
+    }
+
+    /// Set the seed used for the randomized faults
+    pub fn seed(mut self, seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        self.seed = seed.max(1);
+        self
+    }
+
+    /// Accept at most `max` bytes per write, choosing a random size
+    /// between 1 and `max` for each write, so that requests are
+    /// delivered in fragments.
+    pub fn partial_writes(mut self, max: usize) -> Self {
+        self.max_write = Some(max.max(1));
+        self
+    }
+
+    /// Delay each read by `delay`
+    pub fn delay_reads(mut self, delay: Duration) -> Self {
+        self.read_delay = Some(delay);
+        self
+    }
+
+    /// Report EOF once `bytes` bytes have been read from the server
+    pub fn eof_after(mut self, bytes: u64) -> Self {
+        self.eof_after = Some(bytes);
+        self
+    }
+
+    /// Report EOF on each read with the given probability
+    pub fn random_eof(mut self, probability: f64) -> Self {
+        self.eof_probability = probability;
+        self
+    }
+
+    /// Corrupt the byte at `offset` in the data read from the server.
+    /// This can be called multiple times to corrupt several bytes.
+    pub fn corrupt_byte(mut self, offset: u64) -> Self {
+        self.corrupt_at.push(offset);
+        self
+    }
+
+    /// Corrupt a random byte of each read with the given probability
+    pub fn random_corruption(mut self, probability: f64) -> Self {
+        self.corrupt_probability = probability;
+        self
+    }
+
+    pub(crate) fn wrap<S: ReadWriteStream>(self, stream: S) -> FaultyStream<S> {
+        FaultyStream {
+            rng: self.seed,
+            faults: self,
+            stream,
+            delay: None,
+            read_offset: 0,
+            eof: false,
+        }
+    }
+}
+
+impl MockServer {
+    /// Returns a new Client connected to this server, configured
+    /// using the supplied Connector, with `faults` injected into the
+    /// connection.
+    pub fn connect_with_faults(&self, connector: Connector, faults: Faults) -> Client {
+        let stream = self.serve_connection();
+        connector.spawn_client(Box::new(faults.wrap(stream)), None)
+    }
+}
+
+/// A stream that injects faults into the traffic of the stream
+/// that it wraps
+pub(crate) struct FaultyStream<S> {
+    faults: Faults,
+    stream: S,
+    rng: u64,
+    delay: Option<Delay>,
+    read_offset: u64,
+    eof: bool,
+}
+
+impl<S> FaultyStream<S> {
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Returns true with the given probability
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && (self.next_random() as f64 / u64::MAX as f64) < probability
+    }
+}
+
+impl<S: ReadWriteStream> AsyncRead for FaultyStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        if self.eof {
+            return Poll::Ready(Ok(0));
+        }
+
+        if let Some(read_delay) = self.faults.read_delay {
+            let delay = self
+                .delay
+                .get_or_insert_with(|| tokio::time::delay_for(read_delay));
+            match Pin::new(delay).poll(ctx) {
+                Poll::Ready(()) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let eof_probability = self.faults.eof_probability;
+        if self.chance(eof_probability) {
+            self.eof = true;
+            return Poll::Ready(Ok(0));
+        }
+
+        // Don't read beyond the point at which we will report EOF
+        let limit = match self.faults.eof_after {
+            Some(eof_after) => {
+                let remaining = eof_after.saturating_sub(self.read_offset) as usize;
+                if remaining == 0 {
+                    self.eof = true;
+                    return Poll::Ready(Ok(0));
+                }
+                remaining.min(buf.len())
+            }
+            None => buf.len(),
+        };
+
+        let len = match Pin::new(&mut self.stream).poll_read(ctx, &mut buf[..limit]) {
+            Poll::Ready(Ok(len)) => len,
+            other => return other,
+        };
+        self.delay = None;
+
+        let start = self.read_offset;
+        self.read_offset += len as u64;
+        let end = self.read_offset;
+        for offset in self.faults.corrupt_at.clone() {
+            if offset >= start && offset < end {
+                buf[(offset - start) as usize] ^= 0xff;
+            }
+        }
+        let corrupt_probability = self.faults.corrupt_probability;
+        if len > 0 && self.chance(corrupt_probability) {
+            let index = (self.next_random() % len as u64) as usize;
+            buf[index] ^= 0xff;
+        }
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl<S: ReadWriteStream> AsyncWrite for FaultyStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let len = match self.faults.max_write {
+            Some(max) => 1 + (self.next_random() as usize % max),
+            None => buf.len(),
+        };
+        let len = len.min(buf.len());
+        Pin::new(&mut self.stream).poll_write(ctx, &buf[..len])
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.stream).poll_flush(ctx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::Error;
+
+    fn root() -> CanonicalPath {
+        CanonicalPath::with_canonicalized_path("/repo".into())
+    }
+
+    #[tokio::test]
+    async fn tolerates_fragmentation_and_latency() {
+        let server = MockServer::new();
+        let client = server.connect_with_faults(
+            Connector::new(),
+            Faults::new()
+                .partial_writes(3)
+                .delay_reads(Duration::from_millis(1)),
+        );
+        let resolved = client.resolve_root(root()).await.unwrap();
+        client.clock(&resolved, SyncTimeout::Default).await.unwrap();
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn eof() {
+        let server = MockServer::new();
+        let client = server.connect_with_faults(Connector::new(), Faults::new().eof_after(0));
+        match client.resolve_root(root()).await {
+            Err(Error::Generic(message)) => assert_eq!(message, Error::Eof.to_string()),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupted_frame() {
+        let server = MockServer::new();
+        // Offset 0 is the first byte of the BSER magic
+        let client = server.connect_with_faults(Connector::new(), Faults::new().corrupt_byte(0));
+        assert!(client.resolve_root(root()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn random_faults_are_survivable() {
+        for seed in 1..10 {
+            let server = MockServer::new();
+            let client = server
+                .connect_with_faults(
+                    Connector::new(),
+                    Faults::new()
+                        .seed(seed)
+                        .partial_writes(8)
+                        .random_eof(0.05)
+                        .random_corruption(0.2),
+                )
+                .request_timeout(Duration::from_millis(50));
+            // Each request must succeed or fail promptly; a corrupted
+            // length can leave the client waiting, which the request
+            // timeout turns into an error
+            for _ in 0..3 {
+                client.resolve_root(root()).await.ok();
+            }
+        }
+    }
+}
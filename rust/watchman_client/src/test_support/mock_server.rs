@@ -0,0 +1,667 @@
+//! An in-process stand-in for the watchman server.
+//!
+//! The `MockServer` speaks BSER over an in-memory stream.
+//! It has canned responses for the basic commands (`watch-project`,
+//! `clock`, `subscribe`, `unsubscribe` and `version`) that can be
+//! overridden or supplemented, and it can push unilateral PDUs to
+//! connected clients to simulate subscription traffic.
+use crate::{bunser, Client, Connector, PduBuffer, ReadWriteStream};
+use maplit::hashmap;
+use serde_bser::value::Value;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::prelude::*;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// The version string that the MockServer reports in its responses
+pub const MOCK_VERSION: &str = "mock";
+
+/// One end of an in-memory, bidirectional byte stream, such as the
+/// client end of a connection returned by `MockServer::stream`
+pub struct DuplexStream {
+    tx: Option<UnboundedSender<Vec<u8>>>,
+    rx: UnboundedReceiver<Vec<u8>>,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl std::fmt::Debug for DuplexStream {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("DuplexStream")
+            .field("closed", &self.tx.is_none())
+            .field("pending", &(self.pending.len() - self.pos))
+            .finish()
+    }
+}
+
+/// Create a connected pair of in-memory streams
+pub(crate) fn duplex() -> (DuplexStream, DuplexStream) {
+    let (a_tx, a_rx) = unbounded_channel();
+    let (b_tx, b_rx) = unbounded_channel();
+    let a = DuplexStream {
+        tx: Some(a_tx),
+        rx: b_rx,
+        pending: vec![],
+        pos: 0,
+    };
+    let b = DuplexStream {
+        tx: Some(b_tx),
+        rx: a_rx,
+        pending: vec![],
+        pos: 0,
+    };
+    (a, b)
+}
+
+impl AsyncRead for DuplexStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        if self.pos == self.pending.len() {
+            match self.rx.poll_recv(ctx) {
+                Poll::Ready(Some(data)) => {
+                    self.pending = data;
+                    self.pos = 0;
+                }
+                // The other end was dropped or shut down
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.len().min(self.pending.len() - self.pos);
+        buf[..len].copy_from_slice(&self.pending[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _ctx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let sent = self
+            .tx
+            .as_mut()
+            .map(|tx| tx.send(buf.to_vec()).is_ok())
+            .unwrap_or(false);
+        if sent {
+            Poll::Ready(Ok(buf.len()))
+        } else {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        _ctx: &mut Context,
+    ) -> Poll<Result<(), std::io::Error>> {
+        self.tx.take();
+        Poll::Ready(Ok(()))
+    }
+}
+
+type Handler = Arc<dyn Fn(&Value) -> Value + Send + Sync>;
+
+/// Messages processed by the writer half of a mock connection
+enum Outgoing {
+    Pdu(Vec<u8>),
+    Close,
+}
+
+#[derive(Default)]
+struct MockState {
+    handlers: HashMap<String, Handler>,
+    once: HashMap<String, VecDeque<Value>>,
+    requests: Vec<Value>,
+    connections: Vec<UnboundedSender<Outgoing>>,
+}
+
+impl MockState {
+    /// Compute the response to a request PDU
+    fn respond(&mut self, request: Value) -> Value {
+        let command = command_name(&request);
+        self.requests.push(request.clone());
+        if let Some(response) = self
+            .once
+            .get_mut(&command)
+            .and_then(|responses| responses.pop_front())
+        {
+            return response;
+        }
+        match self.handlers.get(&command) {
+            Some(handler) => handler(&request),
+            None => error_response(&format!(
+                "MockServer has no response for command `{}`",
+                command
+            )),
+        }
+    }
+}
+
+/// Returns the command name from a request PDU
+fn command_name(request: &Value) -> String {
+    match request {
+        Value::Array(args) => match args.first() {
+            Some(Value::Utf8String(s)) => s.clone(),
+            Some(Value::ByteString(s)) => s.as_escaped_string(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+/// Returns the positional argument at `index` from a request PDU,
+/// or `Value::Null` if it is not present
+pub fn request_arg(request: &Value, index: usize) -> Value {
+    match request {
+        Value::Array(args) => args.get(index).cloned().unwrap_or(Value::Null),
+        _ => Value::Null,
+    }
+}
+
+/// Construct a response PDU that will be reported by the client
+/// as `Error::WatchmanServerError`
+pub fn error_response(message: &str) -> Value {
+    hashmap! {
+        "version".to_string() => Value::from(MOCK_VERSION),
+        "error".to_string() => Value::from(message),
+    }
+    .into()
+}
+
+/// An in-process fake watchman server.
+///
+/// ```
+/// use watchman_client::prelude::*;
+/// use watchman_client::test_support::MockServer;
+/// use serde_bser::value::Value;
+/// use maplit::hashmap;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let server = MockServer::new();
+/// server.respond("query", |_request| {
+///     hashmap! {
+///         "version".to_string() => Value::from("mock"),
+///         "clock".to_string() => Value::from("c:1:1"),
+///         "files".to_string() => Value::Array(vec!["foo.rs".into()]),
+///     }
+///     .into()
+/// });
+///
+/// let client = server.connect();
+/// let root = client
+///     .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+///     .await?;
+/// let files = client.glob(&root, &["**/*.rs"]).await?;
+/// assert_eq!(files, vec![std::path::PathBuf::from("foo.rs")]);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// `MockServer` must be used from within a tokio runtime as each
+/// connection is serviced by spawned tasks.
+pub struct MockServer {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockServer {
+    /// Create a server with canned responses for the `watch-project`,
+    /// `clock`, `subscribe`, `unsubscribe`, `state-enter`, `state-leave`,
+    /// `log-level` and `version` commands.  The response to `version` claims every
+    /// capability that it is asked about.  The state transitions are
+    /// not broadcast to the subscriptions; use `push` for that.
+    /// Use `serve_files` to add a canned response for `query`.
+    pub fn new() -> Self {
+        let server = Self {
+            state: Arc::new(Mutex::new(MockState::default())),
+        };
+
+        server.respond("version", |request| {
+            // Claim to have every capability that was asked about
+            let mut capabilities = HashMap::new();
+            if let Value::Object(params) = request_arg(request, 1) {
+                for asked in params.into_values() {
+                    if let Value::Array(asked) = asked {
+                        for capability in asked {
+                            if let Value::Utf8String(capability) = capability {
+                                capabilities.insert(capability, Value::Bool(true));
+                            }
+                        }
+                    }
+                }
+            }
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "capabilities".to_string() => capabilities.into(),
+            }
+            .into()
+        });
+        server.respond("watch-project", |request| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "watch".to_string() => request_arg(request, 1),
+                "watcher".to_string() => Value::from("mock"),
+            }
+            .into()
+        });
+        let tick = Arc::new(AtomicUsize::new(1));
+        server.respond("clock", move |_| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "clock".to_string() => Value::from(
+                    format!("c:0:{}", tick.fetch_add(1, Ordering::SeqCst))
+                ),
+            }
+            .into()
+        });
+        server.respond("subscribe", |request| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "subscribe".to_string() => request_arg(request, 2),
+                "clock".to_string() => Value::from("c:0:0"),
+            }
+            .into()
+        });
+        server.respond("unsubscribe", |request| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "unsubscribe".to_string() => request_arg(request, 2),
+            }
+            .into()
+        });
+        server.respond("log-level", |request| {
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "log_level".to_string() => request_arg(request, 1),
+            }
+            .into()
+        });
+        for command in &["state-enter", "state-leave"] {
+            let command = command.to_string();
+            server.respond(&command.clone(), move |request| {
+                // The state is either named by the third element, or
+                // by the `name` field of an object there
+                let name = match request_arg(request, 2) {
+                    Value::Object(params) => params.get("name").cloned().unwrap_or(Value::Null),
+                    name => name,
+                };
+                hashmap! {
+                    "version".to_string() => Value::from(MOCK_VERSION),
+                    "root".to_string() => request_arg(request, 1),
+                    command.clone() => name,
+                }
+                .into()
+            });
+        }
+
+        server
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Set the handler used to produce the response to `command`.
+    /// The handler is passed the request PDU, which is an array whose
+    /// first element is the command name.
+    /// This replaces any existing handler for `command`.
+    pub fn respond<F>(&self, command: &str, handler: F)
+    where
+        F: Fn(&Value) -> Value + Send + Sync + 'static,
+    {
+        self.state()
+            .handlers
+            .insert(command.to_string(), Arc::new(handler));
+    }
+
+    /// Queue up a response for the next `command` request.
+    /// Queued responses take precedence over the handler set by
+    /// `respond` and are used in the order that they were queued.
+    pub fn respond_once(&self, command: &str, response: Value) {
+        self.state()
+            .once
+            .entry(command.to_string())
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Respond to `query` and `find` requests with a result listing
+    /// `files`, as though the watched root contained exactly those files.
+    /// The query expression, generators and `since` clock are not
+    /// evaluated, nor are the patterns given to `find`; every request
+    /// yields all of the files.
+    /// The fields requested by the query are populated with plausible
+    /// values for an empty regular file.
+    pub fn serve_files<P: AsRef<Path>>(&self, files: &[P]) {
+        let files: Vec<PathBuf> = files.iter().map(|f| f.as_ref().to_path_buf()).collect();
+        let found = files.clone();
+        self.respond("find", move |_| {
+            let files = found
+                .iter()
+                .map(|file| {
+                    let name = Value::from(file.to_string_lossy().into_owned());
+                    FIND_FIELDS
+                        .iter()
+                        .map(|&field| (field.to_string(), file_field(field, name.clone())))
+                        .collect::<HashMap<_, _>>()
+                        .into()
+                })
+                .collect();
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "clock".to_string() => Value::from("c:0:0"),
+                "files".to_string() => Value::Array(files),
+            }
+            .into()
+        });
+        let tick = AtomicUsize::new(1);
+        self.respond("query", move |request| {
+            let fields = match request_arg(request, 2) {
+                Value::Object(mut query) => match query.remove("fields") {
+                    Some(Value::Array(fields)) => fields,
+                    _ => vec![],
+                },
+                _ => vec![],
+            };
+            let fields: Vec<String> = fields
+                .into_iter()
+                .filter_map(|f| match f {
+                    Value::Utf8String(s) => Some(s),
+                    _ => None,
+                })
+                .collect();
+            let files = files
+                .iter()
+                .map(|file| {
+                    let name = Value::from(file.to_string_lossy().into_owned());
+                    if fields.len() == 1 && fields[0] == "name" {
+                        // The server sends just the names in this case
+                        name
+                    } else {
+                        fields
+                            .iter()
+                            .map(|field| (field.clone(), file_field(field, name.clone())))
+                            .collect::<HashMap<_, _>>()
+                            .into()
+                    }
+                })
+                .collect();
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "clock".to_string() => Value::from(
+                    format!("c:0:{}", tick.fetch_add(1, Ordering::SeqCst))
+                ),
+                "is_fresh_instance".to_string() => Value::Bool(true),
+                "files".to_string() => Value::Array(files),
+            }
+            .into()
+        });
+    }
+
+    /// Returns the list of requests received by the server so far
+    pub fn requests(&self) -> Vec<Value> {
+        self.state().requests.clone()
+    }
+
+    /// Send a unilateral PDU to all connected clients
+    pub fn push(&self, pdu: Value) {
+        let data = serde_bser::ser::serialize(Vec::new(), pdu).expect("serializing Value");
+        self.state()
+            .connections
+            .retain(|conn| conn.send(Outgoing::Pdu(data.clone())).is_ok());
+    }
+
+    /// Close all connections, as though the server had been shut down.
+    /// The server remains usable for subsequent connections.
+    pub fn disconnect_all(&self) {
+        for conn in self.state().connections.drain(..) {
+            conn.send(Outgoing::Close).ok();
+        }
+    }
+
+    /// Returns a new Client connected to this server
+    pub fn connect(&self) -> Client {
+        self.connect_with(Connector::new())
+    }
+
+    /// Returns a new Client connected to this server, configured
+    /// using the supplied Connector.  The endpoint settings of the
+    /// Connector are ignored.  If `Connector::reconnect` is enabled,
+    /// the client reconnects to this server after `disconnect_all`.
+    pub fn connect_with(&self, mut connector: Connector) -> Client {
+        let server = MockServer {
+            state: Arc::clone(&self.state),
+        };
+        connector.dialer = Some(Arc::new(move || {
+            let stream: Box<dyn ReadWriteStream> = Box::new(server.serve_connection());
+            Box::pin(async move { Ok(stream) })
+        }));
+        connector.with_stream(self.serve_connection())
+    }
+
+    /// Returns the client end of a new connection to this server, for
+    /// use with `Connector::with_stream`.
+    /// Prefer `connect_with`, which also lets the client reconnect.
+    pub fn stream(&self) -> DuplexStream {
+        self.serve_connection()
+    }
+
+    /// Returns the client end of a new connection to this server
+    pub(crate) fn serve_connection(&self) -> DuplexStream {
+        let (client, server) = duplex();
+        self.serve(server);
+        client
+    }
+
+    /// Spawn the tasks that service a connection
+    fn serve(&self, stream: DuplexStream) {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (tx, mut rx) = unbounded_channel();
+        self.state().connections.push(tx.clone());
+
+        tokio::spawn(async move {
+            while let Some(outgoing) = rx.recv().await {
+                match outgoing {
+                    Outgoing::Pdu(data) => {
+                        if writer.write_all(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                    Outgoing::Close => break,
+                }
+            }
+            writer.shutdown().await.ok();
+        });
+
+        let state = Arc::clone(&self.state);
+        tokio::spawn(async move {
+            let mut buf = PduBuffer::new();
+            while let Ok(pdu) = buf.read_pdu(&mut reader).await {
+                let response = match bunser::<Value>(&pdu) {
+                    Ok(request) => match state.lock() {
+                        Ok(mut state) => state.respond(request),
+                        Err(poisoned) => poisoned.into_inner().respond(request),
+                    },
+                    Err(err) => error_response(&err.to_string()),
+                };
+                let data =
+                    serde_bser::ser::serialize(Vec::new(), response).expect("serializing Value");
+                if tx.send(Outgoing::Pdu(data)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// The fields that the server reports for each file found by `find`
+const FIND_FIELDS: &[&str] = &[
+    "name", "exists", "size", "mode", "uid", "gid", "mtime", "ctime", "ino", "dev", "nlink", "new",
+    "cclock", "oclock",
+];
+
+/// Returns a plausible value for `field` of an empty regular file
+fn file_field(field: &str, name: Value) -> Value {
+    match field {
+        "name" => name,
+        "exists" => Value::Bool(true),
+        "new" => Value::Bool(false),
+        "type" => Value::from("f"),
+        "size" | "ctime" | "ctime_ms" | "ctime_us" | "ctime_ns" | "mtime" | "mtime_ms"
+        | "mtime_us" | "mtime_ns" | "ino" | "dev" | "uid" | "gid" => Value::Integer(0),
+        "ctime_f" | "mtime_f" => Value::Real(0.0),
+        "mode" => Value::Integer(0o100644),
+        "nlink" => Value::Integer(1),
+        "cclock" | "oclock" => Value::from("c:0:0"),
+        // The SHA1 of empty content
+        "content.sha1hex" => Value::from("da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+        _ => Value::Null,
+    }
+}
+
+/// Construct a unilateral subscription PDU reporting that `files` have
+/// changed, suitable for passing to `MockServer::push`.
+/// `files` should match the shape of the field list requested by the
+/// subscription: a list of names for `NameOnly`, or a list of objects
+/// otherwise.
+pub fn subscription_pdu(subscription: &str, clock: &str, files: Vec<Value>) -> Value {
+    hashmap! {
+        "version".to_string() => Value::from(MOCK_VERSION),
+        "unilateral".to_string() => Value::Bool(true),
+        "subscription".to_string() => Value::from(subscription),
+        "clock".to_string() => Value::from(clock),
+        "files".to_string() => Value::Array(files),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::SubscriptionData;
+    use serde::Deserialize;
+
+    fn root() -> CanonicalPath {
+        CanonicalPath::with_canonicalized_path("/repo".into())
+    }
+
+    #[tokio::test]
+    async fn canned_responses() {
+        let server = MockServer::new();
+        let client = server.connect();
+
+        let resolved = client.resolve_root(root()).await.unwrap();
+        assert_eq!(resolved.project_root(), std::path::Path::new("/repo"));
+        assert_eq!(resolved.watcher(), "mock");
+
+        server.respond_once("clock", error_response("out of cookies"));
+        match client.clock(&resolved, SyncTimeout::Default).await {
+            Err(crate::Error::WatchmanServerError { message, .. }) => {
+                assert_eq!(message, "out of cookies")
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        client.clock(&resolved, SyncTimeout::Default).await.unwrap();
+
+        match client.glob(&resolved, &["*.rs"]).await {
+            Err(crate::Error::WatchmanServerError { message, .. }) => {
+                assert!(message.contains("`query`"), "{}", message)
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+
+        let commands: Vec<String> = server.requests().iter().map(command_name).collect();
+        assert_eq!(commands, vec!["watch-project", "clock", "clock", "query"]);
+    }
+
+    #[tokio::test]
+    async fn serves_files() {
+        let server = MockServer::new();
+        server.serve_files(&["src/lib.rs", "README.md"]);
+        let client = server.connect();
+        let resolved = client.resolve_root(root()).await.unwrap();
+
+        let files = client.glob(&resolved, &["**/*"]).await.unwrap();
+        assert_eq!(files, vec![PathBuf::from("src/lib.rs"), "README.md".into()]);
+
+        query_result_type! {
+            struct NameAndHash {
+                name: NameField,
+                hash: ContentSha1HexField,
+            }
+        }
+        let result: QueryResult<NameAndHash> = client
+            .query(&resolved, QueryRequestCommon::default())
+            .await
+            .unwrap();
+        let files = result.files.unwrap();
+        assert_eq!(*files[1].name, PathBuf::from("README.md"));
+        assert!(matches!(*files[1].hash, ContentSha1Hex::Hash(_)));
+    }
+
+    #[tokio::test]
+    async fn pushes_subscription_data() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let resolved = client.resolve_root(root()).await.unwrap();
+
+        let (mut sub, _) = client
+            .subscribe::<NameOnly>(&resolved, SubscribeRequest::default())
+            .await
+            .unwrap();
+
+        server.push(subscription_pdu(
+            sub.name(),
+            "c:0:2",
+            vec!["foo.rs".into(), "bar.rs".into()],
+        ));
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                let names: Vec<PathBuf> = result
+                    .files
+                    .unwrap()
+                    .into_iter()
+                    .map(|f| f.name.into_inner())
+                    .collect();
+                assert_eq!(names, vec![PathBuf::from("foo.rs"), "bar.rs".into()]);
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let server = MockServer::new();
+        let client = server.connect();
+        let resolved = client.resolve_root(root()).await.unwrap();
+        let (mut sub, _) = client
+            .subscribe::<NameOnly>(&resolved, SubscribeRequest::default())
+            .await
+            .unwrap();
+        server.disconnect_all();
+        assert!(sub.next().await.is_err());
+    }
+}
@@ -0,0 +1,35 @@
+//! Utilities for testing code that uses this crate.
+//!
+//! This module is available when the `test-support` feature is enabled.
+//!
+//! * [MockServer](struct.MockServer.html) is an in-process stand-in for
+//!   the watchman server, with programmable responses and the ability
+//!   to push unilateral subscription PDUs to its clients.
+//!   Clients are connected to it via `MockServer::connect`, or by
+//!   passing `MockServer::stream` to `Connector::with_stream`.
+//! * [FakeTransport](struct.FakeTransport.html) plays back a fixed
+//!   script of server output and checks the requests that it receives,
+//!   which is useful for exercising error paths such as truncated or
+//!   malformed responses.
+//! * [Faults](struct.Faults.html) injects partial writes, delayed reads,
+//!   EOF and corruption into a `MockServer` connection.
+//! * [fake_subscription](fn.fake_subscription.html) creates a
+//!   `Subscription` whose results are injected directly by the test,
+//!   for unit testing subscription event handling.
+//! * [golden_pdus](fn.golden_pdus.html) returns a corpus of realistic
+//!   server PDUs for validating deserialization.
+//! * [TempWatchmanInstance](struct.TempWatchmanInstance.html) spawns
+//!   an isolated, real watchman server for integration tests.
+mod corpus;
+mod fake_transport;
+mod faults;
+mod mock_server;
+mod subscription_feed;
+mod temp_instance;
+
+pub use corpus::*;
+pub use fake_transport::*;
+pub use faults::*;
+pub use mock_server::*;
+pub use subscription_feed::*;
+pub use temp_instance::*;
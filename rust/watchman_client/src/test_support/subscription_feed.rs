@@ -0,0 +1,265 @@
+//! Feed synthetic data directly into a `Subscription`.
+use super::mock_server::{MockServer, MOCK_VERSION};
+use super::request_arg;
+use crate::backpressure::PduSender;
+use crate::{QueryFieldList, ReceivedPdu, ResolvedRoot, Subscription};
+use maplit::hashmap;
+use serde_bser::value::Value;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Used to make the subscription names unique within a process
+static FEED_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Create a `Subscription` together with a `SubscriptionFeed` that
+/// controls exactly what the subscription yields.
+///
+/// This is intended for unit testing event handling logic: each call
+/// to a `SubscriptionFeed` method queues exactly one item for
+/// `Subscription::next`, in order, without any server round trip.
+///
+/// ```
+/// use watchman_client::prelude::*;
+/// use watchman_client::test_support::fake_subscription;
+/// use watchman_client::SubscriptionData;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (mut sub, feed) = fake_subscription::<NameOnly>("/repo");
+/// feed.fresh_instance(vec!["a.txt".into()]);
+/// feed.state_enter("hg.update", None);
+/// feed.canceled();
+///
+/// match sub.next().await? {
+///     SubscriptionData::FilesChanged(result) => assert!(result.is_fresh_instance),
+///     data => panic!("unexpected {:?}", data),
+/// }
+/// assert!(matches!(sub.next().await?, SubscriptionData::StateEnter { .. }));
+/// assert!(matches!(sub.next().await?, SubscriptionData::Canceled));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The subscription is associated with a client connected to a
+/// [MockServer](struct.MockServer.html), so `Subscription::cancel`
+/// works as normal; use `SubscriptionFeed::was_unsubscribed` to check
+/// that it was called.
+/// This function must be called from within a tokio runtime.
+pub fn fake_subscription<F>(root: impl AsRef<Path>) -> (Subscription<F>, SubscriptionFeed)
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+{
+    let name = format!("fake-sub-{}", FEED_ID.fetch_add(1, Ordering::Relaxed));
+    let server = MockServer::new();
+    let client = server.connect();
+    let (tx, responses) = crate::backpressure::channel(None);
+
+    let subscription = Subscription {
+        name: name.clone(),
+        inner: Arc::clone(&client.inner),
+        root: ResolvedRoot {
+            root: root.as_ref().to_path_buf(),
+            relative: None,
+            watcher: "mock".to_string(),
+        },
+        responses,
+        decode_errors: Default::default(),
+        stats: Default::default(),
+        asserted_states: Default::default(),
+        consolidation: None,
+        debounce: None,
+        metrics: None,
+        clock: None,
+        _phantom: PhantomData,
+    };
+    let feed = SubscriptionFeed {
+        name,
+        tx,
+        server,
+        tick: AtomicUsize::new(1),
+    };
+    (subscription, feed)
+}
+
+/// Controls the data yielded by a `Subscription` created via
+/// [fake_subscription](fn.fake_subscription.html).
+///
+/// Dropping the feed causes subsequent calls to `Subscription::next`
+/// to fail once any queued items have been consumed, as though the
+/// connection to the server had been lost.
+pub struct SubscriptionFeed {
+    name: String,
+    tx: PduSender,
+    server: MockServer,
+    tick: AtomicUsize,
+}
+
+impl std::fmt::Debug for SubscriptionFeed {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("SubscriptionFeed")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl SubscriptionFeed {
+    /// Returns the name of the associated subscription
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queue a raw subscription PDU.
+    /// The PDU must deserialize as a `QueryResult`, so it needs at
+    /// least `version` and `clock` fields.
+    pub fn send_pdu(&self, pdu: Value) {
+        let data = serde_bser::ser::serialize(Vec::new(), pdu).expect("serializing Value");
+        // The subscription may already have been dropped, which
+        // is fine: there is nobody left to observe the data
+        let pdu = ReceivedPdu {
+            received: std::time::Instant::now(),
+            data: data.into(),
+        };
+        self.tx.send(pdu).ok();
+    }
+
+    /// Returns a PDU with the common fields populated and a clock
+    /// value that advances with each call
+    fn pdu(&self) -> std::collections::HashMap<String, Value> {
+        hashmap! {
+            "version".to_string() => Value::from(MOCK_VERSION),
+            "unilateral".to_string() => Value::Bool(true),
+            "subscription".to_string() => Value::from(self.name.as_str()),
+            "clock".to_string() => Value::from(
+                format!("c:0:{}", self.tick.fetch_add(1, Ordering::SeqCst))
+            ),
+        }
+    }
+
+    fn files_pdu(&self, files: Vec<Value>, fresh_instance: bool) {
+        let mut pdu = self.pdu();
+        pdu.insert("files".to_string(), Value::Array(files));
+        pdu.insert("is_fresh_instance".to_string(), Value::Bool(fresh_instance));
+        self.send_pdu(pdu.into());
+    }
+
+    /// Queue a `SubscriptionData::FilesChanged` result for `files`.
+    /// `files` should match the shape of the field list of the
+    /// subscription: a list of names for `NameOnly`, or a list of
+    /// objects otherwise.
+    pub fn files_changed(&self, files: Vec<Value>) {
+        self.files_pdu(files, false);
+    }
+
+    /// Queue a `SubscriptionData::FilesChanged` result for `files`
+    /// that has `is_fresh_instance` set, as happens for the initial
+    /// result of a subscription or after the server has recrawled.
+    pub fn fresh_instance(&self, files: Vec<Value>) {
+        self.files_pdu(files, true);
+    }
+
+    fn state_pdu(&self, key: &str, state_name: &str, metadata: Option<Value>) {
+        let mut pdu = self.pdu();
+        pdu.insert(key.to_string(), Value::from(state_name));
+        if let Some(metadata) = metadata {
+            pdu.insert("metadata".to_string(), metadata);
+        }
+        self.send_pdu(pdu.into());
+    }
+
+    /// Queue a `SubscriptionData::StateEnter` event
+    pub fn state_enter(&self, state_name: &str, metadata: Option<Value>) {
+        self.state_pdu("state-enter", state_name, metadata);
+    }
+
+    /// Queue a `SubscriptionData::StateLeave` event
+    pub fn state_leave(&self, state_name: &str, metadata: Option<Value>) {
+        self.state_pdu("state-leave", state_name, metadata);
+    }
+
+    /// Queue a `SubscriptionData::Canceled` event, as though the
+    /// watch had been removed on the server
+    pub fn canceled(&self) {
+        let mut pdu = self.pdu();
+        pdu.insert("canceled".to_string(), Value::Bool(true));
+        self.send_pdu(pdu.into());
+    }
+
+    /// Returns true if `Subscription::cancel` has been called for
+    /// the associated subscription
+    pub fn was_unsubscribed(&self) -> bool {
+        self.server.requests().iter().any(|request| {
+            request_arg(request, 0) == Value::from("unsubscribe")
+                && request_arg(request, 2) == Value::from(self.name.as_str())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::SubscriptionData;
+
+    #[tokio::test]
+    async fn feeds_in_order() {
+        let (mut sub, feed) = fake_subscription::<NameOnly>("/repo");
+        assert_eq!(sub.name(), feed.name());
+
+        feed.fresh_instance(vec!["a.txt".into()]);
+        feed.files_changed(vec!["b.txt".into()]);
+        feed.state_enter("hg.update", Some(Value::from("rev")));
+        feed.state_leave("hg.update", None);
+        feed.canceled();
+
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                assert!(result.is_fresh_instance);
+                assert_eq!(result.files.unwrap()[0].name.as_os_str(), "a.txt");
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+        match sub.next().await.unwrap() {
+            SubscriptionData::FilesChanged(result) => {
+                assert!(!result.is_fresh_instance);
+                assert_eq!(result.files.unwrap()[0].name.as_os_str(), "b.txt");
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+        match sub.next().await.unwrap() {
+            SubscriptionData::StateEnter {
+                state_name,
+                metadata,
+            } => {
+                assert_eq!(state_name, "hg.update");
+                assert_eq!(metadata, Some(Value::from("rev")));
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+        match sub.next().await.unwrap() {
+            SubscriptionData::StateLeave { metadata, .. } => assert_eq!(metadata, None),
+            data => panic!("unexpected {:?}", data),
+        }
+        assert!(matches!(
+            sub.next().await.unwrap(),
+            SubscriptionData::Canceled
+        ));
+        // Nothing more is delivered after cancellation
+        assert!(sub.next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cancel_and_disconnect() {
+        let (sub, feed) = fake_subscription::<NameOnly>("/repo");
+        assert!(!feed.was_unsubscribed());
+        sub.cancel().await.unwrap();
+        assert!(feed.was_unsubscribed());
+
+        let (mut sub, feed) = fake_subscription::<NameOnly>("/repo");
+        feed.files_changed(vec![]);
+        drop(feed);
+        assert!(sub.next().await.is_ok());
+        assert!(sub.next().await.is_err());
+    }
+}
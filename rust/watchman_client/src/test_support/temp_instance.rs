@@ -0,0 +1,258 @@
+//! An isolated watchman server for integration tests.
+use crate::{CanonicalPath, Client, Connector, Error, ResolvedRoot};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Used to make the temporary directory names unique within a process
+static INSTANCE_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// How long to wait for a newly spawned server to start accepting
+/// connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Locate the watchman binary.
+/// If `WATCHMAN_BINARY` is set in the environment then its value is
+/// used, otherwise the directories in the `PATH` environment variable
+/// are searched.
+/// Returns `None` if the binary could not be found; tests that require
+/// a real watchman server can use this to skip themselves.
+pub fn find_watchman_binary() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("WATCHMAN_BINARY") {
+        return Some(PathBuf::from(path));
+    }
+    let exe = if cfg!(windows) {
+        "watchman.exe"
+    } else {
+        "watchman"
+    };
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(exe))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+/// A watchman server running in its own temporary state directory,
+/// together with a temporary directory to watch and a connected Client.
+///
+/// The server is isolated from any other watchman instance on the
+/// system: it has its own socket, state file, log file and global
+/// configuration file.
+/// The server is killed and the temporary directories are removed
+/// when the `TempWatchmanInstance` is dropped.
+///
+/// ```no_run
+/// use watchman_client::test_support::{find_watchman_binary, TempWatchmanInstance};
+///
+/// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+/// if find_watchman_binary().is_none() {
+///     eprintln!("skipping test: watchman is not installed");
+///     return Ok(());
+/// }
+/// let instance = TempWatchmanInstance::new().await?;
+/// instance.write_file("hello.txt", "hello")?;
+/// let root = instance.resolve_repo().await?;
+/// let files = instance.client().glob(&root, &["*.txt"]).await?;
+/// assert_eq!(files, vec![std::path::PathBuf::from("hello.txt")]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct TempWatchmanInstance {
+    dir: PathBuf,
+    repo: PathBuf,
+    sockname: PathBuf,
+    child: Child,
+    client: Client,
+}
+
+impl std::fmt::Debug for TempWatchmanInstance {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("TempWatchmanInstance")
+            .field("dir", &self.dir)
+            .field("repo", &self.repo)
+            .field("sockname", &self.sockname)
+            .field("pid", &self.child.id())
+            .finish()
+    }
+}
+
+impl TempWatchmanInstance {
+    /// Spawn an instance using the binary found by `find_watchman_binary`
+    pub async fn new() -> Result<Self, Error> {
+        let binary = find_watchman_binary()
+            .ok_or_else(|| Error::generic("unable to locate the watchman binary"))?;
+        Self::with_binary(binary).await
+    }
+
+    /// Spawn an instance using the specified watchman binary
+    pub async fn with_binary<P: AsRef<Path>>(binary: P) -> Result<Self, Error> {
+        let binary = binary.as_ref();
+        let dir = std::env::temp_dir().join(format!(
+            "watchman-client-test-{}-{}",
+            std::process::id(),
+            INSTANCE_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&state_dir)?;
+        let repo = dir.join("repo");
+        std::fs::create_dir_all(&repo)?;
+        // Make the repo a project root in its own right
+        std::fs::write(repo.join(".watchmanconfig"), "{}")?;
+        // Don't pick up the system-wide configuration
+        let global_config = state_dir.join("watchman.json");
+        std::fs::write(&global_config, "{}")?;
+
+        #[cfg(unix)]
+        let sockname = state_dir.join("sock");
+        #[cfg(windows)]
+        let sockname = PathBuf::from(format!(
+            "\\\\.\\pipe\\watchman-client-test-{}-{}",
+            std::process::id(),
+            INSTANCE_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut child = Command::new(binary)
+            .arg("--foreground")
+            .arg("--no-save-state")
+            .arg(format!("--sockname={}", sockname.display()))
+            .arg(format!("--statefile={}", state_dir.join("state").display()))
+            .arg(format!("--logfile={}", state_dir.join("log").display()))
+            .arg(format!("--pidfile={}", state_dir.join("pid").display()))
+            .env("WATCHMAN_CONFIG_FILE", &global_config)
+            .env_remove("WATCHMAN_SOCK")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|source| Error::ConnectionDiscovery {
+                watchman_path: binary.to_path_buf(),
+                reason: source.to_string(),
+                stderr: String::new(),
+            })?;
+
+        match wait_for_server(&mut child, &sockname, &state_dir).await {
+            Ok(client) => Ok(Self {
+                dir,
+                repo,
+                sockname,
+                child,
+                client,
+            }),
+            Err(err) => {
+                child.kill().ok();
+                child.wait().ok();
+                std::fs::remove_dir_all(&dir).ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns the client connected to the instance
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns a new connector that is configured to connect to the
+    /// instance, so that additional clients can be created
+    pub fn connector(&self) -> Connector {
+        Connector::new().unix_domain_socket(&self.sockname)
+    }
+
+    /// Returns the path to the temporary directory that is intended
+    /// to be watched.
+    pub fn repo_path(&self) -> &Path {
+        &self.repo
+    }
+
+    /// Returns the socket path (or named pipe path on Windows) of
+    /// the instance
+    pub fn sockname(&self) -> &Path {
+        &self.sockname
+    }
+
+    /// Resolve the temporary repo using the client
+    pub async fn resolve_repo(&self) -> Result<ResolvedRoot, Error> {
+        self.client
+            .resolve_root(CanonicalPath::canonicalize(&self.repo)?)
+            .await
+    }
+
+    /// Write `contents` to the file at `path`, relative to the repo,
+    /// creating any missing parent directories.
+    pub fn write_file<P: AsRef<Path>, C: AsRef<[u8]>>(
+        &self,
+        path: P,
+        contents: C,
+    ) -> std::io::Result<()> {
+        let path = self.repo.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)
+    }
+
+    /// Remove the file at `path`, relative to the repo
+    pub fn remove_file<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::remove_file(self.repo.join(path))
+    }
+}
+
+/// Repeatedly attempt to connect until the server is ready
+async fn wait_for_server(
+    child: &mut Child,
+    sockname: &Path,
+    state_dir: &Path,
+) -> Result<Client, Error> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        match Connector::new()
+            .unix_domain_socket(sockname)
+            .connect()
+            .await
+        {
+            Ok(client) => return Ok(client),
+            Err(err) => {
+                if let Some(status) = child.try_wait()? {
+                    return Err(Error::generic(format!(
+                        "watchman exited with {} during startup; see {}",
+                        status,
+                        state_dir.join("log").display()
+                    )));
+                }
+                if Instant::now() > deadline {
+                    return Err(err);
+                }
+            }
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+}
+
+impl Drop for TempWatchmanInstance {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn temp_instance() {
+        if find_watchman_binary().is_none() {
+            eprintln!("skipping temp_instance test: watchman is not installed");
+            return;
+        }
+        let instance = TempWatchmanInstance::new().await.unwrap();
+        instance.write_file("dir/hello.txt", "hello").unwrap();
+        let root = instance.resolve_repo().await.unwrap();
+        let files = instance.client().glob(&root, &["**/*.txt"]).await.unwrap();
+        assert_eq!(files, vec![PathBuf::from("dir/hello.txt")]);
+    }
+}
@@ -0,0 +1,337 @@
+//! Opt-in logging of the PDUs exchanged with the watchman server.
+//!
+//! This is intended as a support tool: when debugging a misbehaving
+//! client in an environment that you don't have direct access to,
+//! you can ask for a `TrafficLogger` to be configured on the
+//! `Connector` and then collect the resulting log file.
+//!
+//! Each PDU is written as a single line of JSON that records the
+//! direction, the size of the encoded PDU and the decoded PDU
+//! contents.  File names are frequently sensitive, so a redaction
+//! hook can be supplied to rewrite the string values in the PDU
+//! before they are written out.
+//!
+//! Logging never interferes with the connection: should writing to the
+//! log fail, the logger is discarded and the failure is reported via
+//! `ConnectionEvent::TrafficLogFailed`.
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use watchman_client::traffic_log::TrafficLogger;
+//! # use watchman_client::test_support::MockServer;
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let log_path = std::env::temp_dir().join(format!(
+//! #     "watchman-traffic-doctest-{}.log",
+//! #     std::process::id()
+//! # ));
+//! # let log_path = log_path.to_str().unwrap();
+//! let connector = Connector::new().traffic_logger(
+//! #   TrafficLogger::new(log_path)
+//! #   /*
+//!     TrafficLogger::new("/tmp/watchman-traffic.log")
+//! #   */
+//!         .redact_paths(|s| s.replace("/home/alice", "$HOME")),
+//! );
+//! # /*
+//! let client = connector.connect().await?;
+//! # */
+//! # let client = server.connect_with(connector);
+//! # client
+//! #     .resolve_root(CanonicalPath::with_canonicalized_path("/home/alice/repo".into()))
+//! #     .await?;
+//! # let log = std::fs::read_to_string(log_path)?;
+//! # std::fs::remove_file(log_path).ok();
+//! # assert!(log.contains("$HOME/repo"));
+//! # assert!(!log.contains("alice"));
+//! # Ok(())
+//! # }
+//! ```
+use serde_bser::value::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The default size at which the log file will be rotated
+const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// The default number of rotated files that will be retained
+const DEFAULT_MAX_FILES: usize = 5;
+
+/// Indicates whether a PDU was sent to or received from the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The PDU was sent by the client to the server
+    Send,
+    /// The PDU was received by the client from the server
+    Receive,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Send => "send",
+            Self::Receive => "receive",
+        }
+    }
+}
+
+type RedactFn = Box<dyn Fn(&str) -> String + Send>;
+
+/// Writes every PDU sent or received by a `Client` to a rotating
+/// log file.
+/// Use `Connector::traffic_logger` to enable it for a connection.
+pub struct TrafficLogger {
+    path: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    redact: Option<RedactFn>,
+    file: Option<File>,
+    written: u64,
+}
+
+impl std::fmt::Debug for TrafficLogger {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("TrafficLogger")
+            .field("path", &self.path)
+            .field("max_file_size", &self.max_file_size)
+            .field("max_files", &self.max_files)
+            .field("redact", &self.redact.is_some())
+            .finish()
+    }
+}
+
+impl TrafficLogger {
+    /// Log to the specified path.
+    /// The file is appended to if it already exists.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+            redact: None,
+            file: None,
+            written: 0,
+        }
+    }
+
+    /// Once the log file reaches this size in bytes it is renamed
+    /// to `<path>.1` (shifting any existing rotated files along)
+    /// and a new log file is started.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Specifies the number of rotated files to keep in addition
+    /// to the live log file.  Older files are deleted.
+    pub fn max_files(mut self, count: usize) -> Self {
+        self.max_files = count;
+        self
+    }
+
+    /// Install a redaction hook.
+    /// The hook is passed every string and bytestring value found in
+    /// the PDU (which includes all file names and paths) and returns the
+    /// text that should be written to the log in its place.
+    /// Object keys are not passed to the hook.
+    pub fn redact_paths<F>(mut self, redact: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        self.redact = Some(Box::new(redact));
+        self
+    }
+
+    /// Returns the path of the live log file
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record a PDU.
+    pub(crate) fn record(&mut self, direction: Direction, pdu: &[u8]) -> std::io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        let mut entry = serde_json::Map::new();
+        entry.insert("timestamp".to_string(), timestamp.into());
+        entry.insert("direction".to_string(), direction.as_str().into());
+        entry.insert("size".to_string(), pdu.len().into());
+        match serde_bser::from_slice::<Value>(pdu) {
+            Ok(value) => {
+                entry.insert("pdu".to_string(), self.to_json(value));
+            }
+            Err(err) => {
+                entry.insert("error".to_string(), err.to_string().into());
+            }
+        }
+
+        let mut line = serde_json::to_vec(&serde_json::Value::Object(entry))?;
+        line.push(b'\n');
+
+        self.rotate_if_needed(line.len() as u64)?;
+        let file = self.open()?;
+        file.write_all(&line)?;
+        file.flush()?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn redact(&self, s: &str) -> String {
+        match self.redact.as_ref() {
+            Some(redact) => redact(s),
+            None => s.to_string(),
+        }
+    }
+
+    fn to_json(&self, value: Value) -> serde_json::Value {
+        match value {
+            Value::Null => serde_json::Value::Null,
+            Value::Bool(b) => b.into(),
+            Value::Integer(i) => i.into(),
+            Value::Real(f) => f.into(),
+            Value::Utf8String(s) => self.redact(&s).into(),
+            Value::ByteString(s) => self.redact(&s.as_escaped_string()).into(),
+            Value::Array(values) => values.into_iter().map(|v| self.to_json(v)).collect(),
+            Value::Object(map) => serde_json::Value::Object(
+                map.into_iter().map(|(k, v)| (k, self.to_json(v))).collect(),
+            ),
+        }
+    }
+
+    fn open(&mut self) -> std::io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.written = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().expect("just opened"))
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+
+    /// If writing `additional` bytes would exceed the max file size,
+    /// shift the existing files along and start a new log file.
+    fn rotate_if_needed(&mut self, additional: u64) -> std::io::Result<()> {
+        self.open()?;
+        if self.written == 0 || self.written + additional <= self.max_file_size {
+            return Ok(());
+        }
+
+        self.file = None;
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            std::fs::remove_file(self.rotated_path(self.max_files)).ok();
+            for index in (1..self.max_files).rev() {
+                std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1)).ok();
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::hashmap;
+
+    fn temp_log_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "watchman-traffic-log-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("traffic.log")
+    }
+
+    fn pdu(value: Value) -> Vec<u8> {
+        serde_bser::ser::serialize(Vec::new(), value).unwrap()
+    }
+
+    #[test]
+    fn records_and_redacts() {
+        let path = temp_log_path("redact");
+        let mut logger = TrafficLogger::new(&path).redact_paths(|s| s.replace("secret", "XXX"));
+
+        let request = pdu(vec!["watch-project".into(), "/home/secret/repo".into()].into());
+        logger.record(Direction::Send, &request).unwrap();
+        let response = pdu(hashmap! {
+            "watch".to_string() => "/home/secret/repo".into(),
+        }
+        .into());
+        logger.record(Direction::Receive, &response).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["direction"], "send");
+        assert_eq!(lines[0]["size"], request.len());
+        assert_eq!(
+            lines[0]["pdu"],
+            serde_json::json!(["watch-project", "/home/XXX/repo"])
+        );
+        assert_eq!(lines[1]["direction"], "receive");
+        assert_eq!(lines[1]["pdu"]["watch"], "/home/XXX/repo");
+        assert!(!contents.contains("secret"));
+    }
+
+    #[test]
+    fn rotates() {
+        let path = temp_log_path("rotate");
+        let mut logger = TrafficLogger::new(&path).max_file_size(1).max_files(2);
+
+        let request = pdu(vec!["version".into()].into());
+        for _ in 0..4 {
+            logger.record(Direction::Send, &request).unwrap();
+        }
+
+        let count_lines = |p: PathBuf| std::fs::read_to_string(p).unwrap().lines().count();
+        assert_eq!(count_lines(path.clone()), 1);
+        assert_eq!(count_lines(logger.rotated_path(1)), 1);
+        assert_eq!(count_lines(logger.rotated_path(2)), 1);
+        assert!(!logger.rotated_path(3).exists());
+    }
+
+    #[tokio::test]
+    async fn reports_failure_once() {
+        use crate::lifecycle::ConnectionEvent;
+        use crate::prelude::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = crate::test_support::MockServer::new();
+        let failures = Arc::new(Mutex::new(vec![]));
+        let path = temp_log_path("failure").join("no-such-dir/traffic.log");
+        let connector = Connector::new()
+            .traffic_logger(TrafficLogger::new(&path))
+            .lifecycle_events({
+                let failures = Arc::clone(&failures);
+                move |event| {
+                    if let ConnectionEvent::TrafficLogFailed { path, .. } = event {
+                        failures.lock().unwrap().push(path.clone());
+                    }
+                }
+            });
+        let client = server.connect_with(connector);
+        client.version(&[], &[]).await.unwrap();
+        client.version(&[], &[]).await.unwrap();
+        assert_eq!(*failures.lock().unwrap(), vec![path]);
+    }
+}
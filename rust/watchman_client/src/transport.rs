@@ -0,0 +1,123 @@
+//! Connect to the server over a stream of your own.
+//!
+//! A `Transport` opens the streams that a `Connector` uses to talk to
+//! the server, in place of the local socket or named pipe, so that the
+//! server can be reached through an SSH tunnel or some other proxy.
+//! Any closure that returns a future yielding a `ReadWriteStream` is a
+//! `Transport`:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use watchman_client::prelude::*;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = Arc::new(MockServer::new());
+//! # /*
+//! let tunnel = Arc::new(Tunnel::new("devbox"));
+//! # */
+//! # let tunnel = Arc::clone(&server);
+//! let client = Connector::new()
+//!     .with_transport(move || {
+//!         let tunnel = Arc::clone(&tunnel);
+//! #       /*
+//!         async move { tunnel.open().await }
+//! #       */
+//! #       async move { Ok(tunnel.stream()) }
+//!     })
+//!     .connect()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The transport is used again to reconnect if `Connector::reconnect`
+//! is enabled.
+use crate::{Error, ReadWriteStream};
+use std::future::Future;
+use std::pin::Pin;
+
+/// The future returned by `Transport::connect`
+pub type TransportFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Box<dyn ReadWriteStream>, Error>> + Send + 'a>>;
+
+/// Opens streams to the server on behalf of a `Connector`; see
+/// `Connector::with_transport`.
+pub trait Transport: Send + Sync + 'static {
+    /// Open a new stream to the server, or to something that relays
+    /// its protocol
+    fn connect(&self) -> TransportFuture<'_>;
+}
+
+impl<F, Fut, S> Transport for F
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<S, Error>> + Send + 'static,
+    S: ReadWriteStream + 'static,
+{
+    fn connect(&self) -> TransportFuture<'_> {
+        let stream = self();
+        Box::pin(async move { Ok(Box::new(stream.await?) as Box<dyn ReadWriteStream>) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::ConnectionEvent;
+    use crate::prelude::*;
+    use crate::reconnect::ReconnectPolicy;
+    use crate::test_support::MockServer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn with_transport() {
+        let server = Arc::new(MockServer::new());
+        let dials = Arc::new(AtomicUsize::new(0));
+        let reconnected = Arc::new(AtomicUsize::new(0));
+        let client = Connector::new()
+            .unix_domain_socket("/does/not/exist")
+            .connect_retries(1, Duration::from_millis(1))
+            .reconnect(ReconnectPolicy::new().initial_delay(Duration::from_millis(1)))
+            .lifecycle_events({
+                let reconnected = Arc::clone(&reconnected);
+                move |event| {
+                    if let ConnectionEvent::Reconnected { .. } = event {
+                        reconnected.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+            .with_transport({
+                let server = Arc::clone(&server);
+                let dials = Arc::clone(&dials);
+                move || {
+                    let server = Arc::clone(&server);
+                    let attempt = dials.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        if attempt == 0 {
+                            return Err(Error::generic("the tunnel is not up yet"));
+                        }
+                        Ok(server.stream())
+                    }
+                }
+            })
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(dials.load(Ordering::SeqCst), 2);
+        let root = CanonicalPath::with_canonicalized_path("/repo".into());
+        client.resolve_root(root).await.unwrap();
+
+        // The transport is used again to reconnect
+        server.disconnect_all();
+        while reconnected.load(Ordering::SeqCst) == 0 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        let root = CanonicalPath::with_canonicalized_path("/repo".into());
+        client.resolve_root(root).await.unwrap();
+        assert_eq!(dials.load(Ordering::SeqCst), 3);
+    }
+}
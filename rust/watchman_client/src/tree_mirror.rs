@@ -0,0 +1,518 @@
+//! Maintain an in-memory copy of the metadata for a watched tree.
+//!
+//! A `TreeMirror` performs an initial query and then applies the
+//! changes reported by a subscription, so that lookups can be served
+//! from memory rather than by issuing a query for each one:
+//!
+//! ```
+//! use std::path::Path;
+//! use watchman_client::prelude::*;
+//! use watchman_client::tree_mirror::TreeMirror;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["Cargo.toml", "src/lib.rs"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let mut mirror = TreeMirror::new(&client, &root, None).await?;
+//! let snapshot = mirror.snapshot();
+//! if let Some(metadata) = snapshot.get(Path::new("Cargo.toml")) {
+//!     println!("Cargo.toml is {} bytes", metadata.size);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Call `TreeMirror::update` in a loop, or from a task dedicated to
+//! the purpose, to keep the mirror current.
+//!
+//! The state of a mirror can be saved with `TreeMirror::save` and
+//! restored by `TreeMirror::load_or_new`, which asks the server only
+//! for the changes made since the state was saved.  This gives tools
+//! that are run repeatedly a warm start.
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+query_result_type! {
+    struct MirrorFile {
+        name: NameField,
+        exists: ExistsField,
+        size: SizeField,
+        mtime: MTimeField,
+        file_type: FileTypeField,
+    }
+}
+
+/// The version of the format used by `TreeMirror::save`
+const STATE_VERSION: u32 = 1;
+
+/// The metadata recorded for each file in a `TreeMirror`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// The size of the file in bytes
+    pub size: usize,
+    /// The modification time, in seconds since the unix epoch
+    pub mtime: i64,
+    /// The type of the file
+    #[serde(rename = "type")]
+    pub file_type: FileType,
+    /// False if the file has been deleted since the mirror was
+    /// populated.  The metadata of a deleted file reflects its
+    /// last known state.
+    pub exists: bool,
+}
+
+impl From<&MirrorFile> for FileMetadata {
+    fn from(file: &MirrorFile) -> Self {
+        Self {
+            size: *file.size,
+            mtime: *file.mtime,
+            file_type: *file.file_type,
+            exists: *file.exists,
+        }
+    }
+}
+
+/// An immutable view of a `TreeMirror` at a point in time.
+/// Snapshots are cheap to take and to clone; they share storage with
+/// the mirror until it is next updated.
+#[derive(Debug, Clone)]
+pub struct TreeSnapshot {
+    files: Arc<BTreeMap<PathBuf, FileMetadata>>,
+    clock: Clock,
+}
+
+impl TreeSnapshot {
+    /// Returns the metadata for `path`, which is relative to the root
+    /// of the mirror.
+    /// Files that have been deleted are reported with `exists` set to
+    /// false, until the server next reports a fresh instance.
+    pub fn get(&self, path: &Path) -> Option<&FileMetadata> {
+        self.files.get(path)
+    }
+
+    /// Returns true if `path` is known to exist
+    pub fn exists(&self, path: &Path) -> bool {
+        self.get(path).is_some_and(|metadata| metadata.exists)
+    }
+
+    /// Returns an iterator over the files that exist, in path order
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &FileMetadata)> {
+        self.files
+            .iter()
+            .filter(|(_, metadata)| metadata.exists)
+            .map(|(path, metadata)| (path.as_path(), metadata))
+    }
+
+    /// Returns the number of files that exist
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns true if no files exist
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Returns the clock at which the snapshot was taken
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+}
+
+/// The state written by `TreeMirror::save`
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    version: u32,
+    /// The absolute path of the mirrored directory
+    root: PathBuf,
+    /// The expression that selected the mirrored files
+    expression: serde_json::Value,
+    clock: Clock,
+    files: Vec<(PathBuf, FileMetadata)>,
+}
+
+/// Apply a query or subscription result to `files`, returning the
+/// paths that changed
+fn apply(
+    files: &mut BTreeMap<PathBuf, FileMetadata>,
+    result: &QueryResult<MirrorFile>,
+) -> Vec<PathBuf> {
+    if result.is_fresh_instance {
+        files.clear();
+    }
+    let mut changed = vec![];
+    for file in result.files.iter().flatten() {
+        let name = file.name.to_path_buf();
+        files.insert(name.clone(), FileMetadata::from(file));
+        changed.push(name);
+    }
+    changed
+}
+
+/// An in-memory map of the files beneath a root to their metadata,
+/// maintained via a subscription.
+pub struct TreeMirror {
+    subscription: Subscription<MirrorFile>,
+    snapshot: TreeSnapshot,
+    root: PathBuf,
+    expression: serde_json::Value,
+    resumed: bool,
+}
+
+impl std::fmt::Debug for TreeMirror {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("TreeMirror")
+            .field("subscription", &self.subscription.name())
+            .field("clock", &self.snapshot.clock)
+            .finish()
+    }
+}
+
+impl TreeMirror {
+    /// Populate a mirror of the files beneath `root` that match
+    /// `expression`, or of all files if `expression` is `None`
+    pub async fn new(
+        client: &Client,
+        root: &ResolvedRoot,
+        expression: Option<Expr>,
+    ) -> Result<Self, Error> {
+        Self::start(client, root, expression, None).await
+    }
+
+    /// Restore the mirror saved at `path` by `TreeMirror::save` and
+    /// bring it up to date with the changes made since it was saved.
+    /// If there is no usable saved state, because it is missing,
+    /// corrupt, or was saved for a different root or expression,
+    /// this is equivalent to `TreeMirror::new`.
+    pub async fn load_or_new<P: AsRef<Path>>(
+        client: &Client,
+        root: &ResolvedRoot,
+        expression: Option<Expr>,
+        path: P,
+    ) -> Result<Self, Error> {
+        let fingerprint = Self::fingerprint(&expression)?;
+        let saved = std::fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<SavedState>(&data).ok())
+            .filter(|saved| {
+                saved.version == STATE_VERSION
+                    && saved.root == root.path()
+                    && saved.expression == fingerprint
+            })
+            .map(|saved| TreeSnapshot {
+                files: Arc::new(saved.files.into_iter().collect()),
+                clock: saved.clock,
+            });
+        Self::start(client, root, expression, saved).await
+    }
+
+    fn fingerprint(expression: &Option<Expr>) -> Result<serde_json::Value, Error> {
+        serde_json::to_value(expression).map_err(|source| Error::Serialize {
+            source: Box::new(source),
+        })
+    }
+
+    /// Populate the mirror, starting either from scratch or from a
+    /// previously saved snapshot
+    async fn start(
+        client: &Client,
+        root: &ResolvedRoot,
+        expression: Option<Expr>,
+        saved: Option<TreeSnapshot>,
+    ) -> Result<Self, Error> {
+        let fingerprint = Self::fingerprint(&expression)?;
+        let result: QueryResult<MirrorFile> = client
+            .query(
+                root,
+                QueryRequestCommon {
+                    since: saved.as_ref().map(|saved| saved.clock.clone()),
+                    expression: expression.clone(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        // The server reports a fresh instance if it can't produce the
+        // changes since the saved clock, for example because it has
+        // restarted, in which case the saved files are discarded
+        let resumed = saved.is_some() && !result.is_fresh_instance;
+        let mut files = saved
+            .map(|saved| Arc::try_unwrap(saved.files).unwrap_or_else(|files| (*files).clone()))
+            .unwrap_or_default();
+        apply(&mut files, &result);
+
+        // Subscribing since the clock of the query ensures that no
+        // changes are missed between the two
+        let (subscription, _) = client
+            .subscribe(
+                root,
+                SubscribeRequest {
+                    since: Some(result.clock.clone()),
+                    expression,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(Self {
+            subscription,
+            snapshot: TreeSnapshot {
+                files: Arc::new(files),
+                clock: result.clock,
+            },
+            root: root.path(),
+            expression: fingerprint,
+            resumed,
+        })
+    }
+
+    /// Returns true if the mirror was restored from saved state by
+    /// `TreeMirror::load_or_new`, rather than populated from scratch
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Save the state of the mirror to `path`, for use by a later call
+    /// to `TreeMirror::load_or_new`.
+    /// The state is written to a temporary file which then replaces
+    /// `path`, so that a concurrent or interrupted save never leaves
+    /// partial state behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let state = SavedState {
+            version: STATE_VERSION,
+            root: self.root.clone(),
+            expression: self.expression.clone(),
+            clock: self.snapshot.clock.clone(),
+            files: self
+                .snapshot
+                .files
+                .iter()
+                .map(|(name, metadata)| (name.clone(), *metadata))
+                .collect(),
+        };
+        let data = serde_json::to_vec(&state).map_err(|source| Error::Serialize {
+            source: Box::new(source),
+        })?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".tmp{}", std::process::id()));
+        std::fs::write(&temp, data)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Returns a snapshot of the current state of the mirror
+    pub fn snapshot(&self) -> TreeSnapshot {
+        self.snapshot.clone()
+    }
+
+    /// Returns the metadata for `path`, which is relative to the root
+    /// of the mirror
+    pub fn get(&self, path: &Path) -> Option<FileMetadata> {
+        self.snapshot.get(path).copied()
+    }
+
+    /// Wait for the next change notification from the server and apply
+    /// it to the mirror.  Returns the paths that changed; this is empty
+    /// for notifications that don't describe changes, such as state
+    /// transitions.
+    ///
+    /// If the server reports a fresh instance, the mirror is rebuilt
+    /// from the result and every path is returned.
+    pub async fn update(&mut self) -> Result<Vec<PathBuf>, Error> {
+        let result = match self.subscription.next().await? {
+            SubscriptionData::FilesChanged(result) => result,
+            SubscriptionData::StateEnter { .. } | SubscriptionData::StateLeave { .. } => {
+                return Ok(vec![]);
+            }
+            SubscriptionData::Canceled => {
+                return Err(Error::generic(format!(
+                    "the subscription {} backing the tree mirror was canceled",
+                    self.subscription.name()
+                )));
+            }
+        };
+
+        let changed = apply(Arc::make_mut(&mut self.snapshot.files), &result);
+        self.snapshot.clock = result.clock;
+        Ok(changed)
+    }
+
+    /// Cancel the subscription that maintains the mirror
+    pub async fn cancel(self) -> Result<(), Error> {
+        self.subscription.cancel().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    fn file(name: &str, exists: bool, size: i64, file_type: &str) -> Value {
+        hashmap! {
+            "name".to_string() => Value::from(name),
+            "exists".to_string() => Value::Bool(exists),
+            "size".to_string() => Value::Integer(size),
+            "mtime".to_string() => Value::Integer(1_600_000_000),
+            "type".to_string() => Value::from(file_type),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn applies_deltas() {
+        let server = MockServer::new();
+        server.serve_files(&["a.txt", "src/lib.rs"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        let mut mirror = TreeMirror::new(&client, &root, None).await.unwrap();
+
+        let initial = mirror.snapshot();
+        assert_eq!(initial.len(), 2);
+        assert!(initial.exists(Path::new("src/lib.rs")));
+        assert_eq!(
+            mirror.get(Path::new("a.txt")).unwrap().file_type,
+            FileType::Regular
+        );
+
+        // The subscription continues from the clock of the query
+        let subscribe = server
+            .requests()
+            .into_iter()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .unwrap();
+        let name = match request_arg(&subscribe, 2) {
+            Value::Utf8String(name) => name,
+            other => panic!("unexpected {:?}", other),
+        };
+        match request_arg(&subscribe, 3) {
+            Value::Object(request) => assert!(request.contains_key("since")),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        server.push(subscription_pdu(
+            &name,
+            "c:0:5",
+            vec![
+                file("a.txt", false, 0, "f"),
+                file("b.txt", true, 10, "f"),
+                file("src/lib.rs", true, 20, "f"),
+            ],
+        ));
+        let mut changed = mirror.update().await.unwrap();
+        changed.sort();
+        assert_eq!(
+            changed,
+            vec![
+                PathBuf::from("a.txt"),
+                PathBuf::from("b.txt"),
+                PathBuf::from("src/lib.rs")
+            ]
+        );
+        let snapshot = mirror.snapshot();
+        assert!(!snapshot.exists(Path::new("a.txt")));
+        assert_eq!(snapshot.get(Path::new("b.txt")).unwrap().size, 10);
+        assert_eq!(
+            snapshot.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            vec![Path::new("b.txt"), Path::new("src/lib.rs")]
+        );
+        // The earlier snapshot is unaffected
+        assert!(initial.exists(Path::new("a.txt")));
+
+        let mut pdu = subscription_pdu(&name, "c:0:6", vec![file("c", true, 0, "d")]);
+        if let Value::Object(pdu) = &mut pdu {
+            pdu.insert("is_fresh_instance".to_string(), Value::Bool(true));
+        }
+        server.push(pdu);
+        mirror.update().await.unwrap();
+        let snapshot = mirror.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(
+            snapshot.get(Path::new("c")).unwrap().file_type,
+            FileType::Directory
+        );
+
+        mirror.cancel().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn warm_start() {
+        let state = std::env::temp_dir().join(format!("watchman-mirror-{}", std::process::id()));
+        let server = MockServer::new();
+        server.serve_files(&["a.txt", "b.txt"]);
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let mirror = TreeMirror::load_or_new(&client, &root, None, &state)
+            .await
+            .unwrap();
+        assert!(!mirror.resumed());
+        mirror.save(&state).unwrap();
+
+        // Report a delta for queries that continue from a clock
+        server.respond("query", |request| {
+            let since = match request_arg(request, 2) {
+                Value::Object(query) => query.contains_key("since"),
+                _ => false,
+            };
+            let files = if since {
+                vec![file("a.txt", false, 0, "f"), file("c.txt", true, 3, "f")]
+            } else {
+                vec![file("d.txt", true, 0, "f")]
+            };
+            hashmap! {
+                "version".to_string() => Value::from("mock"),
+                "clock".to_string() => Value::from("c:0:9"),
+                "is_fresh_instance".to_string() => Value::Bool(!since),
+                "files".to_string() => Value::Array(files),
+            }
+            .into()
+        });
+
+        let mirror = TreeMirror::load_or_new(&client, &root, None, &state)
+            .await
+            .unwrap();
+        assert!(mirror.resumed());
+        let snapshot = mirror.snapshot();
+        assert_eq!(
+            snapshot.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            vec![Path::new("b.txt"), Path::new("c.txt")]
+        );
+        assert!(!snapshot.exists(Path::new("a.txt")));
+
+        // State saved for a different expression isn't used
+        let mirror = TreeMirror::load_or_new(&client, &root, Some(Expr::Exists), &state)
+            .await
+            .unwrap();
+        assert!(!mirror.resumed());
+        assert!(mirror.snapshot().exists(Path::new("d.txt")));
+
+        std::fs::write(&state, "not json").unwrap();
+        let mirror = TreeMirror::load_or_new(&client, &root, None, &state)
+            .await
+            .unwrap();
+        assert!(!mirror.resumed());
+
+        std::fs::remove_file(&state).unwrap();
+    }
+}
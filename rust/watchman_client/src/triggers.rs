@@ -0,0 +1,522 @@
+//! Run actions in-process when files change.
+//!
+//! `Triggers` is a client-side alternative to the server's `trigger`
+//! command, which is available via `Client::register_trigger`.  Each registered `Trigger` pairs an expression with an
+//! action: an async callback or an external command.  The crate
+//! maintains a subscription for each trigger and invokes its action
+//! with the files that changed once the tree has settled.
+//!
+//! Unlike server-side triggers, the actions run in the context of the
+//! calling process, they stop when it exits, and a misbehaving
+//! action can't affect other clients of the server.
+//!
+//! ```
+//! use std::time::Duration;
+//! use watchman_client::prelude::*;
+//! use watchman_client::triggers::{Trigger, Triggers};
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let mut triggers = Triggers::new();
+//! triggers
+//!     .register(
+//!         &client,
+//!         &root,
+//!         Trigger::callback(
+//!             "rebuild",
+//!             Expr::Suffix(vec!["rs".into()]),
+//!             |event| async move { println!("{} files changed", event.files.len()) },
+//!         )
+//!         .settle(Duration::from_millis(200))
+//!         .max_concurrency(1),
+//!     )
+//!     .await?;
+//! // ...
+//! triggers.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::{Error, Subscription, SubscriptionData};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinHandle;
+
+/// The settle window used unless overridden via `Trigger::settle`
+const DEFAULT_SETTLE: Duration = Duration::from_millis(20);
+
+type Callback = Arc<dyn Fn(TriggerEvent) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Describes the changes that caused a trigger to fire
+#[derive(Debug, Clone)]
+pub struct TriggerEvent {
+    /// The name of the trigger
+    pub name: String,
+    /// The absolute path of the watched directory; `files` are
+    /// relative to this path
+    pub root: PathBuf,
+    /// The files that changed, including files that were deleted
+    pub files: Vec<PathBuf>,
+    /// True if the server recrawled the tree and may have missed
+    /// changes; `files` is empty in that case, and the action should
+    /// assume that anything may have changed
+    pub is_fresh_instance: bool,
+}
+
+enum Action {
+    Callback(Callback),
+    Command { program: PathBuf, args: Vec<String> },
+}
+
+/// An expression paired with an action to run when files matching
+/// it change
+pub struct Trigger {
+    name: String,
+    expression: Expr,
+    settle: Duration,
+    max_concurrency: usize,
+    action: Action,
+}
+
+impl std::fmt::Debug for Trigger {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Trigger")
+            .field("name", &self.name)
+            .field("expression", &self.expression)
+            .field("settle", &self.settle)
+            .field("max_concurrency", &self.max_concurrency)
+            .finish()
+    }
+}
+
+impl Trigger {
+    fn new(name: &str, expression: Expr, action: Action) -> Self {
+        Self {
+            name: name.to_string(),
+            expression,
+            settle: DEFAULT_SETTLE,
+            max_concurrency: 1,
+            action,
+        }
+    }
+
+    /// Create a trigger that calls `callback` when files matching
+    /// `expression` change
+    pub fn callback<F, Fut>(name: &str, expression: Expr, callback: F) -> Self
+    where
+        F: Fn(TriggerEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::new(
+            name,
+            expression,
+            Action::Callback(Arc::new(move |event| Box::pin(callback(event)))),
+        )
+    }
+
+    /// Create a trigger that runs `program` when files matching
+    /// `expression` change.
+    /// The program is run in the watched directory with `args`
+    /// followed by the paths of the changed files.
+    /// The exit status of the program is not checked.
+    pub fn command<P: Into<PathBuf>>(
+        name: &str,
+        expression: Expr,
+        program: P,
+        args: Vec<String>,
+    ) -> Self {
+        Self::new(
+            name,
+            expression,
+            Action::Command {
+                program: program.into(),
+                args,
+            },
+        )
+    }
+
+    /// Set how long the tree must be free of further matching changes
+    /// before the action is run.  Changes that arrive within the
+    /// window are delivered to a single invocation.
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Set the maximum number of invocations of the action that may
+    /// run at the same time.  The default is 1, which means that a
+    /// further invocation waits for the previous one to complete.
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+}
+
+struct Registration {
+    cancel: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+/// A set of triggers, each backed by its own subscription.
+///
+/// This must be used from within a tokio runtime, which runs the
+/// actions.
+#[derive(Default)]
+pub struct Triggers {
+    registrations: HashMap<String, Registration>,
+}
+
+impl std::fmt::Debug for Triggers {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Triggers")
+            .field("names", &self.names())
+            .finish()
+    }
+}
+
+impl Triggers {
+    /// Create an empty set of triggers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the names of the registered triggers
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.registrations.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Subscribe to changes beneath `root` and run the action of
+    /// `trigger` when they match its expression.
+    /// A trigger that is already registered with the same name is
+    /// replaced.
+    pub async fn register(
+        &mut self,
+        client: &Client,
+        root: &ResolvedRoot,
+        trigger: Trigger,
+    ) -> Result<(), Error> {
+        let (subscription, _) = client
+            .subscribe::<NameOnly>(
+                root,
+                SubscribeRequest {
+                    expression: Some(trigger.expression.clone()),
+                    // The initial result describes the existing files,
+                    // not changes to them
+                    empty_on_fresh_instance: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        self.unregister(&trigger.name).await;
+        let (cancel, canceled) = oneshot::channel();
+        let name = trigger.name.clone();
+        let task = tokio::spawn(run(subscription, root.path(), trigger, canceled));
+        self.registrations
+            .insert(name, Registration { cancel, task });
+        Ok(())
+    }
+
+    /// Remove the trigger named `name`, waiting for any invocations
+    /// of its action that are in progress to complete.
+    /// Returns false if there is no such trigger.
+    pub async fn unregister(&mut self, name: &str) -> bool {
+        match self.registrations.remove(name) {
+            Some(registration) => {
+                registration.cancel.send(()).ok();
+                registration.task.await.ok();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove all of the triggers, waiting for any invocations that
+    /// are in progress to complete
+    pub async fn shutdown(mut self) {
+        let names: Vec<String> = self.registrations.keys().cloned().collect();
+        for name in names {
+            self.unregister(&name).await;
+        }
+    }
+}
+
+/// Collects changes from the subscription and runs the action of the
+/// trigger until it is canceled or the subscription ends
+async fn run(
+    mut subscription: Subscription<NameOnly>,
+    root: PathBuf,
+    trigger: Trigger,
+    mut canceled: oneshot::Receiver<()>,
+) {
+    let semaphore = Arc::new(Semaphore::new(trigger.max_concurrency));
+    let mut invocations = vec![];
+    let mut files = vec![];
+    let mut is_fresh_instance = false;
+    // The first result reports the existing files as a fresh instance
+    let mut initial = true;
+
+    loop {
+        let pending = is_fresh_instance || !files.is_empty();
+        let data = tokio::select! {
+            _ = &mut canceled => {
+                subscription.cancel().await.ok();
+                break;
+            }
+            data = subscription.next() => data,
+            _ = tokio::time::delay_for(trigger.settle), if pending => {
+                let event = TriggerEvent {
+                    name: trigger.name.clone(),
+                    root: root.clone(),
+                    files: std::mem::take(&mut files),
+                    is_fresh_instance: std::mem::replace(&mut is_fresh_instance, false),
+                };
+                let permit = Arc::clone(&semaphore).acquire_owned().await;
+                let invocation = invoke(&trigger.action, event);
+                invocations.push(tokio::spawn(async move {
+                    invocation.await;
+                    drop(permit);
+                }));
+                continue;
+            }
+        };
+        match data {
+            Ok(SubscriptionData::FilesChanged(result)) => {
+                if std::mem::replace(&mut initial, false) && result.is_fresh_instance {
+                    continue;
+                }
+                if result.is_fresh_instance {
+                    is_fresh_instance = true;
+                    files.clear();
+                } else if !is_fresh_instance {
+                    files.extend(
+                        result
+                            .files
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|file| file.name.into_inner()),
+                    );
+                }
+            }
+            Ok(SubscriptionData::StateEnter { .. }) | Ok(SubscriptionData::StateLeave { .. }) => {}
+            Ok(SubscriptionData::Canceled) | Err(_) => break,
+        }
+    }
+
+    for invocation in invocations {
+        invocation.await.ok();
+    }
+}
+
+fn invoke(action: &Action, event: TriggerEvent) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    match action {
+        Action::Callback(callback) => callback(event),
+        Action::Command { program, args } => {
+            let mut command = tokio::process::Command::new(program);
+            command
+                .args(args)
+                .args(&event.files)
+                .current_dir(&event.root);
+            Box::pin(async move {
+                if let Ok(child) = command.spawn() {
+                    child.await.ok();
+                }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_arg, subscription_pdu, MockServer};
+    use serde_bser::value::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+
+    fn subscription_name(server: &MockServer) -> String {
+        server
+            .requests()
+            .iter()
+            .rev()
+            .find(|r| request_arg(r, 0) == Value::from("subscribe"))
+            .map(|r| match request_arg(r, 2) {
+                Value::Utf8String(name) => name,
+                other => panic!("unexpected subscription name {:?}", other),
+            })
+            .unwrap()
+    }
+
+    async fn setup() -> (MockServer, Client, ResolvedRoot) {
+        let server = MockServer::new();
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+        (server, client, root)
+    }
+
+    #[tokio::test]
+    async fn settles_and_batches() {
+        let (server, client, root) = setup().await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut triggers = Triggers::new();
+        triggers
+            .register(
+                &client,
+                &root,
+                Trigger::callback("build", Expr::True, move |event| {
+                    let tx = tx.clone();
+                    async move {
+                        tx.send(event).ok();
+                    }
+                })
+                .settle(Duration::from_millis(50)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(triggers.names(), vec!["build"]);
+
+        let name = subscription_name(&server);
+        let fresh_instance = |clock| {
+            let mut pdu = subscription_pdu(&name, clock, vec![]);
+            if let Value::Object(pdu) = &mut pdu {
+                pdu.insert("is_fresh_instance".to_string(), Value::Bool(true));
+            }
+            pdu
+        };
+        // The initial result doesn't fire the trigger
+        server.push(fresh_instance("c:0:0"));
+        server.push(subscription_pdu(&name, "c:0:1", vec!["a.rs".into()]));
+        server.push(subscription_pdu(&name, "c:0:2", vec!["b.rs".into()]));
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.name, "build");
+        assert_eq!(event.root, PathBuf::from("/repo"));
+        assert_eq!(
+            event.files,
+            vec![PathBuf::from("a.rs"), PathBuf::from("b.rs")]
+        );
+        assert!(!event.is_fresh_instance);
+
+        server.push(fresh_instance("c:0:3"));
+        assert!(rx.recv().await.unwrap().is_fresh_instance);
+
+        assert!(triggers.unregister("build").await);
+        assert!(!triggers.unregister("build").await);
+        assert!(server
+            .requests()
+            .iter()
+            .any(|r| request_arg(r, 0) == Value::from("unsubscribe")));
+    }
+
+    #[tokio::test]
+    async fn limits_concurrency() {
+        let (server, client, root) = setup().await;
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(Mutex::new(0));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut triggers = Triggers::new();
+        {
+            let running = Arc::clone(&running);
+            let peak = Arc::clone(&peak);
+            let calls = Arc::clone(&calls);
+            triggers
+                .register(
+                    &client,
+                    &root,
+                    Trigger::callback("slow", Expr::True, move |_| {
+                        let running = Arc::clone(&running);
+                        let peak = Arc::clone(&peak);
+                        let calls = Arc::clone(&calls);
+                        async move {
+                            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            {
+                                let mut peak = peak.lock().unwrap();
+                                *peak = (*peak).max(now);
+                            }
+                            tokio::time::delay_for(Duration::from_millis(30)).await;
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            calls.fetch_add(1, Ordering::SeqCst);
+                        }
+                    })
+                    .settle(Duration::from_millis(1))
+                    .max_concurrency(2),
+                )
+                .await
+                .unwrap();
+        }
+
+        let name = subscription_name(&server);
+        for tick in 0..6 {
+            server.push(subscription_pdu(
+                &name,
+                &format!("c:0:{}", tick),
+                vec!["a.rs".into()],
+            ));
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+        }
+        while calls.load(Ordering::SeqCst) == 0 {
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+        }
+        triggers.shutdown().await;
+        assert!(*peak.lock().unwrap() <= 2);
+        assert!(calls.load(Ordering::SeqCst) >= 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn runs_commands() {
+        let (server, client, _) = setup().await;
+        let dir = std::env::temp_dir().join(format!("watchman-triggers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path(dir.clone()))
+            .await
+            .unwrap();
+        let mut triggers = Triggers::new();
+        triggers
+            .register(
+                &client,
+                &root,
+                Trigger::command(
+                    "touch",
+                    Expr::True,
+                    "/bin/sh",
+                    vec![
+                        "-c".to_string(),
+                        "touch \"$1.seen\"".to_string(),
+                        "sh".to_string(),
+                    ],
+                ),
+            )
+            .await
+            .unwrap();
+        let name = subscription_name(&server);
+        server.push(subscription_pdu(&name, "c:0:1", vec!["changed".into()]));
+
+        let seen = dir.join("changed.seen");
+        while !seen.exists() {
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+        }
+        triggers.shutdown().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
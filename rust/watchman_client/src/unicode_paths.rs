@@ -0,0 +1,241 @@
+//! Unicode normalization of the paths in queries and results.
+//!
+//! The same name can be spelled with different sequences of code
+//! points: `é` may be a single precomposed character (NFC) or an `e`
+//! followed by a combining accent (NFD).  macOS filesystems store and
+//! report names in a decomposed form, so the names that watchman
+//! reports there may not compare equal to the precomposed strings that
+//! a program holds, even though they refer to the same file.
+//! Normalizing both sides to the same form makes the comparisons
+//! reliable:
+//!
+//! ```
+//! use watchman_client::prelude::*;
+//! use watchman_client::unicode_paths::Normalization;
+//! use std::path::Path;
+//! # use watchman_client::test_support::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["cafe\u{301}.txt"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let root = client
+//!     .resolve_root(CanonicalPath::canonicalize(".")?)
+//!     .await?;
+//! let mut query = QueryRequestCommon {
+//!     expression: Some(Expr::Suffix(vec!["txt".into()])),
+//!     ..Default::default()
+//! };
+//! Normalization::Nfd.normalize_query(&mut query);
+//! let mut result: QueryResult<NameOnly> = client.query(&root, query).await?;
+//! let files = result.files.get_or_insert_with(Vec::new);
+//! Normalization::Nfc.normalize_names(files, |file| &mut file.name);
+//! assert!(files.iter().any(|file| *file.name == Path::new("café.txt")));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Names that are not valid UTF-8 are left as they are.
+//! This module is available when the `unicode-normalization` feature
+//! is enabled.
+use crate::expr::Expr;
+use crate::fields::NameField;
+use crate::pdu::{PathGeneratorElement, QueryRequestCommon, SubscribeRequest};
+use std::path::{Path, PathBuf};
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+/// A unicode normalization form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Canonical composition, in which `é` is a single code point.
+    /// This is the form that most programs produce.
+    Nfc,
+    /// Canonical decomposition, in which `é` is an `e` followed by a
+    /// combining accent.  This is the form stored by macOS filesystems.
+    Nfd,
+}
+
+impl Normalization {
+    /// Returns `text` in this form
+    pub fn normalize_str(self, text: &str) -> String {
+        match self {
+            Self::Nfc => text.nfc().collect(),
+            Self::Nfd => text.nfd().collect(),
+        }
+    }
+
+    /// Returns `path` in this form.  A path that is not valid UTF-8 is
+    /// returned unchanged.
+    pub fn normalize_path(self, path: &Path) -> PathBuf {
+        let mut path = path.to_path_buf();
+        self.normalize_path_in_place(&mut path);
+        path
+    }
+
+    fn normalize_path_in_place(self, path: &mut PathBuf) {
+        if let Some(text) = path.to_str() {
+            if !self.is_normalized(text) {
+                *path = self.normalize_str(text).into();
+            }
+        }
+    }
+
+    fn normalize_string_in_place(self, text: &mut String) {
+        if !self.is_normalized(text) {
+            *text = self.normalize_str(text);
+        }
+    }
+
+    fn is_normalized(self, text: &str) -> bool {
+        match self {
+            Self::Nfc => is_nfc(text),
+            Self::Nfd => is_nfd(text),
+        }
+    }
+
+    /// Normalize the names in a set of query results.  `name` returns
+    /// the name field of a result, which for `NameOnly` and the types
+    /// defined using `query_result_type!` is `|file| &mut file.name`.
+    pub fn normalize_names<F, N>(self, files: &mut [F], mut name: N)
+    where
+        N: FnMut(&mut F) -> &mut NameField,
+    {
+        for file in files {
+            self.normalize_path_in_place(name(file));
+        }
+    }
+
+    /// Normalize the paths and patterns of a query: the generators, the
+    /// relative root and the name matching terms of the expression
+    pub fn normalize_query(self, query: &mut QueryRequestCommon) {
+        for glob in query.glob.iter_mut().flatten() {
+            self.normalize_string_in_place(glob);
+        }
+        for element in query.path.iter_mut().flatten() {
+            match element {
+                PathGeneratorElement::RecursivePath(path)
+                | PathGeneratorElement::ConstrainedDepth { path, .. } => {
+                    self.normalize_path_in_place(path)
+                }
+            }
+        }
+        for suffix in query.suffix.iter_mut().flatten() {
+            self.normalize_path_in_place(suffix);
+        }
+        if let Some(relative_root) = &mut query.relative_root {
+            self.normalize_path_in_place(relative_root);
+        }
+        if let Some(expression) = &mut query.expression {
+            self.normalize_expr(expression);
+        }
+    }
+
+    /// Normalize the paths and patterns of a subscription; see
+    /// `Normalization::normalize_query`
+    pub fn normalize_subscribe_request(self, request: &mut SubscribeRequest) {
+        if let Some(relative_root) = &mut request.relative_root {
+            self.normalize_path_in_place(relative_root);
+        }
+        if let Some(expression) = &mut request.expression {
+            self.normalize_expr(expression);
+        }
+    }
+
+    /// Normalize the paths and patterns of the name matching terms of
+    /// `expression`
+    pub fn normalize_expr(self, expression: &mut Expr) {
+        match expression {
+            Expr::Not(term) => self.normalize_expr(term),
+            Expr::All(terms) | Expr::Any(terms) => {
+                for term in terms {
+                    self.normalize_expr(term);
+                }
+            }
+            Expr::DirName(term) => self.normalize_path_in_place(&mut term.path),
+            Expr::Match(term) | Expr::IMatch(term) => {
+                self.normalize_string_in_place(&mut term.glob)
+            }
+            Expr::Name(term) | Expr::IName(term) => {
+                for path in &mut term.paths {
+                    self.normalize_path_in_place(path);
+                }
+            }
+            Expr::Pcre(term) => self.normalize_string_in_place(&mut term.pattern),
+            Expr::Suffix(suffixes) => {
+                for suffix in suffixes {
+                    self.normalize_path_in_place(suffix);
+                }
+            }
+            Expr::True
+            | Expr::False
+            | Expr::Empty
+            | Expr::Exists
+            | Expr::Since(_)
+            | Expr::Size(_)
+            | Expr::FileType(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::{MatchTerm, NameTerm};
+    use crate::fields::NameOnly;
+
+    const NFC: &str = "caf\u{e9}";
+    const NFD: &str = "cafe\u{301}";
+
+    #[test]
+    fn normalizes() {
+        assert_eq!(Normalization::Nfc.normalize_str(NFD), NFC);
+        assert_eq!(Normalization::Nfd.normalize_str(NFC), NFD);
+        assert_eq!(
+            Normalization::Nfc.normalize_path(Path::new(NFD)),
+            PathBuf::from(NFC)
+        );
+
+        let mut files = vec![NameOnly::from(PathBuf::from(NFD))];
+        Normalization::Nfc.normalize_names(&mut files, |file| &mut file.name);
+        assert_eq!(*files[0].name, PathBuf::from(NFC));
+
+        let mut query = QueryRequestCommon {
+            glob: Some(vec![format!("{}/*", NFC)]),
+            path: Some(vec![PathGeneratorElement::RecursivePath(NFC.into())]),
+            expression: Some(Expr::Not(Box::new(Expr::Any(vec![
+                Expr::Name(NameTerm {
+                    paths: vec![NFC.into()],
+                    wholename: true,
+                }),
+                Expr::Match(MatchTerm {
+                    glob: NFC.into(),
+                    ..Default::default()
+                }),
+            ])))),
+            ..Default::default()
+        };
+        Normalization::Nfd.normalize_query(&mut query);
+        assert_eq!(query.glob.unwrap(), vec![format!("{}/*", NFD)]);
+        match &query.path.unwrap()[0] {
+            PathGeneratorElement::RecursivePath(path) => assert_eq!(path, Path::new(NFD)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match query.expression.unwrap() {
+            Expr::Not(term) => match *term {
+                Expr::Any(terms) => match (&terms[0], &terms[1]) {
+                    (Expr::Name(name), Expr::Match(glob)) => {
+                        assert_eq!(name.paths, vec![PathBuf::from(NFD)]);
+                        assert_eq!(glob.glob, NFD);
+                    }
+                    other => panic!("unexpected {:?}", other),
+                },
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
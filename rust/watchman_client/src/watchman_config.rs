@@ -0,0 +1,346 @@
+//! Read and write `.watchmanconfig` files.
+//!
+//! A `.watchmanconfig` file in the root of a watched project configures
+//! how the server watches that project.
+//! The file is JSON; `WatchmanConfig` provides typed access to the
+//! settings that are documented at
+//! <https://facebook.github.io/watchman/docs/config.html>, while
+//! preserving any other settings so that a config can be modified
+//! without losing information:
+//!
+//! ```
+//! use watchman_client::watchman_config::WatchmanConfig;
+//!
+//! let mut config = WatchmanConfig::parse(r#"{"ignore_dirs": ["target"], "custom": 1}"#)?;
+//! config.settle = Some(200);
+//! config.validate()?;
+//! let json = config.to_json_string()?;
+//! assert!(json.contains("\"settle\": 200"));
+//! assert!(json.contains("\"custom\": 1"));
+//! # Ok::<(), watchman_client::Error>(())
+//! ```
+//!
+//! `Client::get_config` returns the config that the server loaded for a
+//! root that it is watching, which may differ from the file on disk if
+//! the file has changed since the root was watched.
+use crate::pdu::{GetConfigRequest, GetConfigResponse};
+use crate::{Client, Error, ResolvedRoot};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+/// The name of the per-project config file
+pub const CONFIG_FILE_NAME: &str = ".watchmanconfig";
+
+/// The settings held by a `.watchmanconfig` file.
+/// Settings that are not present in the file are `None`, meaning that
+/// the server default applies.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct WatchmanConfig {
+    /// Directories, relative to the root, whose contents are not
+    /// watched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_dirs: Option<Vec<PathBuf>>,
+
+    /// Version control directories whose contents are only partially
+    /// watched, so that their state changes can be observed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_vcs: Option<Vec<String>>,
+
+    /// How long, in milliseconds, the filesystem must be idle before
+    /// subscriptions are notified of changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settle: Option<u64>,
+
+    /// The files whose presence marks a directory as a project root.
+    /// This is normally set in the global config rather than a
+    /// project config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_files: Option<Vec<String>>,
+
+    /// When true, only directories containing one of the `root_files`
+    /// may be watched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enforce_root_files: Option<bool>,
+
+    /// The latency, in seconds, passed to the macOS FSEvents API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fsevents_latency: Option<f64>,
+
+    /// When true, the server attempts to resync from the FSEvents
+    /// journal rather than recrawling when events are dropped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fsevents_try_resync: Option<bool>,
+
+    /// When true, use separate FSEvents streams for files and for
+    /// directories on macOS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefer_split_fsevents_watcher: Option<bool>,
+
+    /// When true, warnings about recrawls are not reported to clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppress_recrawl_warnings: Option<bool>,
+
+    /// How long, in seconds, a deleted file is remembered before it
+    /// is eligible for garbage collection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gc_age_seconds: Option<u64>,
+
+    /// How often, in seconds, garbage collection runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gc_interval_seconds: Option<u64>,
+
+    /// How long, in seconds, a watch with no activity is kept before
+    /// it is removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_reap_age_seconds: Option<u64>,
+
+    /// A hint for sizing internal data structures
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint_num_files_per_dir: Option<u64>,
+
+    /// A hint for sizing internal data structures
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint_num_dirs: Option<u64>,
+
+    /// The number of entries in the content hash cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_hash_max_items: Option<u64>,
+
+    /// Any other settings held by the file
+    #[serde(flatten)]
+    pub other: BTreeMap<String, serde_json::Value>,
+}
+
+impl WatchmanConfig {
+    /// Parse the contents of a `.watchmanconfig` file
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        serde_json::from_str(contents).map_err(|source| Error::Deserialize {
+            source: Box::new(source),
+            data: contents.as_bytes().to_vec(),
+        })
+    }
+
+    /// Read and parse the file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    /// Returns the config as pretty-printed JSON
+    pub fn to_json_string(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self).map_err(|source| Error::Serialize {
+            source: Box::new(source),
+        })
+    }
+
+    /// Write the config to the `.watchmanconfig` file in `dir`,
+    /// replacing any existing file.
+    /// Changes to the config of a project that is already being
+    /// watched only take effect once the watch is re-established.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<(), Error> {
+        let mut contents = self.to_json_string()?;
+        contents.push('\n');
+        std::fs::write(dir.as_ref().join(CONFIG_FILE_NAME), contents)?;
+        Ok(())
+    }
+
+    /// Check the settings for values that the server would reject or
+    /// that can't have the intended effect
+    pub fn validate(&self) -> Result<(), Error> {
+        for dir in self.ignore_dirs.iter().flatten() {
+            let relative = dir.components().all(|c| matches!(c, Component::Normal(_)));
+            if dir.as_os_str().is_empty() || !relative {
+                return Err(Error::generic(format!(
+                    "ignore_dirs entry {} must be a path relative to the root that \
+                     doesn't contain `.` or `..`",
+                    dir.display()
+                )));
+            }
+        }
+        if let Some(root_files) = &self.root_files {
+            if root_files.is_empty() && self.enforce_root_files == Some(true) {
+                return Err(Error::generic(
+                    "enforce_root_files is set but root_files is empty, so no \
+                     directory may be watched",
+                ));
+            }
+            if let Some(name) = root_files
+                .iter()
+                .find(|name| name.is_empty() || name.contains('/'))
+            {
+                return Err(Error::generic(format!(
+                    "root_files entry `{}` must be a file name",
+                    name
+                )));
+            }
+        }
+        if let Some(latency) = self.fsevents_latency {
+            if !latency.is_finite() || latency < 0.0 {
+                return Err(Error::generic(format!(
+                    "fsevents_latency must be a non-negative number of seconds, not {}",
+                    latency
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Client {
+    /// Returns the `.watchmanconfig` settings that the server loaded
+    /// for `root`.  A root without a config file has the default
+    /// settings.
+    pub async fn get_config(&self, root: &ResolvedRoot) -> Result<WatchmanConfig, Error> {
+        let response: GetConfigResponse = self
+            .generic_request(GetConfigRequest("get-config", root.root.clone()))
+            .await?;
+        Ok(response.config)
+    }
+}
+
+/// Returns the path to the `.watchmanconfig` file that governs `path`.
+///
+/// The server treats the nearest directory containing a
+/// `.watchmanconfig` file as the root of the project, so this is the
+/// file in the nearest ancestor of `path`, including `path` itself.
+/// Returns `None` if there is no such file.
+pub fn find_config<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    path.as_ref()
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_support::{request_arg, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+
+    #[test]
+    fn round_trip() {
+        let config = WatchmanConfig::parse(
+            r#"{
+                "ignore_dirs": ["buck-out", "node_modules/.cache"],
+                "settle": 20,
+                "fsevents_latency": 0.05,
+                "enforce_root_files": true,
+                "root_files": [".git"],
+                "lint": {"enable": true}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.ignore_dirs,
+            Some(vec![
+                PathBuf::from("buck-out"),
+                PathBuf::from("node_modules/.cache")
+            ])
+        );
+        assert_eq!(config.settle, Some(20));
+        assert_eq!(config.fsevents_latency, Some(0.05));
+        assert_eq!(config.gc_age_seconds, None);
+        assert!(config.other.contains_key("lint"));
+        config.validate().unwrap();
+
+        let json = config.to_json_string().unwrap();
+        assert!(!json.contains("gc_age_seconds"));
+        assert_eq!(WatchmanConfig::parse(&json).unwrap(), config);
+
+        assert!(WatchmanConfig::parse(r#"{"settle": "soon"}"#).is_err());
+        assert!(WatchmanConfig::parse("[]").is_err());
+        assert_eq!(
+            WatchmanConfig::parse("{}").unwrap(),
+            WatchmanConfig::default()
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let invalid = |config: WatchmanConfig| assert!(config.validate().is_err());
+        invalid(WatchmanConfig {
+            ignore_dirs: Some(vec!["/abs".into()]),
+            ..Default::default()
+        });
+        invalid(WatchmanConfig {
+            ignore_dirs: Some(vec!["../up".into()]),
+            ..Default::default()
+        });
+        invalid(WatchmanConfig {
+            root_files: Some(vec![]),
+            enforce_root_files: Some(true),
+            ..Default::default()
+        });
+        invalid(WatchmanConfig {
+            root_files: Some(vec!["a/b".into()]),
+            ..Default::default()
+        });
+        invalid(WatchmanConfig {
+            fsevents_latency: Some(-1.0),
+            ..Default::default()
+        });
+        WatchmanConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn save_and_find() {
+        let root = std::env::temp_dir().join(format!("watchman-config-{}", std::process::id()));
+        let nested = root.join("a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_config(&nested), None);
+
+        let config = WatchmanConfig {
+            settle: Some(100),
+            ..Default::default()
+        };
+        config.save(&root).unwrap();
+        assert_eq!(find_config(&nested), Some(root.join(CONFIG_FILE_NAME)));
+        assert_eq!(
+            WatchmanConfig::load(root.join(CONFIG_FILE_NAME)).unwrap(),
+            config
+        );
+
+        WatchmanConfig::default().save(root.join("a")).unwrap();
+        assert_eq!(
+            find_config(&nested),
+            Some(root.join("a").join(CONFIG_FILE_NAME))
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_config() {
+        let server = MockServer::new();
+        server.respond("get-config", |request| {
+            assert_eq!(request_arg(request, 1), Value::from("/repo"));
+            hashmap! {
+                "version".to_string() => Value::from(MOCK_VERSION),
+                "config".to_string() => Value::from(hashmap! {
+                    "ignore_dirs".to_string() => Value::Array(vec!["buck-out".into()]),
+                    "fsevents_latency".to_string() => Value::Real(0.05),
+                    "lint".to_string() => Value::Bool(true),
+                }),
+            }
+            .into()
+        });
+        let client = server.connect();
+        let root = client
+            .resolve_root(CanonicalPath::with_canonicalized_path("/repo".into()))
+            .await
+            .unwrap();
+
+        let config = client.get_config(&root).await.unwrap();
+        assert_eq!(config.ignore_dirs, Some(vec![PathBuf::from("buck-out")]));
+        assert_eq!(config.fsevents_latency, Some(0.05));
+        assert_eq!(config.settle, None);
+        assert_eq!(
+            config.other.get("lint"),
+            Some(&serde_json::Value::Bool(true))
+        );
+    }
+}
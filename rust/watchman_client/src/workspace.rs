@@ -0,0 +1,301 @@
+//! Work with several roots at once.
+//!
+//! Monorepo tooling often watches several distinct projects with one
+//! connection.  A `Workspace` owns a client along with the roots that
+//! it is interested in, and fans queries and subscriptions out across
+//! all of them, tagging each result with the root that produced it:
+//!
+//! ```
+//! use tokio::stream::StreamExt;
+//! use watchman_client::prelude::*;
+//! use watchman_client::workspace::Workspace;
+//! # use watchman_client::test_support::{subscription_pdu, MockServer};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let server = MockServer::new();
+//! # server.serve_files(&["main.rs"]);
+//! # let client = server.connect();
+//! # /*
+//! let client = Connector::new().connect().await?;
+//! # */
+//! let mut workspace = Workspace::new(client);
+//! for project in &["/src/app", "/src/lib"] {
+//!     workspace
+//!         .add_root(CanonicalPath::with_canonicalized_path(project.into()))
+//!         .await?;
+//! }
+//!
+//! for result in workspace.query_all::<NameOnly>(Default::default()).await? {
+//!     let files = result.result?.files.unwrap_or_default();
+//!     println!("{}: {} files", result.root.project_root().display(), files.len());
+//! }
+//!
+//! let mut changes = workspace
+//!     .subscribe_all::<NameOnly>(SubscribeRequest::default())
+//!     .await?;
+//! # let name = changes.names().next().unwrap().to_string();
+//! # server.push(subscription_pdu(&name, "c:0:1", vec!["main.rs".into()]));
+//! while let Some(item) = changes.next().await {
+//!     println!("{}: {:?}", item.root.project_root().display(), item.data?);
+//! #   break;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::prelude::*;
+use crate::subscription_group::SubscriptionGroup;
+use crate::{await_pdu, bunser, Error, QueryFieldList};
+use std::path::Path;
+
+/// The result of querying one of the roots of a `Workspace`; returned
+/// by `Workspace::query_all`
+#[derive(Debug)]
+pub struct RootQueryResult<F>
+where
+    F: std::fmt::Debug + Clone,
+{
+    /// The root that was queried
+    pub root: ResolvedRoot,
+    /// The result, as it would have been returned by `Client::query`
+    pub result: Result<QueryResult<F>, Error>,
+}
+
+/// Owns a client and a set of roots, and queries or subscribes to all
+/// of the roots at once
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    client: Client,
+    roots: Vec<ResolvedRoot>,
+}
+
+impl Workspace {
+    /// Create a workspace that has no roots, using `client`
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            roots: vec![],
+        }
+    }
+
+    /// Returns the client used by the workspace
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns the roots of the workspace, in the order that they were
+    /// added
+    pub fn roots(&self) -> &[ResolvedRoot] {
+        &self.roots
+    }
+
+    /// Resolve `path` and add it to the workspace; see
+    /// `Client::resolve_root`.
+    /// Returns the resolved root.
+    pub async fn add_root(&mut self, path: CanonicalPath) -> Result<ResolvedRoot, Error> {
+        let root = self.client.resolve_root(path).await?;
+        self.insert_root(root.clone());
+        Ok(root)
+    }
+
+    /// Add a root that has already been resolved to the workspace.
+    /// A root that is already in the workspace is replaced.
+    pub fn insert_root(&mut self, root: ResolvedRoot) {
+        match self
+            .roots
+            .iter_mut()
+            .find(|existing| existing.project_root() == root.project_root())
+        {
+            Some(existing) => *existing = root,
+            None => self.roots.push(root),
+        }
+    }
+
+    /// Remove the root whose `project_root` is `path` from the
+    /// workspace and return it
+    pub fn remove_root(&mut self, path: &Path) -> Option<ResolvedRoot> {
+        let index = self
+            .roots
+            .iter()
+            .position(|root| root.project_root() == path)?;
+        Some(self.roots.remove(index))
+    }
+
+    /// Run `query` against each of the roots, returning a result for
+    /// each root in the order that they were added.
+    /// The queries are all sent before waiting for any of the responses,
+    /// and the client's `request_timeout` applies to all of them
+    /// together.
+    /// A root that fails to be queried, perhaps because it is no longer
+    /// being watched, has an error as its result; this only fails if
+    /// the queries could not be completed at all.
+    pub async fn query_all<F>(
+        &self,
+        query: QueryRequestCommon,
+    ) -> Result<Vec<RootQueryResult<F>>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let results = async {
+            let requests: Vec<_> = self
+                .roots
+                .iter()
+                .map(|root| self.client.query_request::<F>(root, query.clone()))
+                .collect();
+            // The lock is only held while queueing the requests, as
+            // the client task delivers each response to its own
+            // requestor
+            let (pending, events) = {
+                let mut inner = self.client.inner.lock().await;
+                let mut pending = Vec::with_capacity(requests.len());
+                for request in &requests {
+                    pending.push(inner.queue_request(request).await?);
+                }
+                (pending, inner.events.clone())
+            };
+
+            let mut results = Vec::with_capacity(pending.len());
+            for ((root, request), response) in self.roots.iter().zip(&requests).zip(pending) {
+                let result = match await_pdu(&events, response, request).await {
+                    Ok(pdu) => bunser(&pdu),
+                    Err(err) => Err(err),
+                };
+                results.push(RootQueryResult {
+                    root: root.clone(),
+                    result,
+                });
+            }
+            Ok(results)
+        };
+        match self.client.request_timeout {
+            None => results.await,
+            Some(duration) => {
+                tokio::time::timeout(duration, results)
+                    .await
+                    .map_err(|_| Error::Timeout {
+                        command: "query_all".to_string(),
+                    })?
+            }
+        }
+    }
+
+    /// Subscribe to each of the roots using `request`, returning a
+    /// group whose items are tagged with the root that produced them.
+    /// Should any of the subscriptions fail, those that were already
+    /// established are canceled.
+    pub async fn subscribe_all<F>(
+        &self,
+        request: SubscribeRequest,
+    ) -> Result<SubscriptionGroup<F>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let mut group = SubscriptionGroup::new();
+        for root in &self.roots {
+            if let Err(err) = group.subscribe(&self.client, root, request.clone()).await {
+                group.cancel_all().await.ok();
+                return Err(err);
+            }
+        }
+        Ok(group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{error_response, request_arg, MockServer, MOCK_VERSION};
+    use maplit::hashmap;
+    use serde_bser::value::Value;
+    use std::path::PathBuf;
+
+    async fn workspace(server: &MockServer, projects: &[&str]) -> Workspace {
+        let mut workspace = Workspace::new(server.connect());
+        for project in projects {
+            workspace
+                .add_root(CanonicalPath::with_canonicalized_path(project.into()))
+                .await
+                .unwrap();
+        }
+        workspace
+    }
+
+    #[tokio::test]
+    async fn fans_out() {
+        let server = MockServer::new();
+        server.serve_files(&["main.rs"]);
+        let mut workspace = workspace(&server, &["/a", "/b", "/a"]).await;
+        let projects: Vec<&Path> = workspace.roots().iter().map(|r| r.project_root()).collect();
+        assert_eq!(projects, vec![Path::new("/a"), Path::new("/b")]);
+
+        let results = workspace
+            .query_all::<NameOnly>(Default::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].root.project_root(), Path::new("/b"));
+        for result in results {
+            let files = result.result.unwrap().files.unwrap();
+            assert_eq!(*files[0].name, PathBuf::from("main.rs"));
+        }
+
+        let group = workspace
+            .subscribe_all::<NameOnly>(SubscribeRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(group.len(), 2);
+
+        assert!(workspace.remove_root(Path::new("/a")).is_some());
+        assert!(workspace.remove_root(Path::new("/a")).is_none());
+        assert_eq!(workspace.roots().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reports_failures() {
+        let server = MockServer::new();
+        let workspace = workspace(&server, &["/a", "/b"]).await;
+        server.respond("query", |request| {
+            if request_arg(request, 1) == Value::from("/b") {
+                error_response("unable to resolve root /b")
+            } else {
+                hashmap! {
+                    "version".to_string() => Value::from(MOCK_VERSION),
+                    "clock".to_string() => Value::from("c:0:1"),
+                    "files".to_string() => Value::Array(vec![]),
+                }
+                .into()
+            }
+        });
+        let results = workspace
+            .query_all::<NameOnly>(Default::default())
+            .await
+            .unwrap();
+        assert!(results[0].result.is_ok());
+        assert!(matches!(
+            results[1].result,
+            Err(Error::WatchmanServerError { .. })
+        ));
+
+        // Subscribing to `/a` is undone once `/b` fails
+        server.respond("subscribe", |request| {
+            if request_arg(request, 1) == Value::from("/b") {
+                error_response("unable to resolve root /b")
+            } else {
+                hashmap! {
+                    "version".to_string() => Value::from(MOCK_VERSION),
+                    "subscribe".to_string() => request_arg(request, 2),
+                    "clock".to_string() => Value::from("c:0:0"),
+                }
+                .into()
+            }
+        });
+        assert!(workspace
+            .subscribe_all::<NameOnly>(SubscribeRequest::default())
+            .await
+            .is_err());
+        assert!(server
+            .requests()
+            .iter()
+            .any(|r| request_arg(r, 0) == Value::from("unsubscribe")));
+    }
+}
@@ -0,0 +1,116 @@
+//! This crate provides the `QueryFields` derive macro, which is
+//! re-exported by the prelude of the `watchman_client` crate; see
+//! the documentation there.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields};
+
+/// Implements `QueryFieldList` and `Deserialize` for a struct whose
+/// members are all field types, such as `NameField`, so that the
+/// struct can be used as the result type of a query.
+#[proc_macro_derive(QueryFields)]
+pub fn derive_query_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input,
+                    "QueryFields can only be derived for a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input,
+                "QueryFields can only be derived for a struct",
+            ))
+        }
+    };
+    if fields.is_empty() {
+        return Err(Error::new_spanned(
+            &input,
+            "QueryFields requires at least one field",
+        ));
+    }
+
+    let name = &input.ident;
+    let names: Vec<_> = fields.iter().map(|f| &f.ident).collect();
+    let types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+    let generics = &input.generics;
+    let where_predicates = generics.where_clause.as_ref().map(|w| &w.predicates);
+    let (impl_generics, ty_generics, _) = generics.split_for_impl();
+
+    let mut list_generics = generics.clone();
+    {
+        let where_clause = list_generics.make_where_clause();
+        for ty in &types {
+            where_clause
+                .predicates
+                .push(parse_quote!(#ty: ::watchman_client::fields::QueryFieldName));
+        }
+    }
+    let list_where_clause = &list_generics.where_clause;
+
+    let mut de_generics = generics.clone();
+    de_generics.params.insert(0, parse_quote!('de));
+    {
+        let where_clause = de_generics.make_where_clause();
+        for ty in &types {
+            where_clause
+                .predicates
+                .push(parse_quote!(#ty: ::watchman_client::__private::serde::Deserialize<'de>));
+        }
+    }
+    let (de_impl_generics, _, de_where_clause) = de_generics.split_for_impl();
+
+    // The struct is deserialized by way of a copy of it in which every
+    // member is flattened, as each field type consumes its own key
+    // from the file's object
+    Ok(quote! {
+        impl #impl_generics ::watchman_client::fields::QueryFieldList
+            for #name #ty_generics #list_where_clause
+        {
+            fn field_list() -> ::std::vec::Vec<&'static str> {
+                ::std::vec![
+                    #(<#types as ::watchman_client::fields::QueryFieldName>::field_name(),)*
+                ]
+            }
+        }
+
+        impl #de_impl_generics ::watchman_client::__private::serde::Deserialize<'de>
+            for #name #ty_generics #de_where_clause
+        {
+            fn deserialize<__D>(deserializer: __D) -> ::std::result::Result<Self, __D::Error>
+            where
+                __D: ::watchman_client::__private::serde::Deserializer<'de>,
+            {
+                #[derive(::watchman_client::__private::serde::Deserialize)]
+                #[serde(crate = "::watchman_client::__private::serde")]
+                struct __QueryFields #generics
+                where
+                    #where_predicates
+                {
+                    #(
+                        #[serde(flatten)]
+                        #names: #types,
+                    )*
+                }
+
+                let __QueryFields { #(#names),* } =
+                    <__QueryFields #ty_generics as ::watchman_client::__private::serde::Deserialize<'de>>::deserialize(deserializer)?;
+                ::std::result::Result::Ok(Self { #(#names),* })
+            }
+        }
+    })
+}